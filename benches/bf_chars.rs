@@ -0,0 +1,16 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use bf::parser::bf_chars;
+
+fn comment_heavy(c: &mut Criterion) {
+    // About a megabyte of prose, with a few commands on each line
+    let line = "This line is mostly a comment – with some “quotes” and ünïcödé – then +[->+<]\n";
+    let src = line.repeat(1024 * 1024 / line.len());
+
+    let mut group = c.benchmark_group("bf_chars");
+    group.throughput(Throughput::Bytes(src.len() as u64));
+    group.bench_function("comment heavy", |b| b.iter(|| bf_chars(black_box(&src))));
+    group.finish();
+}
+
+criterion_group!(benches, comment_heavy);
+criterion_main!(benches);
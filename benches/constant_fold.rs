@@ -0,0 +1,16 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use bf::constant_fold;
+use bf::parser::{bf_chars, program};
+
+fn alternating(c: &mut Criterion) {
+    // Runs of length one are the worst case for run counting
+    let src = "+>-<".repeat(50_000);
+    let (_, prog) = program(&src).unwrap();
+    c.bench_function("constant_fold alternating", |b| b.iter(|| constant_fold(black_box(&prog))));
+
+    let (_, prog) = program(&bf_chars(include_str!("../mandelbrot.bf"))).unwrap();
+    c.bench_function("constant_fold mandelbrot", |b| b.iter(|| constant_fold(black_box(&prog))));
+}
+
+criterion_group!(benches, alternating);
+criterion_main!(benches);
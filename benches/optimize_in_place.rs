@@ -0,0 +1,61 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use bf::{constant_fold, optimize_in_place, peephole_optimization};
+use bf::parser::{bf_chars, program};
+
+// Counts every allocation, so the fused driver's savings show up as a number and not
+// only as time
+struct Counting;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn allocs<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCS.load(Ordering::Relaxed);
+    let out = f();
+    (out, ALLOCS.load(Ordering::Relaxed) - before)
+}
+
+fn large_program(c: &mut Criterion) {
+    // About 4MB of commands
+    let src = bf_chars(include_str!("../mandelbrot.bf")).repeat(400);
+    let (_, prog) = program(&src).unwrap();
+
+    let (separate, separate_allocs) = allocs(|| peephole_optimization(constant_fold(&prog)));
+    let mut fused = prog.clone();
+    let ((), fused_allocs) = allocs(|| optimize_in_place(&mut fused));
+    assert_eq!(fused, separate);
+    println!("{} bytes of source: separate passes made {} allocations, optimize_in_place {}",
+        src.len(), separate_allocs, fused_allocs);
+
+    let mut group = c.benchmark_group("optimize_in_place");
+    group.sample_size(10);
+    group.bench_function("separate passes", |b| b.iter(|| peephole_optimization(constant_fold(black_box(&prog)))));
+    group.bench_function("fused", |b| b.iter_batched(|| prog.clone(), |mut s| {
+        optimize_in_place(&mut s);
+        s
+    }, BatchSize::LargeInput));
+    group.finish();
+}
+
+criterion_group!(benches, large_program);
+criterion_main!(benches);
@@ -0,0 +1,58 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use inkwell::context::Context as LlvmContext;
+use inkwell::OptimizationLevel;
+use bf::jit::{probe_jit, CodeGen};
+use bf::parser::program;
+use bf::{optimize, Context, Statement};
+
+// The same program with every `SearchZero` stepping a cell at a time, as a `SearchValue`
+// for zero does, to compare against the jump to the next zero
+fn stepping(stmts: &[Statement]) -> Vec<Statement> {
+    stmts.iter()
+        .map(|s| match s {
+            Statement::SearchZero { stride } => Statement::SearchValue { stride: *stride, target: 0 },
+            Statement::Loop(l) => Statement::Loop(stepping(l)),
+            s => s.clone(),
+        })
+        .collect()
+}
+
+fn long_scans(c: &mut Criterion) {
+    // 30,000 nonzero cells between two zeros, crossed with `[<]` and `[>]` a hundred times
+    let src = format!(">{}{}", "+>".repeat(30_000), "<[<]>[>]".repeat(100));
+    let (_, prog) = program(&src).unwrap();
+    let fused = optimize(&prog);
+    assert!(fused.contains(&Statement::SearchZero { stride: -1 }));
+    let slow = stepping(&fused);
+
+    let mut group = c.benchmark_group("search_zero");
+    group.bench_function("stepping", |b| b.iter(|| Context::with_state(vec![0; 65536]).exec_many(&slow).unwrap()));
+    group.bench_function("skip_to_zero", |b| b.iter(|| Context::with_state(vec![0; 65536]).exec_many(&fused).unwrap()));
+    group.finish();
+}
+
+fn jit_scans(c: &mut Criterion) {
+    if let Err(e) = probe_jit() {
+        eprintln!("skipping search_zero_jit: {}", e);
+        return;
+    }
+
+    // Only `[>]` goes through `memchr`, so the way back is a plain move
+    let src = format!(">{}{}", "+>".repeat(30_000), format!("{}[>]", "<".repeat(30_000)).repeat(100));
+    let (_, prog) = program(&src).unwrap();
+    let fused = optimize(&prog);
+    assert!(fused.contains(&Statement::SearchZero { stride: 1 }));
+    let slow = stepping(&fused);
+
+    let llvm = LlvmContext::create();
+    let mut group = c.benchmark_group("search_zero_jit");
+    for (name, stmts) in &[("stepping", &slow), ("memchr", &fused)] {
+        let gen = CodeGen::new(&llvm, OptimizationLevel::Default);
+        let func = gen.jit_bf(stmts).unwrap();
+        group.bench_function(*name, |b| b.iter(|| unsafe { func.call(std::ptr::null_mut(), 0, std::ptr::null_mut()) }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, long_scans, jit_scans);
+criterion_main!(benches);
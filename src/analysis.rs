@@ -0,0 +1,505 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use crate::bf::{optimize, Statement};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProgramStats {
+    /// Total statements, counting loop bodies
+    pub statements: usize,
+    pub loops: usize,
+    pub max_depth: usize,
+    pub reads_input: bool,
+    pub writes_output: bool,
+    /// Whether any loop moves the pointer by a data-dependent amount
+    pub unbounded_scans: bool,
+    /// Number of statements of each kind, by `Statement::name`
+    pub ops: BTreeMap<&'static str, usize>,
+}
+
+impl ProgramStats {
+    /// `ops`, most common first
+    pub fn histogram(&self) -> Vec<(&'static str, usize)> {
+        let mut ops: Vec<_> = self.ops.iter().map(|(name, n)| (*name, *n)).collect();
+        ops.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        ops
+    }
+}
+
+pub fn program_stats(stmts: impl AsRef<[Statement]>) -> ProgramStats {
+    let mut stats = ProgramStats::default();
+    tally(stmts.as_ref(), 0, &mut stats);
+    stats
+}
+
+fn tally(stmts: &[Statement], depth: usize, stats: &mut ProgramStats) {
+    stats.max_depth = stats.max_depth.max(depth);
+    for s in stmts {
+        stats.statements += 1;
+        *stats.ops.entry(s.name()).or_insert(0) += 1;
+        match s {
+            Statement::In => stats.reads_input = true,
+            Statement::Out => stats.writes_output = true,
+            Statement::SearchZero { .. } | Statement::SearchValue { .. } | Statement::ClearScan { .. } => stats.unbounded_scans = true,
+            Statement::ReadBlock { .. } => {
+                stats.reads_input = true;
+                stats.unbounded_scans = true;
+            }
+            Statement::WriteBlock { .. } => {
+                stats.writes_output = true;
+                stats.unbounded_scans = true;
+            }
+            Statement::Loop(l) => {
+                stats.loops += 1;
+                if net_movement(l) != Some(0) {
+                    stats.unbounded_scans = true;
+                }
+                tally(l, depth + 1, stats);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Net pointer movement of `stmts`, or `None` if it depends on the tape contents.
+pub fn net_movement(stmts: impl AsRef<[Statement]>) -> Option<isize> {
+    stmts.as_ref().iter().try_fold(0isize, |pos, s| match s {
+        Statement::Next(_) | Statement::Prev(_) | Statement::Move(_) => s.movement().map(|n| pos + n),
+        Statement::SearchZero { .. } | Statement::SearchValue { .. } | Statement::ClearScan { .. }
+            | Statement::WriteBlock { .. } | Statement::ReadBlock { .. } => None,
+        Statement::Loop(l) if net_movement(l)? != 0 => None,
+        _ => Some(pos),
+    })
+}
+
+/// Number of cells the program can touch, assuming it starts at cell 0, or `None` if
+/// the pointer range depends on the tape contents (scans or unbalanced loops).
+pub fn estimated_tape_size(stmts: impl AsRef<[Statement]>) -> Option<usize> {
+    let mut pos = 0isize;
+    let mut max = 0isize;
+    pointer_range(stmts.as_ref(), &mut pos, &mut max)?;
+    Some(max as usize + 1)
+}
+
+/// Why `fits_in_tape` couldn't accept a program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapeOverflow {
+    /// The program can touch this many cells
+    Exceeds(usize),
+    /// The pointer range depends on the tape contents, so it may or may not fit
+    MaybeUnbounded,
+}
+
+/// Pre-flight check that a program starting at cell 0 stays within `cells` cells.
+pub fn fits_in_tape(stmts: impl AsRef<[Statement]>, cells: usize) -> Result<(), TapeOverflow> {
+    match estimated_tape_size(stmts) {
+        Some(n) if n <= cells => Ok(()),
+        Some(n) => Err(TapeOverflow::Exceeds(n)),
+        None => Err(TapeOverflow::MaybeUnbounded),
+    }
+}
+
+fn pointer_range(stmts: &[Statement], pos: &mut isize, max: &mut isize) -> Option<()> {
+    for s in stmts {
+        match s {
+            // Moving left of cell 0 clamps, as in the interpreters
+            Statement::Next(_) | Statement::Prev(_) | Statement::Move(_) => {
+                *pos = (*pos + s.movement()?).max(0);
+                *max = (*max).max(*pos);
+            }
+            Statement::MoveValue { offset } | Statement::AddOffset { offset, .. } | Statement::Copy { offset } | Statement::IncAt { offset, .. } => *max = (*max).max(*pos + *offset),
+            Statement::LinearCombine { dest_offset, terms } => {
+                let furthest = terms.iter().map(|&(_, o)| o).fold(*dest_offset, isize::max);
+                *max = (*max).max(*pos + furthest);
+            }
+            Statement::MulAddMany { targets } => {
+                let furthest = targets.iter().map(|&(o, _)| o).fold(0, isize::max);
+                *max = (*max).max(*pos + furthest);
+            }
+            Statement::SearchZero { .. } | Statement::SearchValue { .. } | Statement::ClearScan { .. }
+                | Statement::WriteBlock { .. } | Statement::ReadBlock { .. } => return None,
+            Statement::Loop(l) => {
+                let start = *pos;
+                pointer_range(l, pos, max)?;
+                if *pos != start {
+                    return None;
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(())
+}
+
+/// What is statically known about the current cell
+#[derive(Debug, Clone, Copy)]
+enum CellValue {
+    Unknown,
+    NonZero,
+    Const(i64),
+}
+
+impl CellValue {
+    fn is_nonzero(self) -> bool {
+        // Nonzero modulo 256 is nonzero for every cell width
+        match self {
+            CellValue::NonZero => true,
+            CellValue::Const(v) => v.rem_euclid(256) != 0,
+            CellValue::Unknown => false,
+        }
+    }
+
+    fn add(self, n: i64) -> Self {
+        match self {
+            CellValue::Const(v) => CellValue::Const(v.wrapping_add(n)),
+            _ => CellValue::Unknown,
+        }
+    }
+}
+
+/// Whether `stmts` might write the cell at `target` relative to where they start.
+/// Anything that moves left of the start is treated as a possible write, since
+/// clamping at cell 0 makes relative positions unreliable.
+fn may_write(stmts: &[Statement], target: isize) -> bool {
+    let mut pos = 0isize;
+    for s in stmts {
+        match s {
+            Statement::Next(_) | Statement::Prev(_) | Statement::Move(_) => {
+                pos += s.movement().expect("a move");
+                if pos < 0 {
+                    return true;
+                }
+            }
+            Statement::Inc(_) | Statement::Dec(_) | Statement::In | Statement::Clear | Statement::SetConst(_)
+                if pos == target => return true,
+            Statement::MoveValue { offset } | Statement::AddOffset { offset, .. } if pos == target || pos + *offset == target => return true,
+            Statement::Copy { offset } | Statement::IncAt { offset, .. } if pos + *offset == target => return true,
+            Statement::LinearCombine { dest_offset, terms }
+                if pos + dest_offset == target || terms.iter().any(|&(_, o)| pos + o == target) => return true,
+            Statement::MulAddMany { targets } if pos == target || targets.iter().any(|&(o, _)| pos + o == target) => return true,
+            Statement::SearchZero { .. } | Statement::SearchValue { .. } | Statement::ClearScan { .. }
+                | Statement::WriteBlock { .. } | Statement::ReadBlock { .. } => return true,
+            Statement::Loop(l) => {
+                if pos == target || net_movement(l) != Some(0) || may_write(l, target - pos) {
+                    return true;
+                }
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+// Number of source characters a parsed (unoptimized) statement was read from
+fn source_len(s: &Statement) -> usize {
+    match s {
+        Statement::Loop(l) => 2 + l.iter().map(source_len).sum::<usize>(),
+        // Directives are comments as far as `bf_chars` is concerned
+        Statement::Assert { .. } | Statement::Halt => 0,
+        _ => 1,
+    }
+}
+
+/// Offsets of code that can never run because it follows a loop that is always
+/// entered and never changes its own cell.
+///
+/// `stmts` must be parser output (one statement per character, not optimized), and
+/// the offsets are into the comment-free text it was parsed from. One offset is
+/// reported per block: the first dead statement, as everything after it is dead too.
+pub fn unreachable_code(stmts: impl AsRef<[Statement]>) -> Vec<usize> {
+    let mut out = Vec::new();
+    find_unreachable(stmts.as_ref(), 0, CellValue::Const(0), &mut out);
+    out
+}
+
+// Returns whether the block never finishes
+fn find_unreachable(stmts: &[Statement], mut offset: usize, mut cur: CellValue, out: &mut Vec<usize>) -> bool {
+    for (i, s) in stmts.iter().enumerate() {
+        let body_diverges = match s {
+            Statement::Loop(l) => find_unreachable(l, offset + 1, CellValue::NonZero, out),
+            _ => false,
+        };
+        offset += source_len(s);
+
+        if diverges(s, &mut cur, body_diverges) {
+            if i + 1 < stmts.len() {
+                out.push(offset);
+            }
+            return true;
+        }
+    }
+    false
+}
+
+/// Removes the code reported by `unreachable_code`.
+pub fn strip_unreachable(stmts: impl AsRef<[Statement]>) -> Vec<Statement> {
+    let mut out = stmts.as_ref().to_vec();
+    strip_block(&mut out, CellValue::Const(0));
+    out
+}
+
+fn strip_block(stmts: &mut Vec<Statement>, mut cur: CellValue) -> bool {
+    for i in 0..stmts.len() {
+        let body_diverges = match &mut stmts[i] {
+            Statement::Loop(l) => strip_block(l, CellValue::NonZero),
+            _ => false,
+        };
+
+        if diverges(&stmts[i], &mut cur, body_diverges) {
+            stmts.truncate(i + 1);
+            return true;
+        }
+    }
+    false
+}
+
+// Steps the known current-cell value past `s` and returns whether `s` never finishes
+fn diverges(s: &Statement, cur: &mut CellValue, body_diverges: bool) -> bool {
+    match s {
+        Statement::Loop(l) => {
+            let entered = cur.is_nonzero();
+            *cur = CellValue::Const(0);
+            entered && (body_diverges || (net_movement(l) == Some(0) && !may_write(l, 0)))
+        }
+        Statement::Inc(n) => {
+            *cur = cur.add(*n as i64);
+            false
+        }
+        Statement::Dec(n) => {
+            *cur = cur.add(-(*n as i64));
+            false
+        }
+        Statement::Clear | Statement::SearchZero { .. } | Statement::ClearScan { .. } | Statement::MoveValue { .. }
+            | Statement::WriteBlock { .. } | Statement::ReadBlock { .. } | Statement::MulAddMany { .. } => {
+            *cur = CellValue::Const(0);
+            false
+        }
+        Statement::SearchValue { target, .. } => {
+            *cur = CellValue::Const(*target as i64);
+            false
+        }
+        Statement::SetConst(n) => {
+            *cur = CellValue::Const(*n);
+            false
+        }
+        Statement::LinearCombine { dest_offset, terms } => {
+            if *dest_offset == 0 {
+                *cur = CellValue::Unknown;
+            } else if terms.iter().any(|&(_, o)| o == 0) {
+                *cur = CellValue::Const(0);
+            }
+            false
+        }
+        Statement::InfiniteLoopIfNonZero => {
+            let entered = cur.is_nonzero();
+            *cur = CellValue::Const(0);
+            entered
+        }
+        Statement::Out | Statement::AddOffset { .. } | Statement::Copy { .. } | Statement::Assert { .. }
+            | Statement::Breakpoint | Statement::IncAt { .. } => false,
+        _ => {
+            *cur = CellValue::Unknown;
+            false
+        }
+    }
+}
+
+/// Loops that look like they take quadratic time: each iteration runs a scan (`[<]`,
+/// `[>]` or a fused `SearchZero`, `SearchValue` or `ClearScan`) and also moves the
+/// pointer itself, so the distance scanned tends to grow with every iteration, as in
+/// `,[[<]>[>],]`. This is a structural heuristic, not a proof either way.
+///
+/// Only the outermost such loop is reported. As with `unreachable_code`, `stmts` must be
+/// parser output, and the ranges cover the whole loop in the comment-free text.
+pub fn detect_quadratic_scans(stmts: impl AsRef<[Statement]>) -> Vec<Range<usize>> {
+    let mut out = Vec::new();
+    find_quadratic(stmts.as_ref(), 0, &mut out);
+    out
+}
+
+fn find_quadratic(stmts: &[Statement], mut offset: usize, out: &mut Vec<Range<usize>>) {
+    for s in stmts {
+        if let Statement::Loop(l) = s {
+            if !is_scan(s) {
+                let moves = l.iter().any(|s| s.movement().is_some());
+                if moves && contains_scan(l) {
+                    out.push(offset..offset + source_len(s));
+                } else {
+                    find_quadratic(l, offset + 1, out);
+                }
+            }
+        }
+        offset += source_len(s);
+    }
+}
+
+// A loop that only moves the pointer is a scan until it finds a zero
+fn is_scan(s: &Statement) -> bool {
+    match s {
+        Statement::SearchZero { .. } | Statement::SearchValue { .. } | Statement::ClearScan { .. } => true,
+        Statement::Loop(l) => {
+            l.iter().all(|s| s.movement().is_some())
+                && net_movement(l).map_or(false, |n| n != 0)
+        }
+        _ => false,
+    }
+}
+
+fn contains_scan(stmts: &[Statement]) -> bool {
+    stmts.iter().any(|s| match s {
+        Statement::Loop(l) => is_scan(s) || contains_scan(l),
+        _ => is_scan(s),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapeSize {
+    Cells(usize),
+    Unbounded,
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for TapeSize {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self {
+            TapeSize::Cells(n) => s.serialize_u64(*n as u64),
+            TapeSize::Unbounded => s.serialize_str("unbounded"),
+        }
+    }
+}
+
+/// Summary of a parsed program for tooling (`bf --info`).
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ProgramInfo {
+    pub statements: usize,
+    pub max_nesting: usize,
+    pub estimated_tape_size: TapeSize,
+    pub reads_input: bool,
+    pub writes_output: bool,
+    pub unbounded_scans: bool,
+    pub optimized_statements: usize,
+    /// Fraction of statements removed by `optimize`
+    pub reduction_ratio: f64,
+}
+
+impl ProgramInfo {
+    pub fn new(stmts: impl AsRef<[Statement]>) -> Self {
+        let stmts = stmts.as_ref();
+        let stats = program_stats(stmts);
+        let optimized = program_stats(optimize(stmts));
+
+        let reduction_ratio = if stats.statements == 0 {
+            0.0
+        } else {
+            1.0 - optimized.statements as f64 / stats.statements as f64
+        };
+
+        ProgramInfo {
+            statements: stats.statements,
+            max_nesting: stats.max_depth,
+            estimated_tape_size: estimated_tape_size(stmts).map_or(TapeSize::Unbounded, TapeSize::Cells),
+            reads_input: stats.reads_input,
+            writes_output: stats.writes_output,
+            unbounded_scans: stats.unbounded_scans,
+            optimized_statements: optimized.statements,
+            reduction_ratio,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::program;
+
+    #[test]
+    fn stats_and_tape_size() {
+        let (_, s) = program(">>+[<[->+<]>-]<<.").unwrap();
+        let stats = program_stats(&s);
+        assert_eq!(stats.statements, 15);
+        assert_eq!(stats.loops, 2);
+        assert_eq!(stats.max_depth, 2);
+        assert!(stats.writes_output);
+        assert!(!stats.reads_input);
+        assert!(!stats.unbounded_scans);
+        assert_eq!(estimated_tape_size(&s), Some(3));
+
+        let (_, s) = program("+[>,]").unwrap();
+        assert!(program_stats(&s).unbounded_scans);
+        assert_eq!(estimated_tape_size(&s), None);
+    }
+
+    #[test]
+    fn op_histogram() {
+        let (_, s) = program("++>+++<[->+<]").unwrap();
+        assert_eq!(program_stats(optimize(&s)).histogram(), vec![
+            ("add_offset", 1), ("clear", 1), ("inc", 1), ("inc_at", 1),
+        ]);
+
+        // Loop bodies are counted too
+        let (_, s) = program("+[>[-]<-]").unwrap();
+        assert_eq!(program_stats(optimize(&s)).histogram(), vec![
+            ("move", 2), ("clear", 1), ("dec", 1), ("inc", 1), ("loop", 1),
+        ]);
+    }
+
+    #[test]
+    fn tape_fit() {
+        let (_, s) = program(">>>+<<[->+<]").unwrap();
+        assert_eq!(fits_in_tape(&s, 4), Ok(()));
+        assert_eq!(fits_in_tape(&s, 3), Err(TapeOverflow::Exceeds(4)));
+
+        let (_, s) = program("+[>+]").unwrap();
+        assert_eq!(fits_in_tape(&s, 64 * 1024), Err(TapeOverflow::MaybeUnbounded));
+    }
+
+    #[test]
+    fn unreachable_after_infinite_loop() {
+        let (_, s) = program("+[].>.").unwrap();
+        assert_eq!(unreachable_code(&s), vec![3]);
+        assert_eq!(strip_unreachable(&s), vec![Statement::Inc(1), Statement::Loop(vec![])]);
+
+        // The loop body never touches its own cell, so the outer loop can't exit either
+        let (_, s) = program("+[>+<].").unwrap();
+        assert_eq!(unreachable_code(&s), vec![6]);
+
+        // Not provably entered, or it changes its own cell
+        let (_, s) = program(",[].+[-].[>]+[<+>].").unwrap();
+        assert!(unreachable_code(&s).is_empty());
+    }
+
+    #[test]
+    fn quadratic_scans() {
+        // Walks back to the start and out to the end again for every byte read
+        let (_, s) = program("+>,[[<]>[>],]").unwrap();
+        assert_eq!(detect_quadratic_scans(&s), vec![3..13]);
+        // Inside another loop, only the outer one is reported
+        let (_, s) = program("+[>,[[<]>[>],]]").unwrap();
+        assert_eq!(detect_quadratic_scans(&s), vec![1..15]);
+
+        // Scans outside any loop, and loops that move without scanning
+        let (_, s) = program(",[>,]<[<]>[.>]+[->+<]").unwrap();
+        assert!(detect_quadratic_scans(&s).is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn info_json_shape() {
+        let (_, s) = program("+++[->+<]>.").unwrap();
+        let json = serde_json::to_value(ProgramInfo::new(&s)).unwrap();
+        assert_eq!(json, serde_json::json!({
+            "statements": 10,
+            "max_nesting": 1,
+            "estimated_tape_size": 2,
+            "reads_input": false,
+            "writes_output": true,
+            "unbounded_scans": false,
+            "optimized_statements": 5,
+            "reduction_ratio": 0.5,
+        }));
+
+        let (_, s) = program("[>]").unwrap();
+        let json = serde_json::to_value(ProgramInfo::new(&s)).unwrap();
+        assert_eq!(json["estimated_tape_size"], "unbounded");
+    }
+}
@@ -1,10 +1,22 @@
 use std::io;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Debug, Display, Formatter};
+use std::ops::Range;
 use crate::bf::Error::OutOfBounds;
-use std::io::Read;
+use crate::bf::cell::Cell;
+use crate::bf::input::{InputSource, EofMode, PeekableInput};
+use crate::bf::output::OutputSink;
+use crate::bf::trace::{IoDirection, IoEvent, TraceRecord, TraceRecorder};
+use crate::span::Span;
 
 pub mod panicking;
+pub mod cell;
+pub mod input;
+pub mod output;
+pub mod trace;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Statement {
     Next(usize),
     Prev(usize),
@@ -17,8 +29,42 @@ pub enum Statement {
     Clear,
     // [-], [+]
     // Take value at current position, add it to value at other position
-    AddOffset { mul: i64, offset: usize },
+    AddOffset { mul: i64, offset: isize },
     SearchZero { stride: isize },
+    // -t[+t>-t]+t, move by stride until the current cell is target (truncated to the cell width)
+    SearchValue { stride: isize, target: u64 },
+    // Add value at current position to value at other position, keeping the current value
+    Copy { offset: isize },
+    // [[-]>], clear cells while moving by stride until a zero cell is reached
+    ClearScan { stride: isize },
+    // `@assert cellN == V` directive, pos is its byte offset in the source
+    Assert { cell: usize, expected: i64, pos: usize },
+    // [], never finishes unless the current cell is already zero
+    InfiniteLoopIfNonZero,
+    // [->+<] into a cell known to be zero: set the other cell to the current value, then clear
+    // the current one. The old value of the other cell is overwritten, not added to.
+    MoveValue { offset: isize },
+    // [.>], write cells while moving by stride until a zero cell is reached
+    WriteBlock { stride: isize },
+    // [,>], read into the cell and move by stride until a zero cell is reached
+    ReadBlock { stride: isize },
+    // `@halt` directive, stops the program where it is
+    Halt,
+    // Multiply loops from several cells into one: cell[dest_offset] += mul * cell[offset] for
+    // each term in order, clearing each source cell. No source is the destination.
+    LinearCombine { dest_offset: isize, terms: Vec<(i64, isize)> },
+    // `#`, prints the pointer and the cells around it to stderr
+    Breakpoint,
+    // Runs of `>` or `<` once optimized: moves right by a positive amount, or left by a
+    // negative one, stopping at cell 0 like `Prev`
+    Move(isize),
+    // Multiply loops adding to several cells, like [->+>+<<]: cell[offset] += mul * the current
+    // cell for each (offset, mul) target, reading the current cell once, then clear it
+    MulAddMany { targets: Vec<(isize, i64)> },
+    // [-]+++, set the current cell to the constant, reduced to the cell width when it runs
+    SetConst(i64),
+    // >>++<<, add the amount to the cell at offset without moving the pointer
+    IncAt { offset: isize, amount: i64 },
 }
 
 impl Statement {
@@ -30,6 +76,16 @@ impl Statement {
         matches!(self, Statement::Prev(_))
     }
 
+    /// How far `Next`, `Prev` or `Move` moves the pointer, negative for left
+    pub fn movement(&self) -> Option<isize> {
+        match self {
+            Statement::Next(n) => Some(*n as isize),
+            Statement::Prev(n) => Some(-(*n as isize)),
+            Statement::Move(n) => Some(*n),
+            _ => None,
+        }
+    }
+
     pub fn is_inc(&self) -> bool {
         matches!(self, Statement::Inc(_))
     }
@@ -37,297 +93,3160 @@ impl Statement {
     pub fn is_dec(&self) -> bool {
         matches!(self, Statement::Dec(_))
     }
+
+    /// Short name of the kind of statement, ignoring its operands
+    pub fn name(&self) -> &'static str {
+        match self {
+            Statement::Next(_) => "next",
+            Statement::Prev(_) => "prev",
+            Statement::Inc(_) => "inc",
+            Statement::Dec(_) => "dec",
+            Statement::Out => "out",
+            Statement::In => "in",
+            Statement::Loop(_) => "loop",
+            Statement::Clear => "clear",
+            Statement::AddOffset { .. } => "add_offset",
+            Statement::SearchZero { .. } => "search_zero",
+            Statement::SearchValue { .. } => "search_value",
+            Statement::Copy { .. } => "copy",
+            Statement::ClearScan { .. } => "clear_scan",
+            Statement::Assert { .. } => "assert",
+            Statement::InfiniteLoopIfNonZero => "infinite_loop_if_nonzero",
+            Statement::MoveValue { .. } => "move_value",
+            Statement::WriteBlock { .. } => "write_block",
+            Statement::ReadBlock { .. } => "read_block",
+            Statement::Halt => "halt",
+            Statement::LinearCombine { .. } => "linear_combine",
+            Statement::Breakpoint => "breakpoint",
+            Statement::Move(_) => "move",
+            Statement::MulAddMany { .. } => "mul_add_many",
+            Statement::SetConst(_) => "set_const",
+            Statement::IncAt { .. } => "inc_at",
+        }
+    }
 }
 
 pub fn optimize(stmts: impl AsRef<[Statement]>) -> Vec<Statement> {
-    peephole_optimization(constant_fold(stmts))
+    optimize_with_stats(stmts).0
 }
 
-pub fn constant_fold(stmts: impl AsRef<[Statement]>) -> Vec<Statement> {
-    let mut out = Vec::new();
+/// `optimize`, recognizing only the enabled peephole patterns
+pub fn optimize_with(stmts: impl AsRef<[Statement]>, patterns: Patterns) -> Vec<Statement> {
+    optimize_counting(stmts, patterns).0
+}
+
+/// `optimize`, also saying what it did
+pub fn optimize_with_stats(stmts: impl AsRef<[Statement]>) -> (Vec<Statement>, OptStats) {
+    optimize_counting(stmts, Patterns::default())
+}
+
+/// `optimize_with`, also saying what it did
+pub fn optimize_counting(stmts: impl AsRef<[Statement]>, patterns: Patterns) -> (Vec<Statement>, OptStats) {
     let stmts = stmts.as_ref();
+    let mut stats = OptStats::default();
+    let (out, _) = fixpoint(stmts, patterns, MAX_ROUNDS, &mut stats);
+    stats.before = crate::analysis::program_stats(stmts).statements;
+    stats.after = crate::analysis::program_stats(&out).statements;
+    (out, stats)
+}
 
-    let mut idx = 0usize;
-    while idx < stmts.len() {
-        if let Statement::Loop(l) = &stmts[idx] {
-            out.push(
-                Statement::Loop(constant_fold(l))
-            );
-            idx += 1;
-            continue;
-        } else if matches!(&stmts[idx], Statement::In | Statement::Out | Statement::Clear) {
-            out.push(stmts[idx].clone());
-            idx += 1;
-            continue;
-        }
+/// What the optimizer did to a program. Statement counts include loop bodies.
+///
+/// Rewrites are counted as the passes make them, so a `Clear` that a later round turns
+/// into part of something else still counts, and the counts can exceed what's left of
+/// each statement in the result.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OptStats {
+    pub before: usize,
+    pub after: usize,
+    /// `Clear`s made
+    pub clears: usize,
+    /// Multiply loops rewritten, as `AddOffset`s or `MulAddMany`s
+    pub add_offsets: usize,
+    /// `SearchZero`s made
+    pub search_zeros: usize,
+    /// `SetConst`s made
+    pub set_consts: usize,
+    /// `IncAt`s made
+    pub inc_ats: usize,
+    /// `LinearCombine`s made
+    pub linear_combines: usize,
+    /// Runs of two or more `+`, `-`, `>` or `<` the folding passes merged
+    pub folded_runs: usize,
+}
+
+impl OptStats {
+    // Credits the statements of each counted kind a pass added, going from `before` to `after`
+    fn add_rewrites(&mut self, before: [usize; 6], after: [usize; 6]) {
+        let made = |i: usize| after[i].saturating_sub(before[i]);
+        self.clears += made(0);
+        self.add_offsets += made(1);
+        self.search_zeros += made(2);
+        self.set_consts += made(3);
+        self.inc_ats += made(4);
+        self.linear_combines += made(5);
+    }
+}
 
-        {
-            let next_cnt = stmts[idx..].iter()
-                .take_while(|s| s.is_next())
-                .count();
+impl Display for OptStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "optimized {} -> {} statements: clears={} add_offsets={} search_zeros={} set_consts={} inc_ats={} linear_combines={} folded_runs={}",
+            self.before, self.after, self.clears, self.add_offsets, self.search_zeros, self.set_consts, self.inc_ats, self.linear_combines, self.folded_runs)
+    }
+}
 
-            if next_cnt != 0 {
-                out.push(Statement::Next(next_cnt));
-                idx += next_cnt;
+// Statements of each kind `OptStats` counts, in `stmts` and loop bodies, in the order
+// `OptStats::add_rewrites` takes them
+fn rewrite_counts(stmts: &[Statement], counts: &mut [usize; 6]) {
+    for s in stmts {
+        let kind = match s {
+            Statement::Clear => 0,
+            Statement::AddOffset { .. } | Statement::MulAddMany { .. } => 1,
+            Statement::SearchZero { .. } => 2,
+            Statement::SetConst(_) => 3,
+            Statement::IncAt { .. } => 4,
+            Statement::LinearCombine { .. } => 5,
+            Statement::Loop(l) => {
+                rewrite_counts(l, counts);
                 continue;
             }
+            _ => continue,
+        };
+        counts[kind] += 1;
+    }
+}
+
+/// How many rounds of passes `optimize_with` runs at most
+pub const MAX_ROUNDS: usize = 8;
+
+/// Runs every pass in order, over and over, until a round leaves the program as it was
+/// or `max_rounds` rounds have run. One pass can make room for another that ran before
+/// it: a loop `dead-loops` removes can leave two runs of `+` next to each other.
+///
+/// Returns the program and the number of rounds run, which counts the final round that
+/// changed nothing unless the cap was hit first.
+pub fn optimize_to_fixpoint(stmts: impl AsRef<[Statement]>, patterns: Patterns, max_rounds: usize) -> (Vec<Statement>, usize) {
+    fixpoint(stmts.as_ref(), patterns, max_rounds, &mut OptStats::default())
+}
+
+fn fixpoint(stmts: &[Statement], patterns: Patterns, max_rounds: usize, stats: &mut OptStats) -> (Vec<Statement>, usize) {
+    let passes = counting_passes(patterns);
+    let mut cur = stmts.to_vec();
+    let mut counts = [0; 6];
+    rewrite_counts(&cur, &mut counts);
+    for round in 1..=max_rounds {
+        let next = passes.iter().fold(cur.clone(), |s, (_, pass)| {
+            let out = pass(&s, &mut stats.folded_runs);
+            let mut after = [0; 6];
+            rewrite_counts(&out, &mut after);
+            stats.add_rewrites(counts, after);
+            counts = after;
+            out
+        });
+        if next == cur {
+            return (cur, round);
         }
+        cur = next;
+    }
+    (cur, max_rounds)
+}
 
-        {
-            let prev_cnt = stmts[idx..].iter()
-                .take_while(|s| s.is_prev())
-                .count();
+/// An optimization pass and its name
+pub type Pass = (&'static str, Box<dyn Fn(&[Statement]) -> Vec<Statement>>);
 
-            if prev_cnt != 0 {
-                out.push(Statement::Prev(prev_cnt));
-                idx += prev_cnt;
-                continue;
+// A pass that also adds the runs it folds to a count
+type CountingPass = (&'static str, Box<dyn Fn(&[Statement], &mut usize) -> Vec<Statement>>);
+
+/// The passes `optimize_with` runs, in order
+pub fn passes(patterns: Patterns) -> Vec<Pass> {
+    counting_passes(patterns).into_iter()
+        .map(|(name, pass)| -> Pass { (name, Box::new(move |s: &[Statement]| pass(s, &mut 0))) })
+        .collect()
+}
+
+fn counting_passes(patterns: Patterns) -> Vec<CountingPass> {
+    vec![
+        ("constant-fold", Box::new(|s: &[Statement], runs: &mut usize| constant_fold_counting(s, runs))),
+        ("net-fold", Box::new(move |s: &[Statement], runs: &mut usize| {
+            if patterns.net_fold { net_fold_counting(s, runs) } else { s.to_vec() }
+        })),
+        ("copy-restore", Box::new(|s: &[Statement], _: &mut usize| copy_restore(s))),
+        ("peephole", Box::new(move |s: &[Statement], _: &mut usize| peephole_with(s, patterns))),
+        ("search-value", Box::new(move |s: &[Statement], _: &mut usize| {
+            if patterns.search_value { search_values(s) } else { s.to_vec() }
+        })),
+        ("move-value", Box::new(|s: &[Statement], _: &mut usize| move_values(s))),
+        ("linear-combine", Box::new(move |s: &[Statement], _: &mut usize| {
+            if patterns.linear_combine { linear_combines(s) } else { s.to_vec() }
+        })),
+        ("inc-at", Box::new(move |s: &[Statement], _: &mut usize| {
+            if patterns.inc_at { inc_ats(s) } else { s.to_vec() }
+        })),
+        ("dead-loops", Box::new(move |s: &[Statement], _: &mut usize| {
+            if patterns.dead_loops { dead_loops(s) } else { s.to_vec() }
+        })),
+        ("empty-loops", Box::new(|s: &[Statement], _: &mut usize| empty_loops(s))),
+    ]
+}
+
+/// Removes empty loops where the current cell is known to be zero, e.g. right after
+/// another loop, and turns the rest into `InfiniteLoopIfNonZero` so the hang they
+/// cause if entered is explicit.
+pub fn empty_loops(stmts: impl AsRef<[Statement]>) -> Vec<Statement> {
+    // Nothing is known about the starting tape
+    empty_loops_in(stmts.as_ref(), false)
+}
+
+fn empty_loops_in(stmts: &[Statement], mut zero: bool) -> Vec<Statement> {
+    let mut out = Vec::with_capacity(stmts.len());
+    for s in stmts {
+        match s {
+            Statement::Loop(l) if l.is_empty() => {
+                if !zero {
+                    out.push(Statement::InfiniteLoopIfNonZero);
+                }
             }
+            Statement::Loop(l) => out.push(Statement::Loop(empty_loops_in(l, false))),
+            s => out.push(s.clone()),
+        }
+        zero = leaves_zero(s, zero);
+    }
+    out
+}
+
+/// Removes loops that can't be entered because the current cell is known to be zero:
+/// at the start of the program, since the tape starts out zeroed, and after a `Clear`,
+/// another loop or anything else that leaves the cell zero, as long as nothing in
+/// between moves the pointer or changes the cell. Fused loops that do nothing on a zero
+/// cell, like `SearchZero`, go the same way.
+///
+/// Assumes the program runs on a fresh tape, unlike the other passes.
+pub fn dead_loops(stmts: impl AsRef<[Statement]>) -> Vec<Statement> {
+    dead_loops_in(stmts.as_ref(), true)
+}
+
+fn dead_loops_in(stmts: &[Statement], mut zero: bool) -> Vec<Statement> {
+    let mut out = Vec::with_capacity(stmts.len());
+    for s in stmts {
+        let skips_zero = matches!(s, Statement::Loop(_) | Statement::SearchZero { .. } | Statement::ClearScan { .. }
+            | Statement::WriteBlock { .. } | Statement::ReadBlock { .. } | Statement::InfiniteLoopIfNonZero);
+        if zero && skips_zero {
+            continue;
         }
 
-        {
-            let dec_cnt = stmts[idx..].iter()
-                .take_while(|s| s.is_dec())
-                .count();
+        match s {
+            // The body only runs on a nonzero cell
+            Statement::Loop(l) => out.push(Statement::Loop(dead_loops_in(l, false))),
+            s => out.push(s.clone()),
+        }
+        zero = leaves_zero(s, zero);
+    }
+    out
+}
 
-            if dec_cnt != 0 {
-                out.push(Statement::Dec(dec_cnt as u64));
-                idx += dec_cnt;
-                continue;
-            }
+// Whether the current cell is known to be zero after `s`, given whether it was before
+fn leaves_zero(s: &Statement, zero: bool) -> bool {
+    match s {
+        Statement::Loop(_) | Statement::Clear | Statement::SearchZero { .. } | Statement::ClearScan { .. }
+            | Statement::InfiniteLoopIfNonZero | Statement::MoveValue { .. } | Statement::WriteBlock { .. }
+            | Statement::ReadBlock { .. } | Statement::MulAddMany { .. } | Statement::SetConst(0) => true,
+        Statement::Out | Statement::AddOffset { .. } | Statement::Copy { .. } | Statement::Assert { .. }
+            | Statement::Halt | Statement::Breakpoint | Statement::IncAt { .. } => zero,
+        Statement::LinearCombine { dest_offset, terms } => {
+            *dest_offset != 0 && (zero || terms.iter().any(|&(_, o)| o == 0))
         }
+        _ => false,
+    }
+}
+
+/// Which loop shapes `peephole_with` replaces. Everything but `dead_loops` is on by
+/// default; turning a pattern off leaves those loops as plain `Loop`s, which helps bisect
+/// a miscompile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Patterns {
+    /// `[-]` and `[+]` to `Clear`
+    pub clear: bool,
+    /// `[->+<]` and `[-<+>]` style loops to `AddOffset`, and ones adding to several cells
+    /// to `MulAddMany`
+    pub add_offset: bool,
+    /// `[>]` and `[<]` to `SearchZero`
+    pub search_zero: bool,
+    /// `[[-]>]` and `[[-]<]` to `ClearScan`
+    pub clear_scan: bool,
+    /// `[.>]` to `WriteBlock` and `[,>]` to `ReadBlock`, and their leftward forms
+    pub block_io: bool,
+    /// `-t[+t>-t]+t` to `SearchValue`, see `search_values`
+    pub search_value: bool,
+    /// Multiply loops from several cells into one cell to `LinearCombine`, see `linear_combines`
+    pub linear_combine: bool,
+    /// Mixed runs like `+++--` and `>><<<` to their net effect, see `net_fold`
+    pub net_fold: bool,
+    /// Loops on a cell known to be zero removed, see `dead_loops`. Off by default, since
+    /// it only holds for a whole program about to run on a fresh tape, not for fragments.
+    pub dead_loops: bool,
+    /// `[-]` followed by `+++` or `---` to `SetConst`, when `clear` is on. Exact under any
+    /// `CellArith`, since the constant is added to the cleared cell the way the run was.
+    pub set_const: bool,
+    /// Balanced runs like `>>++<<` to `IncAt`, see `inc_ats`
+    pub inc_at: bool,
+}
+
+impl Default for Patterns {
+    fn default() -> Self {
+        Patterns { clear: true, add_offset: true, search_zero: true, clear_scan: true, block_io: true, search_value: true, linear_combine: true, net_fold: true, dead_loops: false, set_const: true, inc_at: true }
+    }
+}
 
-        {
-            let inc_cnt = stmts[idx..].iter()
-                .take_while(|s| s.is_inc())
-                .count();
+pub fn constant_fold(stmts: impl AsRef<[Statement]>) -> Vec<Statement> {
+    constant_fold_counting(stmts.as_ref(), &mut 0)
+}
+
+// `runs` counts the runs of two or more statements merged into one
+fn constant_fold_counting(stmts: &[Statement], runs: &mut usize) -> Vec<Statement> {
+    let mut out = Vec::new();
 
-            if inc_cnt != 0 {
-                out.push(Statement::Inc(inc_cnt as u64));
-                idx += inc_cnt;
+    let mut idx = 0usize;
+    while idx < stmts.len() {
+        let start = idx;
+        // Anything that isn't a pointer move or cell add is copied as-is
+        let make: fn(u64) -> Statement = match &stmts[idx] {
+            Statement::Next(_) | Statement::Prev(_) | Statement::Move(_) => {
+                // Only moves in the same direction add up, since moving left stops at cell 0
+                let right = stmts[idx].movement() >= Some(0);
+                let mut total = 0isize;
+                while let Some(n) = stmts.get(idx).and_then(Statement::movement).filter(|&n| (n >= 0) == right) {
+                    total += n;
+                    idx += 1;
+                }
+                if idx - start > 1 {
+                    *runs += 1;
+                }
+                if total != 0 {
+                    out.push(Statement::Move(total));
+                }
+                continue;
+            }
+            Statement::Inc(_) => Statement::Inc,
+            Statement::Dec(_) => Statement::Dec,
+            Statement::Loop(l) => {
+                out.push(Statement::Loop(constant_fold_counting(l, runs)));
+                idx += 1;
                 continue;
             }
+            s => {
+                out.push(s.clone());
+                idx += 1;
+                continue;
+            }
+        };
+
+        let kind = std::mem::discriminant(&stmts[idx]);
+        let mut total = 0u64;
+        while idx < stmts.len() && std::mem::discriminant(&stmts[idx]) == kind {
+            total += match &stmts[idx] {
+                Statement::Inc(n) | Statement::Dec(n) => *n,
+                _ => unreachable!(),
+            };
+            idx += 1;
+        }
+        if idx - start > 1 {
+            *runs += 1;
         }
+        out.push(make(total));
     }
 
     // println!("Reduced by {} statements", stmts.len() - out.len());
     out
 }
 
-pub fn peephole_optimization(stmts: impl AsRef<[Statement]>) -> Vec<Statement> {
-    stmts.as_ref().iter()
-        .flat_map(|s| {
-            match s {
-                Statement::Loop(l) => {
-                    match l.as_slice() {
-                        [Statement::Dec(1)] | [Statement::Inc(1)] => vec![Statement::Clear],
-                        [Statement::Dec(1), Statement::Next(n), Statement::Inc(inc), Statement::Prev(m)] if n == m => vec![Statement::AddOffset { mul: *inc as i64, offset: *n }, Statement::Clear],
-                        [Statement::Prev(n)] => vec![Statement::SearchZero { stride: (*n as isize) * -1 }],
-                        [Statement::Next(n)] => vec![Statement::SearchZero { stride: (*n as isize) }],
-                        _ => vec![Statement::Loop(peephole_optimization(l))]
+/// Folds each run of mixed `+` and `-` into one `Inc` or `Dec` of the net amount, dropping
+/// runs that cancel out, and each run of mixed moves into at most a `Move` left followed
+/// by a `Move` right. Anything else, `.` and `,` included, ends a run.
+///
+/// Net amounts aren't reduced to the cell width; that happens when they run. Folding
+/// `+` and `-` together is only exact when cells wrap. Moves are exact either way: moving
+/// left stops at cell 0, so a run that goes at most `m` cells left of where it started is
+/// `Move(-m)` followed by the move right that makes up the net.
+pub fn net_fold(stmts: impl AsRef<[Statement]>) -> Vec<Statement> {
+    net_fold_counting(stmts.as_ref(), &mut 0)
+}
+
+// `runs` counts the runs of two or more statements that fold into something else
+fn net_fold_counting(stmts: &[Statement], runs: &mut usize) -> Vec<Statement> {
+    let mut out = Vec::with_capacity(stmts.len());
+
+    let mut idx = 0usize;
+    while idx < stmts.len() {
+        let (start, mark) = (idx, out.len());
+        match &stmts[idx] {
+            Statement::Inc(_) | Statement::Dec(_) => {
+                let mut net = 0i128;
+                while idx < stmts.len() {
+                    match &stmts[idx] {
+                        Statement::Inc(n) => net += *n as i128,
+                        Statement::Dec(n) => net -= *n as i128,
+                        _ => break,
                     }
+                    idx += 1;
+                }
+                if idx - start > 1 {
+                    *runs += 1;
+                }
+                if net > 0 {
+                    out.push(Statement::Inc(net as u64));
+                } else if net < 0 {
+                    out.push(Statement::Dec(-net as u64));
+                }
+            }
+            Statement::Next(_) | Statement::Prev(_) | Statement::Move(_) => {
+                // Relative to the start of the run
+                let (mut pos, mut lowest) = (0isize, 0isize);
+                while let Some(n) = stmts.get(idx).and_then(Statement::movement) {
+                    pos += n;
+                    lowest = lowest.min(pos);
+                    idx += 1;
+                }
+                if lowest < 0 {
+                    out.push(Statement::Move(lowest));
+                }
+                if pos > lowest {
+                    out.push(Statement::Move(pos - lowest));
+                }
+                // A move left and then right is already folded
+                if idx - start > 1 && out[mark..] != stmts[start..idx] {
+                    *runs += 1;
                 }
-                s => vec![s.clone()]
             }
-        }).collect()
+            Statement::Loop(l) => {
+                out.push(Statement::Loop(net_fold_counting(l, runs)));
+                idx += 1;
+            }
+            s => {
+                out.push(s.clone());
+                idx += 1;
+            }
+        }
+    }
+    out
 }
 
-#[derive(thiserror::Error, Debug)]
-pub enum Error {
-    #[error("IO error occurred: {0}")]
-    IO(#[from] io::Error),
-    #[error("Attempted operation out of bounds at idx: {0}")]
-    OutOfBounds(usize),
+pub fn peephole_optimization(stmts: impl AsRef<[Statement]>) -> Vec<Statement> {
+    peephole_with(stmts, Patterns::default())
 }
 
-pub fn exec(s: Statement) -> Result<(), Error> {
-    let mut ctx = Context::new();
-    ctx.exec(&s)
+pub fn peephole_with(stmts: impl AsRef<[Statement]>, patterns: Patterns) -> Vec<Statement> {
+    // Nothing is known about where a fragment starts, other than it can't be left of cell 0
+    peephole_from(stmts.as_ref(), patterns, 0)
 }
 
-pub fn exec_many(s: &[Statement]) -> Result<(), Error> {
-    let mut ctx = Context::new();
-    ctx.exec_many(s)?;
-    println!("\n{:?}", ctx);
-    Ok(())
+// `floor` is a lower bound on the pointer at the start of `stmts`
+fn peephole_from(stmts: &[Statement], patterns: Patterns, mut floor: usize) -> Vec<Statement> {
+    let mut out = Vec::with_capacity(stmts.len());
+
+    let mut idx = 0usize;
+    while idx < stmts.len() {
+        let l = match &stmts[idx] {
+            Statement::Loop(l) => l,
+            s => {
+                out.push(s.clone());
+                floor = floor_after(floor, s);
+                idx += 1;
+                continue;
+            }
+        };
+        let start = floor;
+        floor = floor_after(floor, &stmts[idx]);
+        idx += 1;
+
+        match l.as_slice() {
+            [Statement::Dec(1)] | [Statement::Inc(1)] if patterns.clear => {
+                // Setting the cell right after clearing it is one write
+                match stmts.get(idx).and_then(set_const).filter(|_| patterns.set_const) {
+                    Some(s) => {
+                        out.push(s);
+                        idx += 1;
+                    }
+                    None => out.push(Statement::Clear),
+                }
+            }
+            [Statement::Move(n)] if patterns.search_zero => out.push(Statement::SearchZero { stride: *n }),
+            [Statement::Out, Statement::Move(n)] if patterns.block_io => out.push(Statement::WriteBlock { stride: *n }),
+            [Statement::In, Statement::Move(n)] if patterns.block_io => out.push(Statement::ReadBlock { stride: *n }),
+            _ => {
+                if let Some(fused) = multiply_loop(l, start).filter(|_| patterns.add_offset) {
+                    out.extend(fused);
+                    continue;
+                }
+
+                // Each pass starts where the last one ended, which is only `start` again
+                // for a balanced body
+                let body = peephole_from(l, patterns, floor);
+                match body.as_slice() {
+                    [Statement::Clear, Statement::Move(n)] if patterns.clear_scan => out.push(Statement::ClearScan { stride: *n }),
+                    _ => out.push(Statement::Loop(body)),
+                }
+            }
+        }
+    }
+    out
 }
 
-#[derive(Debug)]
-pub struct Context {
-    data: Vec<i8>,
-    idx: usize,
+// A lower bound on the pointer after `s`, given one before it. `Prev` stops at cell 0
+// and a balanced loop can only end up further right, so neither breaks the bound.
+fn floor_after(floor: usize, s: &Statement) -> usize {
+    match s {
+        Statement::Loop(l) if crate::analysis::net_movement(l) == Some(0) => floor,
+        Statement::SearchZero { stride } | Statement::SearchValue { stride, .. } | Statement::ClearScan { stride }
+            | Statement::WriteBlock { stride } | Statement::ReadBlock { stride } if *stride >= 0 => floor,
+        Statement::Loop(_) | Statement::SearchZero { .. } | Statement::SearchValue { .. } | Statement::ClearScan { .. }
+            | Statement::WriteBlock { .. } | Statement::ReadBlock { .. } => 0,
+        s => match s.movement() {
+            Some(n) => (floor as isize + n).max(0) as usize,
+            None => floor,
+        },
+    }
 }
 
-impl Context {
-    pub fn new() -> Self {
-        Context { data: vec![0], idx: 0 }
+// The `SetConst` for an `Inc` or `Dec` right after a clear
+fn set_const(s: &Statement) -> Option<Statement> {
+    match s {
+        Statement::Inc(n) => Some(Statement::SetConst(*n as i64)),
+        Statement::Dec(n) => Some(Statement::SetConst(-(*n as i64))),
+        _ => None,
     }
+}
+
+/// Replaces scans for a nonzero value, written as `-t[+t>-t]+t` (subtract the target,
+/// and add it back to each cell before moving on), with `SearchValue`.
+///
+/// Only exact under `CellArith::Wrap`: under the other modes the subtraction can clamp
+/// or fail on cells smaller than the target, where `SearchValue` leaves them alone.
+pub fn search_values(stmts: impl AsRef<[Statement]>) -> Vec<Statement> {
+    let stmts = stmts.as_ref();
+    let mut out = Vec::with_capacity(stmts.len());
 
-    pub fn next(&mut self) {
-        self.idx += 1;
-        if self.idx >= self.data.len() {
-            self.data.push(0);
+    let mut idx = 0usize;
+    while idx < stmts.len() {
+        if let [Statement::Dec(t), Statement::Loop(body), Statement::Inc(t2), ..] = &stmts[idx..] {
+            let stride = match body.as_slice() {
+                [Statement::Inc(a), Statement::Move(n), Statement::Dec(b)] if a == t && b == t => Some(*n),
+                _ => None,
+            };
+            if let Some(stride) = stride.filter(|_| t == t2) {
+                out.push(Statement::SearchValue { stride, target: *t });
+                idx += 3;
+                continue;
+            }
         }
-    }
 
-    pub fn adv(&mut self, a: usize) {
-        self.idx += a;
-        if self.idx >= self.data.len() {
-            self.data.resize(self.idx + 1, 0i8)
+        match &stmts[idx] {
+            Statement::Loop(l) => out.push(Statement::Loop(search_values(l))),
+            s => out.push(s.clone()),
         }
+        idx += 1;
     }
 
-    pub fn ret(&mut self, a: usize) {
-        self.idx = self.idx.saturating_sub(a);
-    }
+    out
+}
 
-    pub fn with_state(v: Vec<i8>) -> Self {
-        Context {
-            data: v,
-            idx: 0,
+/// Replaces a multiply loop such as `[->+>>---<<<]` with a `MulAddMany` holding its
+/// targets in ascending order of offset, so equal loops always give equal statements.
+/// Loops with a single target, on either side, become an `AddOffset` followed by a
+/// `Clear` instead.
+///
+/// The body may only move the pointer, must end where it started, must decrement the
+/// loop cell by exactly one, and must change every other cell with a single `Inc` or
+/// `Dec`, so saturating arithmetic gives the same result. Bodies that move left are only
+/// fused when `floor`, a lower bound on the pointer, shows they can't reach past cell 0,
+/// where a `Prev` would have stopped instead.
+fn multiply_loop(body: &[Statement], floor: usize) -> Option<Vec<Statement>> {
+    let (mut pos, mut lowest) = (0isize, 0isize);
+    let mut deltas = BTreeMap::new();
+    for s in body {
+        let delta = match s {
+            Statement::Move(n) => {
+                pos += n;
+                lowest = lowest.min(pos);
+                continue;
+            }
+            Statement::Inc(n) => *n as i64,
+            Statement::Dec(n) => -(*n as i64),
+            _ => return None,
+        };
+        if deltas.insert(pos, delta).is_some() {
+            return None;
         }
     }
 
-    pub fn prev(&mut self) {
-        self.idx = self.idx.saturating_sub(1);
+    if pos != 0 || deltas.remove(&0) != Some(-1) || deltas.is_empty() {
+        return None;
     }
-
-    pub fn data(&self) -> &[i8] {
-        &self.data
+    if lowest < -(floor as isize) {
+        return None;
     }
 
-    pub fn inc(&mut self) -> Result<(), Error> {
-        self.data[self.idx] = self.data[self.idx].wrapping_add(1);
-        Ok(())
+    if deltas.len() > 1 {
+        return Some(vec![Statement::MulAddMany { targets: deltas.into_iter().collect() }]);
     }
+    let (offset, mul) = deltas.into_iter().next()?;
+    Some(vec![Statement::AddOffset { mul, offset }, Statement::Clear])
+}
 
-    pub fn inc_many(&mut self, a: u8) -> Result<(), Error> {
-        let d = self.data[self.idx] as u8;
-        let d = d.wrapping_add(a);
+/// Equivalent to `peephole_optimization(constant_fold(stmts))`, but done in one traversal
+/// that moves statements instead of cloning them and recycles the buffers of consumed
+/// blocks, so large programs don't allocate a fresh `Vec` per pass and loop body.
+pub fn optimize_in_place(stmts: &mut Vec<Statement>) {
+    let mut pool = Vec::new();
+    fuse_block(stmts, &mut pool, 0);
+}
 
-        self.data[self.idx] = d as i8;
-        Ok(())
-    }
+// `floor` is a lower bound on the pointer, as in `peephole_from`
+fn fuse_block(stmts: &mut Vec<Statement>, pool: &mut Vec<Vec<Statement>>, mut floor: usize) {
+    let mut out = pool.pop().unwrap_or_default();
+    // Whether the last statement pushed is the `Clear` for a `[-]`
+    let mut cleared = false;
 
-    pub fn dec(&mut self) -> Result<(), Error> {
-        self.data[self.idx] = self.data[self.idx].wrapping_sub(1);
-        Ok(())
-    }
+    for s in stmts.drain(..) {
+        let after_clear = std::mem::take(&mut cleared);
+        let mut body = match s {
+            Statement::Loop(body) => body,
+            s => {
+                floor = floor_after(floor, &s);
+                match set_const(&s).filter(|_| after_clear) {
+                    Some(set) => *out.last_mut().expect("a clear") = set,
+                    None => push_folded(&mut out, s),
+                }
+                continue;
+            }
+        };
 
-    pub fn dec_many(&mut self, a: u8) -> Result<(), Error> {
-        let d = self.data[self.idx] as u8;
-        let d = d.wrapping_sub(a);
+        let start = floor;
+        if crate::analysis::net_movement(&body) != Some(0) {
+            floor = 0;
+        }
+        fuse_block(&mut body, pool, floor);
+        if let Some(fused) = multiply_loop(&body, start) {
+            out.extend(fused);
+            body.clear();
+            pool.push(body);
+            continue;
+        }
+        match body.as_slice() {
+            [Statement::Dec(1)] | [Statement::Inc(1)] => {
+                out.push(Statement::Clear);
+                cleared = true;
+            }
+            [Statement::Move(n)] => out.push(Statement::SearchZero { stride: *n }),
+            [Statement::Out, Statement::Move(n)] => out.push(Statement::WriteBlock { stride: *n }),
+            [Statement::In, Statement::Move(n)] => out.push(Statement::ReadBlock { stride: *n }),
+            [Statement::Clear, Statement::Move(n)] => out.push(Statement::ClearScan { stride: *n }),
+            _ => {
+                out.push(Statement::Loop(body));
+                continue;
+            }
+        }
 
-        self.data[self.idx] = d as i8;
-        Ok(())
+        body.clear();
+        pool.push(body);
     }
 
-    pub fn out(&self) -> Result<(), Error> {
-        let d = self.data[self.idx];
-        print!("{}", d as u8 as char);
-        Ok(())
-    }
+    // `stmts` is empty now; hand its buffer back for the next block
+    std::mem::swap(stmts, &mut out);
+    pool.push(out);
+}
 
-    pub fn inp(&mut self) -> Result<(), Error> {
-        let r = &mut self.data[self.idx];
-        let mut res = [0u8];
-        io::stdin().read_exact(&mut res).map_err(Error::from)?;
-        *r = res[0] as i8;
-        Ok(())
+fn push_folded(out: &mut Vec<Statement>, s: Statement) {
+    // Moves become `Move`s, as in `constant_fold`
+    let s = s.movement().map_or(s, Statement::Move);
+    match (out.last_mut(), s) {
+        (Some(Statement::Move(a)), Statement::Move(b)) if (*a >= 0) == (b >= 0) => *a += b,
+        (Some(Statement::Inc(a)), Statement::Inc(b)) => *a += b,
+        (Some(Statement::Dec(a)), Statement::Dec(b)) => *a += b,
+        // The rest of the run a `SetConst` was made from
+        (Some(Statement::SetConst(a)), Statement::Inc(b)) if *a > 0 => *a += b as i64,
+        (Some(Statement::SetConst(a)), Statement::Dec(b)) if *a < 0 => *a -= b as i64,
+        (_, s) => out.push(s),
     }
+}
 
-    pub fn cur(&self) -> Result<i8, Error> {
-        Ok(self.data[self.idx])
-    }
+/// Recognizes the duplicate-through-a-temporary idiom `[->+>+<<]>>[-<<+>>]<<`
+/// (in either order of target and scratch) and replaces the two loops with `Copy`s.
+///
+/// The result is exact even if the scratch cell starts out nonzero: its old value is
+/// added to the source, just as the restore loop would do, before it is cleared.
+pub fn copy_restore(stmts: impl AsRef<[Statement]>) -> Vec<Statement> {
+    let stmts = stmts.as_ref();
+    let mut out = Vec::with_capacity(stmts.len());
 
-    pub fn clear(&mut self) {
-        self.data[self.idx] = 0;
+    let mut idx = 0usize;
+    while idx < stmts.len() {
+        if let [Statement::Loop(dup), Statement::Move(s), Statement::Loop(restore), Statement::Move(back), ..] = &stmts[idx..] {
+            if *s > 0 && *back == -s {
+                if let Some(target) = match_copy_restore(dup, *s, restore) {
+                    out.push(Statement::Copy { offset: target });
+                    out.push(Statement::Move(*s));
+                    out.push(Statement::Copy { offset: -s });
+                    out.push(Statement::Clear);
+                    out.push(Statement::Move(-s));
+                    idx += 4;
+                    continue;
+                }
+            }
+        }
+
+        match &stmts[idx] {
+            Statement::Loop(l) => out.push(Statement::Loop(copy_restore(l))),
+            s => out.push(s.clone()),
+        }
+        idx += 1;
     }
 
-    pub fn exec(&mut self, s: &Statement) -> Result<(), Error> {
-        match s {
-            Statement::Next(a) => Ok(self.adv(*a)),
-            Statement::Prev(a) => Ok(self.ret(*a)),
-            Statement::Inc(a) => self.inc_many(*a as u8),
-            Statement::Dec(a) => self.dec_many(*a as u8),
-            Statement::Out => self.out(),
-            Statement::In => self.inp(),
-            Statement::Clear => Ok(self.clear()),
-            Statement::Loop(l) => {
-                while self.cur()? != 0 {
-                    self.exec_many(l)?;
-                }
+    out
+}
 
-                Ok(())
-            }
-            Statement::AddOffset { mul, offset } => {
-                let mul = *mul;
-                let offset = *offset;
-                let val = (mul * (self.cur()? as i64));
-                self.adv(offset as usize);
+/// Turns `AddOffset { mul: 1 }` followed by `Clear` into `MoveValue` where the
+/// destination cell is known to be zero, so the add (and the multiply) can be dropped.
+/// A clear of the destination right before the move, as in `>[-]<[->+<]`, is dropped too,
+/// since `MoveValue` overwrites it anyway.
+///
+/// Cells are only known to be zero once the program has cleared them; nothing is
+/// assumed about the starting tape.
+pub fn move_values(stmts: impl AsRef<[Statement]>) -> Vec<Statement> {
+    // Programs start at cell 0
+    move_values_in(stmts.as_ref(), Some(0))
+}
 
-                let v = self.cur()? as i64 + val;
-                self.data[self.idx] = v as i8;
-                self.ret(offset as usize);
+// `pos` is the absolute cell index if it is known, which is needed to tell whether `Prev`
+// can clamp at cell 0. `zero` holds offsets from the current cell.
+fn move_values_in(stmts: &[Statement], mut pos: Option<usize>) -> Vec<Statement> {
+    let mut zero = BTreeSet::new();
+    let mut out = Vec::with_capacity(stmts.len());
 
-                Ok(())
-            }
-            Statement::SearchZero { stride } => {
-                while self.cur()? != 0 {
-                    if *stride < 0 {
-                        self.ret((*stride * -1) as usize);
-                    } else {
-                        self.adv(*stride as usize);
+    let mut idx = 0usize;
+    while idx < stmts.len() {
+        let s = match &stmts[idx..] {
+            [Statement::AddOffset { mul: 1, offset }, Statement::Clear, ..] if *offset != 0 && zero.contains(offset) => {
+                if let [.., Statement::Move(a), Statement::Clear, Statement::Move(b)] = out.as_slice() {
+                    if a == offset && *b == -a {
+                        out.truncate(out.len() - 3);
                     }
                 }
+                idx += 1;
+                Statement::MoveValue { offset: *offset }
+            }
+            [Statement::Loop(l), ..] => {
+                let balanced = crate::analysis::net_movement(l) == Some(0);
+                Statement::Loop(move_values_in(l, pos.filter(|_| balanced)))
+            }
+            [s, ..] => s.clone(),
+            [] => unreachable!(),
+        };
+        idx += 1;
 
-                Ok(())
+        match &s {
+            Statement::Next(_) | Statement::Prev(_) | Statement::Move(_) => {
+                let n = s.movement().expect("a move");
+                match pos {
+                    Some(p) if p as isize + n >= 0 => {
+                        zero = zero.iter().map(|o| o - n).collect();
+                        pos = Some((p as isize + n) as usize);
+                    }
+                    None if n >= 0 => {
+                        zero = zero.iter().map(|o| o - n).collect();
+                    }
+                    // Might clamp at cell 0, after which offsets can't be trusted
+                    _ => {
+                        zero.clear();
+                        pos = None;
+                    }
+                }
+            }
+            Statement::Inc(_) | Statement::Dec(_) | Statement::In => {
+                zero.remove(&0);
+            }
+            Statement::Clear | Statement::InfiniteLoopIfNonZero | Statement::SetConst(0) => {
+                zero.insert(0);
+            }
+            Statement::SetConst(_) => {
+                zero.remove(&0);
+            }
+            Statement::IncAt { offset, .. } => {
+                zero.remove(offset);
+            }
+            Statement::AddOffset { offset, .. } => {
+                zero.remove(offset);
+            }
+            Statement::Copy { offset } => {
+                zero.remove(offset);
+            }
+            Statement::MoveValue { offset } => {
+                zero.remove(offset);
+                zero.insert(0);
+            }
+            Statement::LinearCombine { dest_offset, terms } => {
+                zero.remove(dest_offset);
+                zero.extend(terms.iter().map(|&(_, o)| o));
+            }
+            Statement::MulAddMany { targets } => {
+                for (offset, _) in targets {
+                    zero.remove(offset);
+                }
+                zero.insert(0);
+            }
+            Statement::Loop(l) => {
+                if crate::analysis::net_movement(l) != Some(0) {
+                    pos = None;
+                }
+                zero.clear();
+                zero.insert(0);
             }
+            Statement::SearchZero { .. } | Statement::SearchValue { .. } | Statement::ClearScan { .. }
+                | Statement::WriteBlock { .. } | Statement::ReadBlock { .. } => {
+                pos = None;
+                zero.clear();
+                zero.insert(0);
+            }
+            Statement::Out | Statement::Assert { .. } | Statement::Halt | Statement::Breakpoint => {}
         }
+        out.push(s);
     }
 
-    pub fn exec_many(&mut self, blk: impl AsRef<[Statement]>) -> Result<(), Error> {
-        let prog = blk.as_ref();
+    out
+}
 
-        prog.iter().try_for_each(|s| {
-            // println!("{:?}: {}", s, self.idx);
-            self.exec(s)
-        })
+/// Fuses runs of multiply loops from different cells into the same cell, such as the
+/// `AddOffset`/`Clear` pairs `[->>++<<]>[->+++<]` turns into, into a `LinearCombine`
+/// followed by the pointer movement between them. Only loops with a single target are
+/// fused, and only while every source stays at or right of the first one, so none of
+/// the `Prev`s in between can clamp at cell 0.
+pub fn linear_combines(stmts: impl AsRef<[Statement]>) -> Vec<Statement> {
+    let stmts = stmts.as_ref();
+    let mut out = Vec::with_capacity(stmts.len());
+
+    let mut idx = 0usize;
+    while idx < stmts.len() {
+        if let Some((fused, len)) = linear_combine(&stmts[idx..]) {
+            out.extend(fused);
+            idx += len;
+            continue;
+        }
+
+        out.push(match &stmts[idx] {
+            Statement::Loop(l) => Statement::Loop(linear_combines(l)),
+            s => s.clone(),
+        });
+        idx += 1;
     }
+    out
 }
 
-#[cfg(test)]
-mod tests {
+// The statements replacing the run of multiply loops `stmts` starts with, if there are at
+// least two, and how many statements they replace
+fn linear_combine(stmts: &[Statement]) -> Option<(Vec<Statement>, usize)> {
+    let mut dest = None;
+    let mut terms: Vec<(i64, isize)> = Vec::new();
+    // Position relative to the first source, and where the last fused loop left off
+    let mut pos = 0isize;
+    let mut end = (0usize, 0isize);
+
+    let mut idx = 0usize;
+    while let [Statement::AddOffset { mul, offset }, Statement::Clear, ..] = &stmts[idx..] {
+        let target = pos + *offset;
+        if *dest.get_or_insert(target) != target || terms.iter().any(|&(_, src)| src == pos) {
+            break;
+        }
+        terms.push((*mul, pos));
+        idx += 2;
+        end = (idx, pos);
+
+        let mut lowest = pos;
+        while let Some(step) = stmts.get(idx).and_then(Statement::movement) {
+            pos += step;
+            lowest = lowest.min(pos);
+            idx += 1;
+        }
+        if lowest < 0 {
+            break;
+        }
+    }
+
+    if terms.len() < 2 {
+        return None;
+    }
+
+    let (len, pos) = end;
+    let mut out = vec![Statement::LinearCombine { dest_offset: dest?, terms }];
+    if pos != 0 {
+        out.push(Statement::Move(pos));
+    }
+    Some((out, len))
+}
+
+/// Replaces runs that move right, add to cells and come back, like `>>++<-<`, with an
+/// `IncAt` for each add, in order. Nothing in the run goes left of where it started, so
+/// the moves back can't stop early at cell 0 and the pointer ends up where it was.
+pub fn inc_ats(stmts: impl AsRef<[Statement]>) -> Vec<Statement> {
+    let stmts = stmts.as_ref();
+    let mut out = Vec::with_capacity(stmts.len());
+
+    let mut idx = 0usize;
+    while idx < stmts.len() {
+        if let Some((fused, len)) = inc_at_run(&stmts[idx..]) {
+            out.extend(fused);
+            idx += len;
+            continue;
+        }
+
+        out.push(match &stmts[idx] {
+            Statement::Loop(l) => Statement::Loop(inc_ats(l)),
+            s => s.clone(),
+        });
+        idx += 1;
+    }
+    out
+}
+
+// The `IncAt`s for the run `stmts` starts with, and how many statements they replace
+fn inc_at_run(stmts: &[Statement]) -> Option<(Vec<Statement>, usize)> {
+    let mut out = Vec::new();
+    let mut pos = 0isize;
+    for (idx, s) in stmts.iter().enumerate() {
+        let amount = match s {
+            Statement::Move(n) if pos + n > 0 => {
+                pos += n;
+                continue;
+            }
+            Statement::Move(n) if pos + n == 0 && !out.is_empty() => return Some((out, idx + 1)),
+            Statement::Inc(n) if pos > 0 => *n as i64,
+            Statement::Dec(n) if pos > 0 => -(*n as i64),
+            _ => return None,
+        };
+        out.push(Statement::IncAt { offset: pos, amount });
+    }
+    None
+}
+
+// Returns the offset of the copy target if `dup` adds the source to both the target
+// and `scratch`, and `restore` moves `scratch` back into the source.
+fn match_copy_restore(dup: &[Statement], scratch: isize, restore: &[Statement]) -> Option<isize> {
+    let (a, b) = match dup {
+        [Statement::Dec(1), Statement::Move(a), Statement::Inc(1), Statement::Move(d), Statement::Inc(1), Statement::Move(back)]
+            if *a > 0 && *d > 0 && a + d == -back => (*a, -back),
+        _ => return None,
+    };
+
+    match restore {
+        [Statement::Dec(1), Statement::Move(n), Statement::Inc(1), Statement::Move(m)] if *n == -scratch && *m == scratch => (),
+        _ => return None,
+    }
+
+    if scratch == a {
+        Some(b)
+    } else if scratch == b {
+        Some(a)
+    } else {
+        None
+    }
+}
+
+/// What happens when `+`, `-` or a fused multiply-add leaves a cell's range.
+///
+/// Cells are treated as unsigned, as BF programs expect, so saturation and overflow
+/// happen at 0 and at the largest unsigned value of the cell width. The JIT only
+/// supports `Wrap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellArith {
+    Wrap,
+    Saturate,
+    Error,
+}
+
+impl Default for CellArith {
+    fn default() -> Self {
+        CellArith::Wrap
+    }
+}
+
+/// What `Context` does when the pointer moves left of the cell it started on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TapeMode {
+    /// Stop at cell 0, as `StaticContext` and `jit::PointerMode::Unchecked` code do
+    #[default]
+    Clamp,
+    /// Fail with `OutOfBounds(0)`
+    Error,
+    /// Add cells on the left, so every position is a real cell. `data()` then starts
+    /// at the leftmost cell reached, and cell 0 is at `origin()` in it.
+    Growable,
+    /// Join the ends of the tape, which then keeps the length it started with: moving
+    /// left of cell 0 lands on the last cell and moving right of the last cell on cell 0.
+    /// Matches `jit::PointerMode::Wrap` on a compiled tape of the same size.
+    Wrap,
+}
+
+impl CellArith {
+    /// `cell + n * mul` for a cell `bits` wide, as an unsigned value to be truncated back
+    /// to the cell type, or `None` if it overflows under `CellArith::Error`.
+    pub fn add_scaled(self, cell: i128, n: i128, mul: i128, bits: u32) -> Option<i128> {
+        let cell = unsigned(cell, bits);
+        let max = unsigned(-1, bits);
+        match self {
+            CellArith::Wrap => Some(unsigned(cell.wrapping_add(n.wrapping_mul(mul)), bits)),
+            CellArith::Saturate => Some(cell.saturating_add(n.saturating_mul(mul)).max(0).min(max)),
+            CellArith::Error => Some(cell.saturating_add(n.saturating_mul(mul))).filter(|v| (0..=max).contains(v)),
+        }
+    }
+}
+
+/// The multiplier in the signed range of a `bits`-wide cell that has the same effect as
+/// `mul` under `CellArith::Wrap`, e.g. 300 becomes 44 and 200 becomes -56 for 8 bits.
+pub fn wrap_mul(mul: i64, bits: u32) -> i64 {
+    if bits >= 64 {
+        return mul;
+    }
+    let m = 1i128 << bits;
+    let r = (mul as i128).rem_euclid(m);
+    if r >= m / 2 { (r - m) as i64 } else { r as i64 }
+}
+
+/// Reduces every `AddOffset` multiplier with `wrap_mul`, so code for `bits`-wide cells
+/// only deals with multipliers that fit the cell.
+///
+/// Only valid under `CellArith::Wrap`: saturating and checked arithmetic depend on the
+/// full multiplier, which is why `optimize` keeps it.
+pub fn wrap_muls(stmts: impl AsRef<[Statement]>, bits: u32) -> Vec<Statement> {
+    stmts.as_ref().iter()
+        .map(|s| match s {
+            Statement::AddOffset { mul, offset } => Statement::AddOffset { mul: wrap_mul(*mul, bits), offset: *offset },
+            Statement::LinearCombine { dest_offset, terms } => Statement::LinearCombine {
+                dest_offset: *dest_offset,
+                terms: terms.iter().map(|&(mul, offset)| (wrap_mul(mul, bits), offset)).collect(),
+            },
+            Statement::MulAddMany { targets } => Statement::MulAddMany {
+                targets: targets.iter().map(|&(offset, mul)| (offset, wrap_mul(mul, bits))).collect(),
+            },
+            Statement::Loop(l) => Statement::Loop(wrap_muls(l, bits)),
+            s => s.clone(),
+        })
+        .collect()
+}
+
+/// Removes every `Breakpoint`, for runs that should ignore `#`
+pub fn strip_breakpoints(stmts: impl AsRef<[Statement]>) -> Vec<Statement> {
+    stmts.as_ref().iter()
+        .filter(|s| **s != Statement::Breakpoint)
+        .map(|s| match s {
+            Statement::Loop(l) => Statement::Loop(strip_breakpoints(l)),
+            s => s.clone(),
+        })
+        .collect()
+}
+
+/// Cells either side of the pointer that a `Breakpoint` shows
+pub const DUMP_RADIUS: usize = 8;
+
+/// The line a `Breakpoint` prints for a tape of `len` cells with the pointer at `idx`:
+/// the pointer, then the cells within `DUMP_RADIUS` of it, the current one in brackets.
+pub fn tape_dump(idx: usize, len: usize, cell: impl Fn(usize) -> i64) -> String {
+    let start = idx.saturating_sub(DUMP_RADIUS);
+    let end = (idx + DUMP_RADIUS + 1).min(len);
+    let cells: Vec<_> = (start..end)
+        .map(|i| if i == idx { format!("[{}]", cell(i)) } else { cell(i).to_string() })
+        .collect();
+    format!("# ptr {}, cells {}..{}: {}", idx, start, end, cells.join(" "))
+}
+
+/// Checks IR that didn't come from the parser for statements no pass would produce and
+/// the backends don't handle: scans with a stride of 0, which never move, and fused
+/// statements that read and write the same cell.
+///
+/// The error gives the statement's index, followed by its index within each enclosing
+/// loop body.
+pub fn validate(stmts: impl AsRef<[Statement]>) -> Result<(), Error> {
+    validate_in(stmts.as_ref(), &mut Vec::new())
+}
+
+fn validate_in(stmts: &[Statement], path: &mut Vec<usize>) -> Result<(), Error> {
+    for (i, s) in stmts.iter().enumerate() {
+        path.push(i);
+        let problem = match s {
+            Statement::SearchZero { stride } | Statement::SearchValue { stride, .. } | Statement::ClearScan { stride }
+                | Statement::WriteBlock { stride } | Statement::ReadBlock { stride } if *stride == 0 => Some("stride is 0"),
+            Statement::AddOffset { offset: 0, .. } | Statement::MoveValue { offset: 0 } | Statement::Copy { offset: 0 }
+                | Statement::IncAt { offset: 0, .. } => {
+                Some("offset is 0")
+            }
+            Statement::LinearCombine { terms, .. } if terms.is_empty() => Some("no terms"),
+            Statement::MulAddMany { targets } if targets.is_empty() => Some("no targets"),
+            Statement::MulAddMany { targets } => {
+                let mut offsets = BTreeSet::new();
+                if targets.iter().any(|&(o, _)| o == 0) {
+                    Some("a target is the current cell")
+                } else if !targets.iter().all(|&(o, _)| offsets.insert(o)) {
+                    Some("a target appears twice")
+                } else {
+                    None
+                }
+            }
+            Statement::LinearCombine { dest_offset, terms } => {
+                let mut sources = BTreeSet::new();
+                if terms.iter().any(|(_, o)| o == dest_offset) {
+                    Some("a source is the destination")
+                } else if !terms.iter().all(|(_, o)| sources.insert(*o)) {
+                    Some("a source appears twice")
+                } else {
+                    None
+                }
+            }
+            Statement::Loop(l) => {
+                validate_in(l, path)?;
+                None
+            }
+            _ => None,
+        };
+        if let Some(reason) = problem {
+            return Err(Error::InvalidStatement { path: path.clone(), reason });
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+/// A hard ceiling on the bytes allocated for a `Context`'s tape.
+///
+/// Unlike a limit on the number of cells, this counts the spare capacity of the
+/// tape's `Vec` too. While a budget is set the tape still grows geometrically
+/// (doubling) to keep growth amortized, but the last step is cut short at the budget
+/// so the whole budget can be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryBudget {
+    max_bytes: usize,
+}
+
+impl MemoryBudget {
+    pub fn new(max_bytes: usize) -> Self {
+        MemoryBudget { max_bytes }
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    fn max_cells<C>(&self) -> usize {
+        self.max_bytes / std::mem::size_of::<C>()
+    }
+
+    // Capacity, in cells of type `C`, to grow to so that `len` cells fit, or `None` if
+    // they can't
+    fn grow<C>(&self, capacity: usize, len: usize) -> Option<usize> {
+        let max_cells = self.max_cells::<C>();
+        if len > max_cells {
+            return None;
+        }
+        Some(len.max(capacity * 2).min(max_cells))
+    }
+}
+
+/// How cell values are shown in debug output. Storage is unaffected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signedness {
+    Signed,
+    Unsigned,
+}
+
+impl Default for Signedness {
+    fn default() -> Self {
+        Signedness::Unsigned
+    }
+}
+
+/// Cell values as they should be displayed
+pub fn cell_view<T: Copy + Into<i128>>(cells: &[T], bits: u32, signedness: Signedness) -> Vec<i128> {
+    cells.iter()
+        .map(|c| match signedness {
+            Signedness::Signed => (*c).into(),
+            Signedness::Unsigned => unsigned((*c).into(), bits),
+        })
+        .collect()
+}
+
+/// The unsigned value of the low `bits` bits of `v`
+pub fn unsigned(v: i128, bits: u32) -> i128 {
+    v & ((1i128 << bits) - 1)
+}
+
+// Where a `SearchZero` from `idx` can jump to in one go for strides of ±1: the nearest zero
+// cell that way, or else the last cell before the end of `cells`. What happens past the
+// end depends on the interpreter, so it is left to stepping as usual.
+pub(crate) fn skip_to_zero<C: Cell>(cells: &[C], idx: usize, stride: isize) -> usize {
+    match stride {
+        _ if idx >= cells.len() => idx,
+        1 => idx + cells[idx..].iter().position(|c| c.is_zero()).unwrap_or(cells.len() - 1 - idx),
+        -1 => cells[..=idx].iter().rposition(|c| c.is_zero()).unwrap_or(0),
+        _ => idx,
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("IO error occurred: {0}")]
+    IO(#[from] io::Error),
+    #[error("Attempted operation out of bounds at idx: {0}")]
+    OutOfBounds(usize),
+    #[error("Cell overflowed at idx: {0}")]
+    Overflow(usize),
+    /// Not a failure: the number of bytes requested with `set_stop_after_output` was written
+    #[error("Requested output length reached")]
+    OutputComplete,
+    /// Not a failure: the program reached a `Halt`
+    #[error("Halted")]
+    Halted,
+    #[error("Assertion at byte {pos} failed: expected cell{cell} == {expected}, found {actual}")]
+    AssertionFailed { pos: usize, cell: usize, expected: i64, actual: i64 },
+    #[error("Step limit of {0} reached")]
+    StepLimit(u64),
+    #[error("Initial tape of {len} cells doesn't fit in {capacity} cells")]
+    TapeTooLarge { len: usize, capacity: usize },
+    #[error("Final tape differs at cell{cell}: expected {expected}, found {actual}")]
+    TapeMismatch { cell: usize, expected: i64, actual: i64 },
+    /// A panic caught by `run_catching`, with its message
+    #[error("Program panicked: {0}")]
+    Runtime(String),
+    /// Rejected by `validate`
+    #[error("Invalid statement at {path:?}: {reason}")]
+    InvalidStatement { path: Vec<usize>, reason: &'static str },
+}
+
+/// How a run under `exec_many_limited` ended, with the steps it took. A step is one
+/// statement executed, or one loop iteration. Fused statements such as `SearchZero`,
+/// `AddOffset` or `WriteBlock` are one step each however many cells they touch, so the
+/// count measures dispatch work rather than the work of the original program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// Ran to the end, or to a `Halt`
+    Completed { steps: u64 },
+    /// Stopped before taking more than the allowed steps
+    LimitReached { steps: u64 },
+}
+
+pub fn exec(s: Statement) -> Result<(), Error> {
+    let mut ctx = Context::new();
+    ctx.exec(&s)
+}
+
+pub fn exec_many(s: &[Statement]) -> Result<(), Error> {
+    let mut ctx = Context::new();
+    ctx.exec_many(s)?;
+    println!("\n{:?}", ctx);
+    Ok(())
+}
+
+// Most bytes a `WriteBlock` holds on to before writing them out
+pub(crate) const WRITE_CHUNK: usize = 4096;
+
+/// Interpreter over a tape that grows to the right as needed, with cells of type `C`.
+/// Errors are returned rather than panicking.
+pub struct Context<C: Cell = i8> {
+    data: Vec<C>,
+    idx: usize,
+    // Where cell 0 is in `data`, which only moves under `TapeMode::Growable`
+    origin: usize,
+    tape_mode: TapeMode,
+    input: PeekableInput,
+    output: OutputSink,
+    eof: EofMode,
+    remaining_output: Option<usize>,
+    arith: CellArith,
+    soft_limit: Option<usize>,
+    on_soft_limit: Option<Box<dyn FnMut(usize)>>,
+    budget: Option<MemoryBudget>,
+    steps: u64,
+    max_steps: Option<u64>,
+    trace_io: Option<Box<dyn FnMut(&IoEvent)>>,
+    // Source range of the statement running, when run through `exec_many_spanned`
+    source: Option<Range<usize>>,
+    recorder: Option<TraceRecorder>,
+    signedness: Signedness,
+}
+
+pub type Context8 = Context<i8>;
+pub type Context16 = Context<i16>;
+pub type Context32 = Context<i32>;
+pub type Context64 = Context<i64>;
+
+impl<C: Cell> Debug for Context<C> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let shown: Vec<i64> = self.data.iter().map(|c| c.to_signed()).collect();
+        f.debug_struct("Context")
+            .field("data", &cell_view(&shown, C::BITS, self.signedness))
+            .field("idx", &self.idx)
+            .finish()
+    }
+}
+
+/// Constructors for 8-bit cells. Other widths start from `default` or `from_cells`.
+impl Context {
+    pub fn new() -> Self {
+        Context::default()
+    }
+
+    pub fn with_input(input: InputSource) -> Self {
+        let mut ctx = Context::new();
+        ctx.set_input(input);
+        ctx
+    }
+
+    /// `,` reads from `input` and `.` writes to `output`, instead of the standard streams
+    pub fn with_io(input: impl io::Read + 'static, output: impl io::Write + 'static) -> Self {
+        let mut ctx = Context::with_input(InputSource::from_reader(input));
+        ctx.set_output(OutputSink::Writer(Box::new(output)));
+        ctx
+    }
+
+    pub fn with_eof_mode(eof: EofMode) -> Self {
+        let mut ctx = Context::new();
+        ctx.set_eof_mode(eof);
+        ctx
+    }
+
+    pub fn with_tape_mode(mode: TapeMode) -> Self {
+        let mut ctx = Context::new();
+        ctx.set_tape_mode(mode);
+        ctx
+    }
+
+    /// `,` reads from `it`, and the current `EofMode` applies once it runs out.
+    pub fn with_input_iter(it: impl Iterator<Item = u8> + 'static) -> Self {
+        Context::with_input(InputSource::from_bytes(it))
+    }
+
+    pub fn with_state(v: Vec<i8>) -> Self {
+        Context::from_cells(v)
+    }
+}
+
+impl<C: Cell> Default for Context<C> {
+    fn default() -> Self {
+        Context::from_cells(vec![C::zero()])
+    }
+}
+
+impl<C: Cell> Context<C> {
+    /// Starts with `v` as the tape, which then grows as needed
+    pub fn from_cells(v: Vec<C>) -> Self {
+        Context {
+            data: v,
+            idx: 0,
+            origin: 0,
+            tape_mode: TapeMode::Clamp,
+            input: PeekableInput::default(),
+            output: OutputSink::stdout(),
+            eof: EofMode::Error,
+            remaining_output: None,
+            arith: CellArith::Wrap,
+            soft_limit: None,
+            on_soft_limit: None,
+            budget: None,
+            steps: 0,
+            max_steps: None,
+            trace_io: None,
+            source: None,
+            recorder: None,
+            signedness: Signedness::Unsigned,
+        }
+    }
+
+    pub fn set_input(&mut self, input: InputSource) {
+        self.input = input.into();
+    }
+
+    pub fn set_output(&mut self, output: OutputSink) {
+        self.output = output;
+    }
+
+    pub fn output(&self) -> &OutputSink {
+        &self.output
+    }
+
+    /// Flushes the output sink and the trace recorder. This also happens when `exec_many`
+    /// fails and when the context is dropped, so output written before an error isn't lost.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(rec) = &mut self.recorder {
+            rec.flush()?;
+        }
+        self.output.flush()
+    }
+
+    /// Fail with `StepLimit` once more than `max` steps have been taken. Every statement
+    /// executed is a step, including fused ones like `SearchZero`, and so is every
+    /// iteration of a loop.
+    pub fn set_step_limit(&mut self, max: Option<u64>) {
+        self.max_steps = max;
+    }
+
+    /// The limit from `set_step_limit`, if any
+    pub fn step_limit(&self) -> Option<u64> {
+        self.max_steps
+    }
+
+    /// Steps taken so far
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Runs `blk` taking at most `max_steps` more steps, on top of any limit from
+    /// `set_step_limit`. Running out of steps under either limit is an outcome, not an error.
+    pub fn exec_many_limited(&mut self, blk: impl AsRef<[Statement]>, max_steps: u64) -> Result<StepOutcome, Error> {
+        let start = self.steps;
+        let outer = self.max_steps;
+        let limit = start.saturating_add(max_steps);
+        let limit = outer.map_or(limit, |max| max.min(limit));
+        self.max_steps = Some(limit);
+
+        let res = self.exec_many(blk);
+        self.max_steps = outer;
+        // `tick` counts the step it refuses
+        let steps = self.steps.min(limit).saturating_sub(start);
+        match res {
+            Ok(()) | Err(Error::Halted) => Ok(StepOutcome::Completed { steps }),
+            Err(Error::StepLimit(_)) => Ok(StepOutcome::LimitReached { steps }),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn set_eof_mode(&mut self, eof: EofMode) {
+        self.eof = eof;
+    }
+
+    /// What moving left of cell 0 does, `TapeMode::Clamp` by default. Meant to be set
+    /// before running anything: cells already added on the left stay when switching away
+    /// from `Growable`.
+    pub fn set_tape_mode(&mut self, mode: TapeMode) {
+        self.tape_mode = mode;
+    }
+
+    /// Index in `data()` of cell 0, the one the pointer started on. Always 0 unless
+    /// `TapeMode::Growable` added cells to its left.
+    pub fn origin(&self) -> usize {
+        self.origin
+    }
+
+    /// Warn once when the tape first grows past `cells` cells. The warning goes to
+    /// stderr unless a callback is set with `on_soft_limit`. Execution carries on.
+    pub fn set_soft_limit(&mut self, cells: Option<usize>) {
+        self.soft_limit = cells;
+    }
+
+    /// Whether `Debug` shows cells as signed or unsigned
+    pub fn set_signedness(&mut self, signedness: Signedness) {
+        self.signedness = signedness;
+    }
+
+    /// Fail with `OutOfBounds` instead of allocating more tape than `budget` allows.
+    /// Only growth is checked, so the initial tape may already be over it.
+    pub fn set_memory_budget(&mut self, budget: Option<MemoryBudget>) {
+        self.budget = budget;
+    }
+
+    /// Called with every byte `,` reads and `.` writes, see `trace::trace_to`
+    pub fn trace_io(&mut self, f: impl FnMut(&IoEvent) + 'static) {
+        self.trace_io = Some(Box::new(f));
+    }
+
+    /// Record every statement executed from now on, see `trace::TraceRecorder`
+    pub fn record_trace(&mut self, recorder: TraceRecorder) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Stops recording, handing back the flushed recorder
+    pub fn take_trace_recorder(&mut self) -> io::Result<Option<TraceRecorder>> {
+        let mut recorder = self.recorder.take();
+        if let Some(rec) = &mut recorder {
+            rec.flush()?;
+        }
+        Ok(recorder)
+    }
+
+    fn record_io(&mut self, direction: IoDirection, byte: u8) {
+        let event = IoEvent { direction, byte, cell: self.idx, step: self.steps, source: self.source.clone() };
+        if let Some(f) = &mut self.trace_io {
+            f(&event);
+        }
+    }
+
+    /// Called with the new tape length instead of printing the soft limit warning
+    pub fn on_soft_limit(&mut self, f: impl FnMut(usize) + 'static) {
+        self.on_soft_limit = Some(Box::new(f));
+    }
+
+    pub fn set_arith(&mut self, arith: CellArith) {
+        self.arith = arith;
+    }
+
+    pub fn arith(&self) -> CellArith {
+        self.arith
+    }
+
+    /// Stop execution with `Error::OutputComplete` once `n` more bytes have been written,
+    /// for sampling the output of programs that never halt.
+    pub fn set_stop_after_output(&mut self, n: Option<usize>) {
+        self.remaining_output = n;
+    }
+
+    pub fn next(&mut self) -> Result<(), Error> {
+        self.adv(1)
+    }
+
+    pub fn adv(&mut self, a: usize) -> Result<(), Error> {
+        if self.tape_mode == TapeMode::Wrap {
+            self.idx = (self.idx + a % self.data.len()) % self.data.len();
+            return Ok(());
+        }
+        self.grow_to(self.idx + a)?;
+        self.idx += a;
+        Ok(())
+    }
+
+    // Make sure `idx` is on the tape. Fails with `OutOfBounds` rather than aborting when
+    // the allocation can't be made, e.g. for the far end of a bogus `AddOffset`.
+    fn grow_to(&mut self, idx: usize) -> Result<(), Error> {
+        if idx < self.data.len() {
+            return Ok(());
+        }
+        let len = idx.checked_add(1).ok_or(OutOfBounds(idx))?;
+
+        // Checked even when the tape already has room, as a `Vec` handed to `with_state` or
+        // grown before the budget was set may have more capacity than the budget allows
+        if let Some(budget) = self.budget {
+            let cap = budget.grow::<C>(self.data.capacity(), len).ok_or(OutOfBounds(idx))?;
+            if idx >= self.data.capacity() {
+                self.data.reserve_exact(cap - self.data.len());
+            }
+        }
+        self.data.try_reserve(len - self.data.len()).map_err(|_| OutOfBounds(idx))?;
+
+        let old_len = self.data.len();
+        self.data.resize(len, C::zero());
+
+        if let Some(limit) = self.soft_limit {
+            if old_len <= limit && self.data.len() > limit {
+                match &mut self.on_soft_limit {
+                    Some(f) => f(self.data.len()),
+                    None => eprintln!("warning: tape has grown past {} cells", limit),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn ret(&mut self, a: usize) -> Result<(), Error> {
+        if a > self.idx {
+            match self.tape_mode {
+                TapeMode::Clamp => {
+                    self.idx = 0;
+                    return Ok(());
+                }
+                TapeMode::Error => return Err(OutOfBounds(0)),
+                TapeMode::Growable => self.grow_left(a - self.idx)?,
+                TapeMode::Wrap => {
+                    let len = self.data.len();
+                    self.idx = (self.idx + len - a % len) % len;
+                    return Ok(());
+                }
+            }
+        }
+        self.idx -= a;
+        Ok(())
+    }
+
+    // Adds at least `n` cells on the left, shifting the tape. The tape at least doubles
+    // each time, so walking left costs the same amortized as walking right.
+    fn grow_left(&mut self, n: usize) -> Result<(), Error> {
+        let mut extra = n.max(self.data.len());
+        if let Some(budget) = self.budget {
+            let room = budget.max_cells::<C>().saturating_sub(self.data.len());
+            if n > room {
+                return Err(OutOfBounds(0));
+            }
+            extra = extra.min(room);
+        }
+        self.data.try_reserve_exact(extra).map_err(|_| OutOfBounds(0))?;
+        self.data.splice(0..0, std::iter::repeat_n(C::zero(), extra));
+        self.idx += extra;
+        self.origin += extra;
+        Ok(())
+    }
+
+    /// Zeroes the tape and moves back to cell 0 so the context can run another program,
+    /// keeping its allocation and settings. The step count starts over, but the budget
+    /// from `set_stop_after_output` is not restored.
+    pub fn reset(&mut self) {
+        self.data.iter_mut().for_each(|c| *c = C::zero());
+        self.idx = self.origin;
+        self.steps = 0;
+    }
+
+    pub fn prev(&mut self) -> Result<(), Error> {
+        self.ret(1)
+    }
+
+    pub fn data(&self) -> &[C] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [C] {
+        &mut self.data
+    }
+
+    pub fn idx(&self) -> usize {
+        self.idx
+    }
+
+    pub fn set_idx(&mut self, idx: usize) {
+        self.idx = idx;
+    }
+
+    pub fn inc(&mut self) -> Result<(), Error> {
+        self.inc_many(1)
+    }
+
+    /// Adds `a` to the current cell under the configured `CellArith`, as `Inc(a)` does
+    pub fn inc_many(&mut self, a: u64) -> Result<(), Error> {
+        self.add_at(self.idx, a as i128, 1)
+    }
+
+    pub fn dec(&mut self) -> Result<(), Error> {
+        self.dec_many(1)
+    }
+
+    /// Subtracts `a` from the current cell under the configured `CellArith`, as `Dec(a)` does
+    pub fn dec_many(&mut self, a: u64) -> Result<(), Error> {
+        self.add_at(self.idx, a as i128, -1)
+    }
+
+    pub fn out(&mut self) -> Result<(), Error> {
+        if self.remaining_output == Some(0) {
+            return Err(Error::OutputComplete);
+        }
+
+        let d = self.data[self.idx].to_output_byte();
+        self.output.write_byte(d)?;
+        self.record_io(IoDirection::Write, d);
+
+        match &mut self.remaining_output {
+            Some(n) => {
+                *n -= 1;
+                if *n == 0 { Err(Error::OutputComplete) } else { Ok(()) }
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn inp(&mut self) -> Result<(), Error> {
+        // A prompt written before the read should be on screen while it waits
+        self.output.flush()?;
+        let b = match self.input.read_byte()? {
+            Some(b) => {
+                self.record_io(IoDirection::Read, b);
+                C::from_input_byte(b)
+            }
+            None => match self.eof {
+                EofMode::Error => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                EofMode::LeaveUnchanged => return Ok(()),
+                EofMode::Zero => C::zero(),
+                // All ones at any width
+                EofMode::NegativeOne => C::zero().wrapping_add(-1),
+                EofMode::Sentinel { value, .. } => C::from_input_byte(value),
+            },
+        };
+        self.data[self.idx] = b;
+
+        if let EofMode::Sentinel { flag: true, .. } = self.eof {
+            let more = self.input.peek()?.is_some();
+            // Wraps, grows or fails as a move right would
+            let next = self.offset_idx(1)?;
+            self.data[next] = C::from_unsigned(more as u64);
+        }
+        Ok(())
+    }
+
+    pub fn cur(&self) -> Result<C, Error> {
+        Ok(self.data[self.idx])
+    }
+
+    pub fn clear(&mut self) {
+        self.data[self.idx] = C::zero();
+    }
+
+    // data[idx] += n * mul under the configured `CellArith`
+    fn add_at(&mut self, idx: usize, n: i128, mul: i128) -> Result<(), Error> {
+        let v = self.arith.add_scaled(self.data[idx].to_unsigned() as i128, n, mul, C::BITS)
+            .ok_or(Error::Overflow(idx))?;
+        self.data[idx] = C::from_unsigned(v as u64);
+        Ok(())
+    }
+
+    // Index of the cell at `offset` from the current position, growing the tape if needed
+    fn offset_idx(&mut self, offset: isize) -> Result<usize, Error> {
+        if self.tape_mode == TapeMode::Wrap {
+            let len = self.data.len() as isize;
+            return Ok((self.idx as isize + offset.rem_euclid(len)).rem_euclid(len) as usize);
+        }
+        let idx = match (self.idx as isize).checked_add(offset) {
+            Some(idx) if idx >= 0 => idx as usize,
+            Some(idx) if self.tape_mode == TapeMode::Growable => {
+                self.grow_left(idx.unsigned_abs())?;
+                (self.idx as isize + offset) as usize
+            }
+            _ => return Err(OutOfBounds(self.idx)),
+        };
+        self.grow_to(idx)?;
+        Ok(idx)
+    }
+
+    // Moves left or right by `stride` cells
+    fn move_by(&mut self, stride: isize) -> Result<(), Error> {
+        if stride < 0 {
+            self.ret(-stride as usize)
+        } else {
+            self.adv(stride as usize)
+        }
+    }
+
+    // Counts a step against the limit
+    fn tick(&mut self) -> Result<(), Error> {
+        self.steps += 1;
+        match self.max_steps {
+            Some(max) if self.steps > max => Err(Error::StepLimit(max)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn exec(&mut self, s: &Statement) -> Result<(), Error> {
+        self.exec_at(s, None)
+    }
+
+    // `exec`, with the span of `s` if it has one
+    fn exec_at(&mut self, s: &Statement, span: Option<&Span>) -> Result<(), Error> {
+        if let Some(span) = span {
+            self.source = Some(span.range.clone());
+        }
+        self.exec_op(s, span)?;
+
+        if let Some(rec) = &mut self.recorder {
+            // Records keep the low 8 bits of wider cells
+            let value = self.data[self.idx].to_unsigned() as u8 as i8;
+            rec.record(&TraceRecord { op: s.name(), pos: self.idx, value })?;
+        }
+        Ok(())
+    }
+
+    fn exec_op(&mut self, s: &Statement, span: Option<&Span>) -> Result<(), Error> {
+        self.tick()?;
+        match s {
+            Statement::Next(a) => self.adv(*a),
+            Statement::Prev(a) => self.ret(*a),
+            Statement::Move(a) if *a >= 0 => self.adv(*a as usize),
+            Statement::Move(a) => self.ret(-a as usize),
+            Statement::Inc(a) => self.inc_many(*a),
+            Statement::Dec(a) => self.dec_many(*a),
+            Statement::Out => self.out(),
+            Statement::In => self.inp(),
+            Statement::Clear => Ok(self.clear()),
+            Statement::SetConst(n) => {
+                // As the clear and the run of `+` or `-` it came from would
+                self.clear();
+                self.add_at(self.idx, *n as i128, 1)
+            }
+            Statement::IncAt { offset, amount } => {
+                let dest = self.offset_idx(*offset)?;
+                self.add_at(dest, *amount as i128, 1)
+            }
+            Statement::Loop(l) => {
+                while !self.cur()?.is_zero() {
+                    self.tick()?;
+                    self.exec_block(l, span.map(|s| &s.body[..]))?;
+                }
+
+                Ok(())
+            }
+            Statement::AddOffset { mul, offset } => {
+                let times = self.cur()?.to_unsigned() as i128;
+                // The loop this came from never ran, so it never reached the target
+                if times == 0 {
+                    return Ok(());
+                }
+                let dest = self.offset_idx(*offset)?;
+                self.add_at(dest, times, *mul as i128)
+            }
+            Statement::SearchZero { stride } => {
+                self.idx = skip_to_zero(&self.data, self.idx, *stride);
+                while !self.cur()?.is_zero() {
+                    self.move_by(*stride)?;
+                }
+
+                Ok(())
+            }
+            Statement::SearchValue { stride, target } => {
+                while self.cur()?.to_unsigned() as i128 != unsigned(*target as i128, C::BITS) {
+                    self.move_by(*stride)?;
+                }
+
+                Ok(())
+            }
+            Statement::Copy { offset } => {
+                let val = self.cur()?.to_unsigned() as i128;
+                let dest = self.offset_idx(*offset)?;
+                self.add_at(dest, val, 1)
+            }
+            Statement::LinearCombine { dest_offset, terms } => {
+                let dest = self.offset_idx(*dest_offset)?;
+                for (mul, offset) in terms {
+                    let src = self.offset_idx(*offset)?;
+                    self.add_at(dest, self.data[src].to_unsigned() as i128, *mul as i128)?;
+                    self.data[src] = C::zero();
+                }
+                Ok(())
+            }
+            Statement::MulAddMany { targets } => {
+                let times = self.cur()?.to_unsigned() as i128;
+                if times == 0 {
+                    return Ok(());
+                }
+                for (offset, mul) in targets {
+                    let dest = self.offset_idx(*offset)?;
+                    self.add_at(dest, times, *mul as i128)?;
+                }
+                self.clear();
+                Ok(())
+            }
+            Statement::MoveValue { offset } => {
+                let val = self.cur()?;
+                let dest = self.offset_idx(*offset)?;
+                self.data[dest] = val;
+                self.clear();
+                Ok(())
+            }
+            Statement::ClearScan { stride } => {
+                while !self.cur()?.is_zero() {
+                    self.clear();
+                    self.move_by(*stride)?;
+                }
+
+                Ok(())
+            }
+            Statement::WriteBlock { stride } => {
+                if self.remaining_output.is_some() || self.trace_io.is_some() {
+                    // One byte at a time, so the output limit and the trace see each of them
+                    while !self.cur()?.is_zero() {
+                        self.out()?;
+                        self.move_by(*stride)?;
+                    }
+                    return Ok(());
+                }
+
+                // Each cell is a step, as each pass of the loop would be, and the bytes go out
+                // in chunks, so a scan that never finds a zero cell, like `[.<]` stuck at
+                // cell 0, runs into the step limit rather than buffering without end
+                let mut block = Vec::with_capacity(WRITE_CHUNK);
+                let res = loop {
+                    if self.cur()?.is_zero() {
+                        break Ok(());
+                    }
+                    if let Err(e) = self.tick() {
+                        break Err(e);
+                    }
+                    block.push(self.cur()?.to_output_byte());
+                    if block.len() == WRITE_CHUNK {
+                        self.output.write_bytes(&block)?;
+                        block.clear();
+                    }
+                    if let Err(e) = self.move_by(*stride) {
+                        break Err(e);
+                    }
+                };
+                self.output.write_bytes(&block)?;
+                res
+            }
+            Statement::ReadBlock { stride } => {
+                // EOF handling and tracing work a byte at a time, so this still reads byte
+                // by byte, just without going through a `Loop`
+                while !self.cur()?.is_zero() {
+                    self.tick()?;
+                    self.inp()?;
+                    self.move_by(*stride)?;
+                }
+
+                Ok(())
+            }
+            Statement::Assert { cell, expected, pos } => {
+                let actual = self.data.get(*cell).copied().unwrap_or_else(C::zero);
+                if actual.to_unsigned() as i128 == unsigned(*expected as i128, C::BITS) {
+                    Ok(())
+                } else {
+                    Err(Error::AssertionFailed { pos: *pos, cell: *cell, expected: *expected, actual: actual.to_signed() })
+                }
+            }
+            Statement::Halt => Err(Error::Halted),
+            Statement::Breakpoint => {
+                // Whatever the program wrote before the breakpoint should show up before it
+                self.flush()?;
+                eprintln!("{}", tape_dump(self.idx, self.data.len(), |i| self.data[i].to_signed()));
+                Ok(())
+            }
+            Statement::InfiniteLoopIfNonZero => {
+                if !self.cur()?.is_zero() {
+                    if let Some(max) = self.max_steps {
+                        return Err(Error::StepLimit(max));
+                    }
+                    // Same as running `[]`, without burning a core while at it. The output
+                    // so far has to show up first, as nothing else will flush it.
+                    self.flush()?;
+                    loop {
+                        std::thread::park();
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Runs `stmts` with `,` reading from `input` and returns the bytes `.` wrote. Running
+    /// out of input does what the `EofMode` says. The context's own input and output are
+    /// put back afterwards; the tape is not reset first.
+    pub fn run_capturing(&mut self, stmts: impl AsRef<[Statement]>, input: &[u8]) -> Result<Vec<u8>, Error> {
+        let input = InputSource::from_bytes(input.to_vec().into_iter()).into();
+        let input = std::mem::replace(&mut self.input, input);
+        let output = std::mem::replace(&mut self.output, OutputSink::Buffer(Vec::new()));
+
+        let res = self.exec_many(stmts);
+        self.input = input;
+        let captured = match std::mem::replace(&mut self.output, output) {
+            OutputSink::Buffer(buf) => buf,
+            _ => unreachable!("the sink is only replaced here"),
+        };
+        match res {
+            Ok(()) | Err(Error::Halted) | Err(Error::OutputComplete) => Ok(captured),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Runs `blk`, then flushes the output, which is otherwise buffered. On an error the
+    /// output written up to that point is still flushed before the error is returned.
+    pub fn exec_many(&mut self, blk: impl AsRef<[Statement]>) -> Result<(), Error> {
+        let res = self.exec_block(blk.as_ref(), None);
+        let flushed = self.flush();
+        res?;
+        Ok(flushed?)
+    }
+
+    /// `exec_many`, with each `IoEvent` carrying the source range of the statement that
+    /// read or wrote the byte. `spans` runs parallel to `blk`, as from `program_spanned`
+    /// or `optimize_spanned`.
+    pub fn exec_many_spanned(&mut self, blk: impl AsRef<[Statement]>, spans: &[Span]) -> Result<(), Error> {
+        let res = self.exec_block(blk.as_ref(), Some(spans));
+        self.source = None;
+        let flushed = self.flush();
+        res?;
+        Ok(flushed?)
+    }
+
+    // `exec_many` without the flush, for loop bodies
+    fn exec_block(&mut self, prog: &[Statement], spans: Option<&[Span]>) -> Result<(), Error> {
+        prog.iter().enumerate().try_for_each(|(i, s)| {
+            // println!("{:?}: {}", s, self.idx);
+            self.exec_at(s, spans.and_then(|spans| spans.get(i)))
+        })
+    }
+}
+
+impl<C: Cell> Drop for Context<C> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
     use super::*;
     use crate::bf::Statement::{Dec, Next, Inc, Prev};
 
+    // A writer whose bytes can still be looked at after a context took ownership of it,
+    // also from another thread
+    #[derive(Clone, Default)]
+    pub(crate) struct Shared(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Shared {
+        pub(crate) fn bytes(&self) -> Vec<u8> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn adder() {
+        let mut ctx = Context::with_state(vec![10, 20]);
+
+        let prog = Statement::Loop(vec![Dec(1), Next(1), Inc(1), Prev(1)]);
+
+        ctx.exec(&prog).unwrap();
+
+        assert_eq!(ctx.data[1], 30);
+    }
+
+    #[test]
+    fn wide_cells() {
+        let mut ctx = Context16::default();
+        ctx.inc_many(300).unwrap();
+        assert_eq!(ctx.data(), &[300]);
+        ctx.dec_many(301).unwrap();
+        assert_eq!(ctx.data(), &[-1]);
+
+        // 20 * 20 doesn't wrap at 16 bits, and the fused loops agree
+        let (_, prog) = crate::parser::program("++++[>+++++<-]>[>++++++++++++++++++++<-]>+").unwrap();
+        for prog in &[prog.clone(), optimize(&prog)] {
+            let mut ctx = Context16::default();
+            ctx.exec_many(prog).unwrap();
+            assert_eq!(ctx.data(), &[0, 0, 401]);
+        }
+
+        let mut ctx = Context64::from_cells(vec![i64::MAX, 1 << 40]);
+        ctx.exec_many(optimize(crate::parser::program("+>[-<+>]").unwrap().1)).unwrap();
+        assert_eq!(ctx.data(), &[i64::MIN + (1 << 40), 0]);
+
+        // `,` at the end of input under `NegativeOne` fills every bit
+        let mut ctx = Context32::default();
+        ctx.set_input(InputSource::from_bytes(std::iter::empty()));
+        ctx.set_eof_mode(EofMode::NegativeOne);
+        ctx.exec(&Statement::In).unwrap();
+        assert_eq!(ctx.cur().unwrap(), -1);
+    }
+
+    #[test]
+    fn inc_by_more_than_a_byte() {
+        // `Inc(300)` lands where 300 `Inc(1)`s do, reduced at the cell width
+        fn check<C: Cell>(arith: CellArith, expected: u64) {
+            let mut folded = Context::<C>::default();
+            folded.set_arith(arith);
+            folded.exec(&Inc(300)).unwrap();
+
+            let mut stepped = Context::<C>::default();
+            stepped.set_arith(arith);
+            (0..300).for_each(|_| stepped.exec(&Inc(1)).unwrap());
+
+            assert_eq!(folded.cur().unwrap(), stepped.cur().unwrap());
+            assert_eq!(folded.cur().unwrap().to_unsigned(), expected, "{} bits", C::BITS);
+
+            folded.exec(&Dec(300)).unwrap();
+            stepped.dec_many(300).unwrap();
+            assert_eq!(folded.cur().unwrap(), stepped.cur().unwrap());
+        }
+
+        check::<i8>(CellArith::Wrap, 44);
+        check::<i16>(CellArith::Wrap, 300);
+        check::<i32>(CellArith::Wrap, 300);
+        check::<i64>(CellArith::Wrap, 300);
+        check::<i8>(CellArith::Saturate, 255);
+        check::<i16>(CellArith::Saturate, 300);
+
+        let mut ctx = Context8::default();
+        ctx.inc_many(300).unwrap();
+        assert_eq!(ctx.cur().unwrap(), 44);
+    }
+
+    #[test]
+    fn tape_modes() {
+        let run = |mode, src: &str| {
+            let mut ctx = Context::with_tape_mode(mode);
+            let res = ctx.exec_many(crate::parser::program(src).unwrap().1);
+            (res, ctx)
+        };
+
+        let (res, ctx) = run(TapeMode::Clamp, "<+");
+        assert!(res.is_ok());
+        assert_eq!((ctx.data(), ctx.idx()), (&[1][..], 0));
+
+        let (res, ctx) = run(TapeMode::Error, "+><<+");
+        assert!(matches!(res, Err(Error::OutOfBounds(0))));
+        assert_eq!((ctx.data(), ctx.idx()), (&[1, 0][..], 0));
+
+        let (res, mut ctx) = run(TapeMode::Growable, "+<<+>>>");
+        assert!(res.is_ok());
+        assert_eq!((ctx.data(), ctx.origin(), ctx.idx()), (&[0, 1, 0, 1, 0][..], 3, 4));
+        ctx.reset();
+        assert_eq!(ctx.idx(), ctx.origin());
+
+        // Fused statements reach to the left too. `optimize` leaves `+++[-<<+>>]` alone
+        // here, since under `Clamp` the loop stops at cell 0 on the way.
+        let (_, prog) = crate::parser::program("+++[-<<+>>]").unwrap();
+        assert!(matches!(optimize(&prog)[1], Statement::Loop(_)));
+        let opt = [Inc(3), Statement::AddOffset { mul: 1, offset: -2 }, Statement::Clear];
+        let mut ctx = Context::with_tape_mode(TapeMode::Growable);
+        ctx.exec_many(&opt).unwrap();
+        assert_eq!(ctx.data()[ctx.origin() - 2..], [3, 0, 0]);
+
+        // Cells on the left count against the memory budget
+        let mut ctx = Context::with_tape_mode(TapeMode::Growable);
+        ctx.set_memory_budget(Some(MemoryBudget::new(4)));
+        ctx.exec_many(crate::parser::program("<<<").unwrap().1).unwrap();
+        assert_eq!(ctx.data().len(), 4);
+        assert!(matches!(ctx.exec(&Prev(1)), Err(Error::OutOfBounds(0))));
+
+        // The ends of the tape meet, for plain moves and fused statements alike
+        let mut ctx = Context::with_state(vec![0; 4]);
+        ctx.set_tape_mode(TapeMode::Wrap);
+        ctx.exec_many(crate::parser::program("<+>>>>>>+").unwrap().1).unwrap();
+        assert_eq!((ctx.data(), ctx.idx()), (&[0, 1, 0, 1][..], 1));
+        ctx.exec_many(&opt).unwrap();
+        assert_eq!(ctx.data(), &[0, 0, 0, 5]);
+    }
+
+    #[test]
+    fn search_zero_skips_ahead() {
+        use Statement::SearchZero;
+
+        let mut data = vec![1i8; 1000];
+        data[10] = 0;
+        data[700] = 0;
+        let mut ctx = Context::with_state(data);
+        ctx.set_idx(500);
+        ctx.exec(&SearchZero { stride: 1 }).unwrap();
+        assert_eq!(ctx.idx(), 700);
+        ctx.exec(&Prev(1)).unwrap();
+        ctx.exec(&SearchZero { stride: -1 }).unwrap();
+        assert_eq!(ctx.idx(), 10);
+
+        // With no zero left, the ends of the tape behave as they do for `[>]` and `[<]`
+        let mut ctx = Context::with_state(vec![1; 5]);
+        ctx.exec(&SearchZero { stride: 1 }).unwrap();
+        assert_eq!((ctx.idx(), ctx.data().len()), (5, 6));
+
+        let mut ctx = Context::with_state(vec![1; 5]);
+        ctx.set_tape_mode(TapeMode::Error);
+        ctx.set_idx(4);
+        assert!(matches!(ctx.exec(&SearchZero { stride: -1 }), Err(Error::OutOfBounds(0))));
+        assert_eq!(ctx.idx(), 0);
+
+        let mut ctx = Context::with_state(vec![1, 0, 1, 1]);
+        ctx.set_tape_mode(TapeMode::Wrap);
+        ctx.set_idx(2);
+        ctx.exec(&SearchZero { stride: 1 }).unwrap();
+        assert_eq!(ctx.idx(), 1);
+    }
+
+    #[test]
+    fn run_capturing() {
+        let (_, hello) = crate::parser::program(&crate::parser::bf_chars(include_str!("../hello.bf"))).unwrap();
+        let table: &[(&str, &[u8], EofMode, &[u8])] = &[
+            (",[.,]", b"cat", EofMode::Zero, b"cat"),
+            (",[.-]", b"\x03", EofMode::Error, &[3, 2, 1]),
+            (",+.", b"", EofMode::NegativeOne, &[0]),
+            ("++++++++[>+++++++++++++++++++++++++<-]>.", b"", EofMode::Error, &[200]),
+            (",+.,+.", b"a", EofMode::LeaveUnchanged, b"bc"),
+            ("+.@halt.", b"", EofMode::Error, &[1]),
+        ];
+        for (src, input, eof, expected) in table {
+            let prog = crate::parser::program_with_asserts(src).unwrap();
+            let mut ctx = Context::with_eof_mode(*eof);
+            assert_eq!(ctx.run_capturing(&prog, input).unwrap(), *expected, "{}", src);
+        }
+
+        let mut ctx = Context::new();
+        ctx.set_output(OutputSink::Buffer(b"kept".to_vec()));
+        assert_eq!(ctx.run_capturing(&hello, b"").unwrap(), b"Hello World!\n");
+        assert_eq!(ctx.output().captured(), Some(&b"kept"[..]));
+
+        // Stopping at the output limit isn't an error either
+        let mut ctx = Context::new();
+        ctx.set_stop_after_output(Some(2));
+        assert_eq!(ctx.run_capturing(crate::parser::program("+[.+]").unwrap().1, b"").unwrap(), [1, 2]);
+
+        // Running out of input is an error under `EofMode::Error`
+        let mut ctx = Context::new();
+        assert!(matches!(ctx.run_capturing(crate::parser::program(",,").unwrap().1, b"x"), Err(Error::IO(_))));
+    }
+
+    #[test]
+    fn optimized() {
+        let mut ctx = Context::with_state(vec![0, 20]);
+
+        let prog = Statement::Loop(vec![Dec(1), Dec(1), Dec(1), Dec(1), Inc(1), Inc(1), Inc(1), Inc(1), Dec(1)]);
+        let opt = constant_fold(vec![Inc(1), prog]);
+
+
+        ctx.exec_many(&opt).unwrap();
+
+        assert_eq!(ctx.data[0], 0);
+    }
+
     #[test]
-    fn adder() {
-        let mut ctx = Context {
-            idx: 0,
-            data: vec![10, 20],
+    fn copy_restore_preserves_source() {
+        let (_, prog) = crate::parser::program("[->+>+<<]>>[-<<+>>]<<").unwrap();
+        let opt = optimize(&prog);
+
+        assert!(opt.iter().all(|s| !matches!(s, Statement::Loop(_))));
+        assert_eq!(opt[0], Statement::Copy { offset: 1 });
+
+        let mut ctx = Context::with_state(vec![42, 0, 0]);
+        ctx.exec_many(&opt).unwrap();
+        assert_eq!(ctx.data(), &[42, 42, 0]);
+        assert_eq!(ctx.idx, 0);
+    }
+
+    #[test]
+    fn copy_restore_matches_unoptimized() {
+        let (_, prog) = crate::parser::program("[->>+>+<<<]>>>[-<<<+>>>]<<<").unwrap();
+        let opt = optimize(&prog);
+        assert_eq!(opt[0], Statement::Copy { offset: 2 });
+
+        let mut expected = Context::with_state(vec![-7, 3, 5, 9]);
+        expected.exec_many(&prog).unwrap();
+        let mut actual = Context::with_state(vec![-7, 3, 5, 9]);
+        actual.exec_many(&opt).unwrap();
+
+        assert_eq!(actual.data(), expected.data());
+        assert_eq!(actual.data(), &[2, 3, -2, 0]);
+    }
+
+    #[test]
+    fn multiply_overflow_policies() {
+        let (_, prog) = crate::parser::program("[->+++<]").unwrap();
+        let opt = optimize(&prog);
+
+        for p in [&prog, &opt].iter() {
+            let mut ctx = Context::with_state(vec![100, 0]);
+            ctx.exec_many(p).unwrap();
+            assert_eq!(ctx.data()[1], 44);
+
+            let mut ctx = Context::with_state(vec![100, 0]);
+            ctx.set_arith(CellArith::Saturate);
+            ctx.exec_many(p).unwrap();
+            assert_eq!(ctx.data()[1] as u8, 255);
+
+            let mut ctx = Context::with_state(vec![100, 0]);
+            ctx.set_arith(CellArith::Error);
+            assert!(matches!(ctx.exec_many(p), Err(Error::Overflow(1))));
+        }
+    }
+
+    #[test]
+    fn soft_limit_fires_once() {
+        use std::rc::Rc;
+        use std::cell::Cell;
+
+        let fired = Rc::new(Cell::new(0));
+        let mut ctx = Context::new();
+        ctx.set_soft_limit(Some(100));
+        let counter = fired.clone();
+        ctx.on_soft_limit(move |len| {
+            assert_eq!(len, 101);
+            counter.set(counter.get() + 1);
+        });
+
+        let (_, prog) = crate::parser::program(&">".repeat(300)).unwrap();
+        ctx.exec_many(&prog).unwrap();
+        assert_eq!(ctx.data().len(), 301);
+        assert_eq!(fired.get(), 1);
+    }
+
+    #[test]
+    fn signedness_in_debug() {
+        let mut ctx = Context::with_state(vec![200u8 as i8, 1]);
+        assert_eq!(format!("{:?}", ctx), "Context { data: [200, 1], idx: 0 }");
+        ctx.set_signedness(Signedness::Signed);
+        assert_eq!(format!("{:?}", ctx), "Context { data: [-56, 1], idx: 0 }");
+    }
+
+    #[test]
+    fn linear_combine() {
+        // c = 2a + 3b, with a = 5 and b = 7 in cells 0 and 1 and c in cell 2
+        let (_, prog) = crate::parser::program("+++++>+++++++<[->>++<<]>[->+++<]").unwrap();
+        let opt = optimize(&prog);
+        assert_eq!(&opt[opt.len() - 2..], &[
+            Statement::LinearCombine { dest_offset: 2, terms: vec![(2, 0), (3, 1)] },
+            Statement::Move(1),
+        ]);
+
+        for p in &[&prog, &opt] {
+            let mut ctx = Context::new();
+            ctx.exec_many(p).unwrap();
+            assert_eq!((ctx.data(), ctx.idx()), (&[0, 0, 31][..], 1));
+        }
+
+        // Different destinations, the same source twice, or a source left of the first one
+        for src in &["[->+<]>>[->+<]", "[->+<][->+<]", ">[->>+<<]<[->>>+<<<]"] {
+            let (_, prog) = crate::parser::program(src).unwrap();
+            assert!(!optimize(&prog).iter().any(|s| s.name() == "linear_combine"), "{}", src);
+        }
+    }
+
+    #[test]
+    fn iterator_input() {
+        let (_, p) = crate::parser::program(",>,>,").unwrap();
+        let mut ctx = Context::with_input_iter(vec![1, 2, 3].into_iter());
+        ctx.exec_many(&p).unwrap();
+        assert_eq!(ctx.data(), &[1, 2, 3]);
+
+        let mut ctx = Context::with_input_iter(vec![1].into_iter());
+        assert!(matches!(ctx.exec_many(&p), Err(Error::IO(_))));
+
+        let mut ctx = Context::with_input_iter(vec![1].into_iter());
+        ctx.set_eof_mode(EofMode::NegativeOne);
+        ctx.exec_many(&p).unwrap();
+        assert_eq!(ctx.data(), &[1, -1, -1]);
+    }
+
+    #[test]
+    fn in_memory_io() {
+        let (_, hello) = crate::parser::program(&crate::parser::bf_chars(include_str!("../hello.bf"))).unwrap();
+        let mut ctx = Context::with_input(InputSource::from_reader(io::empty()));
+        ctx.set_output(OutputSink::Buffer(Vec::new()));
+        ctx.exec_many(&hello).unwrap();
+        assert_eq!(ctx.output().captured().unwrap(), b"Hello World!\n");
+
+        // Upper-cases its input up to the end
+        let (_, upper) = crate::parser::program(",[--------------------------------.,]").unwrap();
+        let out = Shared::default();
+        let mut ctx = Context::with_io(io::Cursor::new(b"abc".to_vec()), out.clone());
+        ctx.set_eof_mode(EofMode::Zero);
+        ctx.exec_many(&upper).unwrap();
+        ctx.flush().unwrap();
+        assert_eq!(out.bytes(), b"ABC");
+    }
+
+    #[test]
+    fn raw_byte_output() {
+        // Counts from 0 up to 255, printing every value on the way
+        let (_, prog) = crate::parser::program(".+[.+]").unwrap();
+        let mut ctx = Context::new();
+        ctx.set_output(OutputSink::Buffer(Vec::new()));
+        ctx.exec_many(&prog).unwrap();
+        assert_eq!(ctx.output().captured().unwrap(), (0..=255).collect::<Vec<u8>>().as_slice());
+    }
+
+    #[test]
+    fn eof_modes() {
+        let (_, prog) = crate::parser::program("+++,>,").unwrap();
+        let run = |eof| {
+            let mut ctx = Context::with_eof_mode(eof);
+            ctx.set_input(InputSource::from_bytes(std::iter::empty()));
+            ctx.exec_many(&prog).map(|_| ctx.data().to_vec())
         };
+        assert_eq!(run(EofMode::LeaveUnchanged).unwrap(), [3, 0]);
+        assert_eq!(run(EofMode::Zero).unwrap(), [0, 0]);
+        assert_eq!(run(EofMode::NegativeOne).unwrap(), [-1, -1]);
+        assert!(matches!(run(EofMode::Error), Err(Error::IO(e)) if e.kind() == io::ErrorKind::UnexpectedEof));
+    }
 
-        let prog = Statement::Loop(vec![Dec(1), Next(1), Inc(1), Prev(1)]);
+    #[test]
+    fn eof_sentinel() {
+        // Copies input while the flag says more follows, then the last byte
+        let (_, cat) = crate::parser::program(",>[<.,>]<.").unwrap();
+        let run = |input: &[u8], eof| {
+            let mut ctx = Context::with_input_iter(input.to_vec().into_iter());
+            ctx.set_eof_mode(eof);
+            ctx.set_output(OutputSink::Buffer(Vec::new()));
+            ctx.exec_many(&cat).unwrap();
+            ctx.output().captured().unwrap().to_vec()
+        };
+        assert_eq!(run(b"a\0b", EofMode::Sentinel { value: 0, flag: true }), b"a\0b");
 
-        ctx.exec(&prog).unwrap();
+        // `,[.,]` can't tell the zero byte from the end
+        let (_, cat) = crate::parser::program(",[.,]").unwrap();
+        let mut ctx = Context::with_input_iter(b"a\0b".to_vec().into_iter());
+        ctx.set_eof_mode(EofMode::Zero);
+        ctx.set_output(OutputSink::Buffer(Vec::new()));
+        ctx.exec_many(&cat).unwrap();
+        assert_eq!(ctx.output().captured().unwrap(), b"a");
 
-        assert_eq!(ctx.data[1], 30);
+        let (_, p) = crate::parser::program(",>>,").unwrap();
+        let mut ctx = Context::with_input_iter(vec![7].into_iter());
+        ctx.set_eof_mode(EofMode::Sentinel { value: 0, flag: true });
+        ctx.exec_many(&p).unwrap();
+        assert_eq!(ctx.data(), &[7, 0, 0, 0]);
+
+        // Without the flag, the cell to the right is the program's own
+        let mut ctx = Context::with_state(vec![0, 9]);
+        ctx.set_input(InputSource::from_bytes(vec![7].into_iter()));
+        ctx.set_eof_mode(EofMode::Sentinel { value: 5, flag: false });
+        ctx.exec_many(&p).unwrap();
+        assert_eq!(ctx.data(), &[7, 9, 5]);
+
+        // The flag cell of a `,` on the last cell of a ring is cell 0
+        let (_, p) = crate::parser::program(">,").unwrap();
+        let mut ctx = Context::with_state(vec![9, 0]);
+        ctx.set_input(InputSource::from_bytes(vec![7, 8].into_iter()));
+        ctx.set_tape_mode(TapeMode::Wrap);
+        ctx.set_eof_mode(EofMode::Sentinel { value: 0, flag: true });
+        ctx.exec_many(&p).unwrap();
+        assert_eq!(ctx.data(), &[1, 7]);
     }
 
     #[test]
-    fn optimized() {
-        let mut ctx = Context {
-            idx: 0,
-            data: vec![0, 20],
+    fn memory_budget() {
+        let (_, prog) = crate::parser::program("+[>+]").unwrap();
+        let mut ctx = Context::new();
+        ctx.set_memory_budget(Some(MemoryBudget::new(100)));
+
+        assert!(matches!(ctx.exec_many(&prog), Err(Error::OutOfBounds(100))));
+        assert_eq!(ctx.data.len(), 100);
+        assert!(ctx.data.capacity() <= 100);
+        assert_eq!(ctx.idx, 99);
+    }
+
+    #[test]
+    fn add_offset_within_budget() {
+        let prog = [Inc(3), Statement::AddOffset { mul: 1, offset: 100 }];
+        let mut ctx = Context::new();
+        ctx.set_memory_budget(Some(MemoryBudget::new(16)));
+        assert!(matches!(ctx.exec_many(&prog), Err(Error::OutOfBounds(100))));
+        assert_eq!(ctx.data(), &[3]);
+
+        // Spare capacity doesn't let the destination past the budget either
+        let mut tape = Vec::with_capacity(1000);
+        tape.push(3);
+        let mut ctx = Context::with_state(tape);
+        ctx.set_memory_budget(Some(MemoryBudget::new(16)));
+        assert!(matches!(ctx.exec(&prog[1]), Err(Error::OutOfBounds(100))));
+        assert_eq!(ctx.data(), &[3]);
+
+        ctx.exec(&Statement::AddOffset { mul: 1, offset: 15 }).unwrap();
+        assert_eq!((ctx.data().len(), ctx.data()[15]), (16, 3));
+    }
+
+    #[test]
+    fn add_offset_out_of_range() {
+        // Without a budget, only cells left of cell 0 and ones that can't be allocated
+        // are out of bounds
+        let mut ctx = Context::with_state(vec![0, 3]);
+        ctx.next().unwrap();
+        for offset in &[isize::MAX, isize::MAX - 1, isize::MIN, -2] {
+            let res = ctx.exec(&Statement::AddOffset { mul: 1, offset: *offset });
+            assert!(matches!(res, Err(Error::OutOfBounds(_))), "{}: {:?}", offset, res);
+        }
+        assert_eq!((ctx.data(), ctx.idx()), (&[0, 3][..], 1));
+    }
+
+    #[test]
+    fn search_nonzero_sentinel() {
+        // Cells before the 5 wrap below zero while the target is subtracted
+        let (_, prog) = crate::parser::program("+>++>+++++>->++<<<<-----[+++++>-----]+++++").unwrap();
+        let opt = optimize(&prog);
+        assert_eq!(&opt[5..], &[Statement::SearchValue { stride: 1, target: 5 }]);
+
+        let mut expected = Context::new();
+        expected.exec_many(&prog).unwrap();
+        let mut ctx = Context::new();
+        ctx.exec_many(&opt).unwrap();
+        assert_eq!(ctx.data(), expected.data());
+        assert_eq!(ctx.idx(), 2);
+
+        let backward = [Statement::SearchValue { stride: -2, target: 255 }];
+        let mut ctx = Context::with_state(vec![5, 0, -1, 0, 1, 0, 7]);
+        ctx.set_idx(6);
+        ctx.exec_many(&backward).unwrap();
+        assert_eq!(ctx.idx(), 2);
+    }
+
+    #[test]
+    fn empty_loops_flagged_or_removed() {
+        let (_, prog) = crate::parser::program("+[-][]>[]+[]").unwrap();
+        assert_eq!(optimize(&prog), vec![
+            Inc(1), Statement::Clear, Statement::Move(1), Statement::InfiniteLoopIfNonZero,
+            Inc(1), Statement::InfiniteLoopIfNonZero,
+        ]);
+
+        // Not entered, so this finishes
+        let (_, prog) = crate::parser::program(">[]+").unwrap();
+        let mut ctx = Context::new();
+        ctx.exec_many(optimize(&prog)).unwrap();
+        assert_eq!(ctx.data(), &[0, 1]);
+
+        // Entered, so this never finishes, but what it wrote before still shows up
+        let sink = Shared::default();
+        let written = sink.clone();
+        std::thread::spawn(move || {
+            let mut ctx = Context::new();
+            ctx.set_output(OutputSink::Writer(Box::new(io::BufWriter::new(sink))));
+            ctx.exec_many(optimize(crate::parser::program("+.[]").unwrap().1))
+        });
+        let start = std::time::Instant::now();
+        while written.bytes().is_empty() {
+            assert!(start.elapsed() < std::time::Duration::from_secs(10), "the output was never flushed");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(written.bytes(), vec![1]);
+    }
+
+    #[test]
+    fn dead_loops_removed() {
+        // A leading comment loop, a loop right after a clear, and one after another loop
+        let (_, prog) = crate::parser::program("[.,]+[-][>+<.]+[.>][<]").unwrap();
+        let opt = optimize_with(&prog, Patterns { dead_loops: true, ..Patterns::default() });
+        assert_eq!(opt, vec![Inc(1), Statement::Clear, Inc(1), Statement::WriteBlock { stride: 1 }]);
+        assert!(crate::testing::behaviorally_equal(&prog, &opt, &[b""]));
+
+        // Moving away and back, or reading, could leave the cell nonzero
+        for src in &["+[-]><[.]", "+[-]<>[.]", "+[-],[.]"] {
+            let folded = peephole_optimization(constant_fold(crate::parser::program(src).unwrap().1));
+            assert_eq!(dead_loops(&folded), folded, "{}", src);
+        }
+
+        // Off by default, as a fragment may start on a nonzero cell
+        let (_, prog) = crate::parser::program("[.]").unwrap();
+        assert_eq!(optimize(&prog), vec![Statement::Loop(vec![Statement::Out])]);
+    }
+
+    #[test]
+    fn trace_io_events() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        use crate::bf::trace::{IoDirection::*, IoEvent};
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let log = events.clone();
+
+        let (_, prog) = crate::parser::program(">,.").unwrap();
+        let mut ctx = Context::with_input_iter(b"A".to_vec().into_iter());
+        ctx.set_output(OutputSink::Buffer(Vec::new()));
+        ctx.trace_io(move |e| log.borrow_mut().push(e.clone()));
+        ctx.exec_many(&prog).unwrap();
+
+        assert_eq!(*events.borrow(), vec![
+            IoEvent { direction: Read, byte: b'A', cell: 1, step: 2, source: None },
+            IoEvent { direction: Write, byte: b'A', cell: 1, step: 3, source: None },
+        ]);
+        assert_eq!(events.borrow()[0].to_string(), "read 0x41 at cell 1 (step 2)");
+
+        // With spans, each byte points back at its `,` or `.`, inside loops too
+        events.borrow_mut().clear();
+        let (prog, spans) = crate::parser::program_spanned(">,.\n[-]+[.-]").unwrap();
+        let mut ctx = Context::with_input_iter(b"A".to_vec().into_iter());
+        ctx.set_output(OutputSink::Buffer(Vec::new()));
+        let log = events.clone();
+        ctx.trace_io(move |e| log.borrow_mut().push(e.clone()));
+        ctx.exec_many_spanned(&prog, &spans).unwrap();
+
+        let sources: Vec<_> = events.borrow().iter().map(|e| (e.direction, e.source.clone())).collect();
+        assert_eq!(sources, vec![(Read, Some(1..2)), (Write, Some(2..3)), (Write, Some(9..10))]);
+        assert_eq!(events.borrow()[1].to_string(), "write 0x41 at cell 1 (step 3, source bytes 2..3)");
+    }
+
+    #[test]
+    fn recorded_trace() {
+        use crate::bf::trace::read_trace;
+
+        let file = Shared::default();
+        let (_, prog) = crate::parser::program("++[>+<-]>").unwrap();
+        let mut ctx = Context::new();
+        ctx.record_trace(TraceRecorder::new(file.clone()));
+        ctx.exec_many(&prog).unwrap();
+
+        let rec = ctx.take_trace_recorder().unwrap().unwrap();
+        // Two `+`, 4 statements for each of 2 iterations, then the loop itself and `>`
+        assert_eq!(rec.records(), 12);
+
+        let trace = read_trace(&file.bytes()[..]).unwrap();
+        assert_eq!(trace.len(), 12);
+        let r = |op, pos, value| TraceRecord { op, pos, value };
+        assert_eq!(&trace[..6], &[
+            r("inc", 0, 1), r("inc", 0, 2),
+            r("next", 1, 0), r("inc", 1, 1), r("prev", 0, 2), r("dec", 0, 1),
+        ]);
+        assert_eq!(&trace[10..], &[r("loop", 0, 0), r("next", 1, 2)]);
+    }
+
+    #[test]
+    fn buffered_output_flushed_on_error() {
+        use std::io::BufWriter;
+
+        let sink = Shared::default();
+        let (_, prog) = crate::parser::program("++++++++[>++++++++<-]>+.+.+.[]").unwrap();
+        let mut ctx = Context::new();
+        ctx.set_output(OutputSink::Writer(Box::new(BufWriter::new(sink.clone()))));
+        ctx.set_step_limit(Some(1000));
+
+        assert!(matches!(ctx.exec_many(&prog), Err(Error::StepLimit(1000))));
+        assert_eq!(sink.bytes(), b"ABC");
+
+        // And when `exec_many` returns, or the context is dropped
+        let sink = Shared::default();
+        let (_, prog) = crate::parser::program("++++++++[>++++++++<-]>+.").unwrap();
+        let mut ctx = Context::new();
+        ctx.set_output(OutputSink::Writer(Box::new(BufWriter::new(sink.clone()))));
+        ctx.exec_many(&prog).unwrap();
+        assert_eq!(sink.bytes(), b"A");
+        ctx.exec(&Statement::Out).unwrap();
+        assert_eq!(sink.bytes(), b"A");
+        drop(ctx);
+        assert_eq!(sink.bytes(), b"AA");
+    }
+
+    #[test]
+    fn step_limited_runs() {
+        let (_, spin) = crate::parser::program("+[]").unwrap();
+        let mut ctx = Context::new();
+        assert_eq!(ctx.exec_many_limited(&spin, 100).unwrap(), StepOutcome::LimitReached { steps: 100 });
+        // Fused into a single step that never finishes
+        let mut ctx = Context::new();
+        assert!(matches!(ctx.exec_many_limited(optimize(&spin), 100).unwrap(), StepOutcome::LimitReached { .. }));
+
+        // The tighter of the two limits applies, and the outer one stays in place
+        let mut ctx = Context::new();
+        ctx.set_step_limit(Some(10));
+        assert_eq!(ctx.exec_many_limited(&spin, 100).unwrap(), StepOutcome::LimitReached { steps: 10 });
+        assert!(matches!(ctx.exec_many(&spin), Err(Error::StepLimit(10))));
+
+        let (_, prog) = crate::parser::program("+++").unwrap();
+        let mut ctx = Context::new();
+        assert_eq!(ctx.exec_many_limited(&prog, 3).unwrap(), StepOutcome::Completed { steps: 3 });
+        assert_eq!(ctx.exec_many_limited([Statement::Halt, Statement::Inc(1)], 3).unwrap(), StepOutcome::Completed { steps: 1 });
+        assert_eq!(ctx.steps(), 4);
+    }
+
+    #[test]
+    fn output_flushed_once() {
+        use std::cell::Cell;
+        use std::io::Write;
+        use std::rc::Rc;
+
+        #[derive(Clone, Default)]
+        struct Flushes(Rc<Cell<usize>>);
+
+        impl Write for Flushes {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                self.0.set(self.0.get() + 1);
+                Ok(())
+            }
+        }
+
+        // A loop's body ends 255 times without flushing
+        let flushes = Flushes::default();
+        let (_, prog) = crate::parser::program("+[.+]").unwrap();
+        let mut ctx = Context::new();
+        ctx.set_output(OutputSink::Writer(Box::new(flushes.clone())));
+        ctx.exec_many(&prog).unwrap();
+        assert_eq!(flushes.0.get(), 1);
+
+        // Except before reading, so prompts show up
+        let flushes = Flushes::default();
+        let (_, prog) = crate::parser::program(".,.,.").unwrap();
+        let mut ctx = Context::with_input_iter(b"ab".to_vec().into_iter());
+        ctx.set_output(OutputSink::Writer(Box::new(flushes.clone())));
+        ctx.exec_many(&prog).unwrap();
+        assert_eq!(flushes.0.get(), 3);
+    }
+
+    #[test]
+    fn move_into_known_zero() {
+        let (_, prog) = crate::parser::program("+++>>[-]<[-]<[->+<]>[->+<]+++[->>+<<]").unwrap();
+        let opt = optimize(&prog);
+        assert_eq!(&opt[..7], &[
+            Statement::Inc(3), Statement::Move(2), Statement::Clear, Statement::Move(-1), Statement::Clear,
+            Statement::Move(-1), Statement::MoveValue { offset: 1 },
+        ]);
+        // Cell 2 was cleared, and then moved into
+        assert_eq!(opt[8], Statement::MoveValue { offset: 1 });
+        // Cell 3 was never cleared
+        assert_eq!(opt[10], Statement::AddOffset { mul: 1, offset: 2 });
+
+        let mut a = Context::new();
+        a.exec_many(&prog).unwrap();
+        let mut b = Context::new();
+        b.exec_many(&opt).unwrap();
+        assert_eq!(a.data(), b.data());
+        assert_eq!(b.data()[..4], [0, 0, 3, 3]);
+
+        // The pre-clear of the destination is dropped
+        let (_, prog) = crate::parser::program("++>+++<>[-]<[->+<]+++++>>[-]<<[->>+<<]").unwrap();
+        let opt = optimize(&prog);
+        assert_eq!(opt, vec![
+            Statement::Inc(2), Statement::IncAt { offset: 1, amount: 3 }, Statement::MoveValue { offset: 1 },
+            Statement::Inc(5), Statement::MoveValue { offset: 2 },
+        ]);
+        assert!(opt.len() < prog.len());
+        assert!(crate::testing::behaviorally_equal(&prog, &opt, &[b""]));
+
+        // `Prev` may clamp at cell 0, so nothing is known after it
+        let (_, prog) = crate::parser::program(">[-]<<[->+<]").unwrap();
+        assert!(!optimize(&prog).contains(&Statement::MoveValue { offset: 1 }));
+
+        // Moving left into a cleared cell works the same way
+        let (_, prog) = crate::parser::program("+>+++<[-]>[-<+>]").unwrap();
+        let opt = optimize(&prog);
+        assert!(opt.contains(&Statement::MoveValue { offset: -1 }), "{:?}", opt);
+        assert!(crate::testing::behaviorally_equal(&prog, &opt, &[b""]));
+        let mut ctx = Context::new();
+        ctx.exec_many(&opt).unwrap();
+        assert_eq!(ctx.data()[..2], [3, 0]);
+    }
+
+    #[test]
+    fn wide_multiplier() {
+        let text = format!("+++[->{}<]", "+".repeat(300));
+        let (_, prog) = crate::parser::program(&text).unwrap();
+        let opt = optimize(&prog);
+        assert_eq!(opt[1], Statement::AddOffset { mul: 300, offset: 1 });
+        let wrapped = wrap_muls(&opt, 8);
+        assert_eq!(wrapped[1], Statement::AddOffset { mul: 44, offset: 1 });
+        assert_eq!(wrap_mul(200, 8), -56);
+        assert_eq!(wrap_mul(-1, 8), -1);
+        assert_eq!(wrap_mul(70_000, 16), 70_000 - 65_536);
+
+        for p in &[&prog, &opt, &wrapped] {
+            let mut ctx = Context::new();
+            ctx.exec_many(p).unwrap();
+            // 900 mod 256
+            assert_eq!(ctx.data()[1] as u8, 132);
+        }
+
+        // Saturation needs the full multiplier
+        let mut ctx = Context::new();
+        ctx.set_arith(CellArith::Saturate);
+        ctx.exec_many(&opt).unwrap();
+        assert_eq!(ctx.data()[1] as u8, 255);
+    }
+
+    #[test]
+    fn block_io() {
+        use std::cell::RefCell;
+        use std::io::Write;
+        use std::rc::Rc;
+
+        // Records the length of each write
+        #[derive(Clone, Default)]
+        struct Writes(Rc<RefCell<Vec<usize>>>);
+
+        impl Write for Writes {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().push(buf.len());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // Reads into the five cells set up first, then writes them back out
+        let (_, prog) = crate::parser::program("+>+>+>+>+<<<<[,>]<<<<<[.>]").unwrap();
+        let opt = optimize(&prog);
+        assert!(opt.contains(&Statement::ReadBlock { stride: 1 }));
+        assert_eq!(opt.last(), Some(&Statement::WriteBlock { stride: 1 }));
+        assert!(crate::testing::behaviorally_equal(&prog, &opt, &[b"hello", b"he\0lo", b"a"]));
+
+        let writes = Writes::default();
+        let mut ctx = Context::with_input_iter(b"hello".to_vec().into_iter());
+        ctx.set_output(OutputSink::Writer(Box::new(writes.clone())));
+        ctx.exec_many(&opt).unwrap();
+        assert_eq!(*writes.0.borrow(), vec![5]);
+        assert_eq!(ctx.idx(), 5);
+
+        let writes = Writes::default();
+        let mut ctx = Context::with_input_iter(b"hello".to_vec().into_iter());
+        ctx.set_output(OutputSink::Writer(Box::new(writes.clone())));
+        ctx.exec_many(&prog).unwrap();
+        assert_eq!(*writes.0.borrow(), vec![1; 5]);
+
+        // `[>,]` reads until a zero byte, which no single read can do
+        let (_, prog) = crate::parser::program(",[>,]").unwrap();
+        assert!(matches!(optimize(&prog)[1], Statement::Loop(_)));
+
+        // Never finds a zero cell, but each cell is a step, and the output goes out as it goes
+        let (_, prog) = crate::parser::program("+[.<]").unwrap();
+        let opt = optimize(&prog);
+        assert_eq!(opt[1], Statement::WriteBlock { stride: -1 });
+        let writes = Writes::default();
+        let mut ctx = Context::new();
+        ctx.set_output(OutputSink::Writer(Box::new(writes.clone())));
+        ctx.set_step_limit(Some(3 * WRITE_CHUNK as u64));
+        assert!(matches!(ctx.exec_many(&opt), Err(Error::StepLimit(_))));
+        assert_eq!(writes.0.borrow()[..2], [WRITE_CHUNK, WRITE_CHUNK]);
+
+        // Leftward, with a stride
+        let (_, prog) = crate::parser::program(">>>>>>+++++++++[<++++++++<+++++++>>-]<<++<[.<<]").unwrap();
+        let opt = optimize(&prog);
+        assert!(opt.contains(&Statement::WriteBlock { stride: -2 }));
+        assert!(crate::testing::behaviorally_equal(&prog, &opt, &[b""]));
+
+        let (_, prog) = crate::parser::program("[.>][,>]").unwrap();
+        assert_eq!(optimize_with(&prog, Patterns { block_io: false, ..Patterns::default() }), constant_fold(&prog));
+    }
+
+    #[test]
+    fn multiply_loop_order() {
+        let orders = ["[->+>>---<<<]", "[>>>---<<+<-]", "[>+>>---<<<-]", "[>>>---<<<->+<]"];
+        let opts: Vec<_> = orders.iter()
+            .map(|src| optimize(crate::parser::program(src).unwrap().1))
+            .collect();
+
+        assert_eq!(opts[0], vec![Statement::MulAddMany { targets: vec![(1, 1), (3, -3)] }]);
+        for opt in &opts[1..] {
+            assert_eq!(format!("{:?}", opt), format!("{:?}", opts[0]));
+        }
+
+        // A copy into two cells reads the source once, before clearing it
+        let (_, prog) = crate::parser::program("[->+>+<<]").unwrap();
+        let opt = optimize(&prog);
+        assert_eq!(opt, vec![Statement::MulAddMany { targets: vec![(1, 1), (2, 1)] }]);
+        let mut ctx = Context::with_state(vec![3, 1, 2]);
+        ctx.exec_many(&opt).unwrap();
+        assert_eq!(ctx.data(), &[0, 4, 5]);
+
+        let mut s = crate::bf::panicking::StaticContext::<i8, 16>::new();
+        s.exec_many(crate::parser::program("+++>+>++<<").unwrap().1);
+        s.exec_many(&opt);
+        assert_eq!(&s.data()[..3], &[0, 4, 5]);
+
+        assert!(validate(vec![Statement::MulAddMany { targets: vec![(1, 1), (0, 2)] }]).is_err());
+
+        let hash = |s: &Vec<Statement>| {
+            use std::hash::{Hash, Hasher};
+            let mut h = std::collections::hash_map::DefaultHasher::new();
+            s.hash(&mut h);
+            h.finish()
         };
+        assert!(opts.iter().all(|o| hash(o) == hash(&opts[0])));
 
-        let prog = Statement::Loop(vec![Dec(1), Dec(1), Dec(1), Dec(1), Inc(1), Inc(1), Inc(1), Inc(1), Dec(1)]);
-        let opt = constant_fold(vec![Inc(1), prog]);
+        let (_, prog) = crate::parser::program("+++++[>+++>>---<<<-]").unwrap();
+        assert!(crate::testing::behaviorally_equal(&prog, &optimize(&prog), &[b""]));
+
+        // Not multiply loops: a target changed twice, and a loop cell decremented by two
+        for src in &[",[->+>-<+<]", ",[-->+<]"] {
+            let (_, prog) = crate::parser::program(src).unwrap();
+            assert!(matches!(optimize(&prog)[1], Statement::Loop(_)), "{}", src);
+        }
+    }
 
+    #[test]
+    fn mirror_multiply_loops() {
+        // Copies the value two cells back, with the moves going left first
+        let (_, prog) = crate::parser::program(">>+++++[-<<+>>]<<[>>+<<-]>>>+[<]").unwrap();
+        let opt = optimize(&prog);
+        assert_eq!(opt[..4], [Statement::Move(2), Inc(5), Statement::AddOffset { mul: 1, offset: -2 }, Statement::Clear]);
+        assert!(opt.contains(&Statement::SearchZero { stride: -1 }));
+        assert!(crate::testing::behaviorally_equal(&prog, &opt, &[b""]));
 
+        let mut ctx = Context::new();
         ctx.exec_many(&opt).unwrap();
+        assert_eq!((&ctx.data()[..4], ctx.idx()), (&[0, 0, 5, 1][..], 1));
 
-        assert_eq!(ctx.data[0], 0);
+        let (_, prog) = crate::parser::program(">>>>>+++[-<+<---<<<+>>>>>]").unwrap();
+        let opt = optimize(&prog);
+        assert_eq!(opt[2], Statement::MulAddMany { targets: vec![(-5, 1), (-2, -3), (-1, 1)] });
+
+        // Two cells in, the loop would have stopped at cell 0 on the way left, so it stays
+        let (_, prog) = crate::parser::program(">>+++[-<+<---<<<+>>>>>]").unwrap();
+        let opt = optimize(&prog);
+        assert!(matches!(opt[2], Statement::Loop(_)));
+        assert!(crate::testing::behaviorally_equal(&prog, &opt, &[b""]));
+
+        // A zero cell skips the loop, wherever its targets would have been
+        for src in &["[-<+>]", "[-<<+>+>]", ">[-<<+>>]<"] {
+            let (_, prog) = crate::parser::program(src).unwrap();
+            let opt = optimize(&prog);
+            assert!(!opt.iter().any(|s| matches!(s, Statement::AddOffset { .. } | Statement::MulAddMany { .. })), "{}", src);
+            assert!(Context::new().exec_many(&opt).is_ok(), "{}", src);
+        }
+        for s in &[Statement::AddOffset { mul: 1, offset: -1 }, Statement::MulAddMany { targets: vec![(-2, 1), (-1, 1)] }] {
+            assert!(Context::new().exec(s).is_ok());
+            crate::bf::panicking::StaticContext::<i8, 4>::new().exec(s);
+        }
+    }
+
+    #[test]
+    fn distant_add_offset() {
+        let expected = vec![Statement::AddOffset { mul: 1, offset: 3 }, Statement::Clear];
+
+        // The moves only become one `Move(3)` once folded
+        let (_, prog) = crate::parser::program("[->>>+<<<]").unwrap();
+        assert_eq!(constant_fold(&prog), vec![Statement::Loop(vec![Dec(1), Statement::Move(3), Inc(1), Statement::Move(-3)])]);
+        assert_eq!(peephole_optimization(constant_fold(&prog)), expected);
+        assert_eq!(optimize(&prog), expected);
+
+        // Comments between the moves are gone before folding
+        let parsed = crate::parser::parse_source("[-> > >+<< <] moves three").unwrap();
+        assert_eq!(optimize(&parsed.stmts), expected);
+
+        // Ends a cell to the right of where it started, so it is a scan, not a multiply
+        let (_, prog) = crate::parser::program("[->>>+<<]").unwrap();
+        let opt = optimize(&prog);
+        assert_eq!(opt, vec![Statement::Loop(vec![Dec(1), Statement::Move(3), Inc(1), Statement::Move(-2)])]);
+        let mut ctx = Context::with_state(vec![2, 0, 0, 0, 0, 0]);
+        ctx.exec_many(opt).unwrap();
+        assert_eq!((ctx.data(), ctx.idx()), (&[1, 0, 0, 1, 0, 0][..], 1));
+    }
+
+    #[test]
+    fn halt_keeps_intermediate_state() {
+        let src = "+++[>++<-]>+ @halt [-]<+++";
+        let prog = crate::parser::program_with_asserts(src).unwrap();
+        assert_eq!(prog[6], Statement::Halt);
+
+        for p in &[&prog, &optimize(&prog)] {
+            let mut ctx = Context::new();
+            assert!(matches!(ctx.exec_many(p), Err(Error::Halted)));
+            assert_eq!(ctx.data(), &[0, 7]);
+            assert_eq!(ctx.idx(), 1);
+        }
+
+        // From inside a loop
+        let prog = crate::parser::program_with_asserts("+++++[->+< @halt ]").unwrap();
+        let mut ctx = Context::new();
+        assert!(matches!(ctx.exec_many(&prog), Err(Error::Halted)));
+        assert_eq!(ctx.data(), &[4, 1]);
+    }
+
+    #[test]
+    fn stop_after_output() {
+        let (_, prog) = crate::parser::program("+[.>+]").unwrap();
+        let mut ctx = Context::new();
+        ctx.set_stop_after_output(Some(10));
+
+        assert!(matches!(ctx.exec_many(&prog), Err(Error::OutputComplete)));
+        // Stopped right after the tenth `.`, before moving on
+        assert_eq!(ctx.idx, 9);
+        assert_eq!(ctx.data(), &[1; 10]);
+    }
+
+    #[test]
+    fn constant_fold_runs() {
+        let (_, prog) = crate::parser::program("+++-->><<<[->+<],.+").unwrap();
+        assert_eq!(constant_fold(&prog), vec![
+            Inc(3), Dec(2), Statement::Move(2), Statement::Move(-3),
+            Statement::Loop(vec![Dec(1), Statement::Move(1), Inc(1), Statement::Move(-1)]),
+            Statement::In, Statement::Out, Inc(1),
+        ]);
+
+        // Already-folded runs are summed, and other statements pass through
+        let folded = vec![Next(2), Statement::Move(3), Statement::Clear, Statement::SearchZero { stride: 1 }, Inc(4), Inc(1)];
+        assert_eq!(constant_fold(&folded), vec![Statement::Move(5), Statement::Clear, Statement::SearchZero { stride: 1 }, Inc(5)]);
+    }
+
+    #[test]
+    fn net_fold_runs() {
+        let (_, prog) = crate::parser::program("+++--.+-,->><<[>>><<<<+],<<>>>").unwrap();
+        let folded = net_fold(constant_fold(&prog));
+        assert_eq!(folded, vec![
+            Inc(1), Statement::Out, Statement::In, Dec(1),
+            Statement::Loop(vec![Statement::Move(-1), Inc(1)]),
+            Statement::In, Statement::Move(-2), Statement::Move(3),
+        ]);
+
+        // Net amounts are left for the cell width to wrap
+        assert_eq!(net_fold(vec![Inc(200), Inc(100), Dec(1)]), vec![Inc(299)]);
+
+        // `Prev` stopping at cell 0 makes `<<>>>` differ from `>` near the start of the tape
+        let moves = vec![Prev(2), Next(3), Prev(1), Next(1)];
+        for start in 0..5 {
+            let mut expected = Context::with_state(vec![0; 8]);
+            expected.set_idx(start);
+            expected.exec_many(&moves).unwrap();
+            let mut ctx = Context::with_state(vec![0; 8]);
+            ctx.set_idx(start);
+            ctx.exec_many(net_fold(&moves)).unwrap();
+            assert_eq!(ctx.idx(), expected.idx(), "starting at {}", start);
+        }
+    }
+
+    #[test]
+    fn disabled_patterns() {
+        let (_, prog) = crate::parser::program("[->+<][-]").unwrap();
+        let patterns = Patterns { add_offset: false, inc_at: false, ..Patterns::default() };
+        assert_eq!(optimize_with(&prog, patterns), vec![
+            Statement::Loop(vec![Dec(1), Statement::Move(1), Inc(1), Statement::Move(-1)]),
+            Statement::Clear,
+        ]);
+    }
+
+    #[test]
+    fn fused_matches_separate_passes() {
+        let sources = [
+            include_str!("../mandelbrot.bf"),
+            include_str!("../hanoi.bf"),
+            include_str!("../rot13.bf"),
+            ">[[-]>]<<[<]+++[->+<]",
+        ];
+
+        for src in sources.iter() {
+            let (_, prog) = crate::parser::program(&crate::parser::bf_chars(src)).unwrap();
+            let mut fused = prog.clone();
+            optimize_in_place(&mut fused);
+            assert_eq!(fused, peephole_optimization(constant_fold(&prog)));
+        }
+    }
+
+    #[test]
+    fn clear_scan() {
+        let (_, prog) = crate::parser::program(">[[-]>]").unwrap();
+        let opt = optimize(&prog);
+        assert_eq!(opt, vec![Statement::Move(1), Statement::ClearScan { stride: 1 }]);
+
+        let mut ctx = Context::with_state(vec![0, 1, 2, 3, 0, 7]);
+        ctx.exec_many(&opt).unwrap();
+        assert_eq!(ctx.data(), &[0, 0, 0, 0, 0, 7]);
+        assert_eq!(ctx.idx, 4);
+    }
+
+    #[test]
+    fn optimize_reaches_fixpoint() {
+        // `linear-combine` leaves a `Move` next to the one after it, which only the next
+        // round's `constant-fold` merges
+        let (_, prog) = crate::parser::program(",[->>++<<]>[->+++<]>>.").unwrap();
+        let once = passes(Patterns::default()).iter().fold(prog.clone(), |s, (_, pass)| pass(&s));
+        assert_eq!(once[2..4], [Statement::Move(1), Statement::Move(2)]);
+        let (opt, rounds) = optimize_to_fixpoint(&prog, Patterns::default(), MAX_ROUNDS);
+        assert_eq!(opt[2..], [Statement::Move(3), Statement::Out]);
+        assert_eq!(rounds, 3);
+        assert_eq!(optimize(&prog), opt);
+        assert_eq!(optimize_to_fixpoint(&opt, Patterns::default(), MAX_ROUNDS), (opt.clone(), 1));
+
+        // The cap wins over convergence
+        assert_eq!(optimize_to_fixpoint(&prog, Patterns::default(), 1), (once, 1));
+        assert_eq!(optimize_to_fixpoint(&prog, Patterns::default(), 0), (prog.clone(), 0));
+
+        for src in &[include_str!("../mandelbrot.bf"), include_str!("../hanoi.bf")] {
+            let (_, prog) = crate::parser::program(&crate::parser::bf_chars(src)).unwrap();
+            assert!(optimize_to_fixpoint(&prog, Patterns::default(), MAX_ROUNDS).1 < MAX_ROUNDS);
+        }
+    }
+
+    #[test]
+    fn optimize_stats() {
+        let (_, prog) = crate::parser::program("++[->+<]>[-]+++>>[<]<[-]").unwrap();
+        let (opt, stats) = optimize_with_stats(&prog);
+        assert_eq!(opt, optimize(&prog));
+        assert_eq!(stats, OptStats {
+            before: 20,
+            after: 9,
+            clears: 2,
+            add_offsets: 1,
+            search_zeros: 1,
+            set_consts: 1,
+            inc_ats: 0,
+            linear_combines: 0,
+            folded_runs: 3,
+        });
+        assert_eq!(stats.to_string(), "optimized 20 -> 9 statements: clears=2 add_offsets=1 search_zeros=1 set_consts=1 inc_ats=0 linear_combines=0 folded_runs=3");
+
+        let (_, prog) = crate::parser::program("[->+>++<<]<<>+").unwrap();
+        let stats = optimize_with_stats(&prog).1;
+        assert_eq!((stats.add_offsets, stats.clears, stats.folded_runs), (1, 0, 3));
+
+        // `net-fold` and `inc-at` rewrites count too
+        let (_, prog) = crate::parser::program(",+-+>>++<<.").unwrap();
+        let stats = optimize_with_stats(&prog).1;
+        assert_eq!((stats.inc_ats, stats.folded_runs), (1, 4));
+    }
+
+    #[test]
+    fn set_const() {
+        let src = format!("+[-]{}>[-]---<[->+<]+", "+".repeat(300));
+        let (_, prog) = crate::parser::program(&src).unwrap();
+        let opt = optimize(&prog);
+        assert_eq!(opt, vec![
+            Inc(1), Statement::SetConst(300), Statement::Move(1), Statement::SetConst(-3), Statement::Move(-1),
+            Statement::AddOffset { mul: 1, offset: 1 }, Statement::Clear, Inc(1),
+        ]);
+        let mut fused = prog.clone();
+        optimize_in_place(&mut fused);
+        assert_eq!(fused, opt);
+
+        // Reduced to the cell width when it runs
+        let mut ctx = Context::new();
+        ctx.exec_many(&opt[..4]).unwrap();
+        assert_eq!(ctx.data(), &[44, -3]);
+        let mut s = crate::bf::panicking::StaticContext::<i16, 4>::new();
+        s.exec_many(&opt[..4]);
+        assert_eq!(&s.data()[..2], &[300, -3]);
+
+        // Under the configured arithmetic, like the `+`s it replaces
+        let mut ctx = Context::new();
+        ctx.set_arith(CellArith::Saturate);
+        ctx.exec_many(&opt[..4]).unwrap();
+        let mut plain = Context::new();
+        plain.set_arith(CellArith::Saturate);
+        plain.exec_many(&prog[..307]).unwrap();
+        assert_eq!(ctx.data(), &[-1, 0]);
+        assert_eq!(ctx.data(), plain.data());
+        let mut ctx = Context::new();
+        ctx.set_arith(CellArith::Error);
+        assert!(matches!(ctx.exec_many(&opt[..4]), Err(Error::Overflow(0))));
+        let mut s = crate::bf::panicking::StaticContext::<u8, 4>::new();
+        s.set_arith(CellArith::Saturate);
+        s.exec_many(&opt[..4]);
+        assert_eq!(&s.data()[..2], &[255, 0]);
+
+        // Only right after the clear
+        let (_, prog) = crate::parser::program(",[-]>+,[-]<>+").unwrap();
+        assert!(!optimize(&prog).iter().any(|s| matches!(s, Statement::SetConst(_))));
+
+        let (_, prog) = crate::parser::program(",[-]++").unwrap();
+        let patterns = Patterns { set_const: false, ..Patterns::default() };
+        assert_eq!(optimize_with(&prog, patterns), vec![Statement::In, Statement::Clear, Inc(2)]);
+    }
+
+    #[test]
+    fn inc_at_runs() {
+        let (_, prog) = crate::parser::program("+>>++<-<.>>>+<+>>-<<<<").unwrap();
+        let opt = optimize(&prog);
+        assert_eq!(opt, vec![
+            Inc(1), Statement::IncAt { offset: 2, amount: 2 }, Statement::IncAt { offset: 1, amount: -1 }, Statement::Out,
+            Statement::IncAt { offset: 3, amount: 1 }, Statement::IncAt { offset: 2, amount: 1 }, Statement::IncAt { offset: 4, amount: -1 },
+        ]);
+        assert!(crate::testing::behaviorally_equal(&prog, &opt, &[b""]));
+
+        let mut ctx = Context::new();
+        ctx.exec_many(&opt).unwrap();
+        assert_eq!((ctx.data(), ctx.idx()), (&[1, -1, 3, 1, -1][..], 0));
+        let mut s = crate::bf::panicking::StaticContext::<i8, 8>::new();
+        s.exec_many(&opt);
+        assert_eq!(&s.data()[..5], ctx.data());
+
+        // Going left of the start could stop at cell 0, and a run that doesn't come back
+        // moves the pointer
+        for src in &[",<+>.", ",>+<<+>", ",>+>-"] {
+            let (_, prog) = crate::parser::program(src).unwrap();
+            assert!(!optimize(&prog).iter().any(|s| matches!(s, Statement::IncAt { .. })), "{}", src);
+        }
+    }
+
+    #[test]
+    fn breakpoints() {
+        // Only asked for; otherwise `#` is a comment
+        let src = "+>++ # [-#]";
+        assert!(!crate::parser::parse_source(src).unwrap().stmts.contains(&Statement::Breakpoint));
+        let prog = crate::parser::parse_with_breakpoints(src).unwrap().stmts;
+        assert_eq!(prog[4], Statement::Breakpoint);
+        assert_eq!(prog[5], Statement::Loop(vec![Dec(1), Statement::Breakpoint]));
+
+        // Breakpoints only print, and the optimizer works around them
+        let mut ctx = Context::new();
+        ctx.exec_many(optimize(&prog)).unwrap();
+        assert_eq!((ctx.data(), ctx.idx), (&[1, 0][..], 1));
+
+        let stripped = strip_breakpoints(&prog);
+        assert_eq!(stripped, vec![Inc(1), Next(1), Inc(1), Inc(1), Statement::Loop(vec![Dec(1)])]);
+        assert_eq!(optimize(&stripped), vec![Inc(1), Statement::Move(1), Inc(2), Statement::Clear]);
+
+        assert_eq!(tape_dump(1, 2, |i| [1, 2][i]), "# ptr 1, cells 0..2: 1 [2]");
+        assert_eq!(
+            tape_dump(20, 100, |i| i as i64),
+            "# ptr 20, cells 12..29: 12 13 14 15 16 17 18 19 [20] 21 22 23 24 25 26 27 28",
+        );
     }
 }
\ No newline at end of file
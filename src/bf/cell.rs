@@ -0,0 +1,71 @@
+use std::fmt::Debug;
+
+/// A tape cell for `StaticContext`. Implemented for the standard integer types; a custom
+/// implementation can wrap one of them to instrument or change how cells behave.
+///
+/// Values are taken as unsigned, as BF programs expect: `CellArith` saturates and
+/// overflows at 0 and `2^BITS - 1`.
+pub trait Cell: Copy + PartialEq + Debug {
+    /// Width in bits, at most 64
+    const BITS: u32;
+
+    fn zero() -> Self;
+
+    /// `self + n`, wrapping at the cell width. Used for all arithmetic under
+    /// `CellArith::Wrap`.
+    fn wrapping_add(self, n: i64) -> Self;
+
+    /// The value as an unsigned number below `2^BITS`
+    fn to_unsigned(self) -> u64;
+
+    /// The cell holding the low `BITS` bits of `v`
+    fn from_unsigned(v: u64) -> Self;
+
+    /// The value sign-extended from `BITS` bits, for display
+    fn to_signed(self) -> i64 {
+        let shift = 64 - Self::BITS;
+        ((self.to_unsigned() << shift) as i64) >> shift
+    }
+
+    /// What `.` writes
+    fn to_output_byte(self) -> u8 {
+        self.to_unsigned() as u8
+    }
+
+    /// What `,` stores
+    fn from_input_byte(b: u8) -> Self {
+        Self::from_unsigned(b as u64)
+    }
+
+    fn is_zero(self) -> bool {
+        self == Self::zero()
+    }
+}
+
+macro_rules! impl_cell {
+    ($($num:ty => $unsigned:ty),*) => {
+        $(
+            impl Cell for $num {
+                const BITS: u32 = (std::mem::size_of::<$num>() * 8) as u32;
+
+                fn zero() -> Self {
+                    0
+                }
+
+                fn wrapping_add(self, n: i64) -> Self {
+                    <$num>::wrapping_add(self, n as $num)
+                }
+
+                fn to_unsigned(self) -> u64 {
+                    self as $unsigned as u64
+                }
+
+                fn from_unsigned(v: u64) -> Self {
+                    v as $num
+                }
+            }
+        )*
+    };
+}
+
+impl_cell!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, u8 => u8, u16 => u16, u32 => u32, u64 => u64);
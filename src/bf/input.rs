@@ -0,0 +1,212 @@
+use std::fmt::{self, Debug, Formatter};
+use std::io::{self, stdin, Read};
+
+#[cfg(feature = "raw-tty")]
+pub use raw::RawTty;
+
+/// What `,` does to the current cell once the input is exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EofMode {
+    /// Fail with `UnexpectedEof`
+    Error,
+    LeaveUnchanged,
+    Zero,
+    NegativeOne,
+    /// Store `value`. With `flag`, real input can be told apart from it: every `,` also
+    /// sets the cell to its right to 1 if more input follows the byte just read, and to 0
+    /// once the input is exhausted, which looks ahead one byte. Without it, that cell is
+    /// left alone.
+    Sentinel { value: u8, flag: bool },
+}
+
+impl Default for EofMode {
+    fn default() -> Self {
+        EofMode::Error
+    }
+}
+
+/// Where `,` takes its bytes from.
+pub enum InputSource {
+    /// Line-buffered standard input
+    Stdin,
+    /// Single keystrokes from a terminal in raw mode
+    #[cfg(feature = "raw-tty")]
+    RawTty(RawTty),
+    /// Any byte iterator, e.g. generated input or a channel adapter
+    Iter(Box<dyn Iterator<Item = u8>>),
+    /// Any reader, e.g. a file, a socket or a `Cursor` over bytes in memory. It is read a
+    /// byte at a time, so slow readers are best wrapped in a `BufReader`.
+    Reader(Box<dyn Read>),
+}
+
+impl Debug for InputSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            InputSource::Stdin => f.write_str("Stdin"),
+            #[cfg(feature = "raw-tty")]
+            InputSource::RawTty(tty) => f.debug_tuple("RawTty").field(tty).finish(),
+            InputSource::Iter(_) => f.write_str("Iter(..)"),
+            InputSource::Reader(_) => f.write_str("Reader(..)"),
+        }
+    }
+}
+
+impl InputSource {
+    /// Puts the terminal into raw mode so `,` sees each keystroke as it is typed.
+    ///
+    /// When stdin is not a tty (e.g. input is piped in) this falls back to `Stdin`.
+    #[cfg(feature = "raw-tty")]
+    pub fn raw_tty() -> io::Result<Self> {
+        use crossterm::tty::IsTty;
+
+        if stdin().is_tty() {
+            Ok(InputSource::RawTty(RawTty::new()?))
+        } else {
+            Ok(InputSource::Stdin)
+        }
+    }
+
+    pub fn from_bytes(it: impl Iterator<Item = u8> + 'static) -> Self {
+        InputSource::Iter(Box::new(it))
+    }
+
+    pub fn from_reader(r: impl Read + 'static) -> Self {
+        InputSource::Reader(Box::new(r))
+    }
+
+    /// The next input byte, or `None` at end of input
+    pub fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let res = match self {
+            InputSource::Stdin => {
+                let mut res = [0u8];
+                stdin().read_exact(&mut res).map(|_| res[0])
+            }
+            #[cfg(feature = "raw-tty")]
+            InputSource::RawTty(tty) => tty.read_byte(),
+            InputSource::Iter(it) => return Ok(it.next()),
+            InputSource::Reader(r) => {
+                let mut res = [0u8];
+                r.read_exact(&mut res).map(|_| res[0])
+            }
+        };
+
+        match res {
+            Ok(b) => Ok(Some(b)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Default for InputSource {
+    fn default() -> Self {
+        InputSource::Stdin
+    }
+}
+
+/// An `InputSource` that can look at the next byte without consuming it.
+#[derive(Debug, Default)]
+pub struct PeekableInput {
+    input: InputSource,
+    peeked: Option<Option<u8>>,
+}
+
+impl PeekableInput {
+    pub fn new(input: InputSource) -> Self {
+        PeekableInput { input, peeked: None }
+    }
+
+    /// The byte the next `read_byte` will return. Reads from the source the first time,
+    /// so on `Stdin` this waits for input just like a read would.
+    pub fn peek(&mut self) -> io::Result<Option<u8>> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.input.read_byte()?);
+        }
+        Ok(self.peeked.unwrap())
+    }
+
+    pub fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        match self.peeked.take() {
+            Some(b) => Ok(b),
+            None => self.input.read_byte(),
+        }
+    }
+
+    /// Hands back the source. A peeked byte is lost.
+    pub fn into_inner(self) -> InputSource {
+        self.input
+    }
+}
+
+impl From<InputSource> for PeekableInput {
+    fn from(input: InputSource) -> Self {
+        PeekableInput::new(input)
+    }
+}
+
+#[cfg(feature = "raw-tty")]
+mod raw {
+    use std::collections::VecDeque;
+    use std::io;
+    use std::sync::Once;
+    use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
+    use crossterm::terminal;
+
+    static PANIC_HOOK: Once = Once::new();
+
+    fn to_io(e: crossterm::ErrorKind) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, e)
+    }
+
+    /// Raw-mode terminal input. The terminal is restored when this is dropped,
+    /// and also before any panic message is printed.
+    #[derive(Debug)]
+    pub struct RawTty {
+        pending: VecDeque<u8>,
+    }
+
+    impl RawTty {
+        pub fn new() -> io::Result<Self> {
+            PANIC_HOOK.call_once(|| {
+                let prev = std::panic::take_hook();
+                std::panic::set_hook(Box::new(move |info| {
+                    let _ = terminal::disable_raw_mode();
+                    prev(info)
+                }));
+            });
+
+            terminal::enable_raw_mode().map_err(to_io)?;
+            Ok(RawTty { pending: VecDeque::new() })
+        }
+
+        pub fn read_byte(&mut self) -> io::Result<u8> {
+            while self.pending.is_empty() {
+                if let Event::Key(KeyEvent { code, modifiers }) = event::read().map_err(to_io)? {
+                    let ctrl = modifiers.contains(KeyModifiers::CONTROL);
+                    match code {
+                        // Raw mode swallows the signals these would normally send
+                        KeyCode::Char('c') if ctrl => return Err(io::ErrorKind::Interrupted.into()),
+                        KeyCode::Char('d') if ctrl => return Err(io::ErrorKind::UnexpectedEof.into()),
+                        KeyCode::Char(c) => {
+                            let mut buf = [0u8; 4];
+                            self.pending.extend(c.encode_utf8(&mut buf).bytes());
+                        }
+                        KeyCode::Enter => self.pending.push_back(b'\n'),
+                        KeyCode::Tab => self.pending.push_back(b'\t'),
+                        KeyCode::Backspace => self.pending.push_back(8),
+                        KeyCode::Esc => self.pending.push_back(27),
+                        _ => {}
+                    }
+                }
+            }
+
+            Ok(self.pending.pop_front().unwrap())
+        }
+    }
+
+    impl Drop for RawTty {
+        fn drop(&mut self) {
+            let _ = terminal::disable_raw_mode();
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::fmt::{self, Debug, Formatter};
+use std::io::{self, BufWriter, Write};
+
+/// Where `.` sends its bytes.
+pub enum OutputSink {
+    /// Written to standard output byte for byte, through a buffer that is only flushed
+    /// by `flush` (see `OutputSink::stdout`)
+    Stdout(BufWriter<io::Stdout>),
+    /// Collected in memory, e.g. to compare program output in tests
+    Buffer(Vec<u8>),
+    /// Any writer, such as a `BufWriter` around a file or socket
+    Writer(Box<dyn Write>),
+}
+
+impl Debug for OutputSink {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputSink::Stdout(_) => f.write_str("Stdout"),
+            OutputSink::Buffer(buf) => f.debug_tuple("Buffer").field(buf).finish(),
+            OutputSink::Writer(_) => f.write_str("Writer(..)"),
+        }
+    }
+}
+
+impl OutputSink {
+    pub fn stdout() -> Self {
+        OutputSink::Stdout(BufWriter::new(io::stdout()))
+    }
+
+    pub fn write_byte(&mut self, b: u8) -> io::Result<()> {
+        match self {
+            OutputSink::Stdout(w) => w.write_all(&[b]),
+            OutputSink::Buffer(buf) => buf.write_all(&[b]),
+            OutputSink::Writer(w) => w.write_all(&[b]),
+        }
+    }
+
+    /// Writes all of `bytes` at once, where `write_byte` would write them one by one
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            OutputSink::Stdout(w) => w.write_all(bytes),
+            OutputSink::Buffer(buf) => buf.write_all(bytes),
+            OutputSink::Writer(w) => w.write_all(bytes),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputSink::Stdout(w) => w.flush(),
+            OutputSink::Buffer(_) => Ok(()),
+            OutputSink::Writer(w) => w.flush(),
+        }
+    }
+
+    /// Everything written so far, if it was kept
+    pub fn captured(&self) -> Option<&[u8]> {
+        match self {
+            OutputSink::Buffer(buf) => Some(buf),
+            _ => None,
+        }
+    }
+}
+
+impl Default for OutputSink {
+    fn default() -> Self {
+        OutputSink::stdout()
+    }
+}
@@ -1,75 +1,249 @@
-use num_traits::{Num, Signed, FromPrimitive, WrappingAdd, ToPrimitive};
 use crate::Statement;
-use std::io::{stdin, Read};
-use nom::lib::std::fmt::{Debug, Formatter};
+use crate::bf::cell::Cell;
+use crate::bf::input::{EofMode, InputSource, PeekableInput};
+use crate::bf::output::OutputSink;
+use crate::bf::{CellArith, Error, Signedness, StepOutcome, unsigned, skip_to_zero, cell_view, WRITE_CHUNK, tape_dump};
+use std::fmt::{self, Debug, Formatter};
+use std::any::Any;
+use std::cell::{Ref, RefCell};
+use std::rc::Rc;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 
-const NUM_CELLS: usize = (64 * 1024);
+const NUM_CELLS: usize = 64 * 1024;
 
-macro_rules! impl_static_ctx {
-    ($name:ident, $num:ty) => {
-        #[derive(Copy, Clone)]
-pub struct $name {
-    data: [$num; NUM_CELLS],
-    pos: usize
+/// Interpreter over a fixed tape of `N` cells of type `C` that panics on errors, e.g.
+/// when the pointer leaves the tape (see `run_catching`).
+///
+/// A clone has its own copy of the tape but shares the input and output, so what one
+/// reads the other won't see again; `set_input` and `set_output` give it its own.
+#[derive(Clone)]
+pub struct StaticContext<C: Cell, const N: usize> {
+    data: [C; N],
+    pos: usize,
+    input: Rc<RefCell<PeekableInput>>,
+    output: Rc<RefCell<OutputSink>>,
+    eof: EofMode,
+    arith: CellArith,
+    signedness: Signedness,
+    halted: bool,
+    steps: u64,
+    max_steps: Option<u64>,
+    limit_reached: bool
 }
 
-impl $name {
+pub type StaticContext8 = StaticContext<i8, NUM_CELLS>;
+pub type StaticContext16 = StaticContext<i16, NUM_CELLS>;
+pub type StaticContext32 = StaticContext<i32, NUM_CELLS>;
+pub type StaticContext64 = StaticContext<i64, NUM_CELLS>;
+
+impl<C: Cell, const N: usize> StaticContext<C, N> {
     pub fn new() -> Self {
-        $name {
-            data: [0; NUM_CELLS],
-            pos: 0
+        StaticContext {
+            data: [C::zero(); N],
+            pos: 0,
+            input: Rc::new(RefCell::new(PeekableInput::default())),
+            output: Rc::new(RefCell::new(OutputSink::stdout())),
+            eof: EofMode::Error,
+            arith: CellArith::Wrap,
+            signedness: Signedness::Unsigned,
+            halted: false,
+            steps: 0,
+            max_steps: None,
+            limit_reached: false
         }
     }
 
-    pub fn with_state(data: impl AsRef<[$num]>) -> Self {
-        let mut d = [0 as $num; NUM_CELLS];
-        let data = data.as_ref();
-        data.iter()
-            .take(NUM_CELLS)
+    pub fn with_input(input: InputSource) -> Self {
+        let mut ctx = Self::new();
+        ctx.set_input(input);
+        ctx
+    }
+
+    pub fn with_eof_mode(eof: EofMode) -> Self {
+        let mut ctx = Self::new();
+        ctx.set_eof_mode(eof);
+        ctx
+    }
+
+    /// Starts with `data` at the beginning of the tape. Anything past `N` cells is
+    /// dropped; use `try_with_state` to treat that as an error.
+    pub fn with_state(data: impl AsRef<[C]>) -> Self {
+        let mut ctx = Self::new();
+        data.as_ref().iter()
+            .take(N)
             .enumerate()
-            .for_each(|(i, datum)| {d[i] = *datum;});
+            .for_each(|(i, datum)| {ctx.data[i] = *datum;});
+        ctx
+    }
 
-        $name {
-            data: d,
-            pos: 0
+    pub fn try_with_state(data: impl AsRef<[C]>) -> Result<Self, Error> {
+        let len = data.as_ref().len();
+        if len > N {
+            return Err(Error::TapeTooLarge { len, capacity: N });
         }
+        Ok(Self::with_state(data))
+    }
+
+    pub fn set_input(&mut self, input: InputSource) {
+        self.input = Rc::new(RefCell::new(input.into()));
+    }
+
+    pub fn set_output(&mut self, output: OutputSink) {
+        self.output = Rc::new(RefCell::new(output));
+    }
+
+    pub fn output(&self) -> Ref<'_, OutputSink> {
+        self.output.borrow()
+    }
+
+    /// Flushes the output sink. `exec_many` does this when it returns, and dropping the
+    /// context does it too, so output written before a panic isn't lost.
+    pub fn flush(&mut self) {
+        self.output.borrow_mut().flush().unwrap()
+    }
+
+    /// What `,` does once the input runs out. Under `EofMode::Error`, the default, it panics.
+    pub fn set_eof_mode(&mut self, eof: EofMode) {
+        self.eof = eof;
+    }
+
+    pub fn set_arith(&mut self, arith: CellArith) {
+        self.arith = arith;
+    }
+
+    /// Whether `Debug` shows cells as signed or unsigned
+    pub fn set_signedness(&mut self, signedness: Signedness) {
+        self.signedness = signedness;
     }
 
     #[inline]
-    pub fn cur(&self) -> $num {
+    pub fn cur(&self) -> C {
         self.data[self.pos]
     }
 
+    /// Moves the pointer by `offset` cells. Moving left of cell 0 stops at cell 0, as in
+    /// `Context` and the JIT; moving past the end panics on the next access.
     #[inline]
     pub fn adj_pos(&mut self, offset: isize) {
-        self.pos = (self.pos as isize + offset) as usize;
+        self.pos = (self.pos as isize + offset).max(0) as usize;
     }
 
     #[inline]
     pub fn adj_val(&mut self, val: i64) {
-        self.data[self.pos] = ((self.cur() as i64).wrapping_add(val)) as $num;
+        self.add_at(self.pos, val as i128, 1)
+    }
+
+    // data[idx] += n * mul under the configured `CellArith`
+    #[inline]
+    fn add_at(&mut self, idx: usize, n: i128, mul: i128) {
+        if self.arith == CellArith::Wrap {
+            self.data[idx] = self.data[idx].wrapping_add(n.wrapping_mul(mul) as i64);
+            return;
+        }
+
+        match self.arith.add_scaled(self.data[idx].to_unsigned() as i128, n, mul, C::BITS) {
+            Some(v) => self.data[idx] = C::from_unsigned(v as u64),
+            None => panic!("Cell overflowed at idx: {}", idx),
+        }
     }
 
     #[inline]
     pub fn clear(&mut self) {
-        self.data[self.pos] = 0;
+        self.data[self.pos] = C::zero();
     }
 
-    pub fn data(&self) -> &[$num] {
+    pub fn data(&self) -> &[C] {
         &self.data
     }
 
+    /// Whether the program stopped at a `Halt`
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Steps taken so far, counted the same way as `Context::steps`: one per statement
+    /// executed and one per loop iteration
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Stop, as if at a `Halt`, instead of taking more than `max` steps in total.
+    /// `limit_reached` tells the two apart.
+    pub fn set_step_limit(&mut self, max: Option<u64>) {
+        self.max_steps = max;
+    }
+
+    /// Whether the last `exec_many` stopped at the step limit
+    pub fn limit_reached(&self) -> bool {
+        self.limit_reached
+    }
+
+    /// Runs `stmts` taking at most `max_steps` more steps, on top of any limit from
+    /// `set_step_limit`. Panics become errors as in `run_catching`.
+    pub fn exec_many_limited(&mut self, stmts: impl AsRef<[Statement]>, max_steps: u64) -> Result<StepOutcome, Error> {
+        let start = self.steps;
+        let outer = self.max_steps;
+        let limit = start.saturating_add(max_steps);
+        self.max_steps = Some(outer.map_or(limit, |max| max.min(limit)));
+
+        let res = self.run_catching(stmts);
+        self.max_steps = outer;
+        res?;
+        let steps = self.steps - start;
+        Ok(if self.limit_reached { StepOutcome::LimitReached { steps } } else { StepOutcome::Completed { steps } })
+    }
+
+    // Counts a step, unless it would go over the limit
+    fn tick(&mut self) -> bool {
+        match self.max_steps {
+            Some(max) if self.steps >= max => {
+                self.limit_reached = true;
+                false
+            }
+            _ => {
+                self.steps += 1;
+                true
+            }
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        self.halted || self.limit_reached
+    }
+
     pub fn inp(&mut self) {
-        let mut dest = [0u8; 1];
-        stdin().read_exact(&mut dest).unwrap();
-        self.data[self.pos] = dest[0] as $num
+        // A prompt written before the read should be on screen while it waits
+        self.flush();
+        let b = match self.input.borrow_mut().read_byte().unwrap() {
+            Some(b) => C::from_input_byte(b),
+            None => match self.eof {
+                EofMode::Error => panic!("Unexpected end of input"),
+                EofMode::LeaveUnchanged => return,
+                EofMode::Zero => C::zero(),
+                // All ones at any width
+                EofMode::NegativeOne => C::zero().wrapping_add(-1),
+                EofMode::Sentinel { value, .. } => C::from_input_byte(value),
+            },
+        };
+        self.data[self.pos] = b;
+
+        if let EofMode::Sentinel { flag: true, .. } = self.eof {
+            let more = self.input.borrow_mut().peek().unwrap().is_some();
+            if self.pos + 1 == N {
+                panic!("Attempted operation out of bounds at idx: {}", self.pos + 1);
+            }
+            self.data[self.pos + 1] = C::from_unsigned(more as u64);
+        }
     }
 
-    pub fn out(&self) {
-        print!("{}", self.cur() as u8 as char)
+    pub fn out(&mut self) {
+        self.output.borrow_mut().write_byte(self.cur().to_output_byte()).unwrap()
     }
 
     pub fn exec(&mut self, s: &Statement) {
+        if !self.tick() {
+            return;
+        }
         match s {
             Statement::Next(i) => {
                 self.adj_pos(*i as isize)
@@ -77,6 +251,9 @@ impl $name {
             Statement::Prev(i) => {
                 self.adj_pos(-(*i as isize))
             },
+            Statement::Move(i) => {
+                self.adj_pos(*i)
+            },
             Statement::Inc(i) => {
                 self.adj_val(*i as i64)
             },
@@ -90,43 +267,442 @@ impl $name {
                 self.inp()
             },
             Statement::Loop(l) => {
-                while self.cur() != 0 {
-                    self.exec_many(l)
+                while !self.stopped() && !self.cur().is_zero() {
+                    if !self.tick() {
+                        break;
+                    }
+                    self.exec_block(l)
                 }
             },
             Statement::Clear => {
                 self.clear()
             },
+            Statement::SetConst(n) => {
+                self.clear();
+                self.add_at(self.pos, *n as i128, 1)
+            },
+            Statement::IncAt { offset, amount } => {
+                self.add_at((self.pos as isize + offset) as usize, *amount as i128, 1)
+            },
             Statement::AddOffset { mul, offset } => {
-                let c = (self.cur() as i64).wrapping_mul(*mul);
-                let cur_dest_val = self.data[self.pos + offset] as i64;
-                self.data[self.pos + offset] = c.wrapping_add(cur_dest_val) as $num;
+                let times = self.cur().to_unsigned() as i128;
+                if times == 0 {
+                    return;
+                }
+                // Wrapping around would land on a cell at the other end without complaint
+                let idx = (self.pos as isize).checked_add(*offset).filter(|&i| i >= 0)
+                    .unwrap_or_else(|| panic!("Attempted operation out of bounds at idx: {}", self.pos));
+                self.add_at(idx as usize, times, *mul as i128);
+            },
+            Statement::MulAddMany { targets } => {
+                let times = self.cur().to_unsigned() as i128;
+                if times == 0 {
+                    return;
+                }
+                for (offset, mul) in targets {
+                    self.add_at((self.pos as isize + offset) as usize, times, *mul as i128);
+                }
+                self.clear()
             },
             Statement::SearchZero { stride } => {
-                while self.cur() != 0 {
+                self.pos = skip_to_zero(&self.data, self.pos, *stride);
+                while !self.cur().is_zero() {
+                    self.adj_pos(*stride)
+                }
+            },
+            Statement::SearchValue { stride, target } => {
+                while self.cur().to_unsigned() as i128 != unsigned(*target as i128, C::BITS) {
+                    self.adj_pos(*stride)
+                }
+            },
+            Statement::Copy { offset } => {
+                let dest = (self.pos as isize + offset) as usize;
+                self.add_at(dest, self.cur().to_unsigned() as i128, 1);
+            },
+            Statement::MoveValue { offset } => {
+                let idx = (self.pos as isize).checked_add(*offset).filter(|&i| i >= 0)
+                    .unwrap_or_else(|| panic!("Attempted operation out of bounds at idx: {}", self.pos));
+                self.data[idx as usize] = self.cur();
+                self.clear()
+            },
+            Statement::LinearCombine { dest_offset, terms } => {
+                let dest = (self.pos as isize + dest_offset) as usize;
+                for (mul, offset) in terms {
+                    let src = (self.pos as isize + offset) as usize;
+                    self.add_at(dest, self.data[src].to_unsigned() as i128, *mul as i128);
+                    self.data[src] = C::zero();
+                }
+            },
+            Statement::ClearScan { stride } => {
+                while !self.cur().is_zero() {
+                    self.clear();
                     self.adj_pos(*stride)
                 }
             },
+            Statement::WriteBlock { stride } => {
+                // A step per cell and bounded chunks, as in `Context`
+                let mut block = Vec::new();
+                while !self.cur().is_zero() && self.tick() {
+                    block.push(self.cur().to_output_byte());
+                    if block.len() == WRITE_CHUNK {
+                        self.output.borrow_mut().write_bytes(&block).unwrap();
+                        block.clear();
+                    }
+                    self.adj_pos(*stride)
+                }
+                self.output.borrow_mut().write_bytes(&block).unwrap()
+            },
+            Statement::ReadBlock { stride } => {
+                while !self.cur().is_zero() && self.tick() {
+                    self.inp();
+                    self.adj_pos(*stride)
+                }
+            },
+            Statement::Assert { cell, expected, pos } => {
+                let actual = self.data[*cell];
+                if actual.to_unsigned() as i128 != unsigned(*expected as i128, C::BITS) {
+                    panic!("Assertion at byte {} failed: expected cell{} == {}, found {}", pos, cell, expected, actual.to_signed());
+                }
+            },
+            Statement::Halt => {
+                self.halted = true
+            },
+            Statement::Breakpoint => {
+                self.flush();
+                eprintln!("{}", tape_dump(self.pos, N, |i| self.data[i].to_signed()));
+            },
+            Statement::InfiniteLoopIfNonZero => {
+                if !self.cur().is_zero() {
+                    if self.max_steps.is_some() {
+                        // It would run into the limit eventually
+                        self.limit_reached = true;
+                        return;
+                    }
+                    // Nothing else will ever flush what was written so far
+                    self.flush();
+                    loop {
+                        std::thread::park();
+                    }
+                }
+            },
         }
     }
 
+    /// Runs `stmts`, then flushes the output, which is otherwise buffered
     pub fn exec_many(&mut self, stmts: impl AsRef<[Statement]>) {
+        self.limit_reached = false;
+        self.exec_block(stmts.as_ref());
+        self.flush()
+    }
+
+    // `exec_many` without the flush, for loop bodies
+    fn exec_block(&mut self, stmts: &[Statement]) {
+        for s in stmts {
+            if self.stopped() {
+                return;
+            }
+            self.exec(s)
+        }
+    }
+
+    /// `exec_many`, returning `Error::Runtime` instead of unwinding if the program panics,
+    /// e.g. by moving off the tape. The panic hook still runs, so the message is printed
+    /// as usual. After an error the context is only good for inspecting the tape.
+    pub fn run_catching(&mut self, stmts: impl AsRef<[Statement]>) -> Result<(), Error> {
         let stmts = stmts.as_ref();
-        stmts.iter().for_each(|s| self.exec(s))
+        // Nothing outside `self` is touched, and `self` isn't trusted to be consistent
+        // after a panic anyway
+        let res = catch_unwind(AssertUnwindSafe(|| self.exec_many(stmts)));
+        if res.is_err() {
+            // What was written before the panic is still output
+            let _ = self.output.borrow_mut().flush();
+        }
+        res.map_err(|p| Error::Runtime(panic_message(p)))
     }
 }
 
-impl Debug for $name {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}, {}", &self.data[..32], self.pos)
+impl<C: Cell, const N: usize> Drop for StaticContext<C, N> {
+    fn drop(&mut self) {
+        let _ = self.output.borrow_mut().flush();
     }
 }
-    };
+
+impl<C: Cell, const N: usize> Debug for StaticContext<C, N> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let shown: Vec<i64> = self.data[..N.min(32)].iter().map(|c| c.to_signed()).collect();
+        write!(f, "{:?}, {}", cell_view(&shown, C::BITS, self.signedness), self.pos)
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(s) => *s,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(s) => s.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::program;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
 
+    #[test]
+    fn i16_multiply_overflow_policies() {
+        let src = format!("[->{}<]", "+".repeat(100));
+        let (_, prog) = program(&src).unwrap();
+        let opt = crate::optimize(&prog);
+        assert!(matches!(opt[0], Statement::AddOffset { mul: 100, offset: 1 }));
+
+        for p in [&prog, &opt].iter() {
+            let mut ctx = StaticContext16::with_state([1000, 0]);
+            ctx.exec_many(p);
+            assert_eq!(ctx.data()[1] as u16, (100_000 % 65536) as u16);
+
+            let mut ctx = StaticContext16::with_state([1000, 0]);
+            ctx.set_arith(CellArith::Saturate);
+            ctx.exec_many(p);
+            assert_eq!(ctx.data()[1] as u16, u16::MAX);
+
+            let mut ctx = StaticContext16::with_state([1000, 0]);
+            ctx.set_arith(CellArith::Error);
+            assert!(catch_unwind(AssertUnwindSafe(|| ctx.exec_many(p))).is_err());
+        }
+    }
+
+    #[test]
+    fn eof_modes() {
+        let (_, prog) = program("+++,>,").unwrap();
+        let run = |eof| {
+            let mut ctx = StaticContext::<i16, 4>::with_eof_mode(eof);
+            ctx.set_input(InputSource::from_bytes(std::iter::empty()));
+            ctx.exec_many(&prog);
+            ctx.data()[..3].to_vec()
+        };
+        assert_eq!(run(EofMode::LeaveUnchanged), [3, 0, 0]);
+        assert_eq!(run(EofMode::Zero), [0, 0, 0]);
+        assert_eq!(run(EofMode::NegativeOne), [-1, -1, 0]);
+        assert_eq!(run(EofMode::Sentinel { value: 7, flag: true }), [7, 7, 0]);
+        assert_eq!(run(EofMode::Sentinel { value: 7, flag: false }), [7, 7, 0]);
+        assert!(catch_unwind(AssertUnwindSafe(|| run(EofMode::Error))).is_err());
+
+        // Input that is there is read as usual, and the sentinel flag says whether more follows
+        let mut ctx = StaticContext::<u8, 4>::with_eof_mode(EofMode::Sentinel { value: 0, flag: true });
+        ctx.set_input(InputSource::from_bytes(vec![5].into_iter()));
+        ctx.exec_many(program(",>>,").unwrap().1);
+        assert_eq!(ctx.data(), &[5, 0, 0, 0]);
+
+        // Without the flag the cell to the right keeps its value, even past the last cell
+        let mut ctx = StaticContext::<u8, 2>::with_state([0, 9]);
+        ctx.set_eof_mode(EofMode::Sentinel { value: 5, flag: false });
+        ctx.set_input(InputSource::from_bytes(vec![7].into_iter()));
+        ctx.exec_many(program(",>,").unwrap().1);
+        assert_eq!(ctx.data(), &[7, 5]);
+
+        // With it, there is no cell for the flag there
+        let mut ctx = StaticContext::<u8, 2>::with_eof_mode(EofMode::Sentinel { value: 5, flag: true });
+        ctx.set_input(InputSource::from_bytes(vec![7].into_iter()));
+        assert!(ctx.run_catching(program(">,").unwrap().1).is_err());
+    }
+
+    #[test]
+    fn buffered_output() {
+        use crate::bf::tests::Shared;
+        use std::io::BufWriter;
+
+        // Bytes 0 to 255, in order
+        let sink = Shared::default();
+        let mut ctx = StaticContext8::new();
+        ctx.set_output(OutputSink::Writer(Box::new(BufWriter::new(sink.clone()))));
+        ctx.exec_many(program(".+[.+]").unwrap().1);
+        assert_eq!(sink.bytes(), (0..=255).collect::<Vec<u8>>());
+
+        // Output before a panic is flushed too
+        let sink = Shared::default();
+        let mut ctx = StaticContext::<u8, 4>::new();
+        ctx.set_output(OutputSink::Writer(Box::new(BufWriter::new(sink.clone()))));
+        assert!(ctx.run_catching(program("+++.[.>+]").unwrap().1).is_err());
+        assert_eq!(sink.bytes(), [3, 3, 1, 1, 1]);
+    }
+
+    #[test]
+    fn clones_share_streams() {
+        let mut ctx = StaticContext::<u8, 4>::with_input(InputSource::from_bytes(vec![1, 2].into_iter()));
+        ctx.set_output(OutputSink::Buffer(Vec::new()));
+        ctx.exec_many(program(",+.").unwrap().1);
+
+        // The clone starts from the same tape, then reads the byte after the one `ctx` read
+        let mut copy = ctx.clone();
+        copy.exec_many(program(",+.").unwrap().1);
+        assert_eq!((ctx.data()[0], copy.data()[0]), (2, 3));
+        assert_eq!(ctx.output().captured(), Some(&[2, 3][..]));
+    }
+
+    #[test]
+    fn classic_tape_size() {
+        // The 30000 cells of the original implementation, without a type of its own
+        let (_, prog) = program("+[>+]").unwrap();
+        let mut ctx = StaticContext::<u8, 30000>::new();
+        assert!(ctx.run_catching(&prog).is_err());
+        assert_eq!(ctx.data().len(), 30000);
+        assert_eq!(ctx.data()[29999], 1);
+
+        // `Debug` shows the first 32 cells and the position
+        let mut ctx = StaticContext::<i16, 40>::with_state([7; 40]);
+        ctx.exec_many(program(">>").unwrap().1);
+        let shown = format!("{:?}", ctx);
+        assert_eq!(shown, format!("{:?}, 2", [7; 32]));
+    }
+
+    #[test]
+    fn run_catching_turns_panics_into_errors() {
+        // Runs off the end of the tape
+        let (_, prog) = program("+[>+]").unwrap();
+        let mut ctx = StaticContext8::new();
+        match ctx.run_catching(&prog) {
+            Err(Error::Runtime(msg)) => assert!(msg.contains("index out of bounds"), "{}", msg),
+            r => panic!("expected a runtime error, got {:?}", r),
+        }
 
-impl_static_ctx!(StaticContext8, i8);
-impl_static_ctx!(StaticContext16, i16);
-impl_static_ctx!(StaticContext32, i32);
-impl_static_ctx!(StaticContext64, i64);
\ No newline at end of file
+        let prog = crate::parser::program_with_asserts("++@assert cell0 == 3").unwrap();
+        let mut ctx = StaticContext32::new();
+        match ctx.run_catching(&prog) {
+            Err(Error::Runtime(msg)) => assert!(msg.starts_with("Assertion at byte 2 failed"), "{}", msg),
+            r => panic!("expected a runtime error, got {:?}", r),
+        }
+
+        // Targets past either end don't wrap around to the other one
+        for &offset in &[isize::MAX, -2] {
+            for s in vec![Statement::AddOffset { mul: 1, offset }, Statement::MoveValue { offset }] {
+                let prog = [Statement::Next(1), Statement::Inc(1), s];
+                let mut ctx = StaticContext8::new();
+                match ctx.run_catching(&prog) {
+                    Err(Error::Runtime(msg)) => assert!(msg.starts_with("Attempted operation out of bounds"), "{}", msg),
+                    r => panic!("expected a runtime error, got {:?}", r),
+                }
+                assert_eq!(ctx.data()[..2], [0, 1]);
+            }
+        }
+
+        let (_, prog) = program("+++").unwrap();
+        let mut ctx = StaticContext8::new();
+        assert!(ctx.run_catching(&prog).is_ok());
+        assert_eq!(ctx.data()[0], 3);
+    }
+
+    #[test]
+    fn halt_stops_nested_loops() {
+        let prog = crate::parser::program_with_asserts("++[>+++[>+< @halt -]<-]>>+").unwrap();
+        let mut ctx = StaticContext16::new();
+        ctx.exec_many(&prog);
+        assert!(ctx.halted());
+        assert_eq!(&ctx.data()[..3], &[2, 3, 1]);
+    }
+
+    #[test]
+    fn steps_match_context() {
+        // 3 top-level statements, then 2 iterations of a 4-statement body
+        let (_, prog) = program("++[>+<-]").unwrap();
+        let mut ctx = StaticContext8::new();
+        ctx.exec_many(&prog);
+        assert_eq!(ctx.steps(), 3 + 2 * (1 + 4));
+
+        let mut dynamic = crate::Context::new();
+        dynamic.exec_many(&prog).unwrap();
+        assert_eq!(dynamic.steps(), ctx.steps());
+    }
+
+    #[test]
+    fn step_limit() {
+        let (_, spin) = program("+[]").unwrap();
+        for prog in &[spin.clone(), crate::optimize(&spin)] {
+            let mut ctx = StaticContext8::new();
+            assert!(matches!(ctx.exec_many_limited(prog, 100).unwrap(), StepOutcome::LimitReached { .. }));
+            assert!(ctx.limit_reached());
+        }
+
+        let mut ctx = StaticContext8::new();
+        assert_eq!(ctx.exec_many_limited(&spin, 100).unwrap(), StepOutcome::LimitReached { steps: 100 });
+        assert_eq!(ctx.steps(), 100);
+
+        // Stops at the same point as `Context`
+        let (_, prog) = program("++[>+<-]").unwrap();
+        let mut dynamic = crate::Context::new();
+        assert_eq!(dynamic.exec_many_limited(&prog, 7).unwrap(), StepOutcome::LimitReached { steps: 7 });
+        let mut ctx = StaticContext8::new();
+        assert_eq!(ctx.exec_many_limited(&prog, 7).unwrap(), StepOutcome::LimitReached { steps: 7 });
+        assert_eq!(ctx.data()[..2], [dynamic.data()[0], dynamic.data()[1]]);
+
+        let mut ctx = StaticContext8::new();
+        assert_eq!(ctx.exec_many_limited(&prog, 100).unwrap(), StepOutcome::Completed { steps: 13 });
+        assert!(!ctx.limit_reached());
+    }
+
+    #[test]
+    fn try_with_state_rejects_oversized_tape() {
+        let data = vec![1i32; NUM_CELLS + 1];
+        assert!(matches!(
+            StaticContext32::try_with_state(&data),
+            Err(Error::TapeTooLarge { len, capacity: NUM_CELLS }) if len == NUM_CELLS + 1
+        ));
+
+        let ctx = StaticContext32::try_with_state(&data[..NUM_CELLS]).unwrap();
+        assert_eq!(ctx.data()[NUM_CELLS - 1], 1);
+    }
+
+    #[test]
+    fn custom_cell_sees_every_write() {
+        use std::cell::Cell as Counter;
+
+        thread_local! {
+            static WRITES: Counter<usize> = const { Counter::new(0) };
+        }
+
+        // A `u8` cell that counts the arithmetic done on it
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        struct Counted(u8);
+
+        impl Cell for Counted {
+            const BITS: u32 = 8;
+
+            fn zero() -> Self {
+                Counted(0)
+            }
+
+            fn wrapping_add(self, n: i64) -> Self {
+                WRITES.with(|w| w.set(w.get() + 1));
+                Counted(self.0.wrapping_add(n as u8))
+            }
+
+            fn to_unsigned(self) -> u64 {
+                self.0 as u64
+            }
+
+            fn from_unsigned(v: u64) -> Self {
+                Counted(v as u8)
+            }
+        }
+
+        let (_, prog) = program("+++[->++<]>-").unwrap();
+        let mut ctx = StaticContext::<Counted, 4>::new();
+        ctx.exec_many(&prog);
+        assert_eq!(ctx.data(), &[Counted(0), Counted(5), Counted(0), Counted(0)]);
+        // 3 `+`, then 3 iterations of 3 changes, then the last `-`
+        assert_eq!(WRITES.with(|w| w.get()), 13);
+
+        let mut ctx = StaticContext::<Counted, 4>::new();
+        ctx.exec_many(crate::optimize(&prog));
+        assert_eq!(ctx.data()[1], Counted(5));
+    }
+
+    #[test]
+    fn signedness_in_debug() {
+        let mut ctx = StaticContext8::with_state([200u8 as i8]);
+        assert!(format!("{:?}", ctx).starts_with("[200, 0,"));
+        ctx.set_signedness(Signedness::Signed);
+        assert!(format!("{:?}", ctx).starts_with("[-56, 0,"));
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoDirection {
+    Read,
+    Write,
+}
+
+/// A byte read by `,` or written by `.`.
+///
+/// The event is located by the cell the pointer was on and the step (see `Context::steps`)
+/// at which it happened. Statements don't carry source positions themselves, so `source`,
+/// the byte range of the statement in the source, is only there when the program was run
+/// with its spans through `Context::exec_many_spanned`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IoEvent {
+    pub direction: IoDirection,
+    pub byte: u8,
+    pub cell: usize,
+    pub step: u64,
+    pub source: Option<Range<usize>>,
+}
+
+impl Display for IoEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let dir = match self.direction {
+            IoDirection::Read => "read",
+            IoDirection::Write => "write",
+        };
+        write!(f, "{} {:#04x} at cell {} (step {}", dir, self.byte, self.cell, self.step)?;
+        if let Some(r) = &self.source {
+            write!(f, ", source bytes {}..{}", r.start, r.end)?;
+        }
+        f.write_str(")")
+    }
+}
+
+/// An I/O observer that writes one line per event to `w`, e.g. `std::io::stderr()`.
+pub fn trace_to(mut w: impl Write + 'static) -> impl FnMut(&IoEvent) {
+    move |e| {
+        let _ = writeln!(w, "{}", e);
+    }
+}
+
+/// Statement kinds in the order of their codes in a trace, named as by `Statement::name`
+pub const OP_KINDS: &[&str] = &[
+    "next", "prev", "inc", "dec", "out", "in", "loop", "clear", "add_offset", "search_zero",
+    "copy", "clear_scan", "assert", "infinite_loop_if_nonzero", "move_value", "write_block",
+    "read_block", "halt", "search_value", "linear_combine", "breakpoint",
+    "move", "mul_add_many", "set_const", "inc_at",
+];
+
+// Op code, position as a little-endian u64, cell value
+const RECORD_LEN: usize = 10;
+
+/// The state after a statement finished: where the pointer ended up and what the cell
+/// there holds. A loop gets its record after its last iteration, following those of its body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub op: &'static str,
+    pub pos: usize,
+    pub value: i8,
+}
+
+impl TraceRecord {
+    fn encode(&self) -> [u8; RECORD_LEN] {
+        let mut buf = [0u8; RECORD_LEN];
+        buf[0] = OP_KINDS.iter().position(|&k| k == self.op).expect("unknown op kind") as u8;
+        buf[1..9].copy_from_slice(&(self.pos as u64).to_le_bytes());
+        buf[9] = self.value as u8;
+        buf
+    }
+
+    fn decode(buf: &[u8; RECORD_LEN]) -> io::Result<Self> {
+        let op = OP_KINDS.get(buf[0] as usize)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown op code in trace"))?;
+        let mut pos = [0u8; 8];
+        pos.copy_from_slice(&buf[1..9]);
+        Ok(TraceRecord { op, pos: u64::from_le_bytes(pos) as usize, value: buf[9] as i8 })
+    }
+}
+
+/// Appends a fixed-size record for every statement `Context::exec` runs, for replaying
+/// or analyzing a run offline with `read_trace`. Much slower than running untraced.
+pub struct TraceRecorder {
+    w: BufWriter<Box<dyn Write>>,
+    records: u64,
+}
+
+impl TraceRecorder {
+    pub fn new(w: impl Write + 'static) -> Self {
+        TraceRecorder { w: BufWriter::new(Box::new(w)), records: 0 }
+    }
+
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(TraceRecorder::new(File::create(path)?))
+    }
+
+    pub fn record(&mut self, rec: &TraceRecord) -> io::Result<()> {
+        self.w.write_all(&rec.encode())?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Number of records written so far
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+impl fmt::Debug for TraceRecorder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TraceRecorder").field("records", &self.records).finish()
+    }
+}
+
+/// Reads back everything a `TraceRecorder` wrote
+pub fn read_trace(mut r: impl Read) -> io::Result<Vec<TraceRecord>> {
+    let mut bytes = Vec::new();
+    r.read_to_end(&mut bytes)?;
+    if bytes.len() % RECORD_LEN != 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "trace ends inside a record"));
+    }
+
+    bytes.chunks_exact(RECORD_LEN)
+        .map(|c| {
+            let mut buf = [0u8; RECORD_LEN];
+            buf.copy_from_slice(c);
+            TraceRecord::decode(&buf)
+        })
+        .collect()
+}
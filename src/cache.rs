@@ -0,0 +1,84 @@
+//! Optimized programs cached on disk, so repeated runs skip parsing and optimizing.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::bf::{optimize_with, Patterns, Statement};
+use crate::parser::{parse_source, DirectiveError};
+
+#[derive(thiserror::Error, Debug)]
+pub enum CacheError {
+    #[error(transparent)]
+    Parse(#[from] DirectiveError),
+    #[error("Cache IO error: {0}")]
+    IO(#[from] io::Error),
+}
+
+/// Parses `src` and optimizes it with `patterns` (or not at all for `None`), or loads
+/// the result of doing so from `cache_dir` if an earlier call already has. Entries are
+/// keyed by a hash of the source, the patterns and the crate version, so a change to
+/// any of them is a miss. An unreadable entry is treated as a miss and overwritten.
+pub fn load_or_compile(src: &str, patterns: Option<Patterns>, cache_dir: impl AsRef<Path>)
+    -> Result<Vec<Statement>, CacheError> {
+    let path = entry_path(src, patterns, cache_dir.as_ref());
+    if let Ok(bytes) = fs::read(&path) {
+        if let Ok(stmts) = serde_json::from_slice(&bytes) {
+            return Ok(stmts);
+        }
+    }
+
+    let mut stmts = parse_source(src)?.stmts;
+    if let Some(patterns) = patterns {
+        stmts = optimize_with(stmts, patterns);
+    }
+
+    // Written under a temporary name first, so a concurrent reader never sees half of it
+    fs::create_dir_all(cache_dir.as_ref())?;
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    fs::write(&tmp, serde_json::to_vec(&stmts).map_err(io::Error::from)?)?;
+    fs::rename(&tmp, &path)?;
+    Ok(stmts)
+}
+
+fn entry_path(src: &str, patterns: Option<Patterns>, cache_dir: &Path) -> PathBuf {
+    let key = format!("{}\n{:?}\n{}", env!("CARGO_PKG_VERSION"), patterns, src);
+    cache_dir.join(format!("{:016x}.json", fnv1a(key.as_bytes())))
+}
+
+// Stable across builds, unlike `DefaultHasher`
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| (h ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_call_hits_the_cache() {
+        let dir = std::env::temp_dir().join(format!("bf-cache-{}", std::process::id()));
+        let src = "counts down [-] then moves +++[->+<]";
+
+        let first = load_or_compile(src, Some(Patterns::default()), &dir).unwrap();
+        assert_eq!(first, optimize_with(parse_source(src).unwrap().stmts, Patterns::default()));
+
+        // Replace the only entry; if the second call reads it, it didn't compile again
+        let entries: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+        assert_eq!(entries.len(), 1);
+        fs::write(&entries[0], "[\"Out\"]").unwrap();
+        assert_eq!(load_or_compile(src, Some(Patterns::default()), &dir).unwrap(), vec![Statement::Out]);
+
+        // A different source or optimization setting is a different entry
+        assert_eq!(load_or_compile(src, None, &dir).unwrap(), parse_source(src).unwrap().stmts);
+        let no_clear = Patterns { clear: false, ..Patterns::default() };
+        assert_ne!(load_or_compile(src, Some(no_clear), &dir).unwrap(), vec![Statement::Out]);
+        assert_eq!(load_or_compile("+", None, &dir).unwrap(), vec![Statement::Inc(1)]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 4);
+
+        // A corrupt entry is recompiled
+        fs::write(&entries[0], "not json").unwrap();
+        assert_eq!(load_or_compile(src, Some(Patterns::default()), &dir).unwrap(), first);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use std::io;
+use std::path::PathBuf;
+use clap::ArgMatches;
+
+/// Defaults for command line options, read from `bf.toml` in the working directory,
+/// or failing that `~/.config/bf/config.toml`.
+///
+/// Keys are named after the long option they provide a default for:
+///
+/// ```toml
+/// cell-size = "i32"
+/// cell-arith = "saturate"
+/// optimize = true
+/// tape-size = "30000"
+/// ```
+///
+/// An option given on the command line always wins over the config file, which in
+/// turn wins over the option's built-in default.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, rename_all = "kebab-case", deny_unknown_fields))]
+pub struct Config {
+    pub cell_size: Option<String>,
+    pub cell_arith: Option<String>,
+    pub optimize: Option<bool>,
+    pub signed: Option<bool>,
+    pub hot_threshold: Option<String>,
+    pub eof: Option<String>,
+    pub tape: Option<String>,
+    pub tape_size: Option<String>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("Unable to read {0}: {1}")]
+    IO(PathBuf, io::Error),
+    #[cfg(feature = "serde")]
+    #[error("Invalid config file {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+}
+
+impl Config {
+    /// Where the config file is looked for, in order
+    pub fn search_paths() -> Vec<PathBuf> {
+        let mut paths = vec![PathBuf::from("bf.toml")];
+        if let Some(home) = std::env::var_os("HOME") {
+            paths.push(PathBuf::from(home).join(".config/bf/config.toml"));
+        }
+        paths
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn parse(text: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// The first config file found in `search_paths`, or the empty config if there is none
+    #[cfg(feature = "serde")]
+    pub fn load() -> Result<Self, ConfigError> {
+        for path in Config::search_paths() {
+            match std::fs::read_to_string(&path) {
+                Ok(text) => return Config::parse(&text).map_err(|e| ConfigError::Parse(path, e)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(ConfigError::IO(path, e)),
+            }
+        }
+        Ok(Config::default())
+    }
+
+    /// Without serde support there is nothing to parse config files with
+    #[cfg(not(feature = "serde"))]
+    pub fn load() -> Result<Self, ConfigError> {
+        Ok(Config::default())
+    }
+
+    /// The value of option `name`: from the command line if it was given there, else
+    /// from the config file, else the option's default.
+    pub fn value_of<'a>(&'a self, m: &'a ArgMatches, name: &str) -> Option<&'a str> {
+        if m.occurrences_of(name) > 0 {
+            return m.value_of(name);
+        }
+
+        let configured = match name {
+            "cell-size" => &self.cell_size,
+            "cell-arith" => &self.cell_arith,
+            "hot-threshold" => &self.hot_threshold,
+            "eof" => &self.eof,
+            "tape" => &self.tape,
+            "tape-size" => &self.tape_size,
+            _ => &None,
+        };
+        configured.as_deref().or_else(|| m.value_of(name))
+    }
+
+    /// Whether flag `name` is on, either on the command line or in the config file
+    pub fn is_present(&self, m: &ArgMatches, name: &str) -> bool {
+        let configured = match name {
+            "optimize" => self.optimize,
+            "signed" => self.signed,
+            _ => None,
+        };
+        m.is_present(name) || configured.unwrap_or(false)
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+    use clap::{App, Arg};
+
+    #[test]
+    fn flags_override_config() {
+        let app = || App::new("bf")
+            .arg(Arg::with_name("cell-size")
+                .short('c')
+                .takes_value(true)
+                .default_value("i8"))
+            .arg(Arg::with_name("cell-arith")
+                .long("cell-arith")
+                .takes_value(true)
+                .default_value("wrap"))
+            .arg(Arg::with_name("eof")
+                .long("eof")
+                .takes_value(true)
+                .default_value("error"))
+            .arg(Arg::with_name("tape-size")
+                .long("tape-size")
+                .takes_value(true))
+            .arg(Arg::with_name("optimize")
+                .short('o'));
+
+        let config = Config::parse("cell-size = \"i32\"\noptimize = true\neof = \"zero\"").unwrap();
+
+        let m = app().get_matches_from(vec!["bf"]);
+        assert_eq!(config.value_of(&m, "cell-size"), Some("i32"));
+        assert_eq!(config.value_of(&m, "cell-arith"), Some("wrap"));
+        assert!(config.is_present(&m, "optimize"));
+        assert_eq!(config.value_of(&m, "eof"), Some("zero"));
+
+        let m = app().get_matches_from(vec!["bf", "-c", "i16", "--eof", "neg1"]);
+        assert_eq!(config.value_of(&m, "cell-size"), Some("i16"));
+        assert_eq!(config.value_of(&m, "eof"), Some("neg1"));
+
+        // Options without a default are unset unless configured
+        assert_eq!(config.value_of(&m, "tape-size"), None);
+        let config = Config::parse("tape-size = \"30000\"").unwrap();
+        assert_eq!(config.value_of(&m, "tape-size"), Some("30000"));
+        let m = app().get_matches_from(vec!["bf", "--tape-size", "100"]);
+        assert_eq!(config.value_of(&m, "tape-size"), Some("100"));
+
+        assert!(Config::parse("cell-sise = \"i32\"").is_err());
+    }
+}
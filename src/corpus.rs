@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use crate::analysis::program_stats;
+use crate::bf::{optimize_with, Patterns};
+use crate::parser::{parse_source, DirectiveError};
+
+/// Statement kinds the parser produces itself; anything else comes from the optimizer
+const PARSED_OPS: &[&str] = &["next", "prev", "inc", "dec", "out", "in", "loop"];
+
+#[derive(thiserror::Error, Debug)]
+pub enum CorpusError {
+    #[error("Unable to read {0}: {1}")]
+    IO(PathBuf, io::Error),
+    #[error("Unable to parse {0}: {1}")]
+    Parse(PathBuf, DirectiveError),
+}
+
+/// How much `optimize_with` shrank one program
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileReport {
+    pub path: PathBuf,
+    pub before: usize,
+    pub after: usize,
+    /// Statements of each kind the optimizer introduced, by `Statement::name`
+    pub synthetic: BTreeMap<&'static str, usize>,
+    pub elapsed: Duration,
+}
+
+impl FileReport {
+    /// Fraction of statements removed, as in `ProgramInfo::reduction_ratio`
+    pub fn reduction(&self) -> f64 {
+        reduction(self.before, self.after)
+    }
+}
+
+/// `FileReport`s for every `.bf` file in a directory, sorted by path
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CorpusReport {
+    pub files: Vec<FileReport>,
+}
+
+impl CorpusReport {
+    pub fn before(&self) -> usize {
+        self.files.iter().map(|f| f.before).sum()
+    }
+
+    pub fn after(&self) -> usize {
+        self.files.iter().map(|f| f.after).sum()
+    }
+
+    /// Fraction of all statements in the corpus removed
+    pub fn reduction(&self) -> f64 {
+        reduction(self.before(), self.after())
+    }
+
+    pub fn synthetic(&self) -> BTreeMap<&'static str, usize> {
+        let mut total = BTreeMap::new();
+        for (name, n) in self.files.iter().flat_map(|f| &f.synthetic) {
+            *total.entry(*name).or_insert(0) += n;
+        }
+        total
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.files.iter().map(|f| f.elapsed).sum()
+    }
+}
+
+fn reduction(before: usize, after: usize) -> f64 {
+    if before == 0 {
+        0.0
+    } else {
+        1.0 - after as f64 / before as f64
+    }
+}
+
+fn write_ops(f: &mut Formatter<'_>, ops: &BTreeMap<&'static str, usize>) -> fmt::Result {
+    let ops: Vec<_> = ops.iter().map(|(name, n)| format!("{}={}", name, n)).collect();
+    write!(f, "{}", ops.join(" "))
+}
+
+impl Display for CorpusReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for file in &self.files {
+            write!(f, "{}: {} -> {} statements ({:.1}% fewer) ", file.path.display(), file.before, file.after, file.reduction() * 100.0)?;
+            write_ops(f, &file.synthetic)?;
+            writeln!(f)?;
+        }
+        write!(f, "total: {} files, {} -> {} statements ({:.1}% fewer) ", self.files.len(), self.before(), self.after(), self.reduction() * 100.0)?;
+        write_ops(f, &self.synthetic())?;
+        writeln!(f)?;
+        writeln!(f, "optimize took {}ms", self.elapsed().as_millis())
+    }
+}
+
+/// Optimizes every `.bf` file in `dir` (not recursing) and reports the effect of each.
+/// Only the optimizer itself is timed, not reading or parsing the files.
+pub fn bench_optimize(dir: impl AsRef<Path>, patterns: Patterns) -> Result<CorpusReport, CorpusError> {
+    let dir = dir.as_ref();
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(|e| CorpusError::IO(dir.to_path_buf(), e))? {
+        let path = entry.map_err(|e| CorpusError::IO(dir.to_path_buf(), e))?.path();
+        if path.extension().map_or(false, |ext| ext == "bf") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut files = Vec::with_capacity(paths.len());
+    for path in paths {
+        let text = std::fs::read_to_string(&path).map_err(|e| CorpusError::IO(path.clone(), e))?;
+        let stmts = match parse_source(&text) {
+            Ok(p) => p.stmts,
+            Err(e) => return Err(CorpusError::Parse(path, e)),
+        };
+
+        let start = Instant::now();
+        let optimized = optimize_with(&stmts, patterns);
+        let elapsed = start.elapsed();
+
+        let stats = program_stats(&optimized);
+        let synthetic = stats.ops.into_iter()
+            .filter(|(name, _)| !PARSED_OPS.contains(name))
+            .collect();
+        files.push(FileReport {
+            path,
+            before: program_stats(&stmts).statements,
+            after: stats.statements,
+            synthetic,
+            elapsed,
+        });
+    }
+
+    Ok(CorpusReport { files })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixture_corpus() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/corpus");
+        let report = bench_optimize(&dir, Patterns::default()).unwrap();
+
+        let names: Vec<_> = report.files.iter().map(|f| f.path.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, vec!["abc.bf", "clear.bf"]);
+        assert_eq!(report.before(), 47);
+        assert!(report.after() < report.before());
+        assert!(report.reduction() > 0.0);
+
+        let synthetic = report.synthetic();
+        assert_eq!(synthetic.get("clear"), Some(&4));
+        assert_eq!(synthetic.get("move_value"), None);
+        assert_eq!(synthetic.get("add_offset"), Some(&2));
+
+        let text = report.to_string();
+        assert!(text.contains("total: 2 files, 47 -> "));
+    }
+}
@@ -0,0 +1,506 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use crate::bf::{Context, Error, Statement};
+use crate::disasm::describe;
+use crate::parser::command_offsets;
+
+/// One instruction of a flattened program. Loops become a pair of jumps so that they can
+/// be stepped through one iteration at a time.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Stmt(Statement),
+    /// `[`: continue after the `Close` at this index if the current cell is zero
+    Open(usize),
+    /// `]`: continue after the `Open` at this index if the current cell is nonzero
+    Close(usize),
+}
+
+/// Why `Debugger::resume` stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// About to run the op at this index, which has a breakpoint
+    Breakpoint(usize),
+    Finished,
+}
+
+/// What a single `Debugger::step_event` did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepEvent {
+    /// Ran a statement that left the pointer where it was
+    Executed,
+    /// Ran a statement that moved the pointer, which is now at this cell
+    Moved(usize),
+    /// `.` wrote this byte
+    Wrote(u8),
+    /// `,` stored this value in the current cell
+    Read(u8),
+    /// `,` found no input left under `EofMode::Error`. Nothing changed and the op runs
+    /// again on the next step, so more input can be supplied through `context_mut`.
+    ReadPending,
+    /// `[` with a nonzero cell: the next op is the first one of the body
+    LoopEntered,
+    /// `]` with a nonzero cell: back to the start of the body
+    LoopRepeated,
+    /// `[` or `]` with a zero cell: the next op is the one after the loop
+    LoopExited,
+    /// Stopped at a `Halt`; the program is finished
+    Halted,
+    /// There was nothing left to run
+    Finished,
+}
+
+/// Runs a program one op at a time on a `Context`, for `bf debug`.
+///
+/// For parser output, op indexes are positions in the comment-free source, since every
+/// command becomes exactly one op.
+pub struct Debugger {
+    ops: Vec<Op>,
+    depths: Vec<usize>,
+    pc: usize,
+    ctx: Context,
+    breakpoints: BTreeSet<usize>,
+}
+
+impl Debugger {
+    pub fn new(stmts: &[Statement], ctx: Context) -> Self {
+        let mut dbg = Debugger { ops: Vec::new(), depths: Vec::new(), pc: 0, ctx, breakpoints: BTreeSet::new() };
+        dbg.flatten(stmts, 0);
+        dbg
+    }
+
+    fn flatten(&mut self, stmts: &[Statement], depth: usize) {
+        for s in stmts {
+            match s {
+                Statement::Loop(body) => {
+                    let open = self.ops.len();
+                    self.ops.push(Op::Open(0));
+                    self.depths.push(depth);
+                    self.flatten(body, depth + 1);
+                    self.ops[open] = Op::Open(self.ops.len());
+                    self.ops.push(Op::Close(open));
+                    self.depths.push(depth);
+                }
+                s => {
+                    self.ops.push(Op::Stmt(s.clone()));
+                    self.depths.push(depth);
+                }
+            }
+        }
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    /// Index of the next op to run; `ops().len()` once finished
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn finished(&self) -> bool {
+        self.pc >= self.ops.len()
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.ctx
+    }
+
+    pub fn context_mut(&mut self) -> &mut Context {
+        &mut self.ctx
+    }
+
+    /// Adds a breakpoint at op `pc`, or removes it if there is one. Returns whether it is set now.
+    pub fn toggle_breakpoint(&mut self, pc: usize) -> bool {
+        if self.breakpoints.remove(&pc) {
+            false
+        } else {
+            self.breakpoints.insert(pc)
+        }
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<usize> {
+        &self.breakpoints
+    }
+
+    /// Op `i` as shown in a listing, indented by two spaces per loop level
+    pub fn line(&self, i: usize) -> String {
+        let text = match &self.ops[i] {
+            Op::Stmt(s) => describe(s),
+            Op::Open(_) => "loop".to_string(),
+            Op::Close(_) => "end".to_string(),
+        };
+        format!("{:indent$}{}", "", text, indent = self.depths[i] * 2)
+    }
+
+    /// Runs the op at `pc()`. Returns whether the program has finished, which includes
+    /// stopping at a `Halt`. After an error the op is not retried, except when `,` runs
+    /// out of input (see `StepEvent::ReadPending`).
+    pub fn step(&mut self) -> Result<bool, Error> {
+        match self.step_event()? {
+            StepEvent::ReadPending => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            _ => Ok(self.finished()),
+        }
+    }
+
+    /// Runs the op at `pc()` like `step`, and says what it did
+    pub fn step_event(&mut self) -> Result<StepEvent, Error> {
+        let zero = self.ctx.cur()? == 0;
+        let idx = self.ctx.idx();
+        let (next, event) = match self.ops.get(self.pc) {
+            None => return Ok(StepEvent::Finished),
+            Some(Op::Open(close)) if zero => (close + 1, StepEvent::LoopExited),
+            Some(Op::Close(open)) if !zero => (open + 1, StepEvent::LoopRepeated),
+            Some(Op::Open(_)) => (self.pc + 1, StepEvent::LoopEntered),
+            Some(Op::Close(_)) => (self.pc + 1, StepEvent::LoopExited),
+            // The tape is on screen already; `resume` stops here instead
+            Some(Op::Stmt(Statement::Breakpoint)) => (self.pc + 1, StepEvent::Executed),
+            Some(Op::Stmt(s)) => match self.ctx.exec(s) {
+                Ok(()) => {
+                    let cur = self.ctx.cur()? as u8;
+                    let event = match s {
+                        Statement::Out => StepEvent::Wrote(cur),
+                        Statement::In => StepEvent::Read(cur),
+                        _ if self.ctx.idx() != idx => StepEvent::Moved(self.ctx.idx()),
+                        _ => StepEvent::Executed,
+                    };
+                    (self.pc + 1, event)
+                }
+                Err(Error::Halted) => (self.ops.len(), StepEvent::Halted),
+                Err(Error::IO(e)) if *s == Statement::In && e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(StepEvent::ReadPending);
+                }
+                Err(e) => {
+                    self.pc += 1;
+                    return Err(e);
+                }
+            },
+        };
+        self.pc = next;
+        Ok(event)
+    }
+
+    /// Steps at least once, then until the next breakpoint or the end of the program.
+    /// A `Breakpoint` statement always counts as one.
+    pub fn resume(&mut self) -> Result<Stop, Error> {
+        loop {
+            if self.step()? {
+                return Ok(Stop::Finished);
+            }
+            if self.breakpoints.contains(&self.pc) || self.ops[self.pc] == Op::Stmt(Statement::Breakpoint) {
+                return Ok(Stop::Breakpoint(self.pc));
+            }
+        }
+    }
+
+    /// The cells within `radius` of the pointer, and the index of the first one
+    pub fn tape_window(&self, radius: usize) -> (usize, &[i8]) {
+        let data = self.ctx.data();
+        let start = self.ctx.idx().saturating_sub(radius);
+        let end = (self.ctx.idx() + radius + 1).min(data.len());
+        (start, &data[start..end])
+    }
+}
+
+/// Op indexes of the `#` breakpoint markers in `text`, in order and without duplicates:
+/// each stops before the command that follows it. Assumes every command is one op, as
+/// for parser output.
+pub fn breakpoints_from_source(text: &str) -> Vec<usize> {
+    let commands = command_offsets(text);
+    let mut bps: Vec<_> = text.match_indices('#')
+        .map(|(o, _)| commands.partition_point(|&c| c < o))
+        .collect();
+    bps.dedup();
+    bps
+}
+
+/// A line-based front end, for when the terminal UI isn't available. Reads one command
+/// per line from `cmds` until `q` or the end of input:
+///
+/// - `s [N]`: step N ops (default 1)
+/// - `c`: continue to the next breakpoint
+/// - `b [N]`: toggle a breakpoint at op N (default the current one)
+/// - `p N`: print cell N
+/// - `l`: list the ops around the current one
+pub fn run_lines(dbg: &mut Debugger, cmds: impl BufRead, mut out: impl Write) -> io::Result<()> {
+    show_position(dbg, &mut out)?;
+    for cmd in cmds.lines() {
+        let cmd = cmd?;
+        let mut words = cmd.split_whitespace();
+        let arg = |w: Option<&str>| w.and_then(|n| n.parse::<usize>().ok());
+        let res = match words.next() {
+            Some("s") => (0..arg(words.next()).unwrap_or(1))
+                .try_for_each(|_| dbg.step().map(|_| ())),
+            Some("c") => dbg.resume().map(|stop| {
+                if let Stop::Breakpoint(pc) = stop {
+                    let _ = writeln!(out, "breakpoint at op {}", pc);
+                }
+            }),
+            Some("b") => {
+                let pc = arg(words.next()).unwrap_or_else(|| dbg.pc());
+                let set = dbg.toggle_breakpoint(pc);
+                writeln!(out, "breakpoint at op {} {}", pc, if set { "set" } else { "removed" })?;
+                continue;
+            }
+            Some("p") => {
+                match arg(words.next()) {
+                    Some(n) => writeln!(out, "cell{} = {}", n, dbg.context().data().get(n).map_or(0, |&v| v as u8))?,
+                    None => writeln!(out, "usage: p CELL")?,
+                }
+                continue;
+            }
+            Some("l") => Ok(()),
+            Some("q") => break,
+            _ => {
+                writeln!(out, "commands: s [N], c, b [N], p N, l, q")?;
+                continue;
+            }
+        };
+        if let Err(e) = res {
+            writeln!(out, "error: {}", e)?;
+        }
+        show_position(dbg, &mut out)?;
+    }
+    Ok(())
+}
+
+fn show_position(dbg: &Debugger, out: &mut impl Write) -> io::Result<()> {
+    if dbg.finished() {
+        writeln!(out, "finished")?;
+    } else {
+        let start = dbg.pc().saturating_sub(2);
+        let end = (dbg.pc() + 3).min(dbg.ops().len());
+        for i in start..end {
+            let marker = if i == dbg.pc() { "->" } else if dbg.breakpoints().contains(&i) { " *" } else { "  " };
+            writeln!(out, "{} {:4} {}", marker, i, dbg.line(i))?;
+        }
+    }
+
+    let (first, cells) = dbg.tape_window(4);
+    let cells: Vec<String> = cells.iter().enumerate()
+        .map(|(i, &c)| if first + i == dbg.context().idx() { format!("[{}]", c as u8) } else { (c as u8).to_string() })
+        .collect();
+    writeln!(out, "tape from cell{}: {}", first, cells.join(" "))?;
+    match dbg.context().output().captured() {
+        Some(o) if !o.is_empty() => writeln!(out, "output: {:?}", String::from_utf8_lossy(o)),
+        _ => Ok(()),
+    }
+}
+
+/// Full-screen front end: the listing with the current op highlighted, and the tape
+/// around the pointer. Falls back to `run_lines` on stdin and stdout when either isn't
+/// a terminal.
+///
+/// Keys: `s` or space steps, `c` continues to the next breakpoint, `b` toggles a
+/// breakpoint on the current op, left and right pick the cell to inspect, `q` quits.
+#[cfg(feature = "tui")]
+pub fn run_tui(dbg: &mut Debugger) -> io::Result<()> {
+    use crossterm::tty::IsTty;
+
+    if !io::stdin().is_tty() || !io::stdout().is_tty() {
+        return run_lines(dbg, io::stdin().lock(), io::stdout());
+    }
+    tui::run(dbg)
+}
+
+#[cfg(feature = "tui")]
+mod tui {
+    use std::io::{self, Write};
+    use crossterm::cursor::MoveTo;
+    use crossterm::event::{self, Event, KeyCode, KeyEvent};
+    use crossterm::style::{Attribute, Print, SetAttribute};
+    use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+    use crossterm::{execute, queue};
+    use super::{Debugger, Stop};
+
+    fn to_io(e: crossterm::ErrorKind) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, e)
+    }
+
+    // Restores the terminal however `run` exits
+    struct Screen;
+
+    impl Screen {
+        fn enter() -> io::Result<Self> {
+            terminal::enable_raw_mode().map_err(to_io)?;
+            execute!(io::stdout(), EnterAlternateScreen).map_err(to_io)?;
+            Ok(Screen)
+        }
+    }
+
+    impl Drop for Screen {
+        fn drop(&mut self) {
+            let _ = execute!(io::stdout(), LeaveAlternateScreen);
+            let _ = terminal::disable_raw_mode();
+        }
+    }
+
+    pub fn run(dbg: &mut Debugger) -> io::Result<()> {
+        let _screen = Screen::enter()?;
+        let mut status = String::from("s: step  c: continue  b: breakpoint  left/right: inspect  q: quit");
+        let mut inspect = dbg.context().idx();
+
+        loop {
+            draw(dbg, inspect, &status)?;
+            let code = match event::read().map_err(to_io)? {
+                Event::Key(KeyEvent { code, .. }) => code,
+                _ => continue,
+            };
+
+            let res = match code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Char('s') | KeyCode::Char(' ') => dbg.step().map(|_| status.clear()),
+                KeyCode::Char('c') => dbg.resume().map(|stop| status = match stop {
+                    Stop::Breakpoint(pc) => format!("breakpoint at op {}", pc),
+                    Stop::Finished => "finished".to_string(),
+                }),
+                KeyCode::Char('b') => {
+                    let set = dbg.toggle_breakpoint(dbg.pc());
+                    status = format!("breakpoint {}", if set { "set" } else { "removed" });
+                    continue;
+                }
+                KeyCode::Left => {
+                    inspect = inspect.saturating_sub(1);
+                    continue;
+                }
+                KeyCode::Right => {
+                    inspect += 1;
+                    continue;
+                }
+                _ => continue,
+            };
+            if let Err(e) = res {
+                status = format!("error: {}", e);
+            }
+            inspect = dbg.context().idx();
+        }
+    }
+
+    fn draw(dbg: &Debugger, inspect: usize, status: &str) -> io::Result<()> {
+        let (width, height) = terminal::size().map_err(to_io)?;
+        let listing_rows = (height as usize).saturating_sub(5);
+        let first = dbg.pc().saturating_sub(listing_rows / 2);
+
+        let mut out = io::stdout();
+        queue!(out, Clear(ClearType::All)).map_err(to_io)?;
+        for (row, i) in (first..dbg.ops().len()).take(listing_rows).enumerate() {
+            let mark = if dbg.breakpoints().contains(&i) { '*' } else { ' ' };
+            let mut line = format!("{}{:5} {}", mark, i, dbg.line(i));
+            line.truncate(width as usize);
+            queue!(out, MoveTo(0, row as u16)).map_err(to_io)?;
+            if i == dbg.pc() {
+                queue!(out, SetAttribute(Attribute::Reverse), Print(line), SetAttribute(Attribute::Reset)).map_err(to_io)?;
+            } else {
+                queue!(out, Print(line)).map_err(to_io)?;
+            }
+        }
+
+        // One cell per 5 columns, centered on the pointer
+        let radius = (width as usize / 5).saturating_sub(5) / 2;
+        let (start, cells) = dbg.tape_window(radius);
+        let tape: String = cells.iter().enumerate()
+            .map(|(i, &c)| match start + i == dbg.context().idx() {
+                true => format!("[{:>3}]", c as u8),
+                false => format!(" {:>3} ", c as u8),
+            })
+            .collect();
+        let value = dbg.context().data().get(inspect).map_or(0, |&v| v as u8);
+        let shown = if value.is_ascii_graphic() { format!(" {:?}", value as char) } else { String::new() };
+        let output = dbg.context().output().captured().map(String::from_utf8_lossy).unwrap_or_default();
+        let row = height.saturating_sub(4);
+        queue!(
+            out,
+            MoveTo(0, row), Print(format!("tape from cell{}: {}", start, tape)),
+            MoveTo(0, row + 1), Print(format!("cell{} = {} ({:#04x}){}", inspect, value, value, shown)),
+            MoveTo(0, row + 2), Print(format!("output: {:?}", output)),
+            MoveTo(0, row + 3), Print(status),
+        ).map_err(to_io)?;
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_source;
+
+    #[test]
+    fn step_and_break() {
+        let src = "++[->+<] # >.";
+        let prog = parse_source(src).unwrap().stmts;
+        let mut ctx = Context::new();
+        ctx.set_output(crate::bf::output::OutputSink::Buffer(Vec::new()));
+        let mut dbg = Debugger::new(&prog, ctx);
+
+        assert_eq!(dbg.ops()[2], Op::Open(7));
+        assert_eq!(dbg.ops()[7], Op::Close(2));
+        assert_eq!(dbg.line(4), "  next 1");
+
+        // Into the loop body, one op at a time
+        for _ in 0..4 {
+            assert!(!dbg.step().unwrap());
+        }
+        assert_eq!((dbg.pc(), dbg.context().data()), (4, &[1][..]));
+
+        let bps = breakpoints_from_source(src);
+        assert_eq!(bps, vec![8]);
+        bps.into_iter().for_each(|b| { dbg.toggle_breakpoint(b); });
+        assert_eq!(dbg.resume().unwrap(), Stop::Breakpoint(8));
+        assert_eq!((dbg.context().data(), dbg.context().idx()), (&[0, 2][..], 0));
+
+        assert_eq!(dbg.resume().unwrap(), Stop::Finished);
+        assert!(dbg.finished());
+        assert_eq!(dbg.context().output().captured(), Some(&[2][..]));
+    }
+
+    #[test]
+    fn step_events() {
+        use crate::bf::input::InputSource;
+
+        let prog = parse_source("+[.>,]").unwrap().stmts;
+        let mut ctx = Context::with_input_iter(b"a".to_vec().into_iter());
+        ctx.set_output(crate::bf::output::OutputSink::Buffer(Vec::new()));
+        let mut dbg = Debugger::new(&prog, ctx);
+
+        let mut events = Vec::new();
+        loop {
+            match dbg.step_event().unwrap() {
+                StepEvent::ReadPending => break,
+                e => events.push(e),
+            }
+        }
+        assert_eq!(events, [
+            StepEvent::Executed, StepEvent::LoopEntered, StepEvent::Wrote(1), StepEvent::Moved(1),
+            StepEvent::Read(b'a'), StepEvent::LoopRepeated, StepEvent::Wrote(b'a'), StepEvent::Moved(2),
+        ]);
+        // Waiting on the `,` until there is more input
+        assert_eq!(dbg.pc(), 4);
+        assert_eq!(dbg.step_event().unwrap(), StepEvent::ReadPending);
+        assert!(dbg.step().is_err());
+        assert_eq!(dbg.pc(), 4);
+
+        dbg.context_mut().set_input(InputSource::from_bytes(vec![0].into_iter()));
+        assert_eq!(dbg.step_event().unwrap(), StepEvent::Read(0));
+        assert_eq!(dbg.step_event().unwrap(), StepEvent::LoopExited);
+        assert_eq!(dbg.step_event().unwrap(), StepEvent::Finished);
+        assert_eq!((dbg.context().idx(), dbg.context().data()), (2, &[1, 97, 0][..]));
+
+        let mut dbg = Debugger::new(&[Statement::Halt, Statement::Inc(1)], Context::new());
+        assert_eq!(dbg.step_event().unwrap(), StepEvent::Halted);
+        assert!(dbg.finished());
+    }
+
+    #[test]
+    fn line_commands() {
+        let prog = parse_source("+++>++").unwrap().stmts;
+        let mut dbg = Debugger::new(&prog, Context::new());
+        let mut out = Vec::new();
+        run_lines(&mut dbg, &b"s 4\nb 5\nc\np 0\nq\ns\n"[..], &mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("->    4 inc 1\n"), "{}", out);
+        assert!(out.contains("breakpoint at op 5 set\n"));
+        assert!(out.contains("breakpoint at op 5\n"));
+        assert!(out.contains("cell0 = 3\n"));
+        assert_eq!(dbg.pc(), 5);
+    }
+}
@@ -0,0 +1,175 @@
+use std::fmt::Write;
+use crate::bf::{passes, Patterns, Statement};
+use crate::analysis::program_stats;
+
+/// One statement per line, with loop bodies indented by two spaces.
+pub fn disassemble(stmts: impl AsRef<[Statement]>) -> String {
+    let mut out = String::new();
+    write_block(&mut out, stmts.as_ref(), 0);
+    out
+}
+
+fn write_block(out: &mut String, stmts: &[Statement], depth: usize) {
+    let indent = depth * 2;
+    for s in stmts {
+        writeln!(out, "{:indent$}{}", "", describe(s), indent = indent).unwrap();
+        if let Statement::Loop(l) = s {
+            write_block(out, l, depth + 1);
+            writeln!(out, "{:indent$}end", "", indent = indent).unwrap();
+        }
+    }
+}
+
+/// The line `disassemble` shows for `s`. A loop is just `loop`, without its body.
+pub fn describe(s: &Statement) -> String {
+    match s {
+        Statement::Next(n) => format!("next {}", n),
+        Statement::Prev(n) => format!("prev {}", n),
+        Statement::Move(n) => format!("move {}", n),
+        Statement::Inc(n) => format!("inc {}", n),
+        Statement::Dec(n) => format!("dec {}", n),
+        Statement::Out => "out".to_string(),
+        Statement::In => "in".to_string(),
+        Statement::Clear => "clear".to_string(),
+        Statement::SetConst(n) => format!("set_const {}", n),
+        Statement::IncAt { offset, amount } => format!("inc_at offset={} amount={}", offset, amount),
+        Statement::AddOffset { mul, offset } => format!("add_offset offset={} mul={}", offset, mul),
+        Statement::SearchZero { stride } => format!("search_zero stride={}", stride),
+        Statement::SearchValue { stride, target } => format!("search_value stride={} target={}", stride, target),
+        Statement::Copy { offset } => format!("copy offset={}", offset),
+        Statement::WriteBlock { stride } => format!("write_block stride={}", stride),
+        Statement::ReadBlock { stride } => format!("read_block stride={}", stride),
+        Statement::MoveValue { offset } => format!("move_value offset={}", offset),
+        Statement::LinearCombine { dest_offset, terms } => {
+            let terms: Vec<_> = terms.iter().map(|(mul, offset)| format!("{}*{}", mul, offset)).collect();
+            format!("linear_combine dest_offset={} terms={}", dest_offset, terms.join(","))
+        }
+        Statement::MulAddMany { targets } => {
+            let targets: Vec<_> = targets.iter().map(|(offset, mul)| format!("{}*{}", mul, offset)).collect();
+            format!("mul_add_many targets={}", targets.join(","))
+        }
+        Statement::ClearScan { stride } => format!("clear_scan stride={}", stride),
+        Statement::Halt => "halt".to_string(),
+        Statement::Breakpoint => "breakpoint".to_string(),
+        Statement::Assert { cell, expected, .. } => format!("assert cell{} == {}", cell, expected),
+        Statement::InfiniteLoopIfNonZero => "infinite_loop_if_nonzero".to_string(),
+        Statement::Loop(_) => "loop".to_string(),
+    }
+}
+
+/// The program before optimizing and after each pass `optimize_with` runs, each
+/// labeled with the pass name and statement count (loop bodies included). Only the
+/// first round of passes is shown.
+pub fn dump_passes(stmts: impl AsRef<[Statement]>, patterns: Patterns) -> String {
+    let mut out = String::new();
+    let mut cur = stmts.as_ref().to_vec();
+    write_section(&mut out, "input", &cur);
+
+    for (name, pass) in passes(patterns) {
+        cur = pass(&cur);
+        write_section(&mut out, name, &cur);
+    }
+    out
+}
+
+fn write_section(out: &mut String, name: &str, stmts: &[Statement]) {
+    writeln!(out, "== {} ({} statements) ==", name, program_stats(stmts).statements).unwrap();
+    out.push_str(&disassemble(stmts));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::program;
+
+    #[test]
+    fn labeled_passes() {
+        let (_, s) = program("++[->++<]>.").unwrap();
+        assert_eq!(dump_passes(&s, Patterns::default()), "\
+== input (10 statements) ==
+inc 1
+inc 1
+loop
+  dec 1
+  next 1
+  inc 1
+  inc 1
+  prev 1
+end
+next 1
+out
+== constant-fold (8 statements) ==
+inc 2
+loop
+  dec 1
+  move 1
+  inc 2
+  move -1
+end
+move 1
+out
+== net-fold (8 statements) ==
+inc 2
+loop
+  dec 1
+  move 1
+  inc 2
+  move -1
+end
+move 1
+out
+== copy-restore (8 statements) ==
+inc 2
+loop
+  dec 1
+  move 1
+  inc 2
+  move -1
+end
+move 1
+out
+== peephole (5 statements) ==
+inc 2
+add_offset offset=1 mul=2
+clear
+move 1
+out
+== search-value (5 statements) ==
+inc 2
+add_offset offset=1 mul=2
+clear
+move 1
+out
+== move-value (5 statements) ==
+inc 2
+add_offset offset=1 mul=2
+clear
+move 1
+out
+== linear-combine (5 statements) ==
+inc 2
+add_offset offset=1 mul=2
+clear
+move 1
+out
+== inc-at (5 statements) ==
+inc 2
+add_offset offset=1 mul=2
+clear
+move 1
+out
+== dead-loops (5 statements) ==
+inc 2
+add_offset offset=1 mul=2
+clear
+move 1
+out
+== empty-loops (5 statements) ==
+inc 2
+add_offset offset=1 mul=2
+clear
+move 1
+out
+");
+    }
+}
@@ -1,28 +1,110 @@
-use std::io::{stdin, Read};
+use std::io::{stdin, Read, Write};
 use std::process::abort;
 use inkwell::context::Context;
 use inkwell::module::{Module, Linkage};
 use inkwell::builder::Builder;
 use inkwell::execution_engine::{ExecutionEngine, JitFunction};
-use crate::Statement;
-use inkwell::values::{AggregateValue, ArrayValue, PointerValue, FunctionValue};
+use crate::{Statement, wrap_mul};
+use inkwell::values::{AggregateValue, ArrayValue, PointerValue, FunctionValue, IntValue};
 use inkwell::{AddressSpace, OptimizationLevel, IntPredicate};
 use inkwell::passes::{PassManagerSubType, PassManager, PassManagerBuilder};
 
 use bfrt::{read_char, write_char};
 use std::path::Path;
-use inkwell::targets::{Target, TargetMachine, RelocMode, CodeModel, FileType};
+use std::collections::HashMap;
+use std::cell::Cell;
+use std::ffi::c_void;
+use inkwell::basic_block::BasicBlock;
+use inkwell::targets::{Target, TargetMachine, RelocMode, CodeModel, FileType, InitializationConfig};
 
-pub type BFExecFn = unsafe extern "C" fn(*mut i8) -> ();
+/// Runs the program, then copies the final tape into the first argument if it is non-null.
+/// The second argument is that buffer's length; at most `min(cells, len)` cells are
+/// copied, where `cells` is the `CodeGen`'s tape size. Pass a null pointer when only the program's output matters to skip the copy.
+/// The last argument is handed to the `JitIo` callbacks, if any were set.
+pub type BFExecFn = unsafe extern "C" fn(*mut i8, usize, *mut c_void) -> ();
 
+/// Called for `,` in compiled code with the user data pointer, returning the byte read
+pub type ReadCallback = unsafe extern "C" fn(*mut c_void) -> i8;
+
+/// Called for `.` in compiled code with the user data pointer and the byte to write
+pub type WriteCallback = unsafe extern "C" fn(*mut c_void, i8);
+
+/// Where `,` and `.` in JIT-compiled code go instead of the process's stdin and stdout,
+/// e.g. a buffer, a socket or a test harness
+#[derive(Debug, Clone, Copy)]
+pub struct JitIo {
+    pub read: ReadCallback,
+    pub write: WriteCallback,
+}
+
+/// A single compiled loop. Takes the tape and the current cell index, runs the loop to
+/// completion on the tape in place and returns the new cell index.
+pub type BFLoopFn = unsafe extern "C" fn(*mut i8, usize) -> usize;
+
+/// Tape size of compiled code unless `CodeGen::set_cells` says otherwise
 pub const NUM_CELLS: usize = 64 * 1024;
 
+/// Whether programs can be JIT compiled here: LLVM has to support the host and create
+/// an execution engine for it. The error says what failed.
+pub fn probe_jit() -> Result<(), String> {
+    Target::initialize_native(&InitializationConfig::default())?;
+    let ctx = Context::create();
+    let module = ctx.create_module("probe");
+    module.create_jit_execution_engine(OptimizationLevel::None).map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// Whether `create_object_file` can build object files for the host. Linking them also
+/// needs a C compiler, which isn't checked.
+pub fn probe_native() -> Result<(), String> {
+    Target::initialize_native(&InitializationConfig::default())?;
+    let triple = TargetMachine::get_default_triple();
+    let target = Target::from_triple(&triple).map_err(|e| e.to_string())?;
+    target.create_target_machine(&triple, "generic", "", OptimizationLevel::None, RelocMode::Default, CodeModel::Default)
+        .map(|_| ())
+        .ok_or_else(|| format!("no target machine for {:?}", triple))
+}
+
+/// What an object file from `create_object_file` will be linked into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputKind {
+    Executable,
+    /// Position-independent code, for a shared library exporting `bf_main`
+    SharedLib,
+}
+
+/// How generated code keeps the pointer on its tape
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointerMode {
+    /// No checks. Moving left of cell 0 stops there, like `TapeMode::Clamp`, but nothing
+    /// stops the pointer running off the right end; for programs known to stay on the tape.
+    Unchecked,
+    /// The ends of the tape meet, like `TapeMode::Wrap` on a tape of the same size
+    Wrap,
+    /// Leaving the tape at either end aborts the process with `llvm.trap`
+    Trap,
+}
+
+impl Default for PointerMode {
+    fn default() -> Self {
+        PointerMode::Unchecked
+    }
+}
+
+/// Lowers programs to LLVM IR. Cell arithmetic in generated code always wraps,
+/// i.e. it implements `CellArith::Wrap` only.
 pub struct CodeGen<'ctx> {
     pub context: &'ctx Context,
     pub module: Module<'ctx>,
     pub builder: Builder<'ctx>,
     pub execution_engine: ExecutionEngine<'ctx>,
-    opt_level: OptimizationLevel
+    opt_level: OptimizationLevel,
+    cells: usize,
+    pointer_mode: PointerMode,
+    io: Option<JitIo>,
+    // Where `Halt` jumps to in the function being lowered
+    halt: Cell<Option<BasicBlock<'ctx>>>,
+    // What the function being lowered passes to the `JitIo` callbacks
+    user_data: Cell<Option<PointerValue<'ctx>>>,
 }
 
 impl<'ctx> CodeGen<'ctx> {
@@ -33,70 +115,311 @@ impl<'ctx> CodeGen<'ctx> {
             builder: ctx.create_builder(),
             execution_engine: module.create_jit_execution_engine(opt).unwrap(),
             module,
-            opt_level: opt
+            opt_level: opt,
+            cells: NUM_CELLS,
+            pointer_mode: PointerMode::Unchecked,
+            io: None,
+            halt: Cell::new(None),
+            user_data: Cell::new(None),
         }
     }
 
+    /// Cells on the tape of compiled programs, `NUM_CELLS` by default. Only affects code
+    /// lowered after it is set. The tape is allocated on the heap when the program starts,
+    /// which aborts if it can't be.
+    pub fn set_cells(&mut self, cells: usize) {
+        assert!(cells > 0, "a tape needs at least one cell");
+        self.cells = cells;
+    }
+
+    pub fn cells(&self) -> usize {
+        self.cells
+    }
+
+    /// `PointerMode::Unchecked` by default. Only affects code lowered after it is set.
+    pub fn set_pointer_mode(&mut self, mode: PointerMode) {
+        self.pointer_mode = mode;
+    }
+
+    /// Routes I/O in JIT-compiled code through `io` instead of the process's stdio. Only
+    /// affects code lowered after it is set; AOT builds always link against `bfrt`.
+    pub fn set_io(&mut self, io: JitIo) {
+        self.io = Some(io);
+    }
+
     pub fn jit_bf(&self, stmts: impl AsRef<[Statement]>) -> Option<JitFunction<BFExecFn>> {
         if unsafe { self.execution_engine.get_function::<BFExecFn>("jit_bf") }.is_err() {
             self.lower_bf(true, stmts);
+            self.run_passes();
         }
         unsafe { self.execution_engine.get_function("jit_bf").ok() }
     }
 
-    pub fn lower_bf(&self, jit: bool, stmts: impl AsRef<[Statement]>) -> Option<()> {
+    /// `jit_bf` behind a safe interface. The returned function runs the program and copies
+    /// as much of the final tape as fits into its argument. An empty slice skips the copy.
+    /// `JitIo` callbacks get a null user data pointer.
+    ///
+    /// The program is lowered here, with `PointerMode::Unchecked` taken as `Trap`, so one
+    /// that leaves its tape, `<` on cell 0 included, aborts the process instead of writing
+    /// past it. `None` if this `CodeGen` already holds a program, which may be unchecked.
+    pub fn compile_to_fn(&mut self, stmts: impl AsRef<[Statement]>) -> Option<impl FnMut(&mut [u8]) + '_> {
+        if unsafe { self.execution_engine.get_function::<BFExecFn>("jit_bf") }.is_ok() {
+            return None;
+        }
+        if self.pointer_mode == PointerMode::Unchecked {
+            self.pointer_mode = PointerMode::Trap;
+        }
+        let func = self.jit_bf(stmts)?;
+        Some(move |tape: &mut [u8]| {
+            let out = if tape.is_empty() { std::ptr::null_mut() } else { tape.as_mut_ptr() as *mut i8 };
+            // The generated code never writes past `tape.len()` bytes, nor off its own tape
+            unsafe { func.call(out, tape.len(), std::ptr::null_mut()) };
+        })
+    }
+
+    /// Compiles `[body]` as a `BFLoopFn` that works on a tape owned by the caller.
+    /// A `CodeGen` holds at most one compiled loop. `JitIo` callbacks get a null user
+    /// data pointer.
+    pub fn jit_loop(&self, body: &[Statement]) -> Option<JitFunction<BFLoopFn>> {
+        if unsafe { self.execution_engine.get_function::<BFLoopFn>("jit_loop") }.is_err() {
+            self.lower_loop(body)?;
+            self.run_passes();
+        }
+        unsafe { self.execution_engine.get_function("jit_loop").ok() }
+    }
+
+    fn lower_loop(&self, body: &[Statement]) -> Option<()> {
+        // Halting has to stop the caller too, which a loop function can't do, and a
+        // breakpoint can't tell how long the caller's tape is
+        if contains(body, &Statement::Halt) || contains(body, &Statement::Breakpoint) {
+            return None;
+        }
+
+        let i8_type = self.context.i8_type();
+        let index_type = self.context.ptr_sized_int_type(self.execution_engine.get_target_data(), None);
+        let fn_type = index_type.fn_type(&[i8_type.ptr_type(AddressSpace::Generic).into(), index_type.into()], false);
+        let func = self.module.add_function("jit_loop", fn_type, None);
+        self.declare_runtime(true);
+
+        let entry = self.context.append_basic_block(func, "entry");
+        self.builder.position_at_end(entry);
+
+        let data = func.get_nth_param(0)?.into_pointer_value();
+        let pos = self.builder.build_alloca(index_type, "pos");
+        self.builder.build_store(pos, func.get_nth_param(1)?.into_int_value());
+
+        if self.io.is_some() {
+            self.user_data.set(Some(i8_type.ptr_type(AddressSpace::Generic).const_null()));
+        }
+        self.compile_stmt(func, data, pos, &Statement::Loop(body.to_vec()));
+        self.user_data.set(None);
+
+        let end = self.builder.build_load(pos, "end_pos");
+        self.builder.build_return(Some(&end));
+        Some(())
+    }
+
+    // Declares the I/O functions and intrinsics that compiled statements call
+    fn declare_runtime(&self, jit: bool) {
         let i8_type = self.context.i8_type();
         let void_type = self.context.void_type();
         let index_type = self.context.ptr_sized_int_type(self.execution_engine.get_target_data(), None);
-        let fn_type = void_type.fn_type(&[i8_type.ptr_type(AddressSpace::Generic).into()], false);
-        let name = if jit { "jit_bf" } else { "bf_main" };
-        let func = self.module.add_function(name, fn_type, None);
 
-        let writef = self.module.add_function("write_char", void_type.fn_type(&[i8_type.into()], false), None);
-        let readf = self.module.add_function("read_char", i8_type.fn_type(&[], false), None);
+        let i8_ptr = i8_type.ptr_type(AddressSpace::Generic);
+        match self.io {
+            Some(io) if jit => {
+                // The callbacks take the user data first
+                let writef = self.module.add_function("write_char", void_type.fn_type(&[i8_ptr.into(), i8_type.into()], false), None);
+                let readf = self.module.add_function("read_char", i8_type.fn_type(&[i8_ptr.into()], false), None);
+                self.execution_engine.add_global_mapping(&writef, io.write as usize);
+                self.execution_engine.add_global_mapping(&readf, io.read as usize);
+            }
+            _ => {
+                let writef = self.module.add_function("write_char", void_type.fn_type(&[i8_type.into()], false), None);
+                let readf = self.module.add_function("read_char", i8_type.fn_type(&[], false), None);
+                if jit {
+                    self.execution_engine.add_global_mapping(&writef, write_char as usize);
+                    self.execution_engine.add_global_mapping(&readf, read_char as usize);
+                }
+            }
+        }
         if jit {
-            self.execution_engine.add_global_mapping(&writef, write_char as usize);
-            self.execution_engine.add_global_mapping(&readf, read_char as usize);
+            // AOT builds have no runtime to dump the tape with, so breakpoints do nothing there
+            let dumpf = self.module.add_function("debug_dump", void_type.fn_type(&[i8_ptr.into(), index_type.into(), index_type.into()], false), None);
+            self.execution_engine.add_global_mapping(&dumpf, debug_dump as usize);
         }
 
         let f = format!("llvm.usub.sat.{}", index_type.print_to_string().to_string());
         let _ = self.module.add_function(&f, index_type.fn_type(&[index_type.into(), index_type.into()], false), None);
+        let _ = self.module.add_function("llvm.trap", void_type.fn_type(&[], false), None);
+    }
+
+    // Heap-allocates a zeroed tape of `cells` cells with the C library's `calloc`, which
+    // AOT builds are linked with anyway and the JIT finds in the process. Aborts if the
+    // allocation fails.
+    fn build_calloc_tape(&self, func: FunctionValue<'ctx>) -> PointerValue<'ctx> {
+        let i8_ptr = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        let index_type = self.context.ptr_sized_int_type(self.execution_engine.get_target_data(), None);
+        let calloc = self.module.get_function("calloc").unwrap_or_else(|| {
+            self.module.add_function("calloc", i8_ptr.fn_type(&[index_type.into(), index_type.into()], false), None)
+        });
+        let abort = self.module.get_function("abort").unwrap_or_else(|| {
+            self.module.add_function("abort", self.context.void_type().fn_type(&[], false), None)
+        });
+        let tape = self.builder.build_call(calloc, &[index_type.const_int(self.cells as u64, false).into(), index_type.const_int(1, false).into()], "tape")
+            .try_as_basic_value().left().unwrap().into_pointer_value();
+
+        let ok = self.context.append_basic_block(func, "tape_ok");
+        let failed = self.context.append_basic_block(func, "no_tape");
+        let null = self.builder.build_is_null(tape, "no_tape");
+        self.builder.build_conditional_branch(null, failed, ok);
+        self.builder.position_at_end(failed);
+        self.builder.build_call(abort, &[], "aborted");
+        self.builder.build_unreachable();
+        self.builder.position_at_end(ok);
+        tape
+    }
+
+    fn build_free(&self, ptr: PointerValue<'ctx>) {
+        let i8_ptr = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        let free = self.module.get_function("free").unwrap_or_else(|| {
+            self.module.add_function("free", self.context.void_type().fn_type(&[i8_ptr.into()], false), None)
+        });
+        self.builder.build_call(free, &[ptr.into()], "freed");
+    }
+
+    pub fn lower_bf(&self, jit: bool, stmts: impl AsRef<[Statement]>) -> Option<()> {
+        let i8_type = self.context.i8_type();
+        let void_type = self.context.void_type();
+        let index_type = self.context.ptr_sized_int_type(self.execution_engine.get_target_data(), None);
+        let i8_ptr = i8_type.ptr_type(AddressSpace::Generic);
+        // Only JIT-compiled code takes user data, AOT builds keep the plain C signature
+        let fn_type = if jit {
+            void_type.fn_type(&[i8_ptr.into(), index_type.into(), i8_ptr.into()], false)
+        } else {
+            void_type.fn_type(&[i8_ptr.into(), index_type.into()], false)
+        };
+        let name = if jit { "jit_bf" } else { "bf_main" };
+        let func = self.module.add_function(name, fn_type, None);
+        self.declare_runtime(jit);
 
         let entry = self.context.append_basic_block(func, "entry");
         self.builder.position_at_end(entry);
 
         let data = func.get_nth_param(0)?.into_pointer_value();
+        let data_len = func.get_nth_param(1)?.into_int_value();
         let pos = self.builder.build_alloca(index_type, "pos");
-        let data_array = self.builder.build_array_alloca(i8_type, index_type.const_int(NUM_CELLS as u64, false), "data");
-        let memset_ty = void_type.fn_type(&[data_array.get_type().into(), i8_type.into(), self.context.i32_type().into(), self.context.bool_type().into()], false);
-        self.module.add_function("llvm.memset.p0i8.i32", memset_ty, None);
-        self.builder.build_call(self.module.get_function("llvm.memset.p0i8.i32").unwrap(), &[data_array.into(), i8_type.const_zero().into(), self.context.i32_type().const_int(30000, false).into(), self.context.bool_type().const_zero().into()], "cleardata");
+        let num_cells = index_type.const_int(self.cells as u64, false);
+        // Zeroed pages straight from the OS, and no limit from the stack size
+        let data_array = self.build_calloc_tape(func);
 
         self.builder.build_store(pos, index_type.const_zero());
 
+        let finish = self.context.append_basic_block(func, "finish");
+        self.halt.set(Some(finish));
+        if jit && self.io.is_some() {
+            self.user_data.set(Some(func.get_nth_param(2)?.into_pointer_value()));
+        }
         stmts.as_ref().iter().for_each(|s| self.compile_stmt(func, data_array, pos, s));
+        self.halt.set(None);
+        self.user_data.set(None);
+        self.builder.build_unconditional_branch(finish);
+        self.builder.position_at_end(finish);
 
-        self.builder.build_memcpy(data, 1, data_array, 1, index_type.const_int(NUM_CELLS as u64, false)).unwrap();
+        let copy_back = self.context.append_basic_block(func, "copy_back");
+        let exit = self.context.append_basic_block(func, "exit");
+        let no_out = self.builder.build_is_null(data, "no_out");
+        self.builder.build_conditional_branch(no_out, exit, copy_back);
+        self.builder.position_at_end(copy_back);
+        // Never copy more than the caller said its buffer holds
+        let fits = self.builder.build_int_compare(IntPredicate::ULT, data_len, num_cells, "fits");
+        let copy_len = self.builder.build_select(fits, data_len, num_cells, "copy_len").into_int_value();
+        self.builder.build_memcpy(data, 1, data_array, 1, copy_len).unwrap();
+        self.builder.build_unconditional_branch(exit);
+        self.builder.position_at_end(exit);
+        self.build_free(data_array);
         self.builder.build_return(None);
+        Some(())
+    }
 
+    /// Runs the module pass pipeline at the level this `CodeGen` was created with.
+    /// This should happen exactly once, after every function has been added to the module.
+    fn run_passes(&self) {
         let passes = PassManager::create(());
         let pm = PassManagerBuilder::create();
         pm.set_optimization_level(self.opt_level);
         pm.populate_module_pass_manager(&passes);
         passes.add_promote_memory_to_register_pass();
         passes.run_on(&self.module);
-        Some(())
     }
 
-    fn compile_stmt(&self, func: FunctionValue, data: PointerValue, pos: PointerValue, s: &Statement) {
+    // The index `delta` cells from `cur_pos`, kept on the tape as the pointer mode says
+    fn offset_pos(&self, cur_pos: IntValue<'ctx>, delta: i64) -> IntValue<'ctx> {
+        let index_type = self.context.ptr_sized_int_type(self.execution_engine.get_target_data(), None);
+        let num_cells = index_type.const_int(self.cells as u64, false);
+        match self.pointer_mode {
+            PointerMode::Unchecked => self.builder.build_int_add(cur_pos, index_type.const_int(delta as u64, true), "new_pos"),
+            PointerMode::Wrap => {
+                // `cur_pos` is on the tape, so adding less than its length can't overflow
+                let delta = delta.rem_euclid(self.cells as i64) as u64;
+                let new_pos = self.builder.build_int_add(cur_pos, index_type.const_int(delta, false), "new_pos");
+                if self.cells.is_power_of_two() {
+                    self.builder.build_and(new_pos, index_type.const_int(self.cells as u64 - 1, false), "wrapped_pos")
+                } else {
+                    self.builder.build_int_unsigned_rem(new_pos, num_cells, "wrapped_pos")
+                }
+            }
+            PointerMode::Trap => {
+                // Left of cell 0 comes out as a huge index, so one compare covers both ends
+                let new_pos = self.builder.build_int_add(cur_pos, index_type.const_int(delta as u64, true), "new_pos");
+                let on_tape = self.builder.build_int_compare(IntPredicate::ULT, new_pos, num_cells, "on_tape");
+                let func = self.builder.get_insert_block().unwrap().get_parent().unwrap();
+                let ok = self.context.append_basic_block(func, "on_tape");
+                let trap = self.context.append_basic_block(func, "off_tape");
+                self.builder.build_conditional_branch(on_tape, ok, trap);
+                self.builder.position_at_end(trap);
+                self.builder.build_call(self.module.get_function("llvm.trap").unwrap(), &[], "trapped");
+                self.builder.build_unreachable();
+                self.builder.position_at_end(ok);
+                new_pos
+            }
+        }
+    }
+
+    // The cell `offset` cells from the one at `cur_pos`
+    fn cell_at(&self, data: PointerValue<'ctx>, cur_pos: IntValue<'ctx>, offset: i64, name: &str) -> PointerValue<'ctx> {
+        let idx = self.offset_pos(cur_pos, offset);
+        unsafe { self.builder.build_gep(data, &[idx], name) }
+    }
+
+    // Emits `body` to run only when `val` is nonzero. A fused loop that wouldn't have run
+    // must not touch its targets, which may be off the tape, or trap on them.
+    fn if_nonzero(&self, func: FunctionValue<'ctx>, val: IntValue<'ctx>, body: impl FnOnce()) {
+        let then = self.context.append_basic_block(func, "nonzero");
+        let after = self.context.append_basic_block(func, "after_nonzero");
+        let comp = self.builder.build_int_compare(IntPredicate::NE, val, val.get_type().const_zero(), "comp");
+        self.builder.build_conditional_branch(comp, then, after);
+        self.builder.position_at_end(then);
+        body();
+        self.builder.build_unconditional_branch(after);
+        self.builder.position_at_end(after);
+    }
+
+    fn compile_stmt(&self, func: FunctionValue<'ctx>, data: PointerValue<'ctx>, pos: PointerValue<'ctx>, s: &Statement) {
         let index_type = self.context.ptr_sized_int_type(self.execution_engine.get_target_data(), None);
         let i8_type = self.context.i8_type();
         let i64_type = self.context.i64_type();
 
         match s {
+            Statement::Move(n) => self.compile_stmt(func, data, pos, &step(*n)),
             Statement::Next(u) => {
                 let cur_val = self.builder.build_load(pos, "cur_pos");
-                let new_val = self.builder.build_int_add(cur_val.into_int_value(), index_type.const_int(*u as u64, false), "new_pos");
+                let new_val = self.offset_pos(cur_val.into_int_value(), *u as i64);
+                self.builder.build_store(pos, new_val);
+            }
+            Statement::Prev(u) if self.pointer_mode != PointerMode::Unchecked => {
+                let cur_val = self.builder.build_load(pos, "cur_pos");
+                let new_val = self.offset_pos(cur_val.into_int_value(), -(*u as i64));
                 self.builder.build_store(pos, new_val);
             }
             Statement::Prev(u) => {
@@ -126,13 +449,20 @@ impl<'ctx> CodeGen<'ctx> {
                 let cur_pos = self.builder.build_load(pos, "cur_pos");
                 let loc = unsafe { self.builder.build_gep(data, &[cur_pos.into_int_value()], "data_pos") };
                 let cur_val = self.builder.build_load(loc, "cur_val").into_int_value();
-                self.builder.build_call(f, &[cur_val.into()], "printed");
+                match self.user_data.get() {
+                    Some(user_data) => self.builder.build_call(f, &[user_data.into(), cur_val.into()], "printed"),
+                    None => self.builder.build_call(f, &[cur_val.into()], "printed"),
+                };
             }
             Statement::In => {
                 let f = self.module.get_function("read_char").unwrap();
                 let cur_pos = self.builder.build_load(pos, "cur_pos");
                 let loc = unsafe { self.builder.build_gep(data, &[cur_pos.into_int_value()], "data_pos") };
-                let new_val = self.builder.build_call(f, &[], "new_val").try_as_basic_value().left().unwrap();
+                let read = match self.user_data.get() {
+                    Some(user_data) => self.builder.build_call(f, &[user_data.into()], "new_val"),
+                    None => self.builder.build_call(f, &[], "new_val"),
+                };
+                let new_val = read.try_as_basic_value().left().unwrap();
                 self.builder.build_store(loc, new_val);
             }
             Statement::Loop(l) => {
@@ -157,23 +487,71 @@ impl<'ctx> CodeGen<'ctx> {
                 let loc = unsafe { self.builder.build_gep(data, &[cur_pos.into_int_value()], "data_pos") };
                 self.builder.build_store(loc, i8_type.const_zero());
             }
+            Statement::SetConst(n) => {
+                let cur_pos = self.builder.build_load(pos, "cur_pos");
+                let loc = unsafe { self.builder.build_gep(data, &[cur_pos.into_int_value()], "data_pos") };
+                self.builder.build_store(loc, i8_type.const_int(*n as u8 as u64, false));
+            }
+            Statement::IncAt { offset, amount } => {
+                let cur_pos = self.builder.build_load(pos, "cur_pos");
+                let store_loc = self.cell_at(data, cur_pos.into_int_value(), *offset as i64, "store_pos");
+                let old_val = self.builder.build_load(store_loc, "old_val");
+                let new_val = self.builder.build_int_add(old_val.into_int_value(), i8_type.const_int(*amount as u8 as u64, false), "new_val");
+                self.builder.build_store(store_loc, new_val);
+            }
             Statement::AddOffset { mul, offset } => {
                 // Load cur val
                 let cur_pos = self.builder.build_load(pos, "cur_pos");
                 let loc = unsafe { self.builder.build_gep(data, &[cur_pos.into_int_value()], "data_pos") };
                 let cur_val = self.builder.build_load(loc, "cur_val").into_int_value();
-                // Multiply by mul
-                let add_val = self.builder.build_int_s_extend_or_bit_cast(cur_val, i64_type, "add_val");
-                let add_val = self.builder.build_int_mul(add_val, i64_type.const_int(*mul as u64, true), "mul_val");
-                let store_loc = unsafe {self.builder.build_gep(loc, &[index_type.const_int(*offset as u64, false)], "store_pos")};
-                let cur_val = self.builder.build_load(store_loc, "old_val");
-                let cur_val = self.builder.build_int_s_extend_or_bit_cast(cur_val.into_int_value(), i64_type, "old_val");
-                let new_val = self.builder.build_int_add(cur_val, add_val, "new_val");
-                // Store at offset
-                let store_val = self.builder.build_int_truncate_or_bit_cast(new_val, i8_type, "store_size_val");
-                self.builder.build_store(store_loc, store_val);
+                self.if_nonzero(func, cur_val, || {
+                    // Multiply by mul. Like the interpreter, read the cell as unsigned; only the
+                    // low 8 bits of the product are kept, so the multiplier is reduced to match.
+                    let add_val = self.builder.build_int_z_extend_or_bit_cast(cur_val, i64_type, "add_val");
+                    let add_val = self.builder.build_int_mul(add_val, i64_type.const_int(wrap_mul(*mul, 8) as u64, true), "mul_val");
+                    let store_loc = self.cell_at(data, cur_pos.into_int_value(), *offset as i64, "store_pos");
+                    let cur_val = self.builder.build_load(store_loc, "old_val");
+                    let cur_val = self.builder.build_int_s_extend_or_bit_cast(cur_val.into_int_value(), i64_type, "old_val");
+                    let new_val = self.builder.build_int_add(cur_val, add_val, "new_val");
+                    // Store at offset
+                    let store_val = self.builder.build_int_truncate_or_bit_cast(new_val, i8_type, "store_size_val");
+                    self.builder.build_store(store_loc, store_val);
+                });
             },
+            Statement::SearchZero { stride: 1 } => {
+                // Jumps to the next zero on the tape with the C library's vectorized `memchr`.
+                // The scalar search after it only has work left when there is none.
+                let i8_ptr = i8_type.ptr_type(AddressSpace::Generic);
+                let i32_type = self.context.i32_type();
+                let memchr = self.module.get_function("memchr").unwrap_or_else(|| {
+                    self.module.add_function("memchr", i8_ptr.fn_type(&[i8_ptr.into(), i32_type.into(), index_type.into()], false), None)
+                });
+                let usub = self.module.get_function(&format!("llvm.usub.sat.{}", index_type.print_to_string().to_string())).unwrap();
+
+                let cur_pos = self.builder.build_load(pos, "cur_pos").into_int_value();
+                let start = unsafe { self.builder.build_gep(data, &[cur_pos], "data_pos") };
+                let cells = index_type.const_int(self.cells as u64, false);
+                let len = self.builder.build_call(usub, &[cells.into(), cur_pos.into()], "scan_len")
+                    .try_as_basic_value().left().unwrap();
+                let found = self.builder.build_call(memchr, &[start.into(), i32_type.const_zero().into(), len], "found")
+                    .try_as_basic_value().left().unwrap().into_pointer_value();
+                let found_pos = self.builder.build_int_sub(
+                    self.builder.build_ptr_to_int(found, index_type, "found_addr"),
+                    self.builder.build_ptr_to_int(data, index_type, "data_addr"),
+                    "found_pos",
+                );
+                let none = self.builder.build_is_null(found, "none");
+                let new_pos = self.builder.build_select(none, cur_pos, found_pos, "new_pos");
+                self.builder.build_store(pos, new_pos);
+
+                self.compile_stmt(func, data, pos, &Statement::SearchValue { stride: 1, target: 0 });
+            }
             Statement::SearchZero { stride } => {
+                // `memrchr` is a glibc extension, so leftward scans would tie compiled
+                // objects to glibc; they and wider strides stay a cell-by-cell loop.
+                self.compile_stmt(func, data, pos, &Statement::SearchValue { stride: *stride, target: 0 });
+            }
+            Statement::SearchValue { stride, target } => {
                 let body = self.context.append_basic_block(func, "search_body");
                 let condition = self.context.append_basic_block(func, "cond_block");
                 let after_loop = self.context.append_basic_block(func, "after_loop");
@@ -182,42 +560,903 @@ impl<'ctx> CodeGen<'ctx> {
                 let cur_pos = self.builder.build_load(pos, "cur_pos");
                 let loc = unsafe { self.builder.build_gep(data, &[cur_pos.into_int_value()], "data_pos") };
                 let cur_val = self.builder.build_load(loc, "cur_val");
-                let comp = self.builder.build_int_compare(IntPredicate::NE, cur_val.into_int_value(), i8_type.const_zero(), "comp");
+                let target = i8_type.const_int(*target as u8 as u64, false);
+                let comp = self.builder.build_int_compare(IntPredicate::NE, cur_val.into_int_value(), target, "comp");
                 self.builder.build_conditional_branch(comp, body, after_loop);
                 self.builder.position_at_end(body);
                 let old_pos = self.builder.build_load(pos, "cur_pos").into_int_value();
-                let new_pos = self.builder.build_int_add(old_pos, index_type.const_int(*stride as u64, true), "new_pos");
+                let new_pos = self.offset_pos(old_pos, *stride as i64);
                 self.builder.build_store(pos, new_pos);
                 self.builder.build_unconditional_branch(condition);
                 self.builder.position_at_end(after_loop);
             }
+            Statement::ClearScan { stride } => {
+                let body = self.context.append_basic_block(func, "clear_scan_body");
+                let condition = self.context.append_basic_block(func, "cond_block");
+                let after_loop = self.context.append_basic_block(func, "after_loop");
+                self.builder.build_unconditional_branch(condition);
+                self.builder.position_at_end(condition);
+                let cur_pos = self.builder.build_load(pos, "cur_pos");
+                let loc = unsafe { self.builder.build_gep(data, &[cur_pos.into_int_value()], "data_pos") };
+                let cur_val = self.builder.build_load(loc, "cur_val");
+                let comp = self.builder.build_int_compare(IntPredicate::NE, cur_val.into_int_value(), i8_type.const_zero(), "comp");
+                self.builder.build_conditional_branch(comp, body, after_loop);
+                self.builder.position_at_end(body);
+                self.builder.build_store(loc, i8_type.const_zero());
+                let new_pos = self.offset_pos(cur_pos.into_int_value(), *stride as i64);
+                self.builder.build_store(pos, new_pos);
+                self.builder.build_unconditional_branch(condition);
+                self.builder.position_at_end(after_loop);
+            }
+            // Directives are only checked by the interpreters
+            Statement::Assert { .. } => {}
+            Statement::Breakpoint => {
+                if let Some(f) = self.module.get_function("debug_dump") {
+                    let cur_pos = self.builder.build_load(pos, "cur_pos");
+                    let cells = index_type.const_int(self.cells as u64, false);
+                    self.builder.build_call(f, &[data.into(), cur_pos, cells.into()], "dumped");
+                }
+            }
+            Statement::Halt => {
+                // Straight to copying out the tape. Whatever follows is unreachable, but
+                // still needs a block to go in
+                self.builder.build_unconditional_branch(self.halt.get().expect("`Halt` outside of `lower_bf`"));
+                let after = self.context.append_basic_block(func, "after_halt");
+                self.builder.position_at_end(after);
+            }
+            Statement::InfiniteLoopIfNonZero => {
+                let cur_pos = self.builder.build_load(pos, "cur_pos");
+                let loc = unsafe { self.builder.build_gep(data, &[cur_pos.into_int_value()], "data_pos") };
+                let cur_val = self.builder.build_load(loc, "cur_val");
+                let spin = self.context.append_basic_block(func, "spin");
+                let after = self.context.append_basic_block(func, "after_spin");
+                let comp = self.builder.build_int_compare(IntPredicate::NE, cur_val.into_int_value(), i8_type.const_zero(), "comp");
+                self.builder.build_conditional_branch(comp, spin, after);
+                self.builder.position_at_end(spin);
+                self.builder.build_unconditional_branch(spin);
+                self.builder.position_at_end(after);
+            }
+            // Calls into the runtime per byte either way, so these compile back to their loops
+            Statement::WriteBlock { stride } => {
+                self.compile_stmt(func, data, pos, &Statement::Loop(vec![Statement::Out, step(*stride)]));
+            }
+            Statement::ReadBlock { stride } => {
+                self.compile_stmt(func, data, pos, &Statement::Loop(vec![Statement::In, step(*stride)]));
+            }
+            Statement::MoveValue { offset } => {
+                // The destination is zero, so this is a plain store without the multiply-add
+                let cur_pos = self.builder.build_load(pos, "cur_pos");
+                let loc = unsafe { self.builder.build_gep(data, &[cur_pos.into_int_value()], "data_pos") };
+                let cur_val = self.builder.build_load(loc, "cur_val");
+                let store_loc = self.cell_at(data, cur_pos.into_int_value(), *offset as i64, "store_pos");
+                self.builder.build_store(store_loc, cur_val);
+                self.builder.build_store(loc, i8_type.const_zero());
+            }
+            Statement::MulAddMany { targets } => {
+                // The current cell is loaded once for every target, read as unsigned like `AddOffset`
+                let cur_pos = self.builder.build_load(pos, "cur_pos");
+                let loc = unsafe { self.builder.build_gep(data, &[cur_pos.into_int_value()], "data_pos") };
+                let cur_val = self.builder.build_load(loc, "cur_val").into_int_value();
+                self.if_nonzero(func, cur_val, || {
+                    let cur_val = self.builder.build_int_z_extend_or_bit_cast(cur_val, i64_type, "cur_val");
+                    for (offset, mul) in targets {
+                        let add_val = self.builder.build_int_mul(cur_val, i64_type.const_int(wrap_mul(*mul, 8) as u64, true), "mul_val");
+                        let store_loc = self.cell_at(data, cur_pos.into_int_value(), *offset as i64, "store_pos");
+                        let old_val = self.builder.build_load(store_loc, "old_val").into_int_value();
+                        let old_val = self.builder.build_int_s_extend_or_bit_cast(old_val, i64_type, "old_val");
+                        let new_val = self.builder.build_int_add(old_val, add_val, "new_val");
+                        let store_val = self.builder.build_int_truncate_or_bit_cast(new_val, i8_type, "store_size_val");
+                        self.builder.build_store(store_loc, store_val);
+                    }
+                    self.builder.build_store(loc, i8_type.const_zero());
+                });
+            }
+            Statement::LinearCombine { dest_offset, terms } => {
+                // Each source is loaded once, and the destination is updated once with the sum
+                let cur_pos = self.builder.build_load(pos, "cur_pos").into_int_value();
+                let mut sum = i64_type.const_zero();
+                for (mul, offset) in terms {
+                    let src_loc = self.cell_at(data, cur_pos, *offset as i64, "src_pos");
+                    let src_val = self.builder.build_load(src_loc, "src_val").into_int_value();
+                    let src_val = self.builder.build_int_z_extend_or_bit_cast(src_val, i64_type, "src_val");
+                    let term = self.builder.build_int_mul(src_val, i64_type.const_int(wrap_mul(*mul, 8) as u64, true), "term");
+                    sum = self.builder.build_int_add(sum, term, "sum");
+                    self.builder.build_store(src_loc, i8_type.const_zero());
+                }
+                let dest_loc = self.cell_at(data, cur_pos, *dest_offset as i64, "dest_pos");
+                let old_val = self.builder.build_load(dest_loc, "old_val").into_int_value();
+                let old_val = self.builder.build_int_s_extend_or_bit_cast(old_val, i64_type, "old_val");
+                let new_val = self.builder.build_int_add(old_val, sum, "new_val");
+                let store_val = self.builder.build_int_truncate_or_bit_cast(new_val, i8_type, "store_size_val");
+                self.builder.build_store(dest_loc, store_val);
+            }
+            Statement::Copy { offset } => {
+                let cur_pos = self.builder.build_load(pos, "cur_pos");
+                let loc = unsafe { self.builder.build_gep(data, &[cur_pos.into_int_value()], "data_pos") };
+                let cur_val = self.builder.build_load(loc, "cur_val").into_int_value();
+                let store_loc = self.cell_at(data, cur_pos.into_int_value(), *offset as i64, "store_pos");
+                let old_val = self.builder.build_load(store_loc, "old_val").into_int_value();
+                let new_val = self.builder.build_int_add(old_val, cur_val, "new_val");
+                self.builder.build_store(store_loc, new_val);
+            }
         }
     }
 
-    pub fn add_main(&self) {
+    /// Adds a `main` for AOT builds that runs `bf_main`. With `read_argv`, a file named
+    /// as the first argument replaces stdin, so `,` reads from it like the interpreter
+    /// does from redirected input; `main` returns 1 if it can't be opened.
+    pub fn add_main(&self, read_argv: bool) {
         let i32_type = self.context.i32_type();
         let i8_type = self.context.i8_type();
-        let main_ftype = i32_type.fn_type(&[], false);
+        let argv_type = i8_type.ptr_type(AddressSpace::Generic).ptr_type(AddressSpace::Generic);
+        let main_ftype = if read_argv {
+            i32_type.fn_type(&[i32_type.into(), argv_type.into()], false)
+        } else {
+            i32_type.fn_type(&[], false)
+        };
         let func = self.module.add_function("main", main_ftype, None);
         let entry = self.context.append_basic_block(func, "entry");
         self.builder.position_at_end(entry);
-        let out = self.builder.build_array_alloca(i8_type, i32_type.const_int(NUM_CELLS as u64, false), "contents");
-        self.builder.build_call(self.module.get_function("bf_main").unwrap(), &[out.into()], "e");
+        if read_argv {
+            self.redirect_stdin(func);
+        }
+        // Nothing reads the final tape, so skip the copy
+        let index_type = self.context.ptr_sized_int_type(self.execution_engine.get_target_data(), None);
+        let out = i8_type.ptr_type(AddressSpace::Generic).const_null();
+        self.builder.build_call(self.module.get_function("bf_main").unwrap(), &[out.into(), index_type.const_zero().into()], "e");
         self.builder.build_return(Some(&i32_type.const_zero()));
     }
 
-    pub fn create_object_file(&self, p: impl AsRef<Path>, opt_level: OptimizationLevel) {
-        let target = Target::from_triple(&TargetMachine::get_default_triple()).unwrap();
+    // Opens argv[1] if it is there and makes it fd 0. Leaves the builder in the block
+    // where `main` carries on.
+    fn redirect_stdin(&self, main: FunctionValue) {
+        let i32_type = self.context.i32_type();
+        let path_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        let open = self.module.add_function("open", i32_type.fn_type(&[path_type.into(), i32_type.into()], true), None);
+        let dup2 = self.module.add_function("dup2", i32_type.fn_type(&[i32_type.into(), i32_type.into()], false), None);
+
+        let open_input = self.context.append_basic_block(main, "open_input");
+        let use_input = self.context.append_basic_block(main, "use_input");
+        let open_failed = self.context.append_basic_block(main, "open_failed");
+        let run = self.context.append_basic_block(main, "run");
+
+        let argc = main.get_nth_param(0).unwrap().into_int_value();
+        let argv = main.get_nth_param(1).unwrap().into_pointer_value();
+        let has_arg = self.builder.build_int_compare(IntPredicate::SGT, argc, i32_type.const_int(1, false), "has_arg");
+        self.builder.build_conditional_branch(has_arg, open_input, run);
+
+        self.builder.position_at_end(open_input);
+        let arg = unsafe { self.builder.build_gep(argv, &[i32_type.const_int(1, false)], "arg") };
+        let path = self.builder.build_load(arg, "path");
+        // O_RDONLY
+        let fd = self.builder.build_call(open, &[path, i32_type.const_zero().into()], "fd")
+            .try_as_basic_value().left().unwrap().into_int_value();
+        let failed = self.builder.build_int_compare(IntPredicate::SLT, fd, i32_type.const_zero(), "failed");
+        self.builder.build_conditional_branch(failed, open_failed, use_input);
+
+        self.builder.position_at_end(use_input);
+        self.builder.build_call(dup2, &[fd.into(), i32_type.const_zero().into()], "stdin");
+        self.builder.build_unconditional_branch(run);
+
+        self.builder.position_at_end(open_failed);
+        self.builder.build_return(Some(&i32_type.const_int(1, false)));
+
+        self.builder.position_at_end(run);
+    }
+
+    /// The module's textual LLVM IR, as lowered so far
+    pub fn emit_ir(&self) -> String {
+        self.module.print_to_string().to_string()
+    }
+
+    /// The module compiled to assembly for the host at `opt`
+    pub fn emit_asm(&self, opt: OptimizationLevel) -> anyhow::Result<String> {
+        Target::initialize_native(&InitializationConfig::default()).map_err(anyhow::Error::msg)?;
+        let triple = TargetMachine::get_default_triple();
+        let target = Target::from_triple(&triple).map_err(|e| anyhow::anyhow!("{}", e))?;
         let host = TargetMachine::get_host_cpu_name().to_string();
         let features = TargetMachine::get_host_cpu_features().to_string();
-        let tm = target.create_target_machine(
-            &TargetMachine::get_default_triple(),
-            &host,
-            &features,
-            opt_level,
-            RelocMode::Default,
-            CodeModel::Default,
-        ).unwrap();
-        tm.write_to_file(&self.module, FileType::Object, p.as_ref()).unwrap()
-    }
-}
\ No newline at end of file
+        let tm = target.create_target_machine(&triple, &host, &features, opt, RelocMode::Default, CodeModel::Default)
+            .ok_or_else(|| anyhow::anyhow!("no target machine for {:?}", triple))?;
+        let asm = tm.write_to_memory_buffer(&self.module, FileType::Assembly).map_err(|e| anyhow::anyhow!("{}", e))?;
+        Ok(String::from_utf8_lossy(asm.as_slice()).into_owned())
+    }
+
+    /// Writes the module to `p` as an object file for the host. Fails if there is no
+    /// target machine for it or the file can't be written.
+    pub fn create_object_file(&self, p: impl AsRef<Path>, kind: OutputKind) -> anyhow::Result<()> {
+        let reloc = match kind {
+            OutputKind::Executable => RelocMode::Default,
+            OutputKind::SharedLib => RelocMode::PIC,
+        };
+
+        self.run_passes();
+        let triple = TargetMachine::get_default_triple();
+        let target = Target::from_triple(&triple).map_err(|e| anyhow::anyhow!("{}", e))?;
+        let host = TargetMachine::get_host_cpu_name().to_string();
+        let features = TargetMachine::get_host_cpu_features().to_string();
+        let tm = target.create_target_machine(&triple, &host, &features, self.opt_level, reloc, CodeModel::Default)
+            .ok_or_else(|| anyhow::anyhow!("no target machine for {:?}", triple))?;
+        tm.write_to_file(&self.module, FileType::Object, p.as_ref())
+            .map_err(|e| anyhow::anyhow!("can't write {}: {}", p.as_ref().display(), e))
+    }
+}
+
+/// Compiled programs, keyed by their statements, so submitting the same program again
+/// reuses the machine code instead of recompiling. Programs should be optimized before
+/// they are submitted so that equivalent sources share an entry.
+pub struct CompiledCache<'ctx> {
+    context: &'ctx Context,
+    opt_level: OptimizationLevel,
+    entries: HashMap<Vec<Statement>, CodeGen<'ctx>>,
+    compiles: usize,
+}
+
+impl<'ctx> CompiledCache<'ctx> {
+    pub fn new(ctx: &'ctx Context, opt: OptimizationLevel) -> Self {
+        CompiledCache {
+            context: ctx,
+            opt_level: opt,
+            entries: HashMap::new(),
+            compiles: 0,
+        }
+    }
+
+    pub fn get_or_compile(&mut self, stmts: &[Statement]) -> Option<JitFunction<BFExecFn>> {
+        if !self.entries.contains_key(stmts) {
+            let gen = CodeGen::new(self.context, self.opt_level);
+            gen.jit_bf(stmts)?;
+            self.entries.insert(stmts.to_vec(), gen);
+            self.compiles += 1;
+        }
+
+        self.entries[stmts].jit_bf(stmts)
+    }
+
+    /// Number of programs compiled so far, i.e. cache misses
+    pub fn compiles(&self) -> usize {
+        self.compiles
+    }
+}
+
+/// Tiered execution: interprets the program, counting iterations of each loop, and
+/// once a loop has run `threshold` iterations compiles it and runs the rest of it, and
+/// every later entry into it, natively on the interpreter's tape.
+///
+/// Loops that read, write or check assertions always stay interpreted, so output
+/// limits, EOF handling and assertion errors behave as they do in the interpreter.
+/// Compiled loops wrap on overflow, so nothing is compiled unless the context's
+/// arithmetic is `CellArith::Wrap`, or while it has a step limit they couldn't count
+/// against. They can't grow the tape either: one that would leave it, or move left of
+/// cell 0, aborts the process as with `PointerMode::Trap`.
+pub struct Hybrid<'ctx> {
+    context: &'ctx Context,
+    opt_level: OptimizationLevel,
+    threshold: usize,
+    // Loops are identified by their address in the program being run
+    counts: HashMap<*const Statement, usize>,
+    // `None` if the loop can't be compiled
+    compiled: HashMap<*const Statement, Option<CodeGen<'ctx>>>,
+}
+
+impl<'ctx> Hybrid<'ctx> {
+    pub fn new(ctx: &'ctx Context, opt: OptimizationLevel, threshold: usize) -> Self {
+        Hybrid {
+            context: ctx,
+            opt_level: opt,
+            threshold,
+            counts: HashMap::new(),
+            compiled: HashMap::new(),
+        }
+    }
+
+    /// Runs `stmts` on `ctx`. Compiled loops work on the tape as long as it is when they
+    /// are compiled, so it should already be as long as the program needs.
+    pub fn run(&mut self, ctx: &mut crate::Context, stmts: &[Statement]) -> Result<(), crate::Error> {
+        self.counts.clear();
+        self.compiled.clear();
+        let res = self.exec_block(ctx, stmts);
+        // Like `Context::exec_many`, rather than waiting for the context to be dropped
+        let flushed = ctx.flush();
+        res?;
+        Ok(flushed?)
+    }
+
+    /// Number of loops compiled by the last `run`
+    pub fn compiled_loops(&self) -> usize {
+        self.compiled.values().filter(|gen| gen.is_some()).count()
+    }
+
+    fn exec_block(&mut self, ctx: &mut crate::Context, stmts: &[Statement]) -> Result<(), crate::Error> {
+        for s in stmts {
+            match s {
+                Statement::Loop(body) => self.exec_loop(ctx, s, body)?,
+                s => ctx.exec(s)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn exec_loop(&mut self, ctx: &mut crate::Context, s: &Statement, body: &[Statement]) -> Result<(), crate::Error> {
+        let key = s as *const Statement;
+        while ctx.cur()? != 0 {
+            if let Some(Some(gen)) = self.compiled.get(&key) {
+                // A tape that has grown since is longer than the one the loop checks against
+                if let Some(f) = gen.jit_loop(body).filter(|_| gen.cells() == ctx.data().len()) {
+                    let idx = ctx.idx();
+                    let end = unsafe { f.call(ctx.data_mut().as_mut_ptr(), idx) };
+                    ctx.set_idx(end);
+                    return Ok(());
+                }
+            }
+
+            self.exec_block(ctx, body)?;
+
+            let count = self.counts.entry(key).or_insert(0);
+            *count += 1;
+            if *count >= self.threshold && !self.compiled.contains_key(&key) {
+                let exact = ctx.arith() == crate::CellArith::Wrap && ctx.step_limit().is_none();
+                let gen = Some(CodeGen::new(self.context, self.opt_level))
+                    .filter(|_| exact && compilable(body))
+                    .map(|mut gen| {
+                        gen.set_cells(ctx.data().len());
+                        gen.set_pointer_mode(PointerMode::Trap);
+                        gen
+                    })
+                    .filter(|gen| gen.jit_loop(body).is_some());
+                self.compiled.insert(key, gen);
+            }
+        }
+        Ok(())
+    }
+}
+
+// What a `Breakpoint` calls in JIT-compiled code. The tape is the one `lower_bf` sets up,
+// `cells` long.
+extern "C" fn debug_dump(tape: *const i8, pos: usize, cells: usize) {
+    let tape = unsafe { std::slice::from_raw_parts(tape, cells) };
+    let _ = std::io::stdout().flush();
+    eprintln!("{}", crate::bf::tape_dump(pos, cells, |i| tape[i] as i64));
+}
+
+// `Next` or `Prev` by `stride` cells
+fn step(stride: isize) -> Statement {
+    if stride < 0 {
+        Statement::Prev(-stride as usize)
+    } else {
+        Statement::Next(stride as usize)
+    }
+}
+
+fn contains(stmts: &[Statement], stmt: &Statement) -> bool {
+    stmts.iter().any(|s| match s {
+        Statement::Loop(l) => contains(l, stmt),
+        s => s == stmt,
+    })
+}
+
+fn compilable(stmts: &[Statement]) -> bool {
+    stmts.iter().all(|s| match s {
+        Statement::In | Statement::Out | Statement::Assert { .. } | Statement::WriteBlock { .. }
+            | Statement::ReadBlock { .. } | Statement::Halt | Statement::Breakpoint => false,
+        Statement::Loop(l) => compilable(l),
+        _ => true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::program;
+    use std::process::Command;
+
+    #[test]
+    fn probes_succeed_where_the_tests_run() {
+        // Every other test here needs both anyway
+        assert_eq!(probe_jit(), Ok(()));
+        assert_eq!(probe_native(), Ok(()));
+    }
+
+    #[test]
+    fn copy_back_is_optional() {
+        let ctx = Context::create();
+        let gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        let (_, s) = program("++>+++>>>>>>>>[-]<<<<<<<<<[->+<]").unwrap();
+        let func = gen.jit_bf(&s).unwrap();
+
+        let mut tape = vec![0i8; NUM_CELLS];
+        unsafe { func.call(tape.as_mut_ptr(), tape.len(), std::ptr::null_mut()); }
+        assert_eq!(&tape[..3], &[0, 5, 0]);
+
+        // Nothing to copy into; must not write through the null pointer
+        unsafe { func.call(std::ptr::null_mut(), 0, std::ptr::null_mut()); }
+
+        // Only as much as fits is copied back
+        let mut short = vec![7i8; 4];
+        unsafe { func.call(short.as_mut_ptr(), 2, std::ptr::null_mut()); }
+        assert_eq!(short, vec![0, 5, 7, 7]);
+    }
+
+    #[test]
+    fn tape_is_zeroed_to_the_end() {
+        use crate::Statement::*;
+
+        let ctx = Context::create();
+        let gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        let func = gen.jit_bf(&[Next(40000), Inc(1), Next(NUM_CELLS - 40001), Inc(2)]).unwrap();
+
+        // Whatever was in the buffer before is overwritten by the copy-back
+        let mut tape = vec![7i8; NUM_CELLS];
+        unsafe { func.call(tape.as_mut_ptr(), tape.len(), std::ptr::null_mut()); }
+        assert_eq!((tape[40000], tape[NUM_CELLS - 1]), (1, 2));
+        assert!(tape.iter().enumerate().all(|(i, &c)| c == 0 || i == 40000 || i == NUM_CELLS - 1));
+    }
+
+    #[test]
+    fn pointer_modes() {
+        // What `<+>>++[-<<<+>>>]` would fuse to, if the loop couldn't stop at cell 0
+        let s = vec![Statement::Prev(1), Statement::Inc(1), Statement::Move(2), Statement::Inc(2),
+            Statement::AddOffset { mul: 1, offset: -3 }, Statement::Clear];
+
+        let mut interp = crate::Context::with_state(vec![0; NUM_CELLS]);
+        interp.set_tape_mode(crate::TapeMode::Wrap);
+        interp.exec_many(&s).unwrap();
+        assert_eq!((interp.data()[1], interp.data()[NUM_CELLS - 2], interp.data()[NUM_CELLS - 1]), (0, 2, 1));
+
+        let ctx = Context::create();
+        let mut gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        gen.set_pointer_mode(PointerMode::Wrap);
+        let func = gen.jit_bf(&s).unwrap();
+        let mut tape = vec![0i8; NUM_CELLS];
+        unsafe { func.call(tape.as_mut_ptr(), tape.len(), std::ptr::null_mut()); }
+        assert_eq!(tape, interp.data());
+
+        // Leaving the tape would abort the test, so only check the guard is there
+        let (_, s) = program("+>>+<").unwrap();
+        let mut gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        gen.set_pointer_mode(PointerMode::Trap);
+        gen.lower_bf(true, &s).unwrap();
+        assert!(gen.emit_ir().contains("call void @llvm.trap()"));
+        let func = gen.jit_bf(&s).unwrap();
+        let mut tape = vec![0i8; NUM_CELLS];
+        unsafe { func.call(tape.as_mut_ptr(), tape.len(), std::ptr::null_mut()); }
+        assert_eq!(&tape[..3], &[1, 0, 1]);
+
+        let gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        gen.lower_bf(true, &s).unwrap();
+        assert!(!gen.emit_ir().contains("call void @llvm.trap()"));
+
+        // A fused loop on a zero cell never reaches its targets, so it doesn't trap either
+        let s = [Statement::AddOffset { mul: 1, offset: -1 }, Statement::Clear, Statement::MulAddMany { targets: vec![(-2, 1), (1, 1)] }];
+        let mut gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        gen.set_pointer_mode(PointerMode::Trap);
+        let func = gen.jit_bf(&s).unwrap();
+        let mut tape = vec![0i8; NUM_CELLS];
+        unsafe { func.call(tape.as_mut_ptr(), tape.len(), std::ptr::null_mut()); }
+        assert!(tape.iter().all(|&c| c == 0));
+    }
+
+    #[test]
+    fn configurable_cells() {
+        let (_, s) = program("<+>>++<<<<[-]").unwrap();
+
+        // Not a power of two, so wrapping can't mask
+        let mut interp = crate::Context::with_state(vec![0; 100]);
+        interp.set_tape_mode(crate::TapeMode::Wrap);
+        interp.exec_many(&s).unwrap();
+        assert_eq!((interp.data()[1], interp.data()[98], interp.data()[99]), (2, 0, 1));
+
+        let ctx = Context::create();
+        let mut gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        gen.set_cells(100);
+        gen.set_pointer_mode(PointerMode::Wrap);
+        let func = gen.jit_bf(&s).unwrap();
+
+        // Only the compiled tape's cells are copied back
+        let mut tape = vec![7i8; 200];
+        unsafe { func.call(tape.as_mut_ptr(), tape.len(), std::ptr::null_mut()); }
+        assert_eq!(&tape[..100], interp.data());
+        assert!(tape[100..].iter().all(|&c| c == 7));
+
+        // Far more than fits on the stack
+        let cells = 100_000_000;
+        let mut gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        gen.set_cells(cells);
+        let func = gen.jit_bf(&[Statement::Next(cells - 1), Statement::Inc(5), Statement::Out]).unwrap();
+        unsafe { func.call(std::ptr::null_mut(), 0, std::ptr::null_mut()); }
+        assert!(gen.emit_ir().contains("@calloc("));
+    }
+
+    #[test]
+    fn clear_scan() {
+        let ctx = Context::create();
+        let gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        let (_, s) = program(">+>+>++>+++>>++<<[[-]<]").unwrap();
+        let s = crate::optimize(s);
+        assert!(s.contains(&Statement::ClearScan { stride: -1 }));
+        let func = gen.jit_bf(&s).unwrap();
+
+        let mut tape = vec![0i8; NUM_CELLS];
+        unsafe { func.call(tape.as_mut_ptr(), tape.len(), std::ptr::null_mut()); }
+        assert_eq!(&tape[..7], &[0, 0, 0, 0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn emit_ir_and_asm() {
+        let ctx = Context::create();
+        let gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        let (_, s) = program("+[-]").unwrap();
+        let s = crate::optimize(s);
+        assert_eq!(s.last(), Some(&Statement::Clear));
+        gen.lower_bf(true, &[Statement::Clear]).unwrap();
+
+        // `[-]` is one store, with no loop around it
+        let ir = gen.emit_ir();
+        assert_eq!(ir.matches("store i8 ").count(), 1, "{}", ir);
+        assert!(ir.contains("store i8 0,"), "{}", ir);
+        assert!(!ir.contains("loop"), "{}", ir);
+
+        let asm = gen.emit_asm(OptimizationLevel::None).unwrap();
+        assert!(asm.contains("jit_bf"), "{}", asm);
+    }
+
+    #[test]
+    fn move_value_skips_multiply() {
+        let ctx = Context::create();
+        let gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        let (_, s) = program("+++++>[-]<[->+<]").unwrap();
+        let s = crate::optimize(s);
+        assert!(s.contains(&Statement::MoveValue { offset: 1 }));
+        gen.lower_bf(true, &s).unwrap();
+        assert!(!gen.emit_ir().contains(" mul "));
+
+        let func = gen.jit_bf(&s).unwrap();
+        let mut tape = vec![0i8; NUM_CELLS];
+        unsafe { func.call(tape.as_mut_ptr(), tape.len(), std::ptr::null_mut()); }
+        assert_eq!(&tape[..2], &[0, 5]);
+    }
+
+    #[test]
+    fn wide_multiplier_matches_interpreter() {
+        let text = format!("+++[->{}<]>>-[-<{}>]", "+".repeat(300), "-".repeat(1000));
+        let (_, s) = program(&text).unwrap();
+        let s = crate::optimize(s);
+        assert!(s.contains(&Statement::AddOffset { mul: 300, offset: 1 }));
+
+        let mut interp = crate::Context::new();
+        interp.exec_many(&s).unwrap();
+
+        let ctx = Context::create();
+        let gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        let func = gen.jit_bf(&s).unwrap();
+        let mut tape = vec![0i8; NUM_CELLS];
+        unsafe { func.call(tape.as_mut_ptr(), tape.len(), std::ptr::null_mut()); }
+        assert_eq!(&tape[..3], &interp.data()[..3]);
+    }
+
+    #[test]
+    fn halt_returns_early() {
+        let ctx = Context::create();
+        let gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        let s = crate::parser::program_with_asserts("+++>++ @halt >+").unwrap();
+        let func = gen.jit_bf(&s).unwrap();
+
+        let mut tape = vec![0i8; NUM_CELLS];
+        unsafe { func.call(tape.as_mut_ptr(), tape.len(), std::ptr::null_mut()); }
+        assert_eq!(&tape[..3], &[3, 2, 0]);
+
+        assert!(gen.jit_loop(&[Statement::Dec(1), Statement::Halt]).is_none());
+    }
+
+    #[test]
+    fn compile_to_fn_copies_what_fits() {
+        let ctx = Context::create();
+        let mut gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        let (_, s) = program("+++[->++<]>>-").unwrap();
+        let mut run = gen.compile_to_fn(&s).unwrap();
+
+        let mut tape = vec![0u8; NUM_CELLS];
+        run(&mut tape);
+        assert_eq!(&tape[..3], &[0, 6, 255]);
+        run(&mut []);
+
+        let mut short = vec![7u8; 2];
+        run(&mut short);
+        assert_eq!(short, vec![0, 6]);
+        drop(run);
+
+        // Checked, unlike `jit_bf` by default, and only compiled once
+        assert!(gen.emit_ir().contains("call void @llvm.trap()"));
+        assert!(gen.compile_to_fn(&s).is_none());
+
+        let mut gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        gen.jit_bf(&s).unwrap();
+        assert!(gen.compile_to_fn(&s).is_none());
+    }
+
+    #[test]
+    fn search_value() {
+        let ctx = Context::create();
+        let gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        let (_, s) = program("+>++>+++++>->++<<<<-----[+++++>-----]+++++>+").unwrap();
+        let s = crate::optimize(s);
+        assert!(s.contains(&Statement::SearchValue { stride: 1, target: 5 }));
+        let func = gen.jit_bf(&s).unwrap();
+
+        let mut tape = vec![0i8; NUM_CELLS];
+        unsafe { func.call(tape.as_mut_ptr(), tape.len(), std::ptr::null_mut()); }
+        assert_eq!(&tape[..5], &[1, 2, 5, 0, 2]);
+    }
+
+    // Compiled code's stdio, passed as the user data
+    struct Buffers {
+        input: std::collections::VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    // Reads 0 once the input runs out
+    unsafe extern "C" fn buffer_read(data: *mut c_void) -> i8 {
+        let buffers = &mut *(data as *mut Buffers);
+        buffers.input.pop_front().unwrap_or(0) as i8
+    }
+
+    unsafe extern "C" fn buffer_write(data: *mut c_void, c: i8) {
+        let buffers = &mut *(data as *mut Buffers);
+        buffers.output.push(c as u8);
+    }
+
+    // Runs `s` compiled and in the interpreter, both on a zeroed tape of `NUM_CELLS`
+    // cells with the same input, and checks the output and final tapes are the same
+    fn assert_matches_interpreter(s: &[Statement]) {
+        const INPUT: &[u8] = b"xyz\0";
+
+        let mut interp = crate::Context::with_state(vec![0; NUM_CELLS]);
+        let expected = interp.run_capturing(s, INPUT)
+            .unwrap_or_else(|e| panic!("interpreter failed on {:?}: {}", s, e));
+
+        let ctx = Context::create();
+        let mut gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        gen.set_io(JitIo { read: buffer_read, write: buffer_write });
+        let func = gen.jit_bf(s).unwrap();
+        let mut io = Buffers { input: INPUT.iter().copied().collect(), output: Vec::new() };
+        let mut tape = vec![0i8; NUM_CELLS];
+        unsafe { func.call(tape.as_mut_ptr(), tape.len(), &mut io as *mut Buffers as *mut c_void); }
+
+        let first_diff = tape.iter().zip(interp.data()).position(|(j, i)| j != i);
+        assert_eq!(first_diff, None, "compiled and interpreted tapes differ for {:?}", s);
+        assert_eq!(io.output, expected, "compiled and interpreted output differ for {:?}", s);
+    }
+
+    #[test]
+    fn every_statement_matches_interpreter() {
+        use crate::Statement::*;
+
+        // Input is short, and past its end the two differ, so `In` stays within it
+        let cases = vec![
+            vec![In, Out, Next(1), In, Inc(1), Out, Prev(1), Out],
+            vec![Inc(1), ReadBlock { stride: 1 }, Prev(1), Out],
+            vec![Next(1), Inc(97), Next(1), Inc(98), WriteBlock { stride: -1 }],
+            vec![Inc(3), Next(2), Dec(1), Prev(1), Inc(300)],
+            vec![Prev(5), Inc(1), Next(1), Prev(2), Dec(2)],
+            vec![Move(3), Inc(1), Move(-5), Inc(2), Move(1), Dec(1)],
+            vec![Inc(3), Loop(vec![Dec(1), Next(1), Inc(2), Prev(1)])],
+            vec![Inc(7), Clear, Next(1), Dec(1), Clear],
+            vec![Inc(7), SetConst(300), Next(1), SetConst(-3), Inc(1)],
+            vec![Next(1), Inc(3), IncAt { offset: 2, amount: 300 }, IncAt { offset: -1, amount: -5 }, IncAt { offset: 2, amount: 1 }],
+            vec![Inc(200), AddOffset { mul: 3, offset: 2 }, AddOffset { mul: -1, offset: 1 }, Clear],
+            vec![Next(1), Inc(10), Prev(1), Inc(5), AddOffset { mul: -4, offset: 1 }, Clear],
+            vec![Dec(1), AddOffset { mul: 300, offset: 1 }, AddOffset { mul: -1000, offset: 2 }, Clear],
+            vec![Next(3), Inc(7), AddOffset { mul: 2, offset: -3 }, AddOffset { mul: -1, offset: -1 }, Clear],
+            vec![Next(4), Inc(200), MulAddMany { targets: vec![(-2, 3), (1, -1), (3, 300)] }, Inc(1)],
+            vec![Inc(5), Next(1), Inc(200), Next(2), Inc(1), LinearCombine { dest_offset: -1, terms: vec![(2, -2), (-3, -3), (300, 0)] }],
+            vec![Inc(1), Next(1), Inc(1), Next(1), Inc(1), Prev(2), SearchZero { stride: 1 }, Inc(9)],
+            vec![Next(3), Inc(1), Next(2), Inc(1), SearchZero { stride: -2 }, Inc(9)],
+            vec![Inc(4), Next(1), Inc(2), Next(1), Dec(3), Prev(2), SearchValue { stride: 1, target: 253 }, Inc(1)],
+            vec![Next(3), Inc(5), Copy { offset: -2 }, Copy { offset: 1 }],
+            vec![Next(1), Inc(1), Next(1), Inc(2), Next(1), Inc(3), ClearScan { stride: -1 }, Inc(1)],
+            vec![Inc(6), MoveValue { offset: 2 }, Next(1), Inc(1), Next(3), Inc(2), Prev(1), MoveValue { offset: 3 }],
+            vec![Inc(2), Assert { cell: 0, expected: 2, pos: 0 }, Inc(1)],
+            vec![Next(1), InfiniteLoopIfNonZero, Inc(1)],
+            vec![Inc(1), Loop(vec![Next(1), Inc(2), Halt]), Inc(5)],
+            vec![Next(1), WriteBlock { stride: 1 }, ReadBlock { stride: -1 }, Inc(1)],
+        ];
+
+        for s in &cases {
+            assert_matches_interpreter(s);
+        }
+    }
+
+    #[test]
+    fn search_zero_uses_memchr() {
+        let (_, s) = program(&format!(">{}<[<]>[>]+[>]+", "+>".repeat(5000))).unwrap();
+        let s = crate::optimize(s);
+        assert!(s.contains(&Statement::SearchZero { stride: 1 }));
+        assert_matches_interpreter(&s);
+
+        let ctx = Context::create();
+        let gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        gen.lower_bf(true, &s).unwrap();
+        assert!(gen.emit_ir().contains("@memchr("));
+    }
+
+    #[test]
+    fn prev_clamps_in_every_backend() {
+        use crate::bf::panicking::{StaticContext8, StaticContext64};
+
+        let (_, s) = program("<+>><<<<<+").unwrap();
+
+        let mut ctx = crate::Context::new();
+        ctx.exec_many(&s).unwrap();
+        assert_eq!((ctx.data()[0], ctx.idx()), (2, 0));
+
+        let mut ctx = StaticContext8::new();
+        ctx.exec_many(&s);
+        assert_eq!(ctx.data()[0], 2);
+        let mut ctx = StaticContext64::new();
+        ctx.exec_many(&s);
+        assert_eq!(ctx.data()[0], 2);
+
+        let llvm = Context::create();
+        let gen = CodeGen::new(&llvm, OptimizationLevel::None);
+        let func = gen.jit_bf(&s).unwrap();
+        let mut tape = vec![0i8; NUM_CELLS];
+        unsafe { func.call(tape.as_mut_ptr(), tape.len(), std::ptr::null_mut()); }
+        assert_eq!(tape[0], 2);
+    }
+
+    #[test]
+    fn cache_hit() {
+        let ctx = Context::create();
+        let mut cache = CompiledCache::new(&ctx, OptimizationLevel::None);
+        let (_, a) = program("+++[->++<]").unwrap();
+        let (_, b) = program("++").unwrap();
+        let (a, b) = (crate::optimize(a), crate::optimize(b));
+
+        let mut tape = vec![0i8; NUM_CELLS];
+        unsafe { cache.get_or_compile(&a).unwrap().call(tape.as_mut_ptr(), tape.len(), std::ptr::null_mut()); }
+        assert_eq!(cache.compiles(), 1);
+        unsafe { cache.get_or_compile(&b).unwrap().call(tape.as_mut_ptr(), tape.len(), std::ptr::null_mut()); }
+        assert_eq!(cache.compiles(), 2);
+        unsafe { cache.get_or_compile(&a).unwrap().call(tape.as_mut_ptr(), tape.len(), std::ptr::null_mut()); }
+        assert_eq!(cache.compiles(), 2);
+        assert_eq!(&tape[..2], &[0, 6]);
+    }
+
+    // A fresh directory for the files of a test that links with `cc`, or `None` when there
+    // is no `cc` to link with
+    fn link_dir(name: &str) -> Option<std::path::PathBuf> {
+        if Command::new("cc").arg("--version").output().is_err() {
+            return None;
+        }
+        let dir = std::env::temp_dir().join(format!("bf-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Some(dir)
+    }
+
+    // Links `obj` into `exe` with a stand-in for bfrt, written next to `obj`. The end of
+    // input reads as NUL, which stops a loop like `,[.,]`.
+    fn link_with_stub_runtime(obj: &Path, exe: &Path) {
+        let rt = obj.with_file_name("rt.c");
+        std::fs::write(&rt, "#include <stdio.h>\n\
+            void write_char(char c) { putchar(c); }\n\
+            char read_char(void) { int c = getchar(); return c == EOF ? 0 : c; }\n").unwrap();
+        let status = Command::new("cc").arg(obj).arg(&rt).arg("-o").arg(exe).status().unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn aot_main_reads_input_file() {
+        let dir = match link_dir("aot") {
+            Some(dir) => dir,
+            None => return,
+        };
+        let obj = dir.join("cat.o");
+        let exe = dir.join("cat");
+        let input = dir.join("input.txt");
+
+        let ctx = Context::create();
+        let gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        let (_, s) = program(",[.,]").unwrap();
+        gen.lower_bf(false, &s);
+        gen.add_main(true);
+        gen.create_object_file(&obj, OutputKind::Executable).unwrap();
+        link_with_stub_runtime(&obj, &exe);
+
+        std::fs::write(&input, "hello from a file").unwrap();
+        let out = Command::new(&exe).arg(&input).output().unwrap();
+        assert!(out.status.success());
+        assert_eq!(out.stdout, b"hello from a file");
+
+        let out = Command::new(&exe).arg(dir.join("missing")).output().unwrap();
+        assert_eq!(out.status.code(), Some(1));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn aot_tape_is_zeroed_to_the_end() {
+        use crate::Statement::*;
+
+        let dir = match link_dir("aot-tape") {
+            Some(dir) => dir,
+            None => return,
+        };
+        let obj = dir.join("far.o");
+        let exe = dir.join("far");
+
+        // Near the end of the tape, well past anything a stack-sized memset would clear
+        let s = vec![Next(NUM_CELLS - 2), Inc(65), Out, Next(1), Inc(66), Out];
+        let ctx = Context::create();
+        let gen = CodeGen::new(&ctx, OptimizationLevel::None);
+        gen.lower_bf(false, &s);
+        gen.add_main(false);
+        gen.create_object_file(&obj, OutputKind::Executable).unwrap();
+        link_with_stub_runtime(&obj, &exe);
+
+        let out = Command::new(&exe).output().unwrap();
+        assert!(out.status.success());
+        assert_eq!(out.stdout, b"AB");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pic_object_links_into_shared_lib() {
+        let dir = match link_dir("pic") {
+            Some(dir) => dir,
+            None => return,
+        };
+        let obj = dir.join("prog.o");
+        let lib = dir.join("libprog.so");
+
+        let ctx = Context::create();
+        let gen = CodeGen::new(&ctx, OptimizationLevel::Default);
+        let (_, s) = program("++++++++[>++++++++<-]>+.").unwrap();
+        gen.lower_bf(false, &s);
+        gen.create_object_file(&obj, OutputKind::SharedLib).unwrap();
+
+        // `read_char`/`write_char` are left for whatever loads the library to provide
+        let status = Command::new("cc").arg("-shared").arg(&obj).arg("-o").arg(&lib).status().unwrap();
+        assert!(status.success());
+
+        let symbols = Command::new("nm").arg("-D").arg("--defined-only").arg(&lib).output();
+        if let Ok(out) = symbols {
+            assert!(String::from_utf8_lossy(&out.stdout).contains("bf_main"));
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hybrid_compiles_hot_loop() {
+        let (_, s) = program("+++++[>++++++++++[>+++++<-]<-]>>>+<<<").unwrap();
+
+        let mut expected = crate::Context::with_state(vec![0; NUM_CELLS]);
+        expected.exec_many(&s).unwrap();
+
+        let ctx = Context::create();
+        let mut hybrid = Hybrid::new(&ctx, OptimizationLevel::None, 10);
+        let mut tape = crate::Context::with_state(vec![0; NUM_CELLS]);
+        hybrid.run(&mut tape, &s).unwrap();
+
+        // Only the inner loop runs often enough
+        assert_eq!(hybrid.compiled_loops(), 1);
+        assert_eq!(tape.data(), expected.data());
+        assert_eq!(tape.idx(), expected.idx());
+
+        // Compiled code would wrap where these cells saturate
+        let mut tape = crate::Context::with_state(vec![0; NUM_CELLS]);
+        tape.set_arith(crate::CellArith::Saturate);
+        hybrid.run(&mut tape, &s).unwrap();
+        assert_eq!(hybrid.compiled_loops(), 0);
+
+        // Checked against the tape it was compiled for
+        let mut tape = crate::Context::with_state(vec![0; 100]);
+        hybrid.run(&mut tape, &s).unwrap();
+        assert_eq!(hybrid.compiled_loops(), 1);
+        assert_eq!(tape.data(), &expected.data()[..100]);
+        let gen = hybrid.compiled.values().flatten().next().unwrap();
+        assert_eq!(gen.cells(), 100);
+        assert!(gen.emit_ir().contains("call void @llvm.trap()"));
+    }
+}
@@ -2,4 +2,15 @@
 mod bf;
 pub use bf::*;
 pub mod parser;
-pub mod jit;
\ No newline at end of file
+pub mod jit;
+pub mod analysis;
+pub mod disasm;
+pub mod config;
+pub mod testing;
+pub mod stream;
+pub mod corpus;
+pub mod transpile;
+pub mod debugger;
+pub mod span;
+#[cfg(feature = "serde")]
+pub mod cache;
\ No newline at end of file
@@ -1,13 +1,22 @@
-use clap::{App, Arg};
-use std::io::{stdin, Read};
-use bf::parser::{bf_chars, program};
-use bf::{exec_many, optimize};
+use clap::{App, AppSettings, Arg, ArgGroup};
+use std::fs::File;
+use std::io::{stdin, BufReader, Read};
+use bf::parser::{parse_source, parse_with_breakpoints, parse_reader, program_spanned, program_with_asserts, program_with_asserts_and_breakpoints, tape_directive, command_offsets, line_col};
+use bf::{exec_many, optimize_counting, strip_breakpoints, Patterns, CellArith, Signedness, MemoryBudget, TapeMode, cell_view};
 use inkwell::context::Context;
-use bf::jit::{CodeGen, NUM_CELLS};
-use inkwell::passes::{PassManager, PassManagerBuilder};
+use bf::jit::{CodeGen, Hybrid, OutputKind, PointerMode, NUM_CELLS, probe_jit, probe_native};
 use inkwell::OptimizationLevel;
-use inkwell::targets::{TargetMachine, Target, InitializationConfig, TargetTriple, RelocMode, CodeModel, FileType};
 use bf::panicking::{StaticContext8, StaticContext16, StaticContext32, StaticContext64};
+use bf::input::{EofMode, InputSource};
+use bf::trace::trace_to;
+use bf::analysis::{ProgramInfo, program_stats, unreachable_code, strip_unreachable};
+use bf::disasm::dump_passes;
+use bf::config::Config;
+use bf::stream::stream_run;
+use bf::span::optimize_spanned;
+use bf::corpus::bench_optimize;
+use bf::debugger::{breakpoints_from_source, Debugger};
+use bf::output::OutputSink;
 
 
 fn main() -> anyhow::Result<()> {
@@ -18,10 +27,31 @@ fn main() -> anyhow::Result<()> {
     better_panic::debug_install();
 
     let m = App::new("bf")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(App::new("bench-optimize")
+            .about("Report how much the optimizer shrinks each .bf file in a directory")
+            .arg(Arg::with_name("dir")
+                .value_name("DIR")
+                .index(1)
+                .required(true)))
+        .subcommand(App::new("debug")
+            .about("Step through a program; a `#` in the source is a breakpoint")
+            .arg(Arg::with_name("file")
+                .value_name("FILE")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("input")
+                .long("input")
+                .value_name("INPUT")
+                .takes_value(true)
+                .help("File for `,` to read from, since stdin takes debugger commands")))
         .arg(Arg::with_name("source-file")
             .value_name("SOURCE")
             .index(1)
-            .required(true))
+            .required_unless("list-backends"))
+        .arg(Arg::with_name("list-backends")
+            .long("list-backends")
+            .help("Print which ways of running programs work in this build, and why not if they don't"))
         .arg(Arg::with_name("cell-size")
             .short('c')
             .possible_values(&["i8", "i16", "i32", "i64"])
@@ -29,63 +59,417 @@ fn main() -> anyhow::Result<()> {
             .default_value("i8")
             .required(false)
             .conflicts_with_all(&["jit", "native"]))
+        .arg(Arg::with_name("cell-arith")
+            .long("cell-arith")
+            .possible_values(&["wrap", "saturate", "error"])
+            .takes_value(true)
+            .default_value("wrap")
+            .help("What happens when a cell over- or underflows")
+            .conflicts_with_all(&["jit", "native"]))
+        .arg(Arg::with_name("eof")
+            .long("eof")
+            .possible_values(&["error", "unchanged", "zero", "neg1"])
+            .takes_value(true)
+            .default_value("error")
+            .help("What `,` does once input runs out: fail, leave the cell alone, or store 0 or -1")
+            .conflicts_with_all(&["jit", "native"]))
+        .arg(Arg::with_name("init-tape")
+            .long("init-tape")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Start with the bytes of FILE on the tape, one per cell")
+            .conflicts_with_all(&["jit", "native", "hybrid"]))
+        .arg(Arg::with_name("tape")
+            .long("tape")
+            .possible_values(&["grow", "fixed"])
+            .takes_value(true)
+            .default_value("fixed")
+            .help("Grow the tape as the program needs it (8-bit cells only), or use a fixed tape")
+            .conflicts_with_all(&["jit", "native", "hybrid", "trace-io", "stream"]))
+        .arg(Arg::with_name("tape-size")
+            .long("tape-size")
+            .value_name("N")
+            .takes_value(true)
+            .help("Cells on the fixed or compiled tape, instead of 65536 (8-bit cells only)")
+            .conflicts_with_all(&["hybrid", "trace-io", "stream"]))
+        .arg(Arg::with_name("signed")
+            .long("signed")
+            .help("Print the final tape as signed values instead of unsigned"))
         .arg(Arg::with_name("native")
             .short('n')
             .conflicts_with_all(&["cell-size", "jit"]))
+        .arg(Arg::with_name("emit")
+            .long("emit")
+            .value_name("obj[=PATH]")
+            .takes_value(true)
+            .help("Compile to an object file at PATH, or SOURCE.o, like -n. Link it against bfrt with `cc PATH -lbfrt`")
+            .conflicts_with_all(&["cell-size", "jit", "hybrid"]))
+        .group(ArgGroup::with_name("aot")
+            .args(&["native", "emit"])
+            .multiple(true))
+        .arg(Arg::with_name("argv-input")
+            .long("argv-input")
+            .requires("aot")
+            .help("Make the compiled program read `,` from the file named by its first argument"))
+        .arg(Arg::with_name("shared")
+            .long("shared")
+            .requires("aot")
+            .conflicts_with("argv-input")
+            .help("Compile position-independent code without a `main`, to link into a shared library"))
+        .arg(Arg::with_name("bounds")
+            .long("bounds")
+            .possible_values(&["unchecked", "wrap", "trap"])
+            .takes_value(true)
+            .default_value("unchecked")
+            .help("How compiled code keeps the pointer on the tape: not at all, by joining its ends, or by aborting. With --tape-size the interpreter wraps or fails the same way")
+            .conflicts_with("hybrid"))
         .arg(Arg::with_name("optimize")
             .short('o'))
         .arg(Arg::with_name("opt-bf")
             .short('b'))
+        .arg(Arg::with_name("no-clear")
+            .long("no-clear")
+            .help("Don't turn `[-]` into a clear (for bisecting optimizer bugs)"))
+        .arg(Arg::with_name("no-setconst")
+            .long("no-setconst")
+            .help("Don't turn `[-]+++` into setting the cell to a constant"))
+        .arg(Arg::with_name("no-incat")
+            .long("no-incat")
+            .help("Don't turn `>>++<<` into an add at an offset"))
+        .arg(Arg::with_name("no-addoffset")
+            .long("no-addoffset")
+            .help("Don't turn `[->+<]` style loops into an add-at-offset"))
+        .arg(Arg::with_name("no-searchzero")
+            .long("no-searchzero")
+            .help("Don't turn `[>]` and `[<]` into a zero search"))
+        .arg(Arg::with_name("no-searchvalue")
+            .long("no-searchvalue")
+            .help("Don't turn `-t[+t>-t]+t` into a search for t"))
+        .arg(Arg::with_name("no-linearcombine")
+            .long("no-linearcombine")
+            .help("Don't fuse multiply loops from several cells into the same cell"))
+        .arg(Arg::with_name("no-netfold")
+            .long("no-netfold")
+            .help("Don't fold mixed runs like `+++--` and `>><<<` into their net effect"))
+        .arg(Arg::with_name("no-deadloops")
+            .long("no-deadloops")
+            .help("Don't remove loops on a cell known to be zero"))
+        .arg(Arg::with_name("no-clearscan")
+            .long("no-clearscan")
+            .help("Don't turn `[[-]>]` and `[[-]<]` into a clearing scan"))
+        .arg(Arg::with_name("no-blockio")
+            .long("no-blockio")
+            .help("Don't turn `[.>]` and `[,>]` into block writes and reads"))
         .arg(Arg::with_name("dump")
             .short('d'))
+        .arg(Arg::with_name("breakpoints")
+            .long("breakpoints")
+            .help("Print the pointer and the cells around it to stderr at each `#` (ignored otherwise, and in native builds)"))
         .arg(Arg::with_name("jit")
             .short('j'))
+        .arg(Arg::with_name("hybrid")
+            .long("hybrid")
+            .help("Interpret, compiling loops once they get hot")
+            .conflicts_with_all(&["jit", "native"]))
+        .arg(Arg::with_name("hot-threshold")
+            .long("hot-threshold")
+            .takes_value(true)
+            .default_value("1000")
+            .help("Iterations after which --hybrid compiles a loop"))
+        .arg(Arg::with_name("raw-tty")
+            .long("raw-tty")
+            .help("Read each keystroke immediately instead of a line at a time (falls back to buffered stdin when not a tty)")
+            .conflicts_with_all(&["jit", "native"]))
+        .arg(Arg::with_name("no-tape")
+            .long("no-tape")
+            .help("Don't copy the final tape out of the JIT or print it")
+            .requires("jit"))
+        .arg(Arg::with_name("warn-unreachable")
+            .long("warn-unreachable")
+            .help("Warn about code after loops that can never terminate"))
+        .arg(Arg::with_name("strip-unreachable")
+            .long("strip-unreachable")
+            .requires("warn-unreachable")
+            .help("Remove the code reported by --warn-unreachable"))
+        .arg(Arg::with_name("trace-io")
+            .long("trace-io")
+            .help("Log every byte read and written to stderr (always uses 8-bit cells)")
+            .conflicts_with_all(&["jit", "native", "hybrid", "check-tape", "init-tape"]))
+        .arg(Arg::with_name("check-asserts")
+            .long("check-asserts")
+            .help("Check `@assert cellN == V` directives in comments while interpreting, and stop at `@halt`"))
+        .arg(Arg::with_name("check-tape")
+            .long("check-tape")
+            .help("Compare the final tape with the `@tape V0 V1 ...` directive in the source")
+            .conflicts_with_all(&["jit", "native", "hybrid"]))
+        .arg(Arg::with_name("stream")
+            .long("stream")
+            .help("Run the program while it is still being read; SOURCE may be - for stdin")
+            .conflicts_with_all(&["jit", "native", "hybrid", "cell-size", "info", "dump-passes", "count-ops", "check-tape", "init-tape"]))
+        .arg(Arg::with_name("quiet")
+            .short('q')
+            .long("quiet")
+            .help("Only print the program's own output, not the final tape or timings"))
+        .arg(Arg::with_name("timing-json")
+            .long("timing-json")
+            .help("Print the timings to stderr as JSON, split into parsing, optimizing, compiling and running (even with -q)"))
+        .arg(Arg::with_name("count-steps")
+            .long("count-steps")
+            .help("Print how many statements were executed, counting every loop iteration, to stderr (even with -q)")
+            .conflicts_with_all(&["jit", "native", "hybrid"]))
+        .arg(Arg::with_name("info")
+            .long("info")
+            .help("Print a summary of the program instead of running it"))
+        .arg(Arg::with_name("json")
+            .long("json")
+            .requires("info"))
+        .arg(Arg::with_name("count-ops")
+            .long("count-ops")
+            .help("Print how many of each kind of statement the program has (after -o or -b) instead of running it"))
+        .arg(Arg::with_name("dump-passes")
+            .long("dump-passes")
+            .help("Print the program after each optimization pass instead of running it"))
         .get_matches();
 
+    // Defaults from bf.toml, for the options the command line leaves out
+    let config = Config::load()?;
+
+    let patterns = Patterns {
+        clear: !m.is_present("no-clear"),
+        add_offset: !m.is_present("no-addoffset"),
+        search_zero: !m.is_present("no-searchzero"),
+        clear_scan: !m.is_present("no-clearscan"),
+        block_io: !m.is_present("no-blockio"),
+        // Only exact when cells wrap
+        search_value: !m.is_present("no-searchvalue") && config.value_of(&m, "cell-arith") == Some("wrap"),
+        linear_combine: !m.is_present("no-linearcombine"),
+        net_fold: !m.is_present("no-netfold") && config.value_of(&m, "cell-arith") == Some("wrap"),
+        set_const: !m.is_present("no-setconst"),
+        inc_at: !m.is_present("no-incat"),
+        // Only exact on a fresh tape
+        dead_loops: !m.is_present("no-deadloops") && !m.is_present("init-tape"),
+    };
+
+    let signedness = if config.is_present(&m, "signed") {
+        Signedness::Signed
+    } else {
+        Signedness::Unsigned
+    };
+
+    let eof = match config.value_of(&m, "eof").unwrap() {
+        "unchanged" => EofMode::LeaveUnchanged,
+        "zero" => EofMode::Zero,
+        "neg1" => EofMode::NegativeOne,
+        _ => EofMode::Error,
+    };
+
+    let bounds = match m.value_of("bounds").unwrap() {
+        "wrap" => PointerMode::Wrap,
+        "trap" => PointerMode::Trap,
+        _ => PointerMode::Unchecked,
+    };
+
+    let tape_size = config.value_of(&m, "tape-size").map(str::parse::<usize>).transpose()?;
+    if tape_size == Some(0) {
+        anyhow::bail!("--tape-size needs at least one cell");
+    }
+
+    if let Some(sub) = m.subcommand_matches("bench-optimize") {
+        print!("{}", bench_optimize(sub.value_of("dir").unwrap(), patterns)?);
+        return Ok(());
+    }
+
+    if let Some(sub) = m.subcommand_matches("debug") {
+        return debug(sub.value_of("file").unwrap(), sub.value_of("input"));
+    }
+
+    if m.is_present("list-backends") {
+        list_backends();
+        return Ok(());
+    }
+
+    let quiet = m.is_present("quiet");
     let f = m.value_of("source-file").unwrap();
-    let text = std::fs::read_to_string(f)?;
+    if m.is_present("stream") {
+        let mut ctx = bf::Context::new();
+        ctx.set_eof_mode(eof);
+        ctx.set_signedness(signedness);
+        if f == "-" {
+            stream_run(stdin().lock(), &mut ctx)?;
+        } else {
+            stream_run(std::fs::File::open(f)?, &mut ctx)?;
+        }
+        if !quiet {
+            println!("{:?}", ctx);
+        }
+        if m.is_present("count-steps") {
+            eprintln!("{} steps", ctx.steps());
+        }
+        return Ok(());
+    }
+
+    // Directives, breakpoints and source positions need the whole text; otherwise it is parsed as it is read
+    let breakpoints = m.is_present("breakpoints");
+    // --trace-io points each byte at its `,` or `.` when every statement has a span, which
+    // directives and breakpoints don't, and --dump wants the stats the spanned optimizer skips
+    let trace_spans = m.is_present("trace-io") && !m.is_present("check-asserts") && !breakpoints && !m.is_present("dump");
+    let needs_text = m.is_present("check-asserts") || m.is_present("check-tape") || m.is_present("warn-unreachable") || breakpoints || trace_spans;
+    let text = if needs_text { read_source(f)? } else { String::new() };
 
-    let bf_text = bf_chars(&text);
     let sw = stopwatch::Stopwatch::start_new();
-    let (_, mut s) = program(&bf_text).expect("Unable to parse");
-    if m.is_present("optimize") || m.is_present("opt-bf") {
-        s = optimize(s);
+    let expected_tape = if m.is_present("check-tape") {
+        tape_directive(&text)?
+    } else {
+        None
+    };
+
+    let (mut s, had_comments, mut spans) = if m.is_present("check-asserts") || m.is_present("check-tape") {
+        let s = if breakpoints { program_with_asserts_and_breakpoints(&text)? } else { program_with_asserts(&text)? };
+        (s, true, None)
+    } else if trace_spans {
+        let (s, spans) = program_spanned(&text)?;
+        (s, true, Some(spans))
+    } else if needs_text {
+        let parsed = if breakpoints { parse_with_breakpoints(&text)? } else { parse_source(&text)? };
+        (parsed.stmts, parsed.had_comments, None)
+    } else {
+        (parse_reader(BufReader::new(File::open(f)?))?, true, None)
+    };
+    let parsed = sw.elapsed_ms();
+    if m.is_present("info") {
+        return print_info(&s, m.is_present("json"));
+    }
+
+    if m.is_present("dump-passes") {
+        print!("{}", dump_passes(&s, patterns));
+        return Ok(());
+    }
+
+    if m.is_present("warn-unreachable") {
+        // Without comments, offsets into the parsed text are offsets into the source
+        let offsets = if had_comments { command_offsets(&text) } else { Vec::new() };
+        // The offsets are those of the eight commands, which leave out `#`
+        for o in unreachable_code(&strip_breakpoints(&s)) {
+            let o = if had_comments { offsets[o] } else { o };
+            let (line, col) = line_col(&text, o);
+            eprintln!("warning: unreachable code at line {}, col {} (follows a loop that never terminates)", line, col);
+        }
+
+        if m.is_present("strip-unreachable") {
+            s = strip_unreachable(&s);
+            // What is left no longer lines up with the spans
+            spans = None;
+        }
+    }
+
+    let optimize_start = sw.elapsed_ms();
+    let mut opt_stats = None;
+    if config.is_present(&m, "optimize") || m.is_present("opt-bf") {
+        match spans.take() {
+            Some(sp) => {
+                let (opt, sp) = optimize_spanned(&s, &sp, patterns);
+                s = opt;
+                spans = Some(sp);
+            }
+            None => {
+                let (opt, stats) = optimize_counting(s, patterns);
+                s = opt;
+                opt_stats = m.is_present("dump").then(|| stats);
+            }
+        }
+        if m.is_present("warn-unreachable") && contains_empty_loop(&s) {
+            eprintln!("warning: program has an empty loop, which never terminates if entered");
+        }
+    }
+    let optimized = sw.elapsed_ms();
+
+    if m.is_present("count-ops") {
+        for (name, n) in program_stats(&s).histogram() {
+            println!("{:>10} {}", n, name);
+        }
+        return Ok(());
     }
 
     let compile;
     if m.is_present("dump") {
         println!("{:?}", &s);
+        if let Some(stats) = opt_stats {
+            println!("{}", stats);
+        }
     }
     let exec_start;
+    // Only the interpreters count steps
+    let mut steps = None;
 
-    if m.is_present("jit") || m.is_present("native") {
+    let object_file = match m.value_of("emit") {
+        Some("obj") => Some(format!("./{}.o", f)),
+        Some(emit) => match emit.strip_prefix("obj=") {
+            Some(path) => Some(path.to_string()),
+            None => anyhow::bail!("Unknown --emit {:?}, expected obj or obj=PATH", emit),
+        },
+        None if m.is_present("native") => Some(format!("./{}.o", f)),
+        None => None,
+    };
+
+    if m.is_present("jit") || object_file.is_some() {
 
         let ctx = Context::create();
 
-        let opt_level = if m.is_present("optimize") {
+        let opt_level = if config.is_present(&m, "optimize") {
             OptimizationLevel::Default
         } else {
             OptimizationLevel::None
         };
 
-        let gen = CodeGen::new(&ctx, opt_level);
+        let mut gen = CodeGen::new(&ctx, opt_level);
+        gen.set_pointer_mode(bounds);
+        if let Some(cells) = tape_size {
+            gen.set_cells(cells);
+        }
         if m.is_present("jit") {
-            println!("Jitting...");
+            if !quiet {
+                println!("Jitting...");
+            }
             let func = gen.jit_bf(&s).unwrap();
             compile = sw.elapsed_ms();
-            let mut ctx = [0i8; NUM_CELLS];
-            let p = ctx.as_mut_ptr();
-            println!("EXECUTING JIT!");
-            exec_start = sw.elapsed_ms();
-            unsafe { func.call(p); }
-            println!();
-            println!("{:?}", &ctx[..16]);
+            if !quiet {
+                println!("EXECUTING JIT!");
+            }
+            if m.is_present("no-tape") || quiet {
+                exec_start = sw.elapsed_ms();
+                unsafe { func.call(std::ptr::null_mut(), 0, std::ptr::null_mut()); }
+                if !quiet {
+                    println!();
+                }
+            } else {
+                // Only the cells shown are copied back
+                let mut ctx = vec![0i8; gen.cells().min(16)];
+                let (p, len) = (ctx.as_mut_ptr(), ctx.len());
+                exec_start = sw.elapsed_ms();
+                unsafe { func.call(p, len, std::ptr::null_mut()); }
+                println!();
+                println!("{:?}", cell_view(&ctx, 8, signedness));
+            }
         } else {
-            let fname = format!("./{}.o", f);
-            println!("Compiling to object file {}", fname);
+            let fname = object_file.unwrap();
+            if !quiet {
+                println!("Compiling to object file {}", fname);
+            }
             gen.lower_bf(false, &s);
-            gen.add_main();
-            gen.create_object_file(&fname, opt_level);
+            // bfrt provides `read_char` and `write_char`; `main` and the tape come with the object
+            let exe = std::path::Path::new(f).file_stem().and_then(|s| s.to_str()).unwrap_or("a.out");
+            if m.is_present("shared") {
+                gen.create_object_file(&fname, OutputKind::SharedLib)?;
+                if !quiet {
+                    println!("Link with: cc -shared {} -o lib{}.so, then load it into something providing bfrt", fname, exe);
+                }
+            } else {
+                gen.add_main(m.is_present("argv-input"));
+                gen.create_object_file(&fname, OutputKind::Executable)?;
+                if !quiet {
+                    println!("Link with: cc {} -L<dir with libbfrt> -lbfrt -o {}", fname, exe);
+                }
+            }
             compile = sw.elapsed_ms();
             exec_start = sw.elapsed_ms();
         }
@@ -93,51 +477,331 @@ fn main() -> anyhow::Result<()> {
         let dump = m.is_present("dump");
         if dump {
             gen.module.print_to_file(format!("./{}.opt.ir", f)).unwrap();
-            let asm_dest = format!("./{}.opt.S", f);
-            Target::initialize_all(&InitializationConfig::default());
-            let target = Target::from_triple(&TargetMachine::get_default_triple()).unwrap();
-            let host = TargetMachine::get_host_cpu_name().to_string();
-            let features = TargetMachine::get_host_cpu_features().to_string();
-            let tm = target.create_target_machine(
-                &TargetMachine::get_default_triple(),
-                &host,
-                &features,
-                opt_level,
-                RelocMode::Static,
-                CodeModel::JITDefault,
-            ).unwrap();
-            tm.write_to_file(&gen.module, FileType::Assembly, asm_dest.as_ref()).unwrap();
+            std::fs::write(format!("./{}.opt.S", f), gen.emit_asm(opt_level)?)?;
         }
 
+    } else if m.is_present("hybrid") {
+        let threshold = config.value_of(&m, "hot-threshold").unwrap().parse()?;
+        let input = if m.is_present("raw-tty") {
+            raw_tty_input()?
+        } else {
+            InputSource::Stdin
+        };
+
+        let llvm = Context::create();
+        let opt_level = if config.is_present(&m, "optimize") {
+            OptimizationLevel::Default
+        } else {
+            OptimizationLevel::None
+        };
+        let mut hybrid = Hybrid::new(&llvm, opt_level, threshold);
+        let mut ctx = bf::Context::with_state(vec![0; NUM_CELLS]);
+        ctx.set_input(input);
+        ctx.set_eof_mode(eof);
+        compile = sw.elapsed_ms();
+        exec_start = sw.elapsed_ms();
+        hybrid.run(&mut ctx, &s).or_else(halted_ok)?;
+        if !quiet {
+            println!();
+            println!("{:?}", cell_view(&ctx.data()[..16], 8, signedness));
+            eprintln!("{} loops compiled", hybrid.compiled_loops());
+        }
+    } else if m.is_present("trace-io") {
+        // Only the dynamic interpreter reports I/O events
+        let input = if m.is_present("raw-tty") {
+            raw_tty_input()?
+        } else {
+            InputSource::Stdin
+        };
+
+        let mut ctx = bf::Context::with_input(input);
+        ctx.set_eof_mode(eof);
+        ctx.set_signedness(signedness);
+        ctx.trace_io(trace_to(std::io::stderr()));
+        compile = sw.elapsed_ms();
+        exec_start = sw.elapsed_ms();
+        match &spans {
+            Some(spans) => ctx.exec_many_spanned(&s, spans),
+            None => ctx.exec_many(&s),
+        }.or_else(halted_ok)?;
+        steps = Some(ctx.steps());
+        if !quiet {
+            println!("{:?}", ctx);
+        }
     } else {
+        let input = if m.is_present("raw-tty") {
+            raw_tty_input()?
+        } else {
+            InputSource::Stdin
+        };
+
+        let init_tape = m.value_of("init-tape").map(std::fs::read).transpose()?;
+
+        let arith = match config.value_of(&m, "cell-arith").unwrap() {
+            "wrap" => CellArith::Wrap,
+            "saturate" => CellArith::Saturate,
+            "error" => CellArith::Error,
+            // Only possible via the config file, clap checks the flag
+            other => anyhow::bail!("Unknown cell arithmetic {:?}", other),
+        };
+
+        let grow = config.value_of(&m, "tape") == Some("grow");
+        let cell_size = config.value_of(&m, "cell-size").unwrap();
+        if grow && tape_size.is_some() {
+            anyhow::bail!("--tape-size is for --tape fixed");
+        }
+        if grow && bounds == PointerMode::Wrap {
+            anyhow::bail!("--bounds wrap needs a tape that doesn't grow");
+        }
+        if (grow || tape_size.is_some()) && cell_size != "i8" {
+            anyhow::bail!("--tape grow and --tape-size only support 8-bit cells");
+        }
+
         compile = sw.elapsed_ms();
         exec_start = sw.elapsed_ms();
-        match m.value_of("cell-size").unwrap() {
-            "i8" => {
-                let mut ctx = StaticContext8::new();
-                ctx.exec_many(&s);
-                println!("{:?}", ctx);
-            },
-            "i16" => {
-                let mut ctx = StaticContext16::new();
-                ctx.exec_many(&s);
-                println!("{:?}", ctx);
-            },
-            "i32" => {
-                let mut ctx = StaticContext32::new();
-                ctx.exec_many(&s);
-                println!("{:?}", ctx);
-            },
-            "i64" => {
-                let mut ctx = StaticContext64::new();
-                ctx.exec_many(&s);
+        if grow || tape_size.is_some() {
+            // The dynamic interpreter, with a budget standing in for the fixed size
+            let mut tape: Vec<i8> = init_tape.iter().flatten().map(|&b| b as i8).collect();
+            if let Some(size) = tape_size {
+                if tape.len() > size {
+                    return Err(bf::Error::TapeTooLarge { len: tape.len(), capacity: size }.into());
+                }
+                tape.resize(size, 0);
+            }
+            if tape.is_empty() {
+                tape.push(0);
+            }
+
+            let mut ctx = bf::Context::with_state(tape);
+            ctx.set_memory_budget(tape_size.map(MemoryBudget::new));
+            match bounds {
+                PointerMode::Wrap => ctx.set_tape_mode(TapeMode::Wrap),
+                PointerMode::Trap => ctx.set_tape_mode(TapeMode::Error),
+                PointerMode::Unchecked => {}
+            }
+            ctx.set_input(input);
+            ctx.set_eof_mode(eof);
+            ctx.set_arith(arith);
+            ctx.set_signedness(signedness);
+            ctx.exec_many(&s).or_else(halted_ok)?;
+            steps = Some(ctx.steps());
+            if !quiet {
                 println!("{:?}", ctx);
-            },
-            _ => unreachable!()
+            }
+            if let Some(t) = &expected_tape {
+                t.check(ctx.data())?;
+            }
+        } else {
+            match cell_size {
+                "i8" => {
+                    let mut ctx = match &init_tape {
+                        Some(t) => StaticContext8::try_with_state(t.iter().map(|&b| b as i8).collect::<Vec<_>>())?,
+                        None => StaticContext8::new(),
+                    };
+                    ctx.set_input(input);
+                    ctx.set_eof_mode(eof);
+                    ctx.set_arith(arith);
+                    ctx.set_signedness(signedness);
+                    ctx.exec_many(&s);
+                    steps = Some(ctx.steps());
+                    if !quiet {
+                        println!("{:?}", ctx);
+                    }
+                    if let Some(t) = &expected_tape {
+                        t.check(ctx.data())?;
+                    }
+                },
+                "i16" => {
+                    let mut ctx = match &init_tape {
+                        Some(t) => StaticContext16::try_with_state(t.iter().map(|&b| b as i16).collect::<Vec<_>>())?,
+                        None => StaticContext16::new(),
+                    };
+                    ctx.set_input(input);
+                    ctx.set_eof_mode(eof);
+                    ctx.set_arith(arith);
+                    ctx.set_signedness(signedness);
+                    ctx.exec_many(&s);
+                    steps = Some(ctx.steps());
+                    if !quiet {
+                        println!("{:?}", ctx);
+                    }
+                    if let Some(t) = &expected_tape {
+                        t.check(ctx.data())?;
+                    }
+                },
+                "i32" => {
+                    let mut ctx = match &init_tape {
+                        Some(t) => StaticContext32::try_with_state(t.iter().map(|&b| b as i32).collect::<Vec<_>>())?,
+                        None => StaticContext32::new(),
+                    };
+                    ctx.set_input(input);
+                    ctx.set_eof_mode(eof);
+                    ctx.set_arith(arith);
+                    ctx.set_signedness(signedness);
+                    ctx.exec_many(&s);
+                    steps = Some(ctx.steps());
+                    if !quiet {
+                        println!("{:?}", ctx);
+                    }
+                    if let Some(t) = &expected_tape {
+                        t.check(ctx.data())?;
+                    }
+                },
+                "i64" => {
+                    let mut ctx = match &init_tape {
+                        Some(t) => StaticContext64::try_with_state(t.iter().map(|&b| b as i64).collect::<Vec<_>>())?,
+                        None => StaticContext64::new(),
+                    };
+                    ctx.set_input(input);
+                    ctx.set_eof_mode(eof);
+                    ctx.set_arith(arith);
+                    ctx.set_signedness(signedness);
+                    ctx.exec_many(&s);
+                    steps = Some(ctx.steps());
+                    if !quiet {
+                        println!("{:?}", ctx);
+                    }
+                    if let Some(t) = &expected_tape {
+                        t.check(ctx.data())?;
+                    }
+                },
+                other => anyhow::bail!("Unknown cell size {:?}", other),
+            }
         }
 
     }
     let exec = sw.elapsed_ms();
-    println!("Compilation took {}ms, execution took {}ms", compile, exec - exec_start);
+    if let (true, Some(steps)) = (m.is_present("count-steps"), steps) {
+        eprintln!("{} steps", steps);
+    }
+    if m.is_present("timing-json") {
+        print_timings(&Timings {
+            parse_ms: parsed,
+            optimize_ms: optimized - optimize_start,
+            compile_ms: compile - optimized,
+            exec_ms: exec - exec_start,
+        })?;
+    } else if !quiet {
+        // On stderr, so it doesn't end up in the program's piped output
+        eprintln!("Compilation took {}ms, execution took {}ms", compile, exec - exec_start);
+    }
     Ok(())
 }
+
+fn debug(f: &str, input: Option<&str>) -> anyhow::Result<()> {
+    let text = read_source(f)?;
+    let s = parse_source(&text)?.stmts;
+    let input = input.map(std::fs::read).transpose()?.unwrap_or_default();
+
+    let mut ctx = bf::Context::with_input_iter(input.into_iter());
+    ctx.set_output(OutputSink::Buffer(Vec::new()));
+    let mut dbg = Debugger::new(&s, ctx);
+    for b in breakpoints_from_source(&text) {
+        dbg.toggle_breakpoint(b);
+    }
+    run_debugger(&mut dbg)
+}
+
+#[cfg(feature = "tui")]
+fn run_debugger(dbg: &mut Debugger) -> anyhow::Result<()> {
+    Ok(bf::debugger::run_tui(dbg)?)
+}
+
+#[cfg(not(feature = "tui"))]
+fn run_debugger(dbg: &mut Debugger) -> anyhow::Result<()> {
+    Ok(bf::debugger::run_lines(dbg, stdin().lock(), std::io::stdout())?)
+}
+
+fn list_backends() {
+    let jit = probe_jit();
+    let native = probe_native();
+    let backends = [
+        ("interpreter", "8-bit cells on a growable tape (--tape grow, --trace-io, --stream)", Ok(())),
+        ("static-i8", "the default", Ok(())),
+        ("static-i16", "-c i16", Ok(())),
+        ("static-i32", "-c i32", Ok(())),
+        ("static-i64", "-c i64", Ok(())),
+        ("jit", "-j", jit.clone()),
+        ("hybrid", "--hybrid", jit),
+        ("native", "-n, linking needs a C compiler", native),
+    ];
+    for (name, how, status) in backends.iter() {
+        match status {
+            Ok(()) => println!("{:<12} available    {}", name, how),
+            Err(e) => println!("{:<12} unavailable  {} ({})", name, how, e),
+        }
+    }
+}
+
+// Source doesn't have to be UTF-8, as anything but the commands is a comment. Bytes
+// that aren't are replaced, which can shift columns reported after them on that line.
+fn read_source(f: &str) -> anyhow::Result<String> {
+    Ok(String::from_utf8_lossy(&std::fs::read(f)?).into_owned())
+}
+
+// Stopping at `@halt` is a normal way for a run to end
+fn halted_ok(e: bf::Error) -> Result<(), bf::Error> {
+    match e {
+        bf::Error::Halted => Ok(()),
+        e => Err(e),
+    }
+}
+
+fn contains_empty_loop(s: &[bf::Statement]) -> bool {
+    s.iter().any(|s| match s {
+        bf::Statement::InfiniteLoopIfNonZero => true,
+        bf::Statement::Loop(l) => contains_empty_loop(l),
+        _ => false,
+    })
+}
+
+fn print_info(s: &[bf::Statement], json: bool) -> anyhow::Result<()> {
+    let info = ProgramInfo::new(s);
+    if json {
+        print_json(&info)
+    } else {
+        println!("{:#?}", info);
+        Ok(())
+    }
+}
+
+/// Milliseconds spent in each stage, for `--timing-json`
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+struct Timings {
+    parse_ms: i64,
+    optimize_ms: i64,
+    compile_ms: i64,
+    exec_ms: i64,
+}
+
+#[cfg(feature = "serde")]
+fn print_timings(timings: &Timings) -> anyhow::Result<()> {
+    eprintln!("{}", serde_json::to_string(timings)?);
+    Ok(())
+}
+
+#[cfg(not(feature = "serde"))]
+fn print_timings(_timings: &Timings) -> anyhow::Result<()> {
+    anyhow::bail!("--timing-json requires building with the `serde` feature")
+}
+
+#[cfg(feature = "serde")]
+fn print_json(info: &ProgramInfo) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(info)?);
+    Ok(())
+}
+
+#[cfg(not(feature = "serde"))]
+fn print_json(_info: &ProgramInfo) -> anyhow::Result<()> {
+    anyhow::bail!("--json requires building with the `serde` feature")
+}
+
+#[cfg(feature = "raw-tty")]
+fn raw_tty_input() -> anyhow::Result<InputSource> {
+    Ok(InputSource::raw_tty()?)
+}
+
+#[cfg(not(feature = "raw-tty"))]
+fn raw_tty_input() -> anyhow::Result<InputSource> {
+    anyhow::bail!("--raw-tty requires building with the `raw-tty` feature")
+}
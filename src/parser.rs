@@ -5,39 +5,433 @@ use nom:: {
     combinator::map_res,
     sequence::tuple
 };
-use crate::bf::Statement;
-use nom::multi::many0;
+use crate::bf::{Statement, Error, unsigned};
+use nom::multi::{many0, separated_list1};
 use nom::branch::alt;
-use crate::bf::Statement::{Inc, Dec, Next, Prev, Out, In, Loop};
+use crate::bf::Statement::{Inc, Dec, Next, Prev, Out, In, Loop, Breakpoint};
 use nom::sequence::terminated;
 use std::borrow::Cow;
-use std::collections::HashSet;
-use once_cell::sync::Lazy;
-use nom::combinator::all_consuming;
+use nom::combinator::{all_consuming, map, opt, recognize, value};
+use nom::character::complete::{digit1, space0, space1};
+use nom::sequence::{delimited, pair, preceded};
+use std::fmt::Write;
+use std::io::{self, Read};
+use crate::span::Span;
 
-static BF_CHARS: Lazy<HashSet<char>> = Lazy::new(|| {
-    vec!['[', ']', ',', '.', '+', '-', '>', '<']
-        .into_iter()
-        .collect()
-});
+// Indexed by byte. Every command is ASCII, and every byte of a multi-byte UTF-8
+// sequence is >= 0x80, so scanning bytes never mistakes part of a character for one.
+const fn command_table(breakpoints: bool) -> [bool; 256] {
+    let mut t = [false; 256];
+    t[b'[' as usize] = true;
+    t[b']' as usize] = true;
+    t[b',' as usize] = true;
+    t[b'.' as usize] = true;
+    t[b'+' as usize] = true;
+    t[b'-' as usize] = true;
+    t[b'>' as usize] = true;
+    t[b'<' as usize] = true;
+    t[b'#' as usize] = breakpoints;
+    t
+}
+
+static COMMANDS: [bool; 256] = command_table(false);
+// For the `_with_breakpoints` parsers, to which `#` is a command too
+static BREAKPOINT_COMMANDS: [bool; 256] = command_table(true);
+
+fn is_command(c: char, commands: &[bool; 256]) -> bool {
+    c.is_ascii() && commands[c as usize]
+}
+
+pub fn bf_chars(i: &str) -> Cow<'_, str> {
+    keep_commands(i, &COMMANDS)
+}
+
+fn keep_commands<'a>(i: &'a str, commands: &[bool; 256]) -> Cow<'a, str> {
+    if i.bytes().all(|b| commands[b as usize]) {
+        Cow::Borrowed(i)
+    } else {
+        i.bytes().filter(|&b| commands[b as usize]).map(char::from).collect::<String>().into()
+    }
+}
+
+/// The command characters of `i`, in order, without collecting them anywhere
+pub fn bf_char_iter(i: &str) -> impl Iterator<Item = char> + '_ {
+    bf_byte_iter(i.as_bytes()).map(char::from)
+}
+
+/// `bf_char_iter` for source that may not be UTF-8
+pub fn bf_byte_iter(i: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    i.iter().copied().filter(|&b| COMMANDS[b as usize])
+}
 
-pub fn bf_chars(i: &str) -> Cow<str> {
-    if i.chars().all(|c| BF_CHARS.contains(&c)) {
+/// `bf_chars` for source that may not be UTF-8. Every byte that isn't one of the eight
+/// commands is a comment, so any encoding that keeps ASCII as it is works.
+pub fn bf_bytes(i: &[u8]) -> Cow<'_, [u8]> {
+    if i.iter().all(|&b| COMMANDS[b as usize]) {
         Cow::Borrowed(i)
     } else {
-        i.chars()
-            .filter(|c| BF_CHARS.contains(c))
-            .collect::<String>()
-            .into()
+        bf_byte_iter(i).collect::<Vec<_>>().into()
+    }
+}
+
+/// Byte offset in `i` of each command character, so positions in the text returned
+/// by `bf_chars` can be mapped back to the original source.
+pub fn command_offsets(i: &str) -> Vec<usize> {
+    i.bytes()
+        .enumerate()
+        .filter(|(_, b)| COMMANDS[*b as usize])
+        .map(|(o, _)| o)
+        .collect()
+}
+
+/// 1-based line and column of the byte at `offset` in `i`
+pub fn line_col(i: &str, offset: usize) -> (usize, usize) {
+    let before = &i[..offset];
+    let line = before.matches('\n').count() + 1;
+    let col = before.rfind('\n').map_or(before.len(), |nl| before.len() - nl - 1) + 1;
+    (line, col)
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum DirectiveError {
+    #[error("Malformed directive at byte {0}")]
+    Malformed(usize),
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+}
+
+/// A bracket without a partner. `offset` is the bracket's byte offset in the source,
+/// and `line` and `col` are as `line_col` gives them.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    #[error("unmatched '[' at line {line}, col {col}")]
+    UnmatchedOpen { offset: usize, line: usize, col: usize },
+    #[error("unmatched ']' at line {line}, col {col}")]
+    UnmatchedClose { offset: usize, line: usize, col: usize },
+}
+
+/// Checks that every bracket in `i` has a partner. For an unclosed `[`, the error points
+/// at the innermost one still open at the end.
+pub fn check_brackets(i: &str) -> Result<(), ParseError> {
+    let mut open = Vec::new();
+    for (offset, b) in i.bytes().enumerate() {
+        match b {
+            b'[' => open.push(offset),
+            b']' if open.pop().is_none() => {
+                let (line, col) = line_col(i, offset);
+                return Err(ParseError::UnmatchedClose { offset, line, col });
+            }
+            _ => {}
+        }
+    }
+
+    match open.pop() {
+        Some(offset) => {
+            let (line, col) = line_col(i, offset);
+            Err(ParseError::UnmatchedOpen { offset, line, col })
+        }
+        None => Ok(()),
+    }
+}
+
+/// `parse_program` for source that is read as it is parsed, so only the statements are
+/// kept in memory and never the text. Bracket errors are `InvalidData` errors wrapping a
+/// `ParseError`, including for a source that ends inside a loop.
+pub fn parse_reader<R: Read>(mut r: R) -> io::Result<Vec<Statement>> {
+    // Bodies of the loops still being read, innermost last, with where their `[` was
+    let mut open: Vec<((usize, usize, usize), Vec<Statement>)> = Vec::new();
+    let mut top = Vec::new();
+    let mut buf = [0u8; 4096];
+    let (mut offset, mut line, mut col) = (0usize, 1usize, 1usize);
+
+    loop {
+        let n = match r.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        for &b in &buf[..n] {
+            let s = match b {
+                b'[' => {
+                    open.push(((offset, line, col), Vec::new()));
+                    None
+                }
+                b']' => match open.pop() {
+                    Some((_, body)) => Some(Loop(body)),
+                    None => {
+                        let e = ParseError::UnmatchedClose { offset, line, col };
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                    }
+                },
+                _ => command(b),
+            };
+
+            if let Some(s) = s {
+                match open.last_mut() {
+                    Some((_, body)) => body.push(s),
+                    None => top.push(s),
+                }
+            }
+
+            offset += 1;
+            if b == b'\n' {
+                line += 1;
+                col = 1;
+            } else {
+                col += 1;
+            }
+        }
+    }
+
+    match open.pop() {
+        Some(((offset, line, col), _)) => {
+            Err(io::Error::new(io::ErrorKind::InvalidData, ParseError::UnmatchedOpen { offset, line, col }))
+        }
+        None => Ok(top),
     }
 }
 
+/// `parse_program`, also giving the byte range in `i` each statement came from. A loop's
+/// range runs from its `[` to its `]`.
+pub fn program_spanned(i: &str) -> Result<(Vec<Statement>, Vec<Span>), ParseError> {
+    check_brackets(i)?;
+
+    // Loops still open, innermost last: where they start, and their body so far
+    let mut open: Vec<(usize, Vec<Statement>, Vec<Span>)> = Vec::new();
+    let (mut stmts, mut spans) = (Vec::new(), Vec::new());
+    for (offset, b) in i.bytes().enumerate() {
+        let (s, span) = match b {
+            b'[' => {
+                open.push((offset, Vec::new(), Vec::new()));
+                continue;
+            }
+            b']' => {
+                let (start, body, body_spans) = open.pop().expect("brackets are balanced");
+                (Loop(body), Span { range: start..offset + 1, body: body_spans })
+            }
+            _ => match command(b) {
+                Some(s) => (s, Span { range: offset..offset + 1, body: Vec::new() }),
+                None => continue,
+            },
+        };
+
+        match open.last_mut() {
+            Some((_, body, body_spans)) => {
+                body.push(s);
+                body_spans.push(span);
+            }
+            None => {
+                stmts.push(s);
+                spans.push(span);
+            }
+        }
+    }
+    Ok((stmts, spans))
+}
+
+// The statement for a command other than a bracket
+fn command(b: u8) -> Option<Statement> {
+    match b {
+        b'+' => Some(Inc(1)),
+        b'-' => Some(Dec(1)),
+        b'>' => Some(Next(1)),
+        b'<' => Some(Prev(1)),
+        b'.' => Some(Out),
+        b',' => Some(In),
+        _ => None,
+    }
+}
+
+/// Parses source that may contain comments, like `program(&bf_chars(i))`, but with
+/// errors that say where the problem is.
+pub fn parse_program(i: &str) -> Result<Vec<Statement>, ParseError> {
+    check_brackets(i)?;
+    let (_, stmts) = program(&bf_chars(i)).expect("balanced brackets always parse");
+    Ok(stmts)
+}
+
+const ASSERT: &str = "@assert";
+const TAPE: &str = "@tape";
+const HALT: &str = "@halt";
+
+/// Like `bf_chars`, but keeps each `@assert cellN == V` directive in the compact form
+/// `@pos:N=V;` understood by `assert_directive`, where `pos` is its byte offset in `i`,
+/// and each `@halt` as `@h;` for `halt_directive`. `@tape` directives are dropped.
+pub fn bf_chars_with_asserts(i: &str) -> Result<String, DirectiveError> {
+    keep_directives(i, &COMMANDS)
+}
+
+fn keep_directives(i: &str, commands: &[bool; 256]) -> Result<String, DirectiveError> {
+    let mut out = String::with_capacity(i.len());
+
+    let mut idx = 0usize;
+    while let Some(c) = i[idx..].chars().next() {
+        if i[idx..].starts_with(TAPE) {
+            let rest = &i[idx + TAPE.len()..];
+            let (after, _) = tape_body(rest).map_err(|_| DirectiveError::Malformed(idx))?;
+            idx += TAPE.len() + rest.len() - after.len();
+            continue;
+        }
+
+        if i[idx..].starts_with(HALT) {
+            out.push_str("@h;");
+            idx += HALT.len();
+            continue;
+        }
+
+        if i[idx..].starts_with(ASSERT) {
+            let rest = &i[idx + ASSERT.len()..];
+            let (after, (cell, expected)) = assert_body(rest)
+                .map_err(|_| DirectiveError::Malformed(idx))?;
+            write!(out, "@{}:{}={};", idx, cell, expected).unwrap();
+            idx += ASSERT.len() + rest.len() - after.len();
+            continue;
+        }
+
+        if is_command(c, commands) {
+            out.push(c);
+        }
+        idx += c.len_utf8();
+    }
+
+    Ok(out)
+}
+
+// ` cellN == V` after `@assert`
+fn assert_body(i: &str) -> IResult<&str, (usize, i64)> {
+    let (i, (_, _, cell, _, _, _, expected)) = tuple((
+        space1,
+        tag("cell"),
+        map_res(digit1, |d: &str| d.parse::<usize>()),
+        space0,
+        tag("=="),
+        space0,
+        signed,
+    ))(i)?;
+    Ok((i, (cell, expected)))
+}
+
+// ` V0 V1 ...` after `@tape`
+fn tape_body(i: &str) -> IResult<&str, Vec<i64>> {
+    preceded(space1, separated_list1(space1, signed))(i)
+}
+
+fn signed(i: &str) -> IResult<&str, i64> {
+    map_res(recognize(pair(opt(char('-')), digit1)), |d: &str| d.parse::<i64>())(i)
+}
+
+/// Expected final tape, from a `@tape V0 V1 ...` directive
+#[derive(Debug, Clone, PartialEq)]
+pub struct TapeExpectation {
+    /// Byte offset of the directive in the source
+    pub pos: usize,
+    pub cells: Vec<i64>,
+}
+
+impl TapeExpectation {
+    /// Compares the listed cells with `tape`, modulo the cell width. Cells past the
+    /// end of `tape` count as 0, and cells past the end of the list aren't checked.
+    pub fn check<T: Copy + Into<i128>>(&self, tape: &[T]) -> Result<(), Error> {
+        let bits = (std::mem::size_of::<T>() * 8) as u32;
+        for (cell, &expected) in self.cells.iter().enumerate() {
+            let actual = tape.get(cell).map_or(0, |&v| v.into());
+            if unsigned(actual, bits) != unsigned(expected as i128, bits) {
+                return Err(Error::TapeMismatch { cell, expected, actual: actual as i64 });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The last `@tape` directive in `i`, if any
+pub fn tape_directive(i: &str) -> Result<Option<TapeExpectation>, DirectiveError> {
+    let pos = match i.rfind(TAPE) {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+
+    let (_, cells) = tape_body(&i[pos + TAPE.len()..]).map_err(|_| DirectiveError::Malformed(pos))?;
+    Ok(Some(TapeExpectation { pos, cells }))
+}
+
+/// Parses `i` like `program(&bf_chars(i))`, additionally turning `@assert cellN == V`
+/// comments into `Statement::Assert`s and `@halt` into `Statement::Halt` at the point
+/// they appear.
+pub fn program_with_asserts(i: &str) -> Result<Vec<Statement>, DirectiveError> {
+    program_with_directives(i, &COMMANDS)
+}
+
+/// `program_with_asserts`, with each `#` a `Statement::Breakpoint`
+pub fn program_with_asserts_and_breakpoints(i: &str) -> Result<Vec<Statement>, DirectiveError> {
+    program_with_directives(i, &BREAKPOINT_COMMANDS)
+}
+
+fn program_with_directives(i: &str, commands: &[bool; 256]) -> Result<Vec<Statement>, DirectiveError> {
+    // Directives never contain brackets, so they can be checked in the source
+    check_brackets(i)?;
+    let text = keep_directives(i, commands)?;
+    let (_, s) = all_consuming(stmts_with_directives)(&text).expect("balanced brackets always parse");
+    Ok(s)
+}
+
+/// A program parsed from source that may contain comments.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedProgram {
+    pub stmts: Vec<Statement>,
+    /// Whether anything was stripped by `bf_chars`. When this is false, positions in the
+    /// parsed text are already positions in the source and need no `command_offsets`.
+    pub had_comments: bool,
+}
+
+/// `program(&bf_chars(i))`, noting whether `i` had any comments. A `#` is a comment like
+/// any other character; see `parse_with_breakpoints`.
+pub fn parse_source(i: &str) -> Result<ParsedProgram, DirectiveError> {
+    parse_keeping(i, &COMMANDS)
+}
+
+/// `parse_source`, with each `#` a `Statement::Breakpoint` that shows the tape when run.
+/// Only this and `program_with_asserts_and_breakpoints` treat `#` that way, so a `#` in
+/// the comments of an ordinary program doesn't stop to dump the tape.
+pub fn parse_with_breakpoints(i: &str) -> Result<ParsedProgram, DirectiveError> {
+    parse_keeping(i, &BREAKPOINT_COMMANDS)
+}
+
+fn parse_keeping(i: &str, commands: &[bool; 256]) -> Result<ParsedProgram, DirectiveError> {
+    check_brackets(i)?;
+    let text = keep_commands(i, commands);
+    let had_comments = matches!(text, Cow::Owned(_));
+    let (_, stmts) = all_consuming(stmts_with_directives)(&text).expect("balanced brackets always parse");
+    Ok(ParsedProgram { stmts, had_comments })
+}
+
 pub fn program(i: &str) -> IResult<&str, Vec<Statement>> {
     all_consuming(
         stmts
     )(i)
 }
 
+/// `program` for the output of `bf_bytes`. Only the eight commands are recognized, not
+/// the directives `program_with_asserts` adds.
+pub fn program_bytes(i: &[u8]) -> IResult<&[u8], Vec<Statement>> {
+    all_consuming(stmts_bytes)(i)
+}
+
+fn stmts_bytes(i: &[u8]) -> IResult<&[u8], Vec<Statement>> {
+    many0(
+        alt((
+            value(Inc(1), char('+')),
+            value(Dec(1), char('-')),
+            value(Next(1), char('>')),
+            value(Prev(1), char('<')),
+            value(Out, char('.')),
+            value(In, char(',')),
+            map(delimited(char('['), stmts_bytes, char(']')), Loop),
+            ))
+    )(i)
+}
+
 pub fn stmts(i: &str) -> IResult<&str, Vec<Statement>> {
     many0(
         alt((
@@ -47,7 +441,26 @@ pub fn stmts(i: &str) -> IResult<&str, Vec<Statement>> {
             prev,
             out,
             inp,
-            loup
+            loup,
+            ))
+    )(i)
+}
+
+// `stmts`, plus `#` and the compact directives `bf_chars_with_asserts` writes. Only the
+// opt-in parsers keep those in the text they parse, so `program` leaves them out.
+fn stmts_with_directives(i: &str) -> IResult<&str, Vec<Statement>> {
+    many0(
+        alt((
+            inc,
+            dec,
+            next,
+            prev,
+            out,
+            inp,
+            breakpoint,
+            map(delimited(char('['), stmts_with_directives, char(']')), Loop),
+            assert_directive,
+            halt_directive
             ))
     )(i)
 }
@@ -81,6 +494,29 @@ pub fn inp(i: &str) -> IResult<&str, Statement> {
     char_op(',', In, i)
 }
 
+fn breakpoint(i: &str) -> IResult<&str, Statement> {
+    char_op('#', Breakpoint, i)
+}
+
+fn assert_directive(i: &str) -> IResult<&str, Statement> {
+    let (i, (_, pos, _, cell, _, expected, _)) = tuple((
+        char('@'),
+        map_res(digit1, |d: &str| d.parse::<usize>()),
+        char(':'),
+        map_res(digit1, |d: &str| d.parse::<usize>()),
+        char('='),
+        signed,
+        char(';'),
+    ))(i)?;
+
+    Ok((i, Statement::Assert { cell, expected, pos }))
+}
+
+fn halt_directive(i: &str) -> IResult<&str, Statement> {
+    let (i, _) = tag("@h;")(i)?;
+    Ok((i, Statement::Halt))
+}
+
 pub fn loup(i: &str) -> IResult<&str, Statement> {
     let (i, _) = char('[')(i)?;
     let (i, v) = terminated(
@@ -94,7 +530,7 @@ pub fn loup(i: &str) -> IResult<&str, Statement> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::bf::Context;
+    use crate::bf::{Context, Error};
 
     #[test]
     fn parse_adder() {
@@ -105,4 +541,146 @@ mod tests {
         assert_eq!(ctx.data()[2], 60);
         println!("{:?}", &ctx)
     }
+
+    #[test]
+    fn breakpoints_only_when_asked() {
+        assert!(program("+#-").is_err());
+        assert_eq!(parse_source("+#-").unwrap().stmts, vec![Inc(1), Dec(1)]);
+        assert_eq!(parse_with_breakpoints("+#-").unwrap().stmts, vec![Inc(1), Breakpoint, Dec(1)]);
+        assert_eq!(program_with_asserts_and_breakpoints("[#@halt]").unwrap(), vec![Loop(vec![Breakpoint, Statement::Halt])]);
+    }
+
+    #[test]
+    fn assert_directives() {
+        let src = "+++++ @assert cell0 == 5\n[->+<] @assert cell1 == 4";
+        let s = program_with_asserts(src).unwrap();
+        assert_eq!(s[5], Statement::Assert { cell: 0, expected: 5, pos: 6 });
+
+        let mut ctx = Context::new();
+        match ctx.exec_many(&s) {
+            Err(Error::AssertionFailed { pos, cell, expected, actual }) => {
+                assert_eq!((pos, cell, expected, actual), (32, 1, 4, 5));
+            }
+            r => panic!("expected the second assertion to fail, got {:?}", r),
+        }
+
+        assert_eq!(program_with_asserts("+ @assert cel0 == 1"), Err(DirectiveError::Malformed(2)));
+
+        // The compact forms are for `program_with_asserts` alone
+        assert!(program("++@0:0=3;").is_err());
+        assert!(program("[@h;]").is_err());
+    }
+
+    #[test]
+    fn tape_directive_check() {
+        let src = "++++++++[>++++++++<-]>+>++++++++[>++++++++<-]>++\n@tape 0 65 0 66\n";
+        let s = program_with_asserts(src).unwrap();
+        let mut ctx = Context::new();
+        ctx.exec_many(&s).unwrap();
+
+        let tape = tape_directive(src).unwrap().unwrap();
+        assert_eq!(tape.cells, vec![0, 65, 0, 66]);
+        assert!(tape.check(ctx.data()).is_ok());
+
+        let wrong = tape_directive("@tape 0 65 0 -190 1").unwrap().unwrap();
+        assert!(matches!(
+            wrong.check(ctx.data()),
+            Err(Error::TapeMismatch { cell: 4, expected: 1, actual: 0 })
+        ));
+        let wrong = tape_directive("@tape 0 65 0 67").unwrap().unwrap();
+        assert!(matches!(
+            wrong.check(ctx.data()),
+            Err(Error::TapeMismatch { cell: 3, expected: 67, actual: 66 })
+        ));
+
+        assert_eq!(tape_directive("+ @tape x"), Err(DirectiveError::Malformed(2)));
+    }
+
+    #[test]
+    fn clean_and_commented_sources() {
+        let clean = parse_source("++[->+<]").unwrap();
+        assert!(!clean.had_comments);
+
+        let commented = parse_source("++ add [->+<] it\n").unwrap();
+        assert!(commented.had_comments);
+        assert_eq!(commented.stmts, clean.stmts);
+
+        let unclosed = ParseError::UnmatchedOpen { offset: 0, line: 1, col: 1 };
+        assert_eq!(parse_source("[+ oops"), Err(DirectiveError::Parse(unclosed)));
+    }
+
+    #[test]
+    fn reader_matches_parse_program() {
+        // Small reads, so loops and lines span several of them
+        struct Trickle<'a>(&'a [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.0.len().min(buf.len()).min(3);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
+        let src = "++ copy\n[->+>+<<] é\n>>[-<<+>>]<.";
+        assert_eq!(parse_reader(Trickle(src.as_bytes())).unwrap(), parse_program(src).unwrap());
+
+        for bad in &["+[-]\n[>[<] ok\n", "+[-]\n  ]", "[[]"] {
+            let err = parse_reader(Trickle(bad.as_bytes())).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            let err = err.into_inner().unwrap().downcast::<ParseError>().unwrap();
+            assert_eq!(*err, parse_program(bad).unwrap_err());
+        }
+    }
+
+    #[test]
+    fn unmatched_brackets() {
+        assert_eq!(parse_program("+[-]\n[>[<] ok\n").unwrap_err(), ParseError::UnmatchedOpen { offset: 5, line: 2, col: 1 });
+        assert_eq!(parse_program("[[]").unwrap_err(), ParseError::UnmatchedOpen { offset: 0, line: 1, col: 1 });
+        assert_eq!(parse_program("[][[\n").unwrap_err(), ParseError::UnmatchedOpen { offset: 3, line: 1, col: 4 });
+
+        let err = parse_program("+[-]\n  ]").unwrap_err();
+        assert_eq!(err, ParseError::UnmatchedClose { offset: 7, line: 2, col: 3 });
+        assert_eq!(err.to_string(), "unmatched ']' at line 2, col 3");
+
+        assert_eq!(parse_program("x+[-]y").unwrap(), program("+[-]").unwrap().1);
+    }
+
+    #[test]
+    fn lookalikes_are_comments() {
+        // Fullwidth plus and minus, single angle quotes, and a two-byte character
+        let text = "＋－‹›é+[,]";
+        assert_eq!(bf_chars(text), "+[,]");
+        assert_eq!(command_offsets(text), vec![14, 15, 16, 17]);
+        assert!(matches!(bf_chars("+-<>[].,"), Cow::Borrowed(_)));
+        assert_eq!(bf_char_iter(text).collect::<String>(), "+[,]");
+        assert!(bf_byte_iter(text.as_bytes()).eq(b"+[,]".iter().copied()));
+    }
+
+    #[test]
+    fn bytes_and_str_agree() {
+        let ascii = std::fs::read_to_string("hello.bf").unwrap();
+        let (_, from_str) = program(&bf_chars(&ascii)).unwrap();
+        let (_, from_bytes) = program_bytes(&bf_bytes(ascii.as_bytes())).unwrap();
+        assert_eq!(from_bytes, from_str);
+        assert!(matches!(bf_bytes(b"+-<>[].,"), Cow::Borrowed(_)));
+
+        // A BOM, a latin-1 comment and a NUL
+        let mut odd = b"\xef\xbb\xbf caf\xe9 \0 ".to_vec();
+        odd.extend_from_slice(ascii.as_bytes());
+        assert!(std::str::from_utf8(&odd).is_err());
+        assert_eq!(program_bytes(&bf_bytes(&odd)).unwrap().1, from_str);
+
+        assert!(program_bytes(b"[+").is_err());
+        assert!(program_bytes(b"+]").is_err());
+    }
+
+    #[test]
+    fn source_positions() {
+        let text = "a+\nbc[-]";
+        let offsets = command_offsets(text);
+        assert_eq!(offsets, vec![1, 5, 6, 7]);
+        assert_eq!(line_col(text, offsets[0]), (1, 2));
+        assert_eq!(line_col(text, offsets[1]), (2, 3));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,199 @@
+//! Where in the source each statement came from, kept through optimization.
+
+use std::ops::Range;
+use crate::bf::{passes, Patterns, Statement, MAX_ROUNDS};
+
+/// The byte range in the source a statement came from, and the spans of a loop's body.
+/// A list of spans runs parallel to the statements it belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub range: Range<usize>,
+    pub body: Vec<Span>,
+}
+
+impl Span {
+    fn leaf(range: Range<usize>) -> Self {
+        Span { range, body: Vec::new() }
+    }
+}
+
+/// The range of the statement at `path`, given as the statement's index followed by its
+/// index within each enclosing loop body, as in `Error::InvalidStatement`.
+pub fn span_at<'a>(spans: &'a [Span], path: &[usize]) -> Option<&'a Range<usize>> {
+    let (first, rest) = path.split_first()?;
+    let span = spans.get(*first)?;
+    if rest.is_empty() {
+        Some(&span.range)
+    } else {
+        span_at(&span.body, rest)
+    }
+}
+
+/// `optimize_with`, also working out the spans of the optimized statements. A statement
+/// that replaced others, like `Clear` for `[-]` or `Inc(3)` for `+++`, covers all of
+/// them; when several replaced the same statements together, as the `AddOffset`s and the
+/// `Clear` for a multiply loop do, they all cover everything that was replaced.
+pub fn optimize_spanned(stmts: impl AsRef<[Statement]>, spans: &[Span], patterns: Patterns) -> (Vec<Statement>, Vec<Span>) {
+    let passes = passes(patterns);
+    let mut cur = (stmts.as_ref().to_vec(), spans.to_vec());
+    for _ in 0..MAX_ROUNDS {
+        let next = passes.iter()
+            .fold(cur.clone(), |(stmts, spans), (_, pass)| {
+                let out = pass(&stmts);
+                let spans = realign(&stmts, &spans, &out);
+                (out, spans)
+            });
+        if next.0 == cur.0 {
+            break;
+        }
+        cur = next;
+    }
+    cur
+}
+
+// How far apart statements that a pass left alone may be before it is treated as one
+// big rewrite. Passes only look at a few statements at a time.
+const WINDOW: usize = 32;
+
+// Spans for `after`, which a pass produced from `before`. Statements a pass copies
+// unchanged keep their span, and the runs in between are matched up by `gap`.
+fn realign(before: &[Statement], spans: &[Span], after: &[Statement]) -> Vec<Span> {
+    let mut out = Vec::with_capacity(after.len());
+    let (mut i, mut j) = (0usize, 0usize);
+    while j < after.len() {
+        if i < before.len() && before[i] == after[j] {
+            out.push(spans[i].clone());
+            i += 1;
+            j += 1;
+            continue;
+        }
+
+        let (di, dj) = resync(&before[i..], &after[j..]);
+        let at = spans[..i].last().map_or(0, |s| s.range.end);
+        gap(&before[i..i + di], &spans[i..i + di], &after[j..j + dj], at, &mut out);
+        i += di;
+        j += dj;
+    }
+    out
+}
+
+// Lengths of the runs before the next statement `before` and `after` have in common.
+// Passes mostly shrink a program, so of equally distant matches the one that skips
+// more of `before` is tried first; otherwise `+++[-]+` would pair the first `+` with
+// the last.
+fn resync(before: &[Statement], after: &[Statement]) -> (usize, usize) {
+    for k in 1..2 * WINDOW {
+        for di in (k.saturating_sub(WINDOW).max(k.saturating_sub(after.len() - 1))..=k.min(WINDOW).min(before.len().saturating_sub(1))).rev() {
+            let dj = k - di;
+            if dj < after.len() && before[di] == after[dj] {
+                return (di, dj);
+            }
+        }
+    }
+    (before.len(), after.len())
+}
+
+// Spans for `after`, which replaced `before`. `at` is where `before` starts in the source,
+// for when it is empty.
+fn gap(before: &[Statement], spans: &[Span], after: &[Statement], at: usize, out: &mut Vec<Span>) {
+    if let Some(folded) = refold(before, spans, after) {
+        out.extend(folded);
+        return;
+    }
+
+    // Loops that are still loops, with their bodies changed, pair up in order
+    let loops_before: Vec<_> = (0..before.len()).filter(|&i| matches!(before[i], Statement::Loop(_))).collect();
+    let loops_after: Vec<_> = (0..after.len()).filter(|&j| matches!(after[j], Statement::Loop(_))).collect();
+    if !loops_after.is_empty() && loops_before.len() == loops_after.len() {
+        let (mut i, mut j) = (0usize, 0usize);
+        for (&li, &lj) in loops_before.iter().zip(&loops_after) {
+            gap(&before[i..li], &spans[i..li], &after[j..lj], spans[..li].last().map_or(at, |s| s.range.end), out);
+            if let (Statement::Loop(b), Statement::Loop(a)) = (&before[li], &after[lj]) {
+                out.push(Span { range: spans[li].range.clone(), body: realign(b, &spans[li].body, a) });
+            }
+            i = li + 1;
+            j = lj + 1;
+        }
+        let at = spans[..i].last().map_or(at, |s| s.range.end);
+        gap(&before[i..], &spans[i..], &after[j..], at, out);
+        return;
+    }
+
+    let range = match (spans.first(), spans.last()) {
+        (Some(first), Some(last)) => first.range.start..last.range.end,
+        _ => at..at,
+    };
+    out.extend(after.iter().map(|s| covering(s, &range)));
+}
+
+// Merged runs of `+`, `-`, `>` or `<`, each covering the statements it was merged from
+fn refold(before: &[Statement], spans: &[Span], after: &[Statement]) -> Option<Vec<Span>> {
+    // What kind of run a statement can be part of, and how much it adds to it
+    fn amount(s: &Statement) -> Option<(&'static str, u64)> {
+        match s {
+            Statement::Inc(n) => Some(("inc", *n)),
+            Statement::Dec(n) => Some(("dec", *n)),
+            s => s.movement().map(|n| (if n < 0 { "left" } else { "right" }, n.unsigned_abs() as u64)),
+        }
+    }
+
+    let mut out = Vec::with_capacity(after.len());
+    let mut i = 0usize;
+    for s in after {
+        let (kind, want) = amount(s)?;
+        let start = i;
+        let mut total = 0u64;
+        while total < want && i < before.len() && amount(&before[i]).map(|(k, _)| k) == Some(kind) {
+            total += amount(&before[i])?.1;
+            i += 1;
+        }
+        if total != want || i == start {
+            return None;
+        }
+        out.push(Span::leaf(spans[start].range.start..spans[i - 1].range.end));
+    }
+    Some(out).filter(|_| i == before.len())
+}
+
+fn covering(s: &Statement, range: &Range<usize>) -> Span {
+    match s {
+        Statement::Loop(l) => Span { range: range.clone(), body: l.iter().map(|s| covering(s, range)).collect() },
+        _ => Span::leaf(range.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bf::optimize_with;
+    use crate::parser::program_spanned;
+
+    #[test]
+    fn spans_survive_optimizing() {
+        let src = "a +++ b\n[-]>>\n[->+<] c+[,.>]";
+        let (stmts, spans) = program_spanned(src).unwrap();
+        assert_eq!(spans[0].range, 2..3);
+        assert_eq!(spans[3].range, 8..11);
+        assert_eq!(span_at(&spans, &[3, 0]), Some(&(9..10)));
+        assert_eq!(&src[spans.last().unwrap().range.clone()], "[,.>]");
+
+        let (opt, opt_spans) = optimize_spanned(&stmts, &spans, Patterns::default());
+        assert_eq!(opt, optimize_with(&stmts, Patterns::default()));
+        assert_eq!(opt_spans.len(), opt.len());
+
+        let covered: Vec<_> = opt.iter().zip(&opt_spans).map(|(s, span)| (s.name(), &src[span.range.clone()])).collect();
+        assert_eq!(covered, vec![
+            ("inc", "+++"),
+            ("clear", "[-]"),
+            ("move", ">>"),
+            ("add_offset", "[->+<]"),
+            ("clear", "[->+<]"),
+            ("inc", "+"),
+            ("loop", "[,.>]"),
+        ]);
+
+        // The loop body is kept as it was, so its statements keep their own spans
+        let body: Vec<_> = opt_spans[6].body.iter().map(|s| &src[s.range.clone()]).collect();
+        assert_eq!(body, vec![",", ".", ">"]);
+    }
+}
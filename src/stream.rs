@@ -0,0 +1,157 @@
+use std::io::{self, Read};
+use crate::bf::{optimize_with, CellArith, Context, Error, Patterns, Statement};
+
+#[derive(thiserror::Error, Debug)]
+pub enum StreamError {
+    #[error(transparent)]
+    Exec(#[from] Error),
+    #[error("Unmatched `]` at byte {0}")]
+    UnmatchedClose(usize),
+    #[error("Input ended inside the loop opened at byte {0}")]
+    UnclosedLoop(usize),
+}
+
+/// Parses and runs a program as it is read, for programs piped in from elsewhere.
+///
+/// Top-level statements run as soon as they are read; a loop runs (optimized) once its
+/// closing `]` arrives. Everything but the eight commands is a comment, including
+/// directives and `#` breakpoints. The program reads `,` input from `ctx` as usual, so a
+/// program read from stdin should not also take its input from stdin.
+pub fn stream_run<R: Read>(mut r: R, ctx: &mut Context) -> Result<(), StreamError> {
+    // Bodies of the loops still being read, innermost last, with the offsets of their `[`
+    let mut open: Vec<(usize, Vec<Statement>)> = Vec::new();
+    let mut buf = [0u8; 4096];
+    let mut offset = 0usize;
+    // Folding mixed runs and searching for a value are only exact when cells wrap
+    let wrap = ctx.arith() == CellArith::Wrap;
+    let patterns = Patterns { net_fold: wrap, search_value: wrap, ..Patterns::default() };
+
+    loop {
+        let n = match r.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::IO(e).into()),
+        };
+
+        for &b in &buf[..n] {
+            let s = match b {
+                b'+' => Statement::Inc(1),
+                b'-' => Statement::Dec(1),
+                b'>' => Statement::Next(1),
+                b'<' => Statement::Prev(1),
+                b'.' => Statement::Out,
+                b',' => Statement::In,
+                b'[' => {
+                    open.push((offset, Vec::new()));
+                    offset += 1;
+                    continue;
+                }
+                b']' => match open.pop() {
+                    Some((_, body)) => Statement::Loop(body),
+                    None => return Err(StreamError::UnmatchedClose(offset)),
+                },
+                _ => {
+                    offset += 1;
+                    continue;
+                }
+            };
+            offset += 1;
+
+            match open.last_mut() {
+                Some((_, body)) => body.push(s),
+                None if matches!(s, Statement::Loop(_)) => ctx.exec_many(optimize_with(&[s], patterns))?,
+                None => ctx.exec(&s)?,
+            }
+        }
+    }
+
+    match open.first() {
+        Some((start, _)) => Err(StreamError::UnclosedLoop(*start)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bf::output::OutputSink;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // Hands out at most `chunk` bytes per read and counts the reads
+    struct Trickle<'a> {
+        data: &'a [u8],
+        chunk: usize,
+        reads: Rc<Cell<usize>>,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.chunk.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            self.reads.set(self.reads.get() + 1);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn runs_in_small_chunks() {
+        let text = b"hi! ++++++++[>++++++++<-]>+. (just a comment) +.+. <<";
+        let reads = Rc::new(Cell::new(0));
+        let r = Trickle { data: text, chunk: 3, reads: reads.clone() };
+
+        let mut ctx = Context::new();
+        ctx.set_output(OutputSink::Buffer(Vec::new()));
+        stream_run(r, &mut ctx).unwrap();
+        assert_eq!(ctx.output().captured(), Some(&b"ABC"[..]));
+        assert_eq!(&ctx.data()[..2], &[0, 67]);
+        assert!(reads.get() > text.len() / 3);
+    }
+
+    #[test]
+    fn runs_before_input_ends() {
+        // The output is there before the reader gets to the unmatched `]`
+        let mut ctx = Context::new();
+        ctx.set_output(OutputSink::Buffer(Vec::new()));
+        let r = Trickle { data: b"+++++[->+++++++++++++<]>.]", chunk: 2, reads: Rc::default() };
+        assert!(matches!(stream_run(r, &mut ctx), Err(StreamError::UnmatchedClose(25))));
+        assert_eq!(ctx.output().captured(), Some(&b"A"[..]));
+
+        let r = Trickle { data: b"+[[-]", chunk: 2, reads: Rc::default() };
+        assert!(matches!(stream_run(r, &mut Context::new()), Err(StreamError::UnclosedLoop(1))));
+    }
+
+    #[test]
+    fn loops_run_on_the_current_cell() {
+        // Each loop is optimized on its own, but the tape it runs on isn't fresh
+        let mut ctx = Context::new();
+        ctx.set_output(OutputSink::Buffer(Vec::new()));
+        stream_run(&b"+++[.-]"[..], &mut ctx).unwrap();
+        assert_eq!(ctx.output().captured(), Some(&[3, 2, 1][..]));
+
+        let mut ctx = Context::new();
+        stream_run(&b"+>+<[>]"[..], &mut ctx).unwrap();
+        assert_eq!(ctx.idx(), 2);
+    }
+
+    #[test]
+    fn loops_keep_saturating_arithmetic() {
+        // Saturates at 255 on the way up, where the net `+5` wouldn't
+        let text = format!("{}[++++++++++-----.[-]]", "+".repeat(250));
+        let run = |streamed: bool| {
+            let mut ctx = Context::new();
+            ctx.set_arith(CellArith::Saturate);
+            ctx.set_output(OutputSink::Buffer(Vec::new()));
+            if streamed {
+                stream_run(text.as_bytes(), &mut ctx).unwrap();
+            } else {
+                ctx.exec_many(crate::parser::program(&text).unwrap().1).unwrap();
+            }
+            ctx.output().captured().unwrap().to_vec()
+        };
+        assert_eq!(run(false), [250]);
+        assert_eq!(run(true), run(false));
+    }
+}
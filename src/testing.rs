@@ -0,0 +1,181 @@
+use crate::bf::{optimize_with, validate, Context, Error, Patterns, Statement};
+use crate::bf::input::{EofMode, InputSource};
+use crate::bf::output::OutputSink;
+
+/// Steps (see `Context::set_step_limit`) each run in `behaviorally_equal` may take
+pub const MAX_STEPS: u64 = 1_000_000;
+
+/// Whether `a` and `b` behave the same on each of `inputs`: the same output, the same
+/// final tape (ignoring trailing zero cells), and failing in the same way if they fail.
+///
+/// Each run is limited to `MAX_STEPS` statements. If either program hits the limit on
+/// some input they are not considered equal, since the result would be inconclusive.
+pub fn behaviorally_equal(a: &[Statement], b: &[Statement], inputs: &[&[u8]]) -> bool {
+    let mut ctx = Context::new();
+    ctx.set_step_limit(Some(MAX_STEPS));
+
+    inputs.iter().all(|input| {
+        match (run_in(&mut ctx, a, input), run_in(&mut ctx, b, input)) {
+            ((a, false), (b, false)) => a == b,
+            _ => false,
+        }
+    })
+}
+
+/// How each run in `run_multi` is set up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunOptions {
+    pub step_limit: Option<u64>,
+    pub eof: EofMode,
+    /// Optimize with these patterns first, for `run_ir`
+    pub optimize: Option<Patterns>,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions { step_limit: Some(MAX_STEPS), eof: EofMode::default(), optimize: None }
+    }
+}
+
+/// What a program did on one input
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunOutcome {
+    pub output: Vec<u8>,
+    /// The final tape, without trailing zero cells
+    pub tape: Vec<i8>,
+    /// The error the run stopped with, as text
+    pub error: Option<String>,
+}
+
+/// Runs `stmts` on each of `inputs` and collects what each run did, reusing one
+/// `Context` (reset in between) for all of them. Optimize `stmts` first to have that
+/// done once for every input.
+///
+/// Runs are interpreted: compiled code does its I/O on the process's own stdin and
+/// stdout, so its output can't be collected per input.
+pub fn run_multi(stmts: &[Statement], inputs: &[Vec<u8>], opts: RunOptions) -> Vec<RunOutcome> {
+    let mut ctx = Context::new();
+    ctx.set_step_limit(opts.step_limit);
+    ctx.set_eof_mode(opts.eof);
+
+    inputs.iter().map(|input| run_in(&mut ctx, stmts, input).0).collect()
+}
+
+/// Runs IR built by hand, generated or deserialized rather than parsed, on `input`. It
+/// is checked with `validate` first, so a malformed program is an error rather than a
+/// hang or a wrong result, and optimized if `opts.optimize` is set. Failures while
+/// running end up in the outcome, as with `run_multi`.
+pub fn run_ir(stmts: Vec<Statement>, input: &[u8], opts: RunOptions) -> Result<RunOutcome, Error> {
+    validate(&stmts)?;
+    let stmts = match opts.optimize {
+        Some(patterns) => optimize_with(stmts, patterns),
+        None => stmts,
+    };
+
+    let mut ctx = Context::new();
+    ctx.set_step_limit(opts.step_limit);
+    ctx.set_eof_mode(opts.eof);
+    Ok(run_in(&mut ctx, &stmts, input).0)
+}
+
+// Runs `stmts` from a reset `ctx`, also returning whether the step limit was reached
+fn run_in(ctx: &mut Context, stmts: &[Statement], input: &[u8]) -> (RunOutcome, bool) {
+    ctx.reset();
+    ctx.set_input(InputSource::from_bytes(input.to_vec().into_iter()));
+    ctx.set_output(OutputSink::Buffer(Vec::new()));
+
+    let res = ctx.exec_many(stmts);
+    let limited = matches!(res, Err(Error::StepLimit(_)));
+
+    let mut tape = ctx.data().to_vec();
+    while tape.last() == Some(&0) {
+        tape.pop();
+    }
+
+    let outcome = RunOutcome {
+        output: ctx.output().captured().unwrap_or_default().to_vec(),
+        tape,
+        error: res.err().map(|e| e.to_string()),
+    };
+    (outcome, limited)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::program;
+
+    #[test]
+    fn unrolled_copy_loop() {
+        let inputs: &[&[u8]] = &[b"\x00", b"\x03", b"\xff", b"A"];
+        let (_, a) = program(",[->+<]>.").unwrap();
+        // Reads the count one cell over, so the loop has to go the other way
+        let (_, b) = program(">,[-<+>]<[->+<]>.").unwrap();
+        assert!(behaviorally_equal(&a, &b, inputs));
+        assert!(behaviorally_equal(&a, &crate::optimize(&a), inputs));
+
+        let (_, c) = program(",[->++<]>.").unwrap();
+        assert!(!behaviorally_equal(&a, &c, inputs));
+
+        // Never finishes, so nothing can be concluded
+        let (_, d) = program("+[]").unwrap();
+        assert!(!behaviorally_equal(&d, &d, inputs));
+    }
+
+    #[test]
+    fn cat_many_inputs() {
+        let (_, cat) = program(",[.,]").unwrap();
+        let cat = crate::optimize(cat);
+        let inputs = vec![b"hello".to_vec(), Vec::new(), b"a longer line\n".to_vec()];
+        let opts = RunOptions { eof: EofMode::Zero, ..RunOptions::default() };
+
+        let outcomes = run_multi(&cat, &inputs, opts);
+        assert_eq!(outcomes.len(), 3);
+        for (outcome, input) in outcomes.iter().zip(&inputs) {
+            assert_eq!(&outcome.output, input);
+            assert_eq!(outcome.tape, Vec::<i8>::new());
+            assert_eq!(outcome.error, None);
+        }
+
+        // Nothing is left over from the previous run
+        let (_, count) = program(",[>+<,]").unwrap();
+        let outcomes = run_multi(&count, &inputs, opts);
+        let tapes: Vec<_> = outcomes.iter().map(|o| o.tape.clone()).collect();
+        assert_eq!(tapes, vec![vec![0, 5], vec![], vec![0, 14]]);
+
+        // Running out of input is an error by default
+        let outcomes = run_multi(&cat, &inputs[..1], RunOptions::default());
+        assert!(outcomes[0].error.is_some());
+        assert_eq!(outcomes[0].output, b"hello");
+    }
+
+    #[test]
+    fn hand_built_ir() {
+        use crate::Statement::*;
+
+        // Adds 2 to each byte until the terminating zero
+        let prog = vec![
+            In,
+            Loop(vec![Inc(2), Out, Clear, In]),
+            LinearCombine { dest_offset: 1, terms: vec![(1, 0)] },
+        ];
+        let opts = RunOptions { optimize: Some(Patterns::default()), ..RunOptions::default() };
+        let outcome = run_ir(prog, b"HAL\0", opts).unwrap();
+        assert_eq!(outcome.output, b"JCN");
+        assert_eq!(outcome.error, None);
+
+        let bad = vec![Inc(1), Loop(vec![Next(1), SearchZero { stride: 0 }])];
+        match run_ir(bad, b"", RunOptions::default()) {
+            Err(e @ Error::InvalidStatement { .. }) => {
+                assert_eq!(e.to_string(), "Invalid statement at [1, 1]: stride is 0");
+            }
+            other => panic!("expected InvalidStatement, got {:?}", other),
+        }
+
+        let bad = vec![LinearCombine { dest_offset: 2, terms: vec![(1, 1), (3, 2)] }];
+        assert!(matches!(
+            run_ir(bad, b"", RunOptions::default()),
+            Err(Error::InvalidStatement { reason: "a source is the destination", .. })
+        ));
+    }
+}
@@ -0,0 +1,158 @@
+use std::fmt::Write;
+use crate::bf::{wrap_mul, Statement};
+
+/// A Rust function named `fn_name` that runs `stmts`, for embedding a program from a
+/// `build.rs` without an interpreter or LLVM at runtime. The function has the signature
+///
+/// ```text
+/// pub fn fn_name(tape: &mut [u8], input: &mut impl std::io::Read, output: &mut impl std::io::Write)
+///     -> std::io::Result<()>
+/// ```
+///
+/// and only needs `std`. Cells are 8 bits and wrap, moving left of cell 0 clamps as in the
+/// interpreter, and running off the end of `tape` (e.g. a `[u8; 30000]`) panics. `,` at
+/// the end of input fails with `UnexpectedEof`. Asserts are left out, as in the JIT.
+pub fn emit_rust(stmts: &[Statement], fn_name: &str) -> String {
+    let mut out = String::new();
+    writeln!(out, "#[allow(unused_mut, unused_variables, unreachable_code)]").unwrap();
+    writeln!(out, "pub fn {}(tape: &mut [u8], input: &mut impl std::io::Read, output: &mut impl std::io::Write) -> std::io::Result<()> {{", fn_name).unwrap();
+    writeln!(out, "    let mut p = 0usize;").unwrap();
+    emit_block(&mut out, stmts, 1);
+    writeln!(out, "    Ok(())").unwrap();
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+fn emit_block(out: &mut String, stmts: &[Statement], depth: usize) {
+    let indent = "    ".repeat(depth);
+    for s in stmts {
+        let line = match s {
+            Statement::Next(n) => format!("p += {};", n),
+            Statement::Prev(n) => format!("p = p.saturating_sub({});", n),
+            Statement::Move(n) if *n >= 0 => format!("p += {};", n),
+            Statement::Move(n) => format!("p = p.saturating_sub({});", -n),
+            Statement::Inc(n) => format!("tape[p] = tape[p].wrapping_add({});", *n as u8),
+            Statement::Dec(n) => format!("tape[p] = tape[p].wrapping_sub({});", *n as u8),
+            Statement::Out => "output.write_all(&[tape[p]])?;".to_string(),
+            Statement::In => "input.read_exact(std::slice::from_mut(&mut tape[p]))?;".to_string(),
+            Statement::Clear => "tape[p] = 0;".to_string(),
+            Statement::SetConst(n) => format!("tape[p] = {};", *n as u8),
+            Statement::IncAt { offset, amount } => format!("tape[{0}] = tape[{0}].wrapping_add({1});", at(*offset), *amount as u8),
+            Statement::AddOffset { mul, offset } => {
+                let dest = at(*offset);
+                format!("tape[{0}] = tape[{0}].wrapping_add(tape[p].wrapping_mul({1}));", dest, wrap_mul(*mul, 8) as u8)
+            }
+            Statement::MulAddMany { targets } => {
+                let lines: Vec<_> = targets.iter()
+                    .map(|(offset, mul)| format!("tape[{0}] = tape[{0}].wrapping_add(tape[p].wrapping_mul({1}));", at(*offset), wrap_mul(*mul, 8) as u8))
+                    .collect();
+                format!("{}\n{}tape[p] = 0;", lines.join(&format!("\n{}", indent)), indent)
+            }
+            Statement::Copy { offset } => format!("tape[{0}] = tape[{0}].wrapping_add(tape[p]);", at(*offset)),
+            Statement::MoveValue { offset } => format!("tape[{}] = tape[p];\n{}tape[p] = 0;", at(*offset), indent),
+            Statement::LinearCombine { dest_offset, terms } => {
+                let dest = at(*dest_offset);
+                let lines: Vec<_> = terms.iter()
+                    .map(|(mul, offset)| format!(
+                        "tape[{0}] = tape[{0}].wrapping_add(tape[{1}].wrapping_mul({2}));\n{3}tape[{1}] = 0;",
+                        dest, at(*offset), wrap_mul(*mul, 8) as u8, indent,
+                    ))
+                    .collect();
+                lines.join(&format!("\n{}", indent))
+            }
+            Statement::SearchZero { stride } => format!("while tape[p] != 0 {{ {} }}", step(*stride)),
+            Statement::SearchValue { stride, target } => format!("while tape[p] != {} {{ {} }}", *target as u8, step(*stride)),
+            Statement::ClearScan { stride } => format!("while tape[p] != 0 {{ tape[p] = 0; {} }}", step(*stride)),
+            Statement::WriteBlock { stride } => format!("while tape[p] != 0 {{ output.write_all(&[tape[p]])?; {} }}", step(*stride)),
+            Statement::ReadBlock { stride } => {
+                format!("while tape[p] != 0 {{ input.read_exact(std::slice::from_mut(&mut tape[p]))?; {} }}", step(*stride))
+            }
+            Statement::Assert { cell, expected, .. } => format!("// @assert cell{} == {}", cell, expected),
+            Statement::Halt => "return Ok(());".to_string(),
+            // Only the interpreters and the JIT dump the tape
+            Statement::Breakpoint => "// #".to_string(),
+            Statement::InfiniteLoopIfNonZero => "if tape[p] != 0 { loop { std::thread::park(); } }".to_string(),
+            Statement::Loop(l) => {
+                writeln!(out, "{}while tape[p] != 0 {{", indent).unwrap();
+                emit_block(out, l, depth + 1);
+                writeln!(out, "{}}}", indent).unwrap();
+                continue;
+            }
+        };
+        writeln!(out, "{}{}", indent, line).unwrap();
+    }
+}
+
+// The index of the cell at `offset` from the current one
+fn at(offset: isize) -> String {
+    match offset {
+        0 => "p".to_string(),
+        o if o < 0 => format!("p - {}", -o),
+        o => format!("p + {}", o),
+    }
+}
+
+// Moving the pointer by `stride` cells
+fn step(stride: isize) -> String {
+    if stride < 0 {
+        format!("p = p.saturating_sub({});", -stride)
+    } else {
+        format!("p += {};", stride)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::program;
+    use std::process::Command;
+
+    #[test]
+    fn statements_as_rust() {
+        let (_, s) = program("++[->+++<]>[>]<,.").unwrap();
+        let code = emit_rust(&crate::optimize(s), "embedded");
+        assert!(code.contains("pub fn embedded(tape: &mut [u8], "));
+        assert!(code.contains("tape[p + 1] = tape[p + 1].wrapping_add(tape[p].wrapping_mul(3));"));
+        assert!(code.contains("while tape[p] != 0 { p += 1; }"));
+        assert!(code.contains("input.read_exact(std::slice::from_mut(&mut tape[p]))?;"));
+    }
+
+    #[test]
+    fn compiled_output_matches_interpreter() {
+        // Needs a Rust compiler to build the emitted code with
+        let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+        if Command::new(&rustc).arg("--version").output().is_err() {
+            return;
+        }
+
+        let src = "++++++++[>++++++++<-]>+.+.+.>,[.,]<<+++[->>>++++++++<<<]>>>[-<<+>>]<<.>-[-<->]<.";
+        let (_, s) = program(src).unwrap();
+        let s = crate::optimize(s);
+
+        let mut ctx = crate::Context::with_input_iter(b"xyz".to_vec().into_iter());
+        ctx.set_eof_mode(crate::bf::input::EofMode::Zero);
+        ctx.set_output(crate::bf::output::OutputSink::Buffer(Vec::new()));
+        ctx.exec_many(&s).unwrap();
+        let expected = ctx.output().captured().unwrap().to_vec();
+
+        let dir = std::env::temp_dir().join(format!("bf-transpile-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let main_rs = dir.join("main.rs");
+        let exe = dir.join("embedded");
+        // `read_exact` fails at the end of input, so end it with the zero the cat loop stops at
+        let code = format!("{}\nfn main() {{\n    let mut tape = [0u8; 64];\n    \
+            prog(&mut tape, &mut &b\"xyz\\0\"[..], &mut std::io::stdout()).unwrap();\n    \
+            eprint!(\"{{:?}}\", &tape[..8]);\n}}\n", emit_rust(&s, "prog"));
+        std::fs::write(&main_rs, code).unwrap();
+
+        let status = Command::new(&rustc).arg(&main_rs).arg("-o").arg(&exe).status().unwrap();
+        assert!(status.success());
+        let out = Command::new(&exe).output().unwrap();
+        assert!(out.status.success());
+        assert_eq!(out.stdout, expected);
+        let tape: Vec<u8> = (0..8).map(|i| ctx.data().get(i).map_or(0, |&c| c as u8)).collect();
+        assert_eq!(String::from_utf8_lossy(&out.stderr), format!("{:?}", tape));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
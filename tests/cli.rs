@@ -0,0 +1,292 @@
+use std::process::Command;
+
+fn bf() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_bf"))
+}
+
+#[test]
+fn quiet_prints_only_program_output() {
+    let out = bf().args(&["-q", "hello.bf"]).output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "Hello World!\n");
+    assert!(out.stderr.is_empty());
+
+    // Without -q the tape follows the output, and the timing goes to stderr
+    let out = bf().arg("hello.bf").output().unwrap();
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.starts_with("Hello World!\n") && stdout.len() > "Hello World!\n".len());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Compilation took"));
+}
+
+#[test]
+fn bench_optimize_reports_totals() {
+    let out = bf().args(&["bench-optimize", "tests/fixtures/corpus"]).output().unwrap();
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("abc.bf: 27 -> "));
+    assert!(stdout.contains("total: 2 files, 47 -> "));
+}
+
+#[test]
+fn tape_grow_and_fixed_size() {
+    // Needs 100,001 cells, more than the default fixed tape has
+    let dir = std::env::temp_dir().join(format!("bf-cli-tape-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let src = dir.join("far.bf");
+    std::fs::write(&src, format!("{}+++", ">".repeat(100_000))).unwrap();
+    let src = src.to_str().unwrap();
+
+    let out = bf().args(&["-q", "--tape", "grow", src]).output().unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let out = bf().args(&["-q", "--tape-size", "200000", src]).output().unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    // Compiled code keeps a tape this size on the heap
+    let out = bf().args(&["-q", "--jit", "--tape-size", "100000000", src]).output().unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let out = bf().args(&["-q", "--tape", "fixed", "--tape-size", "50000", src]).output().unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("out of bounds at idx: 50000"));
+
+    let out = bf().args(&["-q", "--tape", "grow", "-c", "i16", src]).output().unwrap();
+    assert!(!out.status.success());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn bounds_on_a_fixed_tape() {
+    // Lands back on the last cell after going once around a tape of three
+    let src = std::env::temp_dir().join(format!("bf-cli-bounds-{}.bf", std::process::id()));
+    std::fs::write(&src, format!("<{}>>>.", "+".repeat(65))).unwrap();
+    let src = src.to_str().unwrap();
+
+    let out = bf().args(&["-q", "--tape-size", "3", "--bounds", "wrap", src]).output().unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(out.stdout, b"A");
+
+    let out = bf().args(&["-q", "--tape-size", "3", "--bounds", "trap", src]).output().unwrap();
+    assert!(!out.status.success());
+
+    let out = bf().args(&["-q", "--tape", "grow", "--bounds", "wrap", src]).output().unwrap();
+    assert!(!out.status.success());
+
+    std::fs::remove_file(src).unwrap();
+}
+
+#[test]
+fn unmatched_bracket() {
+    let src = std::env::temp_dir().join(format!("bf-cli-unmatched-{}.bf", std::process::id()));
+    std::fs::write(&src, "+++\n  [>+\n").unwrap();
+    let out = bf().arg(&src).output().unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("unmatched '[' at line 2, col 3"));
+    std::fs::remove_file(&src).unwrap();
+}
+
+#[test]
+fn non_utf8_source() {
+    let mut src = b"\xef\xbb\xbf caf\xe9 \0\n".to_vec();
+    src.extend(std::fs::read("hello.bf").unwrap());
+    let path = std::env::temp_dir().join(format!("bf-cli-latin1-{}.bf", std::process::id()));
+    std::fs::write(&path, &src).unwrap();
+
+    for args in &[&["-q"][..], &["-q", "--check-asserts"][..]] {
+        let out = bf().args(*args).arg(&path).output().unwrap();
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "Hello World!\n");
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn breakpoints() {
+    let path = std::env::temp_dir().join(format!("bf-cli-breakpoint-{}.bf", std::process::id()));
+    std::fs::write(&path, "++>+++ # <.").unwrap();
+
+    let out = bf().args(&["-q", "--breakpoints"]).arg(&path).output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"\x02");
+    assert!(String::from_utf8_lossy(&out.stderr).contains("# ptr 1, cells 0..10: 2 [3] 0"));
+
+    let out = bf().arg("-q").arg(&path).output().unwrap();
+    assert!(out.status.success());
+    assert!(!String::from_utf8_lossy(&out.stderr).contains("# ptr"));
+
+    // Directives and breakpoints together
+    let out = bf().args(&["-q", "--check-asserts", "--breakpoints"]).arg(&path).output().unwrap();
+    assert!(out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("# ptr 1, cells 0..10: 2 [3] 0"));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn list_backends() {
+    let out = bf().arg("--list-backends").output().unwrap();
+    assert!(out.status.success());
+    let listing = String::from_utf8_lossy(&out.stdout);
+    for backend in &["interpreter", "static-i8", "static-i16", "static-i32", "static-i64"] {
+        let line = listing.lines().find(|l| l.split_whitespace().next() == Some(backend)).unwrap();
+        assert_eq!(line.split_whitespace().nth(1), Some("available"), "{}", line);
+    }
+    for backend in &["jit", "hybrid", "native"] {
+        assert!(listing.lines().any(|l| l.starts_with(backend)), "{} missing", backend);
+    }
+}
+
+#[test]
+fn emit_obj_links_with_cc() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    // Needs a working native backend and a C compiler
+    let listing = bf().arg("--list-backends").output().unwrap();
+    let native = String::from_utf8_lossy(&listing.stdout).lines().any(|l| l.starts_with("native") && l.contains(" available "));
+    if !native || Command::new("cc").arg("--version").output().is_err() {
+        eprintln!("skipping emit_obj_links_with_cc: no native backend or no cc");
+        return;
+    }
+
+    let dir = std::env::temp_dir().join(format!("bf-cli-emit-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let src = dir.join("cat.bf");
+    let obj = dir.join("out.o");
+    let rt = dir.join("rt.c");
+    let exe = dir.join("cat");
+    std::fs::write(&src, ",[.,]").unwrap();
+
+    let emit = format!("obj={}", obj.display());
+    let out = bf().args(&["-q", "--emit", &emit]).arg(&src).output().unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    // Stand-in for bfrt
+    std::fs::write(&rt, "#include <stdio.h>\n\
+        void write_char(char c) { putchar(c); }\n\
+        char read_char(void) { int c = getchar(); return c == EOF ? 0 : c; }\n").unwrap();
+    let status = Command::new("cc").arg(&obj).arg(&rt).arg("-o").arg(&exe).status().unwrap();
+    assert!(status.success());
+
+    let mut child = Command::new(&exe)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"hello").unwrap();
+    assert_eq!(child.wait_with_output().unwrap().stdout, b"hello");
+
+    let out = bf().args(&["-q", "--emit", "exe"]).arg(&src).output().unwrap();
+    assert!(!out.status.success());
+
+    // A path that can't be written is an error, not a panic
+    let emit = format!("obj={}", dir.join("missing").join("out.o").display());
+    let out = bf().args(&["-q", "--emit", &emit]).arg(&src).output().unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("can't write") && !stderr.contains("panicked"), "{}", stderr);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn count_steps() {
+    let out = bf().args(&["-q", "--count-steps", "hello.bf"]).output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "Hello World!\n");
+    let optimized = bf().args(&["-q", "-o", "--count-steps", "hello.bf"]).output().unwrap();
+    assert!(optimized.status.success());
+
+    let steps = |out: &std::process::Output| -> u64 {
+        String::from_utf8_lossy(&out.stderr).trim().strip_suffix(" steps").unwrap().parse().unwrap()
+    };
+    assert!(steps(&optimized) < steps(&out));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn timing_json() {
+    let out = bf().args(&["-q", "-o", "--timing-json", "hello.bf"]).output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "Hello World!\n");
+
+    let timings: serde_json::Value = serde_json::from_slice(&out.stderr).unwrap();
+    for key in &["parse_ms", "optimize_ms", "compile_ms", "exec_ms"] {
+        assert!(timings[key].is_i64(), "{} missing from {}", key, timings);
+    }
+}
+
+#[test]
+fn dump_summarizes_optimizing() {
+    let out = bf().args(&["-q", "-o", "-d", "hello.bf"]).output().unwrap();
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.lines().any(|l| l.starts_with("optimized ") && l.contains(" statements: clears=")), "{}", stdout);
+
+    // Nothing to summarize without -o
+    let out = bf().args(&["-q", "-d", "hello.bf"]).output().unwrap();
+    assert!(!String::from_utf8_lossy(&out.stdout).contains("optimized "));
+}
+
+#[test]
+fn raw_byte_output() {
+    let dir = std::env::temp_dir().join(format!("bf-cli-bytes-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let src = dir.join("bytes.bf");
+    std::fs::write(&src, ".+[.+]").unwrap();
+    let src = src.to_str().unwrap();
+
+    for tape in &["fixed", "grow"] {
+        let out = bf().args(&["-q", "--tape", tape, src]).output().unwrap();
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        assert_eq!(out.stdout, (0..=255).collect::<Vec<u8>>(), "on a {} tape", tape);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn eof_modes() {
+    use std::process::Stdio;
+
+    let dir = std::env::temp_dir().join(format!("bf-cli-eof-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let src = dir.join("read.bf");
+    std::fs::write(&src, "+,+.").unwrap();
+    let src = src.to_str().unwrap();
+
+    for (mode, byte) in &[("unchanged", 2u8), ("zero", 1), ("neg1", 0)] {
+        for tape in &["fixed", "grow"] {
+            let out = bf().args(&["-q", "--tape", tape, "--eof", mode, src]).stdin(Stdio::null()).output().unwrap();
+            assert!(out.status.success(), "{}: {}", mode, String::from_utf8_lossy(&out.stderr));
+            assert_eq!(out.stdout, [*byte], "{} on a {} tape", mode, tape);
+        }
+    }
+
+    let out = bf().args(&["-q", "--tape", "grow", src]).stdin(Stdio::null()).output().unwrap();
+    assert!(!out.status.success());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn trace_io_points_at_the_source() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let dir = std::env::temp_dir().join(format!("bf-cli-trace-io-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let src = dir.join("echo.bf");
+    std::fs::write(&src, "echo ,.\n").unwrap();
+
+    let mut child = bf().args(&["-q", "--trace-io", src.to_str().unwrap()])
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+        .spawn().unwrap();
+    child.stdin.take().unwrap().write_all(b"A").unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(out.stdout, b"A");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("read 0x41 at cell 0") && stderr.contains("source bytes 5..6)"), "{}", stderr);
+    assert!(stderr.contains("write 0x41 at cell 0") && stderr.contains("source bytes 6..7)"), "{}", stderr);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,2 @@
+Clear two cells and move a value
++++++[-]>+++<[->+<]>[-]